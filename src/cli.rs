@@ -0,0 +1,436 @@
+use crate::{
+    p2p::node::{OperationConfig, Output},
+    primitives::file::FileID,
+    GeneralError,
+};
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+/// The usage string of the meros binary.
+pub const USAGE: &str = "usage:
+    rust_meros put <path> [--shards N] [--encrypt] [--compress] [--min-nodes N]
+                          [--identity NAME] [--passphrase] [--port PORT]
+    rust_meros get <fileid> <out> [--decrypt] [--min-nodes N]
+                                  [--identity NAME] [--passphrase] [--port PORT]
+    rust_meros serve <port> [--identity NAME] [--passphrase]
+    rust_meros keygen <name>
+    rust_meros rekey <identity>
+
+A get writes the file to <out>, or to the standard output if <out> is -.
+With --passphrase, the identity is kept encrypted with a passphrase read from
+the terminal (a plain text identity is encrypted). An identity that is already
+encrypted always asks for its passphrase.";
+
+/// The flags that take a value (as opposed to switches).
+const VALUE_FLAGS: &[&str] = &["--shards", "--min-nodes", "--identity", "--port"];
+
+/// The default identity used when `--identity` is not given.
+const DEFAULT_IDENTITY: &str = "default";
+
+/// The default number of shards used when `--shards` is not given.
+const DEFAULT_SHARDS: usize = 5;
+
+/// The options shared by every command that runs a node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeArgs {
+    /// The local name of the node's identity on the disk
+    pub identity: String,
+
+    /// The port to listen on (0 picks one)
+    pub port: u16,
+
+    /// Whether the identity is kept encrypted with a passphrase
+    pub passphrase: bool,
+}
+
+/// A command given to the meros binary.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Put a file onto the network.
+    Put {
+        path: PathBuf,
+        shards: usize,
+        encrypt: bool,
+        compress: bool,
+        min_nodes: u16,
+        node: NodeArgs,
+    },
+
+    /// Get a file from the network and write it to `output`.
+    Get {
+        file_id: FileID,
+        output: String,
+        decrypt: bool,
+        min_nodes: u16,
+        node: NodeArgs,
+    },
+
+    /// Run a node that only serves shards.
+    Serve { node: NodeArgs },
+
+    /// Generate a keypair and write it to the disk.
+    Keygen { name: String },
+
+    /// Change the passphrase that an identity is encrypted with. The old and
+    /// new passphrases are read from stdin.
+    Rekey { identity: String },
+}
+
+impl Command {
+    /// The configuration of the network operation described by this command, if
+    /// it describes one.
+    pub fn operation_config(&self) -> Option<OperationConfig> {
+        match self {
+            Command::Put { min_nodes, .. } => Some(OperationConfig {
+                output: Output::Discard,
+                min_nodes: *min_nodes,
+                decrypt: false,
+                wait_for_peers: None,
+                force: false,
+            }),
+            Command::Get {
+                output,
+                decrypt,
+                min_nodes,
+                ..
+            } => Some(OperationConfig {
+                output: Output::from(output.as_str()),
+                min_nodes: *min_nodes,
+                decrypt: *decrypt,
+                wait_for_peers: None,
+                force: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The command line arguments split into positional arguments and flags.
+struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, Option<String>>,
+}
+
+impl Args {
+    /// Split the raw arguments into positional arguments and flags.
+    fn split(args: &[String]) -> Result<Self, GeneralError> {
+        let mut positional = Vec::new();
+        let mut flags = HashMap::new();
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg.clone());
+                continue;
+            }
+
+            let value = if VALUE_FLAGS.contains(&arg.as_str()) {
+                match iter.next() {
+                    Some(v) => Some(v.clone()),
+                    None => {
+                        return Err(GeneralError::new(
+                            format!("{} expects a value", arg).as_str(),
+                        ))
+                    }
+                }
+            } else {
+                None
+            };
+
+            if flags.insert(arg.clone(), value).is_some() {
+                return Err(GeneralError::new(
+                    format!("{} given more than once", arg).as_str(),
+                ));
+            }
+        }
+
+        Ok(Self { positional, flags })
+    }
+
+    /// Make sure that exactly `n` positional arguments and only the `allowed`
+    /// flags were given.
+    fn expect(&self, n: usize, allowed: &[&str]) -> Result<(), GeneralError> {
+        if self.positional.len() != n {
+            return Err(GeneralError::new(
+                format!("expected {} argument(s), got {}", n, self.positional.len())
+                    .as_str(),
+            ));
+        }
+
+        match self.flags.keys().find(|f| !allowed.contains(&f.as_str())) {
+            Some(flag) => Err(GeneralError::new(
+                format!("unknown flag '{}'", flag).as_str(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Check whether a switch was given.
+    fn switch(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    /// Parse the value of a flag, or use the default if it was not given.
+    fn value<T: FromStr>(&self, name: &str, default: T) -> Result<T, GeneralError> {
+        match self.flags.get(name) {
+            Some(Some(v)) => parse_value(name, v),
+            _ => Ok(default),
+        }
+    }
+
+    /// Parse the shared node flags.
+    fn node(&self, port: u16) -> Result<NodeArgs, GeneralError> {
+        Ok(NodeArgs {
+            identity: self.value("--identity", DEFAULT_IDENTITY.to_string())?,
+            port: self.value("--port", port)?,
+            passphrase: self.switch("--passphrase"),
+        })
+    }
+}
+
+/// Parse a single argument value.
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, GeneralError> {
+    value.parse::<T>().map_err(|_| {
+        GeneralError::new(format!("invalid value '{}' for {}", value, name).as_str())
+    })
+}
+
+/// Parse the command line arguments (without the program name) into a command.
+pub fn parse(args: &[String]) -> Result<Command, GeneralError> {
+    let (command, rest) = match args.split_first() {
+        Some(split) => split,
+        None => return Err(GeneralError::new("no command given")),
+    };
+    let args = Args::split(rest)?;
+
+    match command.as_str() {
+        "put" => {
+            args.expect(
+                1,
+                &[
+                    "--shards",
+                    "--encrypt",
+                    "--compress",
+                    "--min-nodes",
+                    "--identity",
+                    "--passphrase",
+                    "--port",
+                ],
+            )?;
+
+            let shards = args.value("--shards", DEFAULT_SHARDS)?;
+            if shards == 0 {
+                return Err(GeneralError::new("--shards must be at least 1"));
+            }
+
+            Ok(Command::Put {
+                path: PathBuf::from(&args.positional[0]),
+                shards,
+                encrypt: args.switch("--encrypt"),
+                compress: args.switch("--compress"),
+                min_nodes: args.value("--min-nodes", 0)?,
+                node: args.node(0)?,
+            })
+        }
+        "get" => {
+            args.expect(
+                2,
+                &[
+                    "--decrypt",
+                    "--min-nodes",
+                    "--identity",
+                    "--passphrase",
+                    "--port",
+                ],
+            )?;
+
+            let file_id = &args.positional[0];
+            if file_id.len() != 2 * crate::crypto::hash::HASH_SIZE {
+                return Err(GeneralError::new(
+                    format!("'{}' is not a valid file id", file_id).as_str(),
+                ));
+            }
+
+            Ok(Command::Get {
+                file_id: FileID::from_hex(file_id).map_err(|_| {
+                    GeneralError::new(
+                        format!("'{}' is not a valid file id", file_id).as_str(),
+                    )
+                })?,
+                output: args.positional[1].clone(),
+                decrypt: args.switch("--decrypt"),
+                min_nodes: args.value("--min-nodes", 0)?,
+                node: args.node(0)?,
+            })
+        }
+        "serve" => {
+            args.expect(1, &["--identity", "--passphrase"])?;
+            let port = parse_value("port", &args.positional[0])?;
+            Ok(Command::Serve {
+                node: args.node(port)?,
+            })
+        }
+        "keygen" => {
+            args.expect(1, &[])?;
+            Ok(Command::Keygen {
+                name: args.positional[0].clone(),
+            })
+        }
+        "rekey" => {
+            args.expect(1, &[])?;
+            Ok(Command::Rekey {
+                identity: args.positional[0].clone(),
+            })
+        }
+        _ => Err(GeneralError::new(
+            format!("unknown command '{}'", command).as_str(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    const FILE_ID: &str =
+        "0101010101010101010101010101010101010101010101010101010101010101";
+
+    #[test]
+    fn test_parse_put() {
+        let cmd = parse(&args(
+            "put a.txt --shards 8 --encrypt --min-nodes 3 --identity alice",
+        ))
+        .unwrap();
+        assert_eq!(
+            cmd,
+            Command::Put {
+                path: PathBuf::from("a.txt"),
+                shards: 8,
+                encrypt: true,
+                compress: false,
+                min_nodes: 3,
+                node: NodeArgs {
+                    identity: "alice".to_string(),
+                    port: 0,
+                    passphrase: false,
+                },
+            }
+        );
+        assert_eq!(cmd.operation_config().unwrap().min_nodes, 3);
+
+        // Defaults
+        match parse(&args("put a.txt")).unwrap() {
+            Command::Put { shards, node, .. } => {
+                assert_eq!(shards, DEFAULT_SHARDS);
+                assert_eq!(node.identity, DEFAULT_IDENTITY);
+            }
+            _ => panic!("expected a put"),
+        }
+    }
+
+    #[test]
+    fn test_parse_get() {
+        let cmd = parse(&args(&format!(
+            "get {} out.txt --decrypt --port 4000",
+            FILE_ID
+        )))
+        .unwrap();
+        match &cmd {
+            Command::Get {
+                file_id,
+                output,
+                decrypt,
+                node,
+                ..
+            } => {
+                assert_eq!(file_id.to_hex(), FILE_ID);
+                assert_eq!(output, "out.txt");
+                assert!(decrypt);
+                assert_eq!(node.port, 4000);
+            }
+            _ => panic!("expected a get"),
+        }
+
+        let config = cmd.operation_config().unwrap();
+        match config.output {
+            Output::Path(path) => assert_eq!(path, PathBuf::from("out.txt")),
+            output => panic!("expected a path, got {:?}", output),
+        }
+        assert!(config.decrypt);
+    }
+
+    #[test]
+    fn test_parse_serve() {
+        assert_eq!(
+            parse(&args("serve 3000 --identity bob --passphrase")).unwrap(),
+            Command::Serve {
+                node: NodeArgs {
+                    identity: "bob".to_string(),
+                    port: 3000,
+                    passphrase: true,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_keygen() {
+        assert_eq!(
+            parse(&args("keygen mykey")).unwrap(),
+            Command::Keygen {
+                name: "mykey".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rekey() {
+        assert_eq!(
+            parse(&args("rekey alice")).unwrap(),
+            Command::Rekey {
+                identity: "alice".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_input() {
+        let bad = [
+            "",
+            "frobnicate",
+            "put",
+            "put a.txt b.txt",
+            "put a.txt --shards",
+            "put a.txt --shards zero",
+            "put a.txt --shards 0",
+            "put a.txt --decrypt",
+            "put a.txt --encrypt --encrypt",
+            "get nothex out.txt",
+            "get abcd out.txt",
+            "serve",
+            "serve notaport",
+            "serve 70000",
+            "keygen",
+            "rekey",
+            "rekey alice --identity bob",
+        ];
+        for b in bad.iter() {
+            assert!(parse(&args(b)).is_err(), "'{}' should not parse", b);
+        }
+    }
+
+    #[test]
+    fn test_parse_too_few_args() {
+        let err = parse(&args("serve --identity bob")).unwrap_err();
+        assert_eq!(err.to_string(), "expected 1 argument(s), got 0");
+
+        let err = parse(&args("get")).unwrap_err();
+        assert_eq!(err.to_string(), "expected 2 argument(s), got 0");
+
+        let err = parse(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "no command given");
+    }
+}
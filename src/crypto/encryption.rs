@@ -3,8 +3,16 @@ use crate::{
     primitives::{file::File, shard::Shard},
     CanSerialize,
 };
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm, Key, Nonce,
+};
 use ecies_ed25519::{decrypt, encrypt, generate_keypair, PublicKey, SecretKey};
-use rand;
+use hkdf::Hkdf;
+use hmac::Hmac;
+use rand::{self, CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::{
     fs::{create_dir_all, File as StdFile},
     io::{Read, Write},
@@ -29,6 +37,44 @@ pub enum KeyType {
 /// A handy shorthand type representing a keypair.
 type Keypair = (SecretKey, PublicKey);
 
+/// A 256-bit key for symmetric (AES-256-GCM) encryption.
+pub type SymmetricKey = [u8; 32];
+
+/// The size of the nonce that prefixes symmetrically encrypted bytes.
+const NONCE_SIZE: usize = 12;
+
+/// How many bytes larger symmetrically encrypted bytes are than the plaintext
+/// (the nonce and the authentication tag).
+pub const SYMMETRIC_OVERHEAD: usize = NONCE_SIZE + 16;
+
+/// The number of bytes that encrypting with ecies adds: the ephemeral public
+/// key, the nonce, and the authentication tag.
+const ECIES_OVERHEAD: usize = 32 + SYMMETRIC_OVERHEAD;
+
+/// How data is encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionScheme {
+    /// Encrypt the data itself to a public key with ecies.
+    Ecies,
+
+    /// Encrypt the data with a random AES-256-GCM key, and encrypt only that
+    /// key to a public key with ecies. Much faster for large data.
+    Aes256Gcm,
+}
+
+/// How the keys that encrypt the shards of some data are derived.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum KeyDerivation {
+    /// The data is encrypted once, as a whole, before it is split into shards.
+    None,
+
+    /// Every shard is encrypted on its own, with a key derived (with
+    /// HKDF-SHA256) from a master key and the index of the shard. Only the
+    /// master key is stored, and a leaked shard key exposes only its shard.
+    /// Requires the `Aes256Gcm` scheme.
+    HkdfPerShard,
+}
+
 /// Write a single key to the disk.
 fn write_key<K>(key: &K, key_type: KeyType) -> Result<(), CryptoError>
 where
@@ -65,8 +111,36 @@ fn write_keypair(
 
 /// Generate a public-private keypair and write to disk with the given name.
 pub fn gen_keypair(name: &str, write: bool) -> Result<Keypair, CryptoError> {
-    let mut csprng = rand::thread_rng();
-    let (priv_key, pub_key) = generate_keypair(&mut csprng);
+    gen_keypair_with(name, write, &mut rand::thread_rng())
+}
+
+/// Generate a public-private keypair from the given source of randomness and
+/// write to disk with the given name.
+pub fn gen_keypair_with<R: CryptoRng + RngCore>(
+    name: &str,
+    write: bool,
+    rng: &mut R,
+) -> Result<Keypair, CryptoError> {
+    let (priv_key, pub_key) = generate_keypair(rng);
+    if write {
+        write_keypair((&priv_key, &pub_key), name)?;
+    }
+    Ok((priv_key, pub_key))
+}
+
+/// Derive a public-private keypair from a 32-byte seed (like one recovered
+/// from a mnemonic) and write to disk with the given name. The seed is the
+/// ed25519 secret key itself, so the same seed always derives the same
+/// keypair, and so the same `PeerId`, unlike `gen_keypair_with`, whose keys
+/// depend on the algorithm of the random number generator.
+pub fn gen_keypair_from_seed(
+    name: &str,
+    seed: &[u8; 32],
+    write: bool,
+) -> Result<Keypair, CryptoError> {
+    let priv_key =
+        SecretKey::from_bytes(seed).map_err(|e| CryptoError::EncryptionError(e))?;
+    let pub_key = PublicKey::from_secret(&priv_key);
     if write {
         write_keypair((&priv_key, &pub_key), name)?;
     }
@@ -134,17 +208,142 @@ pub fn encrypt_bytes(
     key: &PublicKey,
     bytes: &Vec<u8>,
 ) -> Result<Vec<u8>, CryptoError> {
-    let mut csprng = rand::thread_rng();
-    encrypt(key, &bytes, &mut csprng).map_err(|e| CryptoError::EncryptionError(e))
+    encrypt_bytes_with(key, bytes, &mut rand::thread_rng())
+}
+
+/// Encrypt bytes using the given source of randomness.
+pub fn encrypt_bytes_with<R: CryptoRng + RngCore>(
+    key: &PublicKey,
+    bytes: &Vec<u8>,
+    rng: &mut R,
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt(key, &bytes, rng).map_err(|e| CryptoError::EncryptionError(e))
 }
 
 pub fn decrypt_bytes(
     key: &SecretKey,
     bytes: &Vec<u8>,
 ) -> Result<Vec<u8>, CryptoError> {
+    // ecies slices the ephemeral key and nonce off the front of the bytes
+    // without checking that they are there
+    if bytes.len() < ECIES_OVERHEAD {
+        return Err(CryptoError::EncryptionError(
+            ecies_ed25519::Error::DecryptionFailedCiphertextShort,
+        ));
+    }
     decrypt(key, &bytes).map_err(|e| CryptoError::EncryptionError(e))
 }
 
+/// Generate a random symmetric key.
+pub fn gen_symmetric_key() -> SymmetricKey {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Encrypt bytes with AES-256-GCM under a random nonce. The nonce is prepended
+/// to the ciphertext.
+pub fn encrypt_symmetric(
+    key: &SymmetricKey,
+    bytes: &Vec<u8>,
+) -> Result<Vec<u8>, CryptoError> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    encrypt_symmetric_with_nonce(key, &nonce, bytes)
+}
+
+/// Encrypt bytes with AES-256-GCM under a fixed nonce, so that the same key
+/// and bytes always give the same ciphertext. Only safe for a key that
+/// encrypts a single message, like a key from `derive_shard_key`. The output
+/// is decrypted by `decrypt_symmetric`.
+pub fn encrypt_symmetric_once(
+    key: &SymmetricKey,
+    bytes: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt_symmetric_with_nonce(key, &[0u8; NONCE_SIZE], bytes)
+}
+
+fn encrypt_symmetric_with_nonce(
+    key: &SymmetricKey,
+    nonce: &[u8; NONCE_SIZE],
+    bytes: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(nonce), bytes)
+        .map_err(|e| CryptoError::SymmetricEncryptionError(e))?;
+    Ok([&nonce[..], &ciphertext[..]].concat())
+}
+
+/// Decrypt bytes encrypted by `encrypt_symmetric`. Fails if the bytes were
+/// tampered with.
+pub fn decrypt_symmetric(
+    key: &SymmetricKey,
+    bytes: &Vec<u8>,
+) -> Result<Vec<u8>, CryptoError> {
+    if bytes.len() < NONCE_SIZE {
+        return Err(CryptoError::SymmetricEncryptionError(aes_gcm::Error));
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+
+    let cipher = Aes256Gcm::new(Key::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|e| CryptoError::SymmetricEncryptionError(e))
+}
+
+/// How many rounds of PBKDF2 turn a passphrase into a key.
+const PASSPHRASE_ROUNDS: u32 = 100_000;
+
+/// Derive a symmetric key from a passphrase and a random salt with
+/// PBKDF2-HMAC-SHA256, which is slow on purpose so that passphrases are
+/// expensive to guess.
+pub fn passphrase_key(passphrase: &str, salt: &[u8]) -> SymmetricKey {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(
+        passphrase.as_bytes(),
+        salt,
+        PASSPHRASE_ROUNDS,
+        &mut key,
+    );
+    key
+}
+
+/// Derive the key of the shard at `index` from a master key.
+pub fn derive_shard_key(master: &SymmetricKey, index: u32) -> SymmetricKey {
+    let info = [&b"meros shard key"[..], &index.to_be_bytes()[..]].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, master)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Encrypt a symmetric key to a public key with ecies, so that it can travel
+/// with the data it encrypts.
+pub fn wrap_symmetric_key(
+    pub_key: &PublicKey,
+    key: &SymmetricKey,
+) -> Result<Vec<u8>, CryptoError> {
+    encrypt_bytes(pub_key, &key.to_vec())
+}
+
+/// Decrypt a symmetric key wrapped by `wrap_symmetric_key`.
+pub fn unwrap_symmetric_key(
+    priv_key: &SecretKey,
+    wrapped: &Vec<u8>,
+) -> Result<SymmetricKey, CryptoError> {
+    let bytes = decrypt_bytes(priv_key, wrapped)?;
+    if bytes.len() != 32 {
+        return Err(CryptoError::InvalidKey(crate::GeneralError::new(
+            "a wrapped symmetric key must be 32 bytes",
+        )));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
 pub trait CanEncrypt: CanSerialize {
     type D: CanEncrypt;
 
@@ -9,3 +9,35 @@ pub type Hash = [u8; HASH_SIZE];
 pub fn hash_bytes(bytes: Vec<u8>) -> Hash {
     *blake3::hash(&bytes).as_bytes()
 }
+
+/// Calculate the root of a merkle tree over some leaves. Leaves and inner nodes
+/// are hashed with different prefixes, so a leaf can't pose as an inner node,
+/// and a node without a sibling is carried up to the next level as it is.
+pub fn merkle_root(leaves: &[&[u8]]) -> Hash {
+    merkle_root_of_hashes(leaves.iter().map(|leaf| merkle_leaf(leaf)).collect())
+}
+
+/// Hash a single leaf of a merkle tree.
+pub fn merkle_leaf(leaf: &[u8]) -> Hash {
+    hash_bytes([&[0u8][..], leaf].concat())
+}
+
+/// Like `merkle_root`, but over leaves that were already hashed with
+/// `merkle_leaf`, so that the leaves don't have to be held at once.
+pub fn merkle_root_of_hashes(mut level: Vec<Hash>) -> Hash {
+    if level.is_empty() {
+        return hash_bytes(vec![0u8]);
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    hash_bytes([&[1u8][..], &left[..], &right[..]].concat())
+                }
+                _ => pair[0],
+            })
+            .collect();
+    }
+    level[0]
+}
@@ -10,6 +10,7 @@ use std::fmt;
 pub enum CryptoError {
     SerializationError(bincode::Error),
     EncryptionError(ecies_ed25519::Error),
+    SymmetricEncryptionError(aes_gcm::Error),
     IOError(std::io::Error),
     InvalidKey(crate::GeneralError),
 }
@@ -22,30 +23,53 @@ impl fmt::Display for CryptoError {
 
 impl Error for CryptoError {}
 
+/// Build an `InvalidKey` error.
+fn invalid_key<E: fmt::Debug>(what: &str, e: E) -> CryptoError {
+    CryptoError::InvalidKey(crate::GeneralError::new(
+        format!("invalid {}: {:?}", what, e).as_str(),
+    ))
+}
+
 /// Convert an ecies keypair into a libp2p keypair. Annoying.
 pub fn ecies_to_libp2p(
     sk: &ecies_ed25519::SecretKey,
     pk: &ecies_ed25519::PublicKey,
-) -> identity::Keypair {
+) -> Result<identity::Keypair, CryptoError> {
+    if &ecies_ed25519::PublicKey::from_secret(sk) != pk {
+        return Err(CryptoError::InvalidKey(crate::GeneralError::new(
+            "the public key does not belong to the secret key",
+        )));
+    }
+
     // Convert to a dalek key (the internal of a libp2p key)
-    let dalek_pair = ed25519_dalek::Keypair {
-        secret: ed25519_dalek::SecretKey::from_bytes(&sk.to_bytes()).unwrap(),
-        public: ed25519_dalek::PublicKey::from_bytes(&pk.to_bytes()).unwrap(),
-    };
+    let dalek_sk = ed25519_dalek::SecretKey::from_bytes(&sk.to_bytes())
+        .map_err(|e| invalid_key("secret key", e))?;
 
-    let libp2p_sk =
-        identity::ed25519::SecretKey::from_bytes(dalek_pair.secret.to_bytes())
-            .unwrap();
+    let libp2p_sk = identity::ed25519::SecretKey::from_bytes(dalek_sk.to_bytes())
+        .map_err(|e| invalid_key("secret key", e))?;
 
-    identity::Keypair::Ed25519(identity::ed25519::Keypair::from(libp2p_sk)) // libp2p pair
+    Ok(identity::Keypair::Ed25519(
+        identity::ed25519::Keypair::from(libp2p_sk),
+    )) // libp2p pair
 }
 
 /// Convert a ecies public key into a libp2p public key.
-pub fn ecies_pub_to_libp2p(pk: &ecies_ed25519::PublicKey) -> identity::PublicKey {
-    let dalek = ed25519_dalek::PublicKey::from_bytes(&pk.to_bytes()).unwrap();
-    identity::PublicKey::Ed25519(
-        identity::ed25519::PublicKey::decode(&dalek.to_bytes()).unwrap(),
-    )
+pub fn ecies_pub_to_libp2p(
+    pk: &ecies_ed25519::PublicKey,
+) -> Result<identity::PublicKey, CryptoError> {
+    pub_bytes_to_libp2p(&pk.to_bytes())
+}
+
+/// Convert the bytes of an ed25519 public key into a libp2p public key.
+pub fn pub_bytes_to_libp2p(
+    bytes: &[u8],
+) -> Result<identity::PublicKey, CryptoError> {
+    let dalek = ed25519_dalek::PublicKey::from_bytes(bytes)
+        .map_err(|e| invalid_key("public key", e))?;
+    Ok(identity::PublicKey::Ed25519(
+        identity::ed25519::PublicKey::decode(&dalek.to_bytes())
+            .map_err(|e| invalid_key("public key", e))?,
+    ))
 }
 
 #[cfg(test)]
@@ -57,7 +81,7 @@ mod tests {
     fn test_ecies_to_libp2p() {
         let mut csprng = rand::thread_rng();
         let (sk, pk) = ecies_ed25519::generate_keypair(&mut csprng);
-        let kp = match ecies_to_libp2p(&sk, &pk) {
+        let kp = match ecies_to_libp2p(&sk, &pk).unwrap() {
             libp2p::identity::Keypair::Ed25519(key) => key,
             _ => panic!("impossible arm"),
         };
@@ -70,4 +94,163 @@ mod tests {
         println!("{:?}", ecies_pk.to_bytes());
         assert!(pk == ecies_pk);
     }
+
+    #[test]
+    fn test_corrupted_keys() {
+        let mut csprng = rand::thread_rng();
+        let (sk, pk) = ecies_ed25519::generate_keypair(&mut csprng);
+        let (_, other_pk) = ecies_ed25519::generate_keypair(&mut csprng);
+
+        match ecies_to_libp2p(&sk, &other_pk) {
+            Err(CryptoError::InvalidKey(_)) => {}
+            r => panic!("expected an invalid key, got {:?}", r.map(|_| ())),
+        }
+
+        let bytes = pk.to_bytes();
+        assert!(pub_bytes_to_libp2p(&bytes).is_ok());
+        let corrupted: [&[u8]; 3] = [&bytes[..31], &[], &[0xFFu8; 33]];
+        for corrupted in corrupted.iter() {
+            match pub_bytes_to_libp2p(corrupted) {
+                Err(CryptoError::InvalidKey(_)) => {}
+                r => panic!("expected an invalid key, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decrypt_fuzzed() {
+        let (sk, pk) = encryption::gen_keypair("fuzz", false).unwrap();
+        let key = encryption::gen_symmetric_key();
+        let wrapped = encryption::wrap_symmetric_key(&pk, &key).unwrap();
+        let encrypted = encryption::encrypt_symmetric(&key, &vec![7u8; 64]).unwrap();
+
+        // Malformed ciphertext fails to decrypt, however short it is
+        for bytes in crate::test_util::fuzzed(&wrapped, 200) {
+            assert!(encryption::decrypt_bytes(&sk, &bytes).is_err());
+            assert!(encryption::unwrap_symmetric_key(&sk, &bytes).is_err());
+        }
+        for bytes in crate::test_util::fuzzed(&encrypted, 200) {
+            assert!(encryption::decrypt_symmetric(&key, &bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn test_symmetric_encryption() {
+        let key = encryption::gen_symmetric_key();
+        let bytes = vec![7u8; 1000];
+
+        let encrypted = encryption::encrypt_symmetric(&key, &bytes).unwrap();
+        assert_ne!(encrypted[12..].to_vec(), bytes);
+        assert_eq!(
+            encryption::decrypt_symmetric(&key, &encrypted).unwrap(),
+            bytes
+        );
+
+        // The key can travel wrapped to a public key
+        let (sk, pk) = ecies_ed25519::generate_keypair(&mut rand::thread_rng());
+        let wrapped = encryption::wrap_symmetric_key(&pk, &key).unwrap();
+        assert_eq!(
+            encryption::unwrap_symmetric_key(&sk, &wrapped).unwrap(),
+            key
+        );
+    }
+
+    #[test]
+    fn test_derive_shard_key() {
+        let master = encryption::gen_symmetric_key();
+        let keys: Vec<_> = (0..4)
+            .map(|i| encryption::derive_shard_key(&master, i))
+            .collect();
+
+        // Every shard gets its own key, and the same key every time
+        for (i, key) in keys.iter().enumerate() {
+            assert_ne!(key, &master);
+            assert_eq!(key, &encryption::derive_shard_key(&master, i as u32));
+            for other in keys[i + 1..].iter() {
+                assert_ne!(key, other);
+            }
+        }
+
+        let other_master = encryption::gen_symmetric_key();
+        assert_ne!(encryption::derive_shard_key(&other_master, 0), keys[0]);
+    }
+
+    #[test]
+    fn test_symmetric_tampering() {
+        let key = encryption::gen_symmetric_key();
+        let encrypted = encryption::encrypt_symmetric(&key, &vec![1, 2, 3]).unwrap();
+
+        // Flipping any bit (of the nonce, ciphertext, or tag) fails the tag check
+        for i in [0, 12, encrypted.len() - 1].iter() {
+            let mut tampered = encrypted.clone();
+            tampered[*i] ^= 0x01;
+            match encryption::decrypt_symmetric(&key, &tampered) {
+                Err(CryptoError::SymmetricEncryptionError(_)) => {}
+                r => panic!("expected a failed decryption, got {:?}", r),
+            }
+        }
+
+        let other = encryption::gen_symmetric_key();
+        assert!(encryption::decrypt_symmetric(&other, &encrypted).is_err());
+        assert!(
+            encryption::decrypt_symmetric(&key, &encrypted[..5].to_vec()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_seeded_encryption() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let (sk, pk) =
+            encryption::gen_keypair_with("seeded", false, &mut rng).unwrap();
+        let (sk2, pk2) = encryption::gen_keypair_with(
+            "seeded",
+            false,
+            &mut StdRng::seed_from_u64(7),
+        )
+        .unwrap();
+        assert_eq!(sk.to_bytes(), sk2.to_bytes());
+        assert_eq!(pk, pk2);
+
+        let bytes = vec![1u8, 2, 3, 4];
+        let a = encryption::encrypt_bytes_with(
+            &pk,
+            &bytes,
+            &mut StdRng::seed_from_u64(1),
+        )
+        .unwrap();
+        let b = encryption::encrypt_bytes_with(
+            &pk,
+            &bytes,
+            &mut StdRng::seed_from_u64(1),
+        )
+        .unwrap();
+        assert_eq!(a, b);
+        assert_eq!(encryption::decrypt_bytes(&sk, &a).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_keypair_from_seed() {
+        let seed = [42u8; 32];
+        let (sk, pk) =
+            encryption::gen_keypair_from_seed("seed", &seed, false).unwrap();
+        let (sk2, pk2) =
+            encryption::gen_keypair_from_seed("seed", &seed, false).unwrap();
+        assert_eq!(sk.to_bytes(), sk2.to_bytes());
+        assert_eq!(pk, pk2);
+
+        // The identity on the network is stable too
+        let peer_id = |sk, pk| {
+            libp2p::PeerId::from_public_key(
+                ecies_to_libp2p(sk, pk).unwrap().public(),
+            )
+        };
+        assert_eq!(peer_id(&sk, &pk), peer_id(&sk2, &pk2));
+        assert_eq!(sk.to_bytes(), seed);
+
+        let (_, other) =
+            encryption::gen_keypair_from_seed("seed", &[7u8; 32], false).unwrap();
+        assert_ne!(pk, other);
+    }
 }
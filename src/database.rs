@@ -0,0 +1,177 @@
+use crate::CanSerialize;
+use std::{error::Error, marker::PhantomData};
+
+/// A type that can be used as the key of a `TypedTree`.
+pub trait IsKey: CanSerialize<S = Self> {}
+
+/// A type that can be stored as a value of a `TypedTree`.
+pub trait IsValue: CanSerialize<S = Self> {}
+
+impl<T: CanSerialize<S = T>> IsKey for T {}
+impl<T: CanSerialize<S = T>> IsValue for T {}
+
+/// A sled tree whose keys and values are of known types, serialized with
+/// `CanSerialize`.
+#[derive(Clone)]
+pub struct TypedTree<K: IsKey, V: IsValue> {
+    tree: sled::Tree,
+    types: PhantomData<(K, V)>,
+}
+
+impl<K: IsKey, V: IsValue> TypedTree<K, V> {
+    /// Wrap a sled tree.
+    pub fn new(tree: sled::Tree) -> Self {
+        Self {
+            tree,
+            types: PhantomData,
+        }
+    }
+
+    /// Open (or create) the tree called `name` in a database.
+    pub fn open(db: &sled::Db, name: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::new(db.open_tree(name)?))
+    }
+
+    /// Insert a value, returning the value that was there before, if any.
+    pub fn insert(&self, key: &K, value: &V) -> Result<Option<V>, Box<dyn Error>> {
+        match self.tree.insert(key.to_bytes()?, value.to_bytes()?)? {
+            Some(old) => Ok(Some(V::from_bytes(old.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the value of a key, or `None` if the tree doesn't hold the key.
+    pub fn get(&self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        match self.tree.get(key.to_bytes()?)? {
+            Some(bytes) => Ok(Some(V::from_bytes(bytes.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a key, returning its value, if it had one.
+    pub fn remove(&self, key: &K) -> Result<Option<V>, Box<dyn Error>> {
+        match self.tree.remove(key.to_bytes()?)? {
+            Some(old) => Ok(Some(V::from_bytes(old.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check whether the tree holds a key.
+    pub fn contains_key(&self, key: &K) -> Result<bool, Box<dyn Error>> {
+        Ok(self.tree.contains_key(key.to_bytes()?)?)
+    }
+
+    /// Iterate over every entry of the tree, in the order of the serialized
+    /// keys.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(K, V), Box<dyn Error>>> {
+        self.tree
+            .iter()
+            .map(|entry| -> Result<(K, V), Box<dyn Error>> {
+                let (key, value) = entry?;
+                Ok((K::from_bytes(key.to_vec())?, V::from_bytes(value.to_vec())?))
+            })
+    }
+
+    /// The number of entries in the tree.
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    /// Check whether the tree is empty.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Flush the tree to disk.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        self.tree.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::{file::FileID, shard::Shard};
+
+    fn tree() -> TypedTree<FileID, Shard> {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        TypedTree::open(&db, "test").unwrap()
+    }
+
+    fn id(n: u8) -> FileID {
+        FileID::try_from_slice(&[n; 32]).unwrap()
+    }
+
+    fn shard(n: u8) -> Shard {
+        Shard::new_at(vec![n; 4], n as u32, 1_600_000_000)
+    }
+
+    #[test]
+    fn test_insert_get() {
+        let tree = tree();
+        assert_eq!(tree.get(&id(1)).unwrap(), None);
+        assert_eq!(tree.insert(&id(1), &shard(1)).unwrap(), None);
+        assert_eq!(tree.get(&id(1)).unwrap(), Some(shard(1)));
+        assert_eq!(tree.get(&id(2)).unwrap(), None);
+
+        // Inserting again replaces the old value and returns it
+        assert_eq!(tree.insert(&id(1), &shard(2)).unwrap(), Some(shard(1)));
+        assert_eq!(tree.get(&id(1)).unwrap(), Some(shard(2)));
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_contains_key() {
+        let tree = tree();
+        assert!(tree.is_empty());
+        tree.insert(&id(1), &shard(1)).unwrap();
+        assert!(tree.contains_key(&id(1)).unwrap());
+        assert!(!tree.contains_key(&id(2)).unwrap());
+
+        assert_eq!(tree.remove(&id(1)).unwrap(), Some(shard(1)));
+        assert!(!tree.contains_key(&id(1)).unwrap());
+        assert_eq!(tree.remove(&id(1)).unwrap(), None);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_iter() {
+        let tree = tree();
+        for n in &[3, 1, 2] {
+            tree.insert(&id(*n), &shard(*n)).unwrap();
+        }
+        let entries: Vec<(FileID, Shard)> =
+            tree.iter().collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            entries,
+            vec![(id(1), shard(1)), (id(2), shard(2)), (id(3), shard(3))]
+        );
+        assert_eq!(tree.len(), 3);
+    }
+
+    #[test]
+    fn test_corrupt_value() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree("test")
+            .unwrap()
+            .insert(id(1).to_bytes().unwrap(), vec![1u8])
+            .unwrap();
+        let tree: TypedTree<FileID, Shard> = TypedTree::open(&db, "test").unwrap();
+        assert!(tree.get(&id(1)).is_err());
+        assert!(tree.iter().next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_persists_in_db() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree: TypedTree<FileID, Shard> = TypedTree::open(&db, "test").unwrap();
+        tree.insert(&id(1), &shard(1)).unwrap();
+
+        // Another handle to the same tree sees the entry, another tree doesn't
+        let same: TypedTree<FileID, Shard> = TypedTree::open(&db, "test").unwrap();
+        let other: TypedTree<FileID, Shard> = TypedTree::open(&db, "other").unwrap();
+        assert_eq!(same.get(&id(1)).unwrap(), Some(shard(1)));
+        assert_eq!(other.get(&id(1)).unwrap(), None);
+    }
+}
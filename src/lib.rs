@@ -1,8 +1,13 @@
+pub mod cli;
 pub mod common;
 pub mod crypto;
+pub mod database;
 pub mod p2p;
 pub mod primitives;
 
+#[cfg(test)]
+mod test_util;
+
 use std::fmt;
 
 #[derive(Debug)]
@@ -1,75 +1,127 @@
 use async_std;
-use rust_meros::p2p::node::{Node, Operation, OperationConfig};
+use async_std::task;
+use futures::StreamExt;
+use rust_meros::cli::{self, Command};
+use rust_meros::p2p::{
+    config::NodeConfig, identity::Identity, node::Node, progress::PutProgress,
+};
 use rust_meros::{
     crypto::encryption,
     primitives::{file, shard},
 };
-use std::error::Error;
-use std::path::Path;
+use std::{error::Error, fs};
+
+/// Print a prompt and read a passphrase from the terminal, without echoing it.
+fn prompt_passphrase(message: &str) -> Result<String, Box<dyn Error>> {
+    Ok(rpassword::prompt_password_stdout(message)?)
+}
 
-fn test_config() -> OperationConfig {
-    OperationConfig {
-        output_file: "none".to_string(),
-        min_nodes: 0,
-        decompress: false,
-        decrypt: false,
+/// Create the node that a command runs on. Its identity is kept encrypted if
+/// `--passphrase` was given or it already is, and then its passphrase is read
+/// from the terminal.
+fn node(args: &cli::NodeArgs) -> Result<Node, Box<dyn Error>> {
+    if args.passphrase || Identity::is_encrypted(&args.identity) {
+        let passphrase = prompt_passphrase(
+            format!("passphrase of identity '{}': ", args.identity).as_str(),
+        )?;
+        Node::with_passphrase(&args.identity, &passphrase, NodeConfig::default())
+    } else {
+        Node::new(&args.identity)
     }
 }
 
-/// Create a test operation
-fn get_test_operation() -> Operation {
-    let (sk1, pk1) = encryption::gen_keypair("testkey", false).unwrap();
-    let (file, shards) = file::File::new(
-        Path::new("testfile.txt"),
-        shard::ShardConfig::new(5, &pk1),
-        &sk1,
-    )
-    .unwrap();
+/// Run a parsed command.
+async fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match (command.operation_config(), command) {
+        (_, Command::Keygen { name }) => {
+            encryption::gen_keypair(&name, true)?;
+            println!("generated keypair '{}'", name);
+            Ok(())
+        }
 
-    let bytes =
-        shard::Shard::reconstruct(&shards, &shard::ShardConfig::new(5, &pk1), None)
-            .unwrap();
+        (_, Command::Rekey { identity }) => {
+            let old = prompt_passphrase("old passphrase: ")?;
+            let new = prompt_passphrase("new passphrase: ")?;
+            Identity::change_passphrase(&identity, &old, &new)?;
+            println!("changed the passphrase of identity '{}'", identity);
+            Ok(())
+        }
 
-    Operation::PutFile {
-        file_metadata: file,
-        file_bytes: bytes,
-        config: test_config(),
-    }
-}
+        (_, Command::Serve { node }) => {
+            self::node(&node)?.start_listening(node.port).await
+        }
 
-/// Run a test node with a test put operation (this will be replaced with a better
-/// interface, CLI or CL flags)
-async fn run_node() -> Result<(), Box<dyn Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() >= 3 {
-        let mut node = Node::new(args[1].as_str()).unwrap();
+        (
+            Some(config),
+            Command::Put {
+                path,
+                shards,
+                encrypt,
+                compress,
+                node,
+                ..
+            },
+        ) => {
+            // The file is signed with the keypair of the same name as the identity
+            let sk = encryption::load_priv_key(&encryption::KeyType::Private(
+                node.identity.clone(),
+            ))?;
+            let pk = encryption::load_pub_key(&encryption::KeyType::Public(
+                node.identity.clone(),
+            ))?;
 
-        if args.len() == 4 {
-            match args[3].as_str() {
-                "test-put" => node.push_operation(get_test_operation()),
-                "test-sub" => node.push_operation(Operation::TestSub),
-                "test-get" => {
-                    let test_op = get_test_operation();
-                    node.push_operation(test_op.clone());
-                    if let Operation::PutFile { file_metadata, .. } = test_op {
-                        node.push_operation(Operation::GetFile {
-                            file_id: file_metadata.id.clone(),
-                            config: test_config(),
-                        });
+            let mut shard_config = shard::ShardConfig::new(shards, &pk);
+            shard_config.encrypt = encrypt;
+            shard_config.compress = compress;
+            let (file_metadata, _) = file::File::new(&path, shard_config, &sk)?;
+            println!("putting {:?} as {}", path, file_metadata.id.to_hex());
+
+            let mut n = self::node(&node)?;
+            let mut progress =
+                n.put_file_with_progress(file_metadata, fs::read(&path)?, config);
+            task::spawn(async move {
+                while let Some(event) = progress.next().await {
+                    match event {
+                        PutProgress::Sharding => println!("sharding..."),
+                        PutProgress::PublishedMetadata => {
+                            println!("published metadata")
+                        }
+                        PutProgress::ShardSent { index, peer } => {
+                            println!("sent shard {} to {}", index, peer)
+                        }
+                        PutProgress::Complete => println!("done"),
                     }
                 }
-                _ => panic!("unknown command '{}'", args[3]),
-            };
+            });
+            n.start_listening(node.port).await
         }
 
-        return node.start_listening(args[2].parse::<u16>().unwrap()).await;
-    }
-    panic!("must specify an identity and a port");
+        (Some(config), Command::Get { file_id, node, .. }) => {
+            let mut n = self::node(&node)?;
+            n.queue_get_file(file_id, config);
+            n.start_listening(node.port).await
+        }
 
-    Ok(())
+        // Every put and get has an operation config
+        (None, command) => {
+            Err(format!("cannot run {:?}\n\n{}", command, cli::USAGE).into())
+        }
+    }
 }
 
 #[async_std::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    run_node().await
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(command).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
@@ -1 +1,117 @@
+use super::{
+    config::NodeConfig,
+    events::NodeEvents,
+    node::{Node, OperationConfig, OperationId},
+};
+use crate::primitives::file;
+use std::{error::Error, path::Path};
 
+/// A participant of the network that only puts and gets files. A client runs
+/// the same operations as a `Node`, but never stores the shards of its peers,
+/// so it can come and go without taking any shards with it.
+pub struct Client {
+    node: Node,
+}
+
+impl Client {
+    /// Initialize a new client.
+    /// # Arguments
+    /// * `name` - The local name of the client on the disk.
+    pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+        Self::with_config(name, NodeConfig::default())
+    }
+
+    /// Initialize a new client with a custom configuration. The client never
+    /// hosts shards, whatever `config.hosts_shards` says.
+    /// # Arguments
+    /// * `name` - The local name of the client on the disk.
+    /// * `config` - The configuration of the client.
+    pub fn with_config(
+        name: &str,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let config = NodeConfig {
+            hosts_shards: false,
+            ..config
+        };
+        Ok(Self {
+            node: Node::with_config(name, config)?,
+        })
+    }
+
+    /// Initialize a new client that is kept in a directory of its own (see
+    /// `Node::in_dir`). The client never hosts shards.
+    /// # Arguments
+    /// * `dir` - The directory that the client is kept in.
+    /// * `config` - The configuration of the client.
+    pub fn in_dir(dir: &Path, config: NodeConfig) -> Result<Self, Box<dyn Error>> {
+        let config = NodeConfig {
+            hosts_shards: false,
+            ..config
+        };
+        Ok(Self {
+            node: Node::in_dir(dir, config)?,
+        })
+    }
+
+    /// Queue a put of a file onto the network. The put runs once the client
+    /// is started with `run`.
+    pub fn put_file(
+        &mut self,
+        file_metadata: file::File,
+        file_bytes: Vec<u8>,
+        config: OperationConfig,
+    ) -> OperationId {
+        self.node.queue_put_file(file_metadata, file_bytes, config)
+    }
+
+    /// Queue a get of a file from the network, written to `config.output`.
+    /// The get runs once the client is started with `run`.
+    pub fn get_file(
+        &mut self,
+        file_id: file::FileID,
+        config: OperationConfig,
+    ) -> OperationId {
+        self.node.queue_get_file(file_id, config)
+    }
+
+    /// Cancel a put or get. Returns `false` if there is no such operation (or
+    /// it has already finished).
+    pub fn cancel_operation(&mut self, id: OperationId) -> bool {
+        self.node.cancel_operation(id)
+    }
+
+    /// Subscribe to the client's events.
+    pub fn subscribe(&self) -> NodeEvents {
+        self.node.subscribe()
+    }
+
+    /// Connect to the network at a tcp port (`0` for any free port) and run
+    /// the queued operations.
+    pub async fn run(&mut self, port: u16) -> Result<(), Box<dyn Error>> {
+        self.node.start_listening(port).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::node::Output;
+
+    #[test]
+    fn test_queue_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut client = Client::in_dir(dir.path(), NodeConfig::default()).unwrap();
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 0,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+
+        let get = client.get_file(file::FileID::new_at("a", &vec![1], 0), config);
+        assert!(client.cancel_operation(get));
+        assert!(!client.cancel_operation(get));
+    }
+}
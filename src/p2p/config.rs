@@ -0,0 +1,223 @@
+use super::protocol::MAX_MESSAGE_SIZE;
+use super::pubsub::PubsubKind;
+use super::retry::RetryPolicy;
+use super::scoring::{HolderSelection, ScoreWeights};
+use super::MAX_SHARD_PEERS;
+use libp2p::{
+    kad::{record::store::MemoryStoreConfig, KademliaConfig, KademliaStoreInserts},
+    swarm::ConnectionLimits,
+};
+use std::{num::NonZeroUsize, time::Duration};
+
+/// How much larger than the largest record a DHT message may be, to leave
+/// room for the key and the rest of the message
+const PACKET_OVERHEAD: usize = 16 * 1024;
+
+/// The configuration of a node's Kademlia DHT.
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    /// How long a stored record lives (`None` for forever)
+    pub record_ttl: Option<Duration>,
+
+    /// How often stored records are replicated to the closest peers (`None`
+    /// to never replicate them)
+    pub replication_interval: Option<Duration>,
+
+    /// How many peers every record is stored on
+    pub replication_factor: NonZeroUsize,
+
+    /// How many peers a query contacts at once
+    pub parallelism: NonZeroUsize,
+
+    /// How long a query may run
+    pub query_timeout: Duration,
+
+    /// The largest a record (like the metadata of a file) may be, in bytes.
+    /// The metadata of a file with a large shard map can outgrow the default.
+    pub max_record_bytes: usize,
+}
+
+impl DhtConfig {
+    /// The Kademlia configuration of the DHT.
+    pub fn kademlia_config(&self) -> KademliaConfig {
+        let mut config = KademliaConfig::default();
+        config
+            .set_record_ttl(self.record_ttl)
+            .set_replication_interval(self.replication_interval)
+            .set_replication_factor(self.replication_factor)
+            .set_parallelism(self.parallelism)
+            .set_query_timeout(self.query_timeout)
+            .set_max_packet_size(self.max_record_bytes + PACKET_OVERHEAD)
+            // The node stores inbound records itself, to note their files
+            .set_record_filtering(KademliaStoreInserts::FilterBoth);
+        config
+    }
+
+    /// The configuration of the store that holds the node's share of the DHT.
+    pub fn store_config(&self) -> MemoryStoreConfig {
+        MemoryStoreConfig {
+            max_value_bytes: self.max_record_bytes,
+            ..MemoryStoreConfig::default()
+        }
+    }
+}
+
+impl Default for DhtConfig {
+    /// The defaults of libp2p's Kademlia.
+    fn default() -> Self {
+        Self {
+            record_ttl: Some(Duration::from_secs(36 * 60 * 60)),
+            replication_interval: Some(Duration::from_secs(60 * 60)),
+            replication_factor: NonZeroUsize::new(20).expect("20 is not zero"),
+            parallelism: NonZeroUsize::new(3).expect("3 is not zero"),
+            query_timeout: Duration::from_secs(60),
+            max_record_bytes: 65 * 1024,
+        }
+    }
+}
+
+/// The configuration of a node on the Meros network.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// How failed DHT puts should be retried
+    pub put_retry: RetryPolicy,
+
+    /// The maximum number of established inbound connections (`None` for no
+    /// limit)
+    pub max_inbound: Option<u32>,
+
+    /// The maximum number of established outbound connections (`None` for no
+    /// limit)
+    pub max_outbound: Option<u32>,
+
+    /// The maximum number of established connections to a single peer (`None`
+    /// for no limit)
+    pub max_per_peer: Option<u32>,
+
+    /// How long a get may wait for all of a file's shards to arrive
+    pub get_timeout: Duration,
+
+    /// How many gets of a batch (see `Node::get_batch`) run at once. The rest
+    /// of the batch waits for a running get to finish.
+    pub max_concurrent_gets: usize,
+
+    /// How many shard holders that the node isn't connected to may be dialed
+    /// at once. Only the holders of the shards being fetched are dialed.
+    pub max_concurrent_dials: usize,
+
+    /// How many holders of a shard a get requests it from at once. The first
+    /// valid copy to arrive is used.
+    pub get_fan_out: usize,
+
+    /// The order that the holders of a shard are asked for it in. A holder
+    /// that fails to deliver the shard is fallen through to the next one.
+    pub holder_selection: HolderSelection,
+
+    /// How many holders of a shard a get asks for it in all, counting the
+    /// ones fallen through to, before the get fails (`None` for no limit)
+    pub max_holder_attempts: Option<usize>,
+
+    /// How long the holders of a single shard get to deliver it before a get
+    /// moves on to the next holders of that shard (`None` to never move on).
+    /// The whole get is still bounded by `get_timeout`.
+    pub shard_timeout: Option<Duration>,
+
+    /// How often the node publishes its free space to its peers
+    pub heartbeat_interval: Duration,
+
+    /// How often stored shards are scrubbed (checked for corruption)
+    pub scrub_interval: Duration,
+
+    /// How many of the least recently verified shards are checked per scrub
+    pub scrub_batch: usize,
+
+    /// How many peers every shard held by this node should be replicated to in
+    /// the background (`None` to not replicate in the background)
+    pub replication_target: Option<usize>,
+
+    /// How often the replication of the held shards is checked
+    pub replication_interval: Duration,
+
+    /// The publish-subscribe protocol that the shard channel runs over.
+    /// Floodsub is the default, to stay compatible with older nodes.
+    pub pubsub: PubsubKind,
+
+    /// The largest file (in bytes) the node will reconstruct from its shards
+    pub max_reconstruct_bytes: u64,
+
+    /// The configuration of the node's DHT
+    pub dht: DhtConfig,
+
+    /// The largest payload (in bytes) that the node will decode from the
+    /// network: shard messages, pubsub messages, and DHT records. Larger
+    /// payloads are dropped without being decoded.
+    pub max_payload_bytes: usize,
+
+    /// How the trust, latency, and free capacity of peers are weighed against
+    /// each other when choosing which peers to place shards on and request
+    /// shards from
+    pub score_weights: ScoreWeights,
+
+    /// Whether the node keeps a copy of the metadata of every file that it
+    /// stores shards of, so that the file can still be reconstructed from the
+    /// node alone if the file's DHT record is lost
+    pub cache_metadata: bool,
+
+    /// Whether the node stores the shards that its peers send it. A node that
+    /// doesn't (like a `Client`) only puts and gets files, refuses every
+    /// shard, and advertises no free space
+    pub hosts_shards: bool,
+
+    /// The number of threads that CPU-bound shard work (sharding, compression,
+    /// encryption, and erasure coding) runs on, off of the thread that polls
+    /// the swarm (`None` for one per CPU)
+    pub cpu_threads: Option<usize>,
+
+    /// The most peers that the shards of a single put are spread over (the
+    /// candidates that its shards are placed on). It doesn't bound the size of
+    /// the file's metadata: the metadata lists a holder for every shard, so
+    /// that grows with the number of shards instead.
+    pub max_shard_peers: usize,
+}
+
+impl NodeConfig {
+    /// The connection limits the swarm should enforce.
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits::default()
+            .with_max_established_incoming(self.max_inbound)
+            .with_max_established_outgoing(self.max_outbound)
+            .with_max_established_per_peer(self.max_per_peer)
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            put_retry: RetryPolicy::default(),
+            max_inbound: Some(128),
+            max_outbound: Some(128),
+            max_per_peer: Some(2),
+            get_timeout: Duration::from_secs(60),
+            max_concurrent_gets: 8,
+            max_concurrent_dials: 8,
+            get_fan_out: 3,
+            holder_selection: HolderSelection::Score,
+            max_holder_attempts: Some(8),
+            shard_timeout: Some(Duration::from_secs(10)),
+            heartbeat_interval: Duration::from_secs(30),
+            scrub_interval: Duration::from_secs(60 * 60),
+            scrub_batch: 64,
+            replication_target: None,
+            replication_interval: Duration::from_secs(60 * 60),
+            pubsub: PubsubKind::Floodsub,
+            max_reconstruct_bytes: 4 * 1024 * 1024 * 1024,
+            dht: DhtConfig::default(),
+            max_payload_bytes: MAX_MESSAGE_SIZE,
+            score_weights: ScoreWeights::default(),
+            cache_metadata: false,
+            hosts_shards: true,
+            cpu_threads: None,
+            max_shard_peers: MAX_SHARD_PEERS,
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::primitives::file::FileID;
+use futures::channel::mpsc;
+use libp2p::PeerId;
+use std::sync::{Arc, Mutex};
+
+/// An event on the network that a node took part in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeEvent {
+    /// A connection to a peer was established.
+    PeerJoined(PeerId),
+
+    /// The connection to a peer was closed.
+    PeerLeft(PeerId),
+
+    /// A peer was discovered on the local network.
+    PeerDiscovered(PeerId),
+
+    /// A floodsub message arrived.
+    Message { source: PeerId, data: Vec<u8> },
+
+    /// The metadata of a file was stored in the DHT.
+    MetadataPublished(FileID),
+
+    /// A shard sent by another peer was stored.
+    ShardStored { file_id: FileID, index: u32 },
+
+    /// A stored shard was served to a peer that requested it.
+    ShardServed { file_id: FileID, index: u32 },
+
+    /// A shard of a file being fetched arrived from one of its holders.
+    ShardReceived {
+        file_id: FileID,
+        index: u32,
+        holder: PeerId,
+    },
+
+    /// Every shard of a file being fetched has arrived.
+    FileReceived(FileID),
+}
+
+/// The receiving half of a subscription to a node's events.
+pub type NodeEvents = mpsc::UnboundedReceiver<NodeEvent>;
+
+/// Broadcasts a node's events to every subscriber. Clones share subscribers.
+#[derive(Clone, Default)]
+pub struct EventBus(Arc<Mutex<Vec<mpsc::UnboundedSender<NodeEvent>>>>);
+
+impl EventBus {
+    /// Subscribe to every event emitted from now on.
+    pub fn subscribe(&self) -> NodeEvents {
+        let (tx, rx) = mpsc::unbounded();
+        if let Ok(mut subscribers) = self.0.lock() {
+            subscribers.push(tx);
+        }
+        rx
+    }
+
+    /// Emit an event to every subscriber, forgetting the ones that are gone.
+    pub fn emit(&self, event: NodeEvent) {
+        if let Ok(mut subscribers) = self.0.lock() {
+            subscribers.retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::default();
+        let peer = PeerId::random();
+
+        // Events emitted before subscribing aren't received
+        bus.emit(NodeEvent::PeerLeft(peer.clone()));
+        let mut a = bus.subscribe();
+        let b = bus.clone().subscribe();
+        bus.emit(NodeEvent::PeerJoined(peer.clone()));
+
+        assert_eq!(
+            a.try_next().unwrap(),
+            Some(NodeEvent::PeerJoined(peer.clone()))
+        );
+        assert!(a.try_next().is_err());
+
+        // Dropped subscribers are forgotten
+        drop(b);
+        bus.emit(NodeEvent::PeerDiscovered(peer.clone()));
+        assert_eq!(bus.0.lock().unwrap().len(), 1);
+        assert_eq!(
+            futures::executor::block_on(a.next()),
+            Some(NodeEvent::PeerDiscovered(peer))
+        );
+    }
+}
@@ -1,11 +1,9 @@
-use libp2p::{
-    floodsub,
-    kad::{
-        record::{store::MemoryStore, Key},
-        Kademlia, Quorum, Record,
-    },
+use libp2p::kad::{
+    record::{store::MemoryStore, Key},
+    Kademlia, Quorum, Record,
 };
 
+use super::pubsub::Pubsub;
 use crate::primitives::file;
 use crate::CanSerialize;
 
@@ -13,18 +11,20 @@ use crate::CanSerialize;
 // will do this (mainly the kademlia.get_record and kademlia.put_record)
 pub fn handle_stdin_line(
     kademlia: &mut Kademlia<MemoryStore>,
-    floodsub: &mut floodsub::Floodsub,
+    pubsub: &mut Pubsub,
     line: String,
 ) {
     let mut args = line.split(" ");
     match args.next() {
         Some("GET") => {
             let key = match args.next() {
-                Some(key) => {
-                    let raw = file::FileID::from_hex(key)
-                        .expect("could not deserialzie fileid");
-                    Key::new(&raw.to_bytes().unwrap())
-                }
+                Some(key) => match file::FileID::from_hex(key) {
+                    Ok(raw) => Key::new(&raw.to_bytes().unwrap()),
+                    Err(e) => {
+                        eprintln!("invalid file id {}: {:?}", key, e);
+                        return;
+                    }
+                },
                 None => {
                     eprintln!("expected a key");
                     return;
@@ -61,8 +61,8 @@ pub fn handle_stdin_line(
                 .put_record(record, Quorum::One)
                 .expect("Failed to store record locally");
 
-            floodsub.publish(
-                floodsub::Topic::new(super::node::SHARD_CHANNEL),
+            pubsub.publish(
+                super::node::SHARD_CHANNEL,
                 "published a record via stdin".as_bytes(),
             );
         }
@@ -1,7 +1,16 @@
-use crate::{common, GeneralError};
-use libp2p::{identity, PeerId};
+use crate::{common, crypto::encryption, GeneralError};
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
+use rand::RngCore;
 use std::{error::Error, fs, io::Write, path::Path};
 
+/// The file that an identity's keypair is kept in when it is encrypted with a
+/// passphrase: a random salt, then the keypair encrypted with the key derived
+/// from the passphrase and the salt.
+const ENCRYPTED_KEYPAIR: &str = "keypair.enc";
+
+/// The size (in bytes) of the salt of an encrypted keypair
+const SALT_SIZE: usize = 16;
+
 pub struct Identity {
     /// The node's libp2p ed25519 keypair
     pub keypair: identity::Keypair,
@@ -13,9 +22,21 @@ pub struct Identity {
 impl Identity {
     pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
         let path = Path::new(common::DATADIR).join("identities").join(name);
+        if Self::is_encrypted(name) {
+            return Err(Box::new(GeneralError::new(
+                format!("identity {} is encrypted, and needs its passphrase", name)
+                    .as_str(),
+            )));
+        }
+
+        Self::at(&path)
+    }
 
+    /// Load the identity kept in a directory, or create it there if the
+    /// directory doesn't hold one yet. The keypair is kept in plain text.
+    pub fn at(path: &Path) -> Result<Self, Box<dyn Error>> {
         // If the identity already exists, load it from disk
-        if path.exists() {
+        if path.join("keypair").exists() {
             let keypair =
                 identity::Keypair::Ed25519(identity::ed25519::Keypair::decode(
                     &mut fs::read(path.join("keypair"))?,
@@ -29,7 +50,7 @@ impl Identity {
         // If it does not, create it and persist it to disk
         let keypair = identity::Keypair::generate_ed25519();
         let peer_id = PeerId::from_public_key(keypair.public());
-        fs::create_dir_all(&path)?;
+        fs::create_dir_all(path)?;
         if let identity::Keypair::Ed25519(k) = &keypair {
             fs::File::create(&path.join("keypair"))?.write_all(&k.encode())?;
             return Ok(Identity { keypair, peer_id });
@@ -38,4 +59,358 @@ impl Identity {
             details: String::from("error creating identity"),
         }))
     }
+
+    /// Like `new`, but keep the keypair encrypted with a passphrase on the
+    /// disk. An identity that was kept in plain text is encrypted, and its
+    /// plain text copy removed.
+    pub fn with_passphrase(
+        name: &str,
+        passphrase: &str,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = Path::new(common::DATADIR).join("identities").join(name);
+        let keypair = if path.join(ENCRYPTED_KEYPAIR).exists() {
+            read_encrypted_keypair(&path, name, passphrase)?
+        } else {
+            let keypair = match Self::new(name)?.keypair {
+                identity::Keypair::Ed25519(k) => k,
+                _ => {
+                    return Err(Box::new(GeneralError::new(
+                        "error creating identity",
+                    )))
+                }
+            };
+            write_encrypted_keypair(&path, &keypair, passphrase)?;
+            fs::remove_file(path.join("keypair"))?;
+            keypair
+        };
+
+        let keypair = identity::Keypair::Ed25519(keypair);
+        Ok(Identity {
+            peer_id: PeerId::from_public_key(keypair.public()),
+            keypair,
+        })
+    }
+
+    /// Check whether the keypair of an identity is kept encrypted with a
+    /// passphrase (see `with_passphrase`).
+    pub fn is_encrypted(name: &str) -> bool {
+        Path::new(common::DATADIR)
+            .join("identities")
+            .join(name)
+            .join(ENCRYPTED_KEYPAIR)
+            .exists()
+    }
+
+    /// Change the passphrase that the keypair of an identity is encrypted
+    /// with, keeping the keypair. The keypair is decrypted with the `old`
+    /// passphrase, and fails to if it is wrong. The newly encrypted keypair
+    /// replaces the old one atomically, so a crash can't lose the identity.
+    pub fn change_passphrase(
+        name: &str,
+        old: &str,
+        new: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = Path::new(common::DATADIR).join("identities").join(name);
+        if !path.join(ENCRYPTED_KEYPAIR).exists() {
+            return Err(Box::new(GeneralError::new(
+                format!("identity {} isn't encrypted with a passphrase", name)
+                    .as_str(),
+            )));
+        }
+        let keypair = read_encrypted_keypair(&path, name, old)?;
+        write_encrypted_keypair(&path, &keypair, new)
+    }
+}
+
+/// Read the encrypted keypair of the identity at `path`.
+fn read_encrypted_keypair(
+    path: &Path,
+    name: &str,
+    passphrase: &str,
+) -> Result<identity::ed25519::Keypair, Box<dyn Error>> {
+    open_keypair(&fs::read(path.join(ENCRYPTED_KEYPAIR))?, name, passphrase)
+}
+
+/// Decrypt a keypair sealed by `seal_keypair` with its passphrase. `name`
+/// names the identity in the errors.
+pub(crate) fn open_keypair(
+    bytes: &[u8],
+    name: &str,
+    passphrase: &str,
+) -> Result<identity::ed25519::Keypair, Box<dyn Error>> {
+    if bytes.len() < SALT_SIZE {
+        return Err(Box::new(GeneralError::new(
+            format!("the keypair of identity {} is corrupted", name).as_str(),
+        )));
+    }
+    let (salt, ciphertext) = bytes.split_at(SALT_SIZE);
+    let key = encryption::passphrase_key(passphrase, salt);
+    let mut encoded = encryption::decrypt_symmetric(&key, &ciphertext.to_vec())
+        .map_err(|_| {
+            GeneralError::new(
+                format!("wrong passphrase for identity {}", name).as_str(),
+            )
+        })?;
+    Ok(identity::ed25519::Keypair::decode(&mut encoded)?)
+}
+
+/// Encrypt the keypair of the identity at `path` with a passphrase, and
+/// atomically replace its encrypted keypair (if it has one) with it.
+fn write_encrypted_keypair(
+    path: &Path,
+    keypair: &identity::ed25519::Keypair,
+    passphrase: &str,
+) -> Result<(), Box<dyn Error>> {
+    write_sealed_keypair(path, &seal_keypair(keypair, passphrase)?)
+}
+
+/// Encrypt a keypair with the key derived from a passphrase and a random salt,
+/// and return the salt followed by the ciphertext.
+pub(crate) fn seal_keypair(
+    keypair: &identity::ed25519::Keypair,
+    passphrase: &str,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut salt = [0u8; SALT_SIZE];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let key = encryption::passphrase_key(passphrase, &salt);
+    let mut sealed = salt.to_vec();
+    sealed.extend(encryption::encrypt_symmetric(
+        &key,
+        &keypair.encode().to_vec(),
+    )?);
+    Ok(sealed)
+}
+
+/// Atomically replace the encrypted keypair of the identity at `path` (if it
+/// has one) with a keypair sealed by `seal_keypair`.
+pub(crate) fn write_sealed_keypair(
+    path: &Path,
+    sealed: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    // Write the whole keypair next to the old one, then swap them
+    let tmp = path.join(format!("{}.tmp", ENCRYPTED_KEYPAIR));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(sealed)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path.join(ENCRYPTED_KEYPAIR))?;
+    Ok(())
+}
+
+/// The public identity of a node on the network: where it can be dialed, and
+/// its public key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeIdentity {
+    /// The dialable transport address of the node (without its peer id)
+    address: Multiaddr,
+
+    /// The node's peer id, as the node claims it
+    peer_id: PeerId,
+
+    /// The node's public key
+    public_key: identity::PublicKey,
+}
+
+impl NodeIdentity {
+    /// Create the identity of a node, rejecting addresses that can't be dialed.
+    /// An address is dialable if it is an ip (or dns name) followed by either a
+    /// tcp port or a udp port and quic.
+    pub fn new(
+        address: Multiaddr,
+        public_key: identity::PublicKey,
+    ) -> Result<Self, Box<dyn Error>> {
+        let peer_id = PeerId::from_public_key(public_key.clone());
+        Self::advertised(address, peer_id, public_key)
+    }
+
+    /// Create the identity of a node from what the node advertised: its
+    /// address, its peer id, and its public key. Nothing checks that the key
+    /// belongs to the peer id until `verify` is called.
+    pub fn advertised(
+        address: Multiaddr,
+        peer_id: PeerId,
+        public_key: identity::PublicKey,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !is_dialable(&address) {
+            return Err(Box::new(GeneralError::new(
+                format!("{} is not a dialable address", address).as_str(),
+            )));
+        }
+        Ok(Self {
+            address,
+            peer_id,
+            public_key,
+        })
+    }
+
+    /// Check that the node's public key hashes to its peer id, so that the
+    /// node can't pose as another peer with a key of its own.
+    pub fn verify(&self) -> bool {
+        PeerId::from_public_key(self.public_key.clone()) == self.peer_id
+    }
+
+    /// The transport address of the node.
+    pub fn address(&self) -> &Multiaddr {
+        &self.address
+    }
+
+    /// The node's public key.
+    pub fn public_key(&self) -> &identity::PublicKey {
+        &self.public_key
+    }
+
+    /// The node's peer id.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id.clone()
+    }
+
+    /// The key to encrypt data to the node with. Identities that fail
+    /// verification aren't trusted with it.
+    pub fn encryption_key(
+        &self,
+    ) -> Result<ecies_ed25519::PublicKey, Box<dyn Error>> {
+        if !self.verify() {
+            return Err(Box::new(GeneralError::new(
+                format!("the public key of {} is not its own", self.peer_id)
+                    .as_str(),
+            )));
+        }
+        match &self.public_key {
+            identity::PublicKey::Ed25519(key) => {
+                Ok(ecies_ed25519::PublicKey::from_bytes(&key.encode())?)
+            }
+            _ => Err(Box::new(GeneralError::new(
+                "only ed25519 keys can be encrypted to",
+            ))),
+        }
+    }
+
+    /// The full address to dial the node at, including its peer id.
+    pub fn dial_target(&self) -> Multiaddr {
+        self.address
+            .clone()
+            .with(Protocol::P2p(self.peer_id().into()))
+    }
+}
+
+/// Check that an address is an ip (or dns name) followed by a tcp port, or by
+/// a udp port and quic. Port 0 can't be dialed.
+fn is_dialable(address: &Multiaddr) -> bool {
+    let protocols: Vec<Protocol> = address.iter().collect();
+    let host = match protocols.first() {
+        Some(Protocol::Ip4(_)) | Some(Protocol::Ip6(_)) => true,
+        Some(Protocol::Dns(_))
+        | Some(Protocol::Dns4(_))
+        | Some(Protocol::Dns6(_)) => true,
+        _ => false,
+    };
+    let transport = match &protocols[1.min(protocols.len())..] {
+        [Protocol::Tcp(port)] => *port != 0,
+        [Protocol::Udp(port), Protocol::Quic] => *port != 0,
+        _ => false,
+    };
+    host && transport
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_passphrase() {
+        let name = format!("test_passphrase_identity_{}", rand::random::<u32>());
+        let identity = Identity::with_passphrase(&name, "old passphrase").unwrap();
+        let path = Path::new(common::DATADIR).join("identities").join(&name);
+        assert!(!path.join("keypair").exists());
+        assert!(Identity::is_encrypted(&name));
+        assert!(Identity::new(&name).is_err());
+
+        // The same passphrase loads the same identity, and others don't
+        let loaded = Identity::with_passphrase(&name, "old passphrase").unwrap();
+        assert_eq!(loaded.peer_id, identity.peer_id);
+        assert!(Identity::with_passphrase(&name, "wrong").is_err());
+
+        // A wrong old passphrase changes nothing
+        assert!(
+            Identity::change_passphrase(&name, "wrong", "new passphrase").is_err()
+        );
+        Identity::with_passphrase(&name, "old passphrase").unwrap();
+
+        // The new passphrase loads the same keys, and the old one doesn't
+        Identity::change_passphrase(&name, "old passphrase", "new passphrase")
+            .unwrap();
+        let loaded = Identity::with_passphrase(&name, "new passphrase").unwrap();
+        assert_eq!(loaded.peer_id, identity.peer_id);
+        let e = Identity::with_passphrase(&name, "old passphrase")
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("wrong passphrase"));
+        assert!(!path.join("keypair.enc.tmp").exists());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_node_identity() {
+        let public_key = identity::Keypair::generate_ed25519().public();
+        let peer_id = PeerId::from_public_key(public_key.clone());
+
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let node = NodeIdentity::new(address.clone(), public_key.clone()).unwrap();
+        assert_eq!(node.peer_id(), peer_id);
+        assert_eq!(
+            node.dial_target(),
+            format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", peer_id)
+                .parse::<Multiaddr>()
+                .unwrap()
+        );
+
+        let quic: Multiaddr = "/ip6/::1/udp/4001/quic".parse().unwrap();
+        assert!(NodeIdentity::new(quic, public_key.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_node_identity_verify() {
+        let (sk, pk) = crate::test_util::test_keypair();
+        let keypair = crate::crypto::ecies_to_libp2p(&sk, &pk).unwrap();
+        let public_key = keypair.public();
+        let peer_id = PeerId::from_public_key(public_key.clone());
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+
+        // A consistent identity is trusted
+        let node =
+            NodeIdentity::advertised(address.clone(), peer_id, public_key.clone())
+                .unwrap();
+        assert!(node.verify());
+        assert_eq!(node.encryption_key().unwrap(), pk);
+        assert!(NodeIdentity::new(address.clone(), public_key.clone())
+            .unwrap()
+            .verify());
+
+        // A key that doesn't belong to the peer id isn't
+        let other = PeerId::random();
+        let spoofed =
+            NodeIdentity::advertised(address, other.clone(), public_key).unwrap();
+        assert!(!spoofed.verify());
+        assert_eq!(spoofed.peer_id(), other);
+        assert!(spoofed.encryption_key().is_err());
+    }
+
+    #[test]
+    fn test_node_identity_not_dialable() {
+        let public_key = identity::Keypair::generate_ed25519().public();
+        let peer_id = PeerId::from_public_key(public_key.clone());
+
+        for address in [
+            format!("/p2p/{}", peer_id),
+            "/ip4/127.0.0.1".to_string(),
+            "/ip4/127.0.0.1/tcp/0".to_string(),
+            "/ip4/127.0.0.1/udp/4001".to_string(),
+            "/tcp/4001".to_string(),
+        ]
+        .iter()
+        {
+            let address: Multiaddr = address.parse().unwrap();
+            assert!(NodeIdentity::new(address, public_key.clone()).is_err());
+        }
+    }
 }
@@ -1,8 +1,25 @@
 pub mod client;
+pub mod config;
+pub mod events;
 pub mod handler;
 pub mod identity;
 pub mod node;
+pub mod placement;
+pub mod progress;
+pub mod protocol;
+pub mod pubsub;
+pub mod retry;
+pub mod scoring;
+pub mod snapshot;
 pub mod store;
+pub mod store_manager;
+pub mod stream;
+pub mod transport;
+pub mod workers;
 
 /// The maximum number of shards of a singular file.
 pub const MAX_SHARDS: usize = 15;
+
+/// The default maximum number of peers that the shards of a single file are
+/// spread over (see `NodeConfig::max_shard_peers`).
+pub const MAX_SHARD_PEERS: usize = 256;
@@ -1,481 +1,6692 @@
 use crate::CanSerialize;
 use libp2p::{
-    floodsub::{self, Floodsub, FloodsubEvent},
     kad::{
-        record::{store::MemoryStore, Key},
-        Kademlia, KademliaEvent, QueryResult, Quorum, Record,
+        record::{
+            store::{MemoryStore, RecordStore},
+            Key,
+        },
+        GetProvidersError, InboundRequest, Kademlia, KademliaEvent, QueryId,
+        QueryResult, Quorum, Record,
     },
     mdns::{Mdns, MdnsConfig, MdnsEvent},
-    swarm::{NetworkBehaviourEventProcess, SwarmEvent},
-    NetworkBehaviour, PeerId, Swarm,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourEventProcess, SwarmBuilder, SwarmEvent},
+    Multiaddr, NetworkBehaviour, PeerId, Swarm,
 };
 
 use async_std::{io, task};
-use futures::prelude::*;
+use ecies_ed25519::SecretKey;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
 use std::{
     clone::Clone,
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
+    fs, iter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use super::identity::Identity;
+use super::config::{DhtConfig, NodeConfig};
+use super::identity::{self, Identity};
+use super::placement;
+use super::progress::{
+    self, ProgressSink, ProgressStream, PutProgress, PutReceipt, ReceiptCollector,
+    ReceiptSink, ReceiptStream,
+};
+use super::protocol::{self, Heartbeat, ShardCodec, ShardMessage, ShardProtocol};
+use super::pubsub::{Pubsub, PubsubEvent};
+use super::retry::RetryTracker;
+use super::scoring::{HolderSelection, PeerScores};
+use super::snapshot::{SavedOperation, Snapshot, SNAPSHOT_VERSION};
 use super::store::ShardStore;
+use super::store_manager::{PlacementPolicy, StoreManager};
+use super::stream::{self, BatchItem, ShardCollector, ShardSink, ShardStream};
+use super::transport;
+use super::workers::ShardWorkers;
 use crate::{
+    common::DATADIR,
+    crypto::encryption,
     primitives::{file, shard},
     GeneralError,
 };
 
-/// The floodsub topic string where shards are exchanged
+/// The pubsub topic string where shards are exchanged
 pub(super) const SHARD_CHANNEL: &str = "shard_channel";
 
-/// The main network behavior for the Meros protocol.
-#[derive(NetworkBehaviour)]
-struct MerosBehavior {
-    /// The main Kademlia DHT, which stores metadata to files and shards
-    kademlia: Kademlia<MemoryStore>,
+/// The pubsub topic string where nodes publish their heartbeats
+pub(super) const STATUS_CHANNEL: &str = "status_channel";
 
-    /// Mdns instance for peer discovery
-    mdns: Mdns,
+/// The latest heartbeat of each peer, shared by a node and its swarm.
+type Capacities = Arc<Mutex<HashMap<PeerId, Heartbeat>>>;
+
+/// The latest round trip time measured to each peer, shared by a node and its
+/// swarm.
+type Latencies = Arc<Mutex<HashMap<PeerId, Duration>>>;
+
+/// The receipt of the latest successful put of each file, shared by a node and
+/// its swarm.
+type Receipts = Arc<Mutex<HashMap<file::FileID, PutReceipt>>>;
+
+/// The files whose metadata records have passed through the local DHT store,
+/// shared by a node and its swarm.
+type DhtFiles = Arc<Mutex<HashSet<file::FileID>>>;
+
+/// A count that is only an estimate, such as a count over a node's local view
+/// of the network.
+pub type Estimate = usize;
+
+/// The name of the store a node is created with
+pub const PRIMARY_STORE: &str = "primary";
+
+/// The name of the file in a bundle directory that holds the file's manifest.
+/// Each shard is next to it, in a file named `shard_<index>`.
+const BUNDLE_MANIFEST: &str = "manifest";
 
-    /// Floodsub for communicating shard data
-    floodsub: Floodsub,
+/// The result of a metadata lookup by `Node::get_metadata`.
+#[derive(Debug, Clone)]
+pub struct FileMetadata {
+    /// The metadata of the file
+    pub file: file::File,
+
+    /// The owner of the file, or `None` if the file is public
+    pub owner: Option<PeerId>,
 }
 
-impl MerosBehavior {
-    /// Get a list of the alive peers in the DHT.
-    pub fn get_online_peers(&mut self) -> Vec<PeerId> {
-        let mut nodes: Vec<PeerId> = Vec::new();
-        let buckets = self.kademlia.kbuckets();
-        for bucket in buckets {
-            for node in bucket.iter() {
-                let id = node.to_owned().node.key.into_preimage();
-                if !nodes.contains(&id) {
-                    nodes.push(id);
-                }
-            }
-        }
-        nodes
-    }
+/// Where the result of a metadata lookup is sent.
+pub type MetadataSink = mpsc::UnboundedSender<Result<file::File, GeneralError>>;
 
-    /// Say hi
-    pub fn hi(&mut self) {
-        self.floodsub
-            .publish(floodsub::Topic::new(SHARD_CHANNEL), "hi".as_bytes());
-    }
+/// Where the result of a shard holder lookup is sent.
+pub type HolderSink = mpsc::UnboundedSender<Result<Vec<PeerId>, GeneralError>>;
+
+/// Where the result of a reconstruction feasibility check is sent.
+pub type FeasibilitySink = mpsc::UnboundedSender<Result<Feasibility, GeneralError>>;
+
+/// Where the round trip time of a ping is sent.
+pub type PingSink = mpsc::UnboundedSender<Result<Duration, GeneralError>>;
+
+/// Whether a file can currently be reconstructed from the shards that are
+/// reachable on the network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Feasibility {
+    /// The number of shards of the file that have at least one holder
+    pub reachable: usize,
+
+    /// The number of shards needed to reconstruct the file. With parity
+    /// shards, any shards of the file (data or parity) can make up this number.
+    pub required: usize,
 }
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for MerosBehavior {
-    /// Upon an Mdns event
-    fn inject_event(&mut self, event: MdnsEvent) {
-        // Add the discovered peers to the dht and remove stale peers
-        match event {
-            MdnsEvent::Discovered(discovered_peers) => {
-                for (peer_id, multiaddr) in discovered_peers {
-                    self.kademlia.add_address(&peer_id, multiaddr);
-                    self.floodsub.add_node_to_partial_view(peer_id);
-                    println!("found peer {:?}", peer_id);
-                }
-            }
-            MdnsEvent::Expired(expired_peers) => {
-                for (peer_id, _) in expired_peers {
-                    self.kademlia.remove_peer(&peer_id);
-                    self.floodsub.remove_node_from_partial_view(&peer_id);
-                    println!("removed peer {:?}", peer_id);
-                }
-            }
-        }
+impl Feasibility {
+    /// Check whether enough shards are reachable to reconstruct the file.
+    pub fn is_possible(&self) -> bool {
+        self.reachable >= self.required
     }
 }
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for MerosBehavior {
-    /// Upon a floodsub event
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        match event {
-            FloodsubEvent::Message(msg) => {
-                println!(
-                    "\n=========\nreceived msg: {:?} from {:?}\n========\n",
-                    String::from_utf8_lossy(&msg.data),
-                    msg.source
-                );
+/// The health of a node, for liveness and readiness probes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// Whether the node is listening on the network
+    pub listening: bool,
 
-                //std::process::Command::new("brave").output().expect("err"); // lolll
-            }
-            _ => println!("FLOODSUB EVENT: {:?}", event),
-        };
-    }
-}
+    /// The number of peers that the node is connected to
+    pub connected_peers: usize,
 
-impl NetworkBehaviourEventProcess<KademliaEvent> for MerosBehavior {
-    /// Upon a Kademlia event
-    fn inject_event(&mut self, event: KademliaEvent) {
-        match event {
-            // If the event is a query
-            KademliaEvent::OutboundQueryCompleted { result, .. } => {
-                match result {
-                    // If the query is a GET
-                    QueryResult::GetRecord(Ok(ok)) => {
-                        for query in ok.records {
-                            println!(
-                                "KAD EVENT: got record {:?} {:?}",
-                                query.record.key.as_ref(),
-                                &query.record.value
-                            );
+    /// Whether every shard store of the node can be written to
+    pub store_writable: bool,
 
-                            let f = file::File::from_bytes(query.record.value)
-                                .expect("corrupted file::File bytes from store");
+    /// The number of operations waiting to be run
+    pub pending_ops: usize,
+}
 
-                            println!("file: {:?}", f);
+impl HealthStatus {
+    /// Check whether the node is ready to store and serve shards.
+    pub fn is_ready(&self) -> bool {
+        self.listening && self.store_writable
+    }
+}
 
-                            let read_node =
-                                PeerId::from_bytes(&f.shards()[0]).unwrap();
+/// A cloneable handle on the health of a node. Unlike the node itself, it can
+/// be checked from another task or thread while the node is running (like by
+/// the server of a probe). The stores of the node stay open for as long as a
+/// handle on it lives.
+#[derive(Clone)]
+pub struct HealthHandle {
+    /// The health of the node as of its latest change
+    status: Arc<Mutex<HealthStatus>>,
 
-                            self.hi();
+    /// The shard stores of the node, which are probed on every check
+    stores: Arc<Mutex<StoreManager>>,
+}
 
-                            println!("shard node: {:?}", read_node);
-                        }
-                    }
-                    // If the query is a failed GET
-                    QueryResult::GetRecord(Err(err)) => {
-                        eprintln!("failed to get record: {:?}", err);
-                    }
+impl HealthHandle {
+    fn new(stores: StoreManager) -> Self {
+        HealthHandle {
+            status: Arc::new(Mutex::new(HealthStatus {
+                listening: false,
+                connected_peers: 0,
+                store_writable: true,
+                pending_ops: 0,
+            })),
+            stores: Arc::new(Mutex::new(stores)),
+        }
+    }
 
-                    // If the query is a PUT
-                    QueryResult::PutRecord(Ok(ok)) => {
-                        println!(
-                            "KAD EVENT: put record {:?}",
-                            file::FileID::from_bytes(ok.key.as_ref().into())
-                                .expect("corrupted fileid")
-                        );
-                    }
+    /// Report the health of the node: whether it is listening, how many peers
+    /// it is connected to, whether its shard stores can be written to, and
+    /// how many operations are waiting to be run.
+    pub fn status(&self) -> HealthStatus {
+        let mut status = match self.status.lock() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        status.store_writable = self
+            .stores
+            .lock()
+            .map_or(false, |stores| stores.is_writable());
+        status
+    }
 
-                    // If the query is a failed PUT
-                    QueryResult::PutRecord(Err(err)) => {
-                        eprintln!("KAD EVENT: failed to put record: {:?}", err);
-                    }
+    /// Check whether the node is listening, without probing its stores.
+    fn is_listening(&self) -> bool {
+        self.status.lock().map_or(false, |status| status.listening)
+    }
 
-                    _ => {}
-                }
-            }
-            _ => {}
+    /// Change the health of the node.
+    fn update<F: FnOnce(&mut HealthStatus)>(&self, change: F) {
+        if let Ok(mut status) = self.status.lock() {
+            change(&mut status);
+        }
+    }
+
+    /// Replace the shard stores that are probed, after they change.
+    fn set_stores(&self, stores: StoreManager) {
+        if let Ok(mut probed) = self.stores.lock() {
+            *probed = stores;
         }
     }
 }
 
-/// A node on the Meros network. A Node stores and broadcasts shards on the network
-/// to host files.
-pub struct Node {
-    /// The node's identity and private key on the network (keypair and peer id)
-    identity: Identity,
+/// A reconstruction feasibility check whose shard holder lookups are in flight.
+struct FeasibilityCheck {
+    sink: FeasibilitySink,
+    required: usize,
+    reachable: usize,
+    outstanding: usize,
+}
 
-    /// The collection of shards that this node holds. This is a map from
-    /// fileIDs to a Vec of shards, using sled db.
-    shards: ShardStore, // Make Arc<RwLock<>>
+/// How often the node wakes up to run its timed maintenance (like retries)
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
 
-    /// This node's list of pending operations.
-    pending_ops: Vec<Operation>, // Make Arc<RwLock<>>
+/// How many of the peers that win a shard under rendezvous hashing compete on
+/// their score to hold it
+const PLACEMENT_CANDIDATES: usize = 3;
+
+/// A check of how many peers hold a shard of a file, which should be held by
+/// `target` peers.
+struct ReplicaCheck {
+    file_id: file::FileID,
+    index: u32,
+    shard_id: shard::ShardID,
+    target: usize,
 }
 
-/// An operation that a node on the network can perform. This enum will
-/// grow as features on the network grow.
-#[derive(Clone)]
-pub enum Operation {
-    /// Store a file on the network. Also sends the shards to all other nodes.
-    PutFile {
-        file_metadata: file::File,
-        file_bytes: Vec<u8>,
-        config: OperationConfig,
-    },
+/// The main network behavior for the Meros protocol.
+#[derive(NetworkBehaviour)]
+struct MerosBehavior {
+    /// The main Kademlia DHT, which stores metadata to files and shards
+    kademlia: Kademlia<MemoryStore>,
 
-    /// Poll all the necessary nodes to get a file from the network.
-    GetFile {
-        file_id: file::FileID,
-        config: OperationConfig,
-    },
+    /// Mdns instance for peer discovery
+    mdns: Mdns,
 
-    /// Send a test floodsub msg.
-    TestSub,
-}
+    /// Floodsub or gossipsub for communicating shard data
+    pubsub: Pubsub,
 
-/// Parameters for a client operation on the network.
-#[derive(Debug, Clone)]
-pub struct OperationConfig {
-    /// Output location for a get file request on the disk
-    pub output_file: String,
+    /// The request-response protocol that shards are sent and requested over
+    shard_protocol: RequestResponse<ShardCodec>,
 
-    /// Minimum number of nodes that the operation must contact to be valid.
-    pub min_nodes: u16,
+    /// The DHT puts that are in flight or waiting to be retried
+    #[behaviour(ignore)]
+    put_retries: RetryTracker<QueryId, Record>,
 
-    /// Should the output be automatically decompressed.
-    pub decompress: bool,
+    /// The id of the local node
+    #[behaviour(ignore)]
+    local_peer_id: PeerId,
 
-    /// Should the output be automatically decrypted.
-    pub decrypt: bool,
-}
+    /// Where the node's events are broadcast
+    #[behaviour(ignore)]
+    events: EventBus,
 
-impl Node {
-    /// Initialize a new node.
-    /// # Arguments
-    /// * `name` - The local name of the node on the disk.
-    pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
-        Ok(Node {
-            identity: Identity::new(name)?,
-            shards: ShardStore::new(name)?,
-            pending_ops: Vec::new(),
-        })
-    }
+    /// The node's local shard storage, used to serve shard requests
+    #[behaviour(ignore)]
+    store: StoreManager,
 
-    /// Push a network operation to this node's stack of operations.
-    pub fn push_operation(&mut self, op: Operation) {
-        self.pending_ops.push(op);
-    }
+    /// The gets whose shards are still arriving
+    #[behaviour(ignore)]
+    pending_gets: HashMap<file::FileID, ShardCollector>,
 
-    /// Start listening on a node
-    pub async fn start_listening(
-        &mut self,
-        port: u16,
-    ) -> Result<(), Box<dyn Error>> {
-        // Build the swarm
-        let transport =
-            libp2p::development_transport(self.identity.keypair.clone()).await?;
+    /// The gets whose files are written to an output once they arrive
+    #[behaviour(ignore)]
+    pending_outputs: HashMap<file::FileID, PendingOutput>,
 
-        let shard_channel = floodsub::Topic::new(SHARD_CHANNEL);
+    /// The gets of batches that are waiting for fewer gets to be running
+    #[behaviour(ignore)]
+    queued_gets: VecDeque<(file::FileID, ShardSink)>,
 
-        let mut swarm = {
-            let kademlia = {
-                let store = MemoryStore::new(self.identity.peer_id.clone());
-                Kademlia::new(self.identity.peer_id.clone(), store)
-            };
-            let mdns = task::block_on(Mdns::new(MdnsConfig::default()))?;
-            let floodsub = Floodsub::new(self.identity.peer_id.clone());
-            let mut behavior = MerosBehavior {
-                kademlia,
-                mdns,
-                floodsub,
-            };
+    /// How many gets of batches may run at once
+    #[behaviour(ignore)]
+    max_concurrent_gets: usize,
 
-            if behavior.floodsub.subscribe(shard_channel.clone()) == true {
-                println!("SUBSCRIBED SUCCESSFULLY");
-            } else {
-                println!("did not subscribe");
-            }
+    /// How long a get may wait for all of its shards
+    #[behaviour(ignore)]
+    get_timeout: Duration,
 
-            Swarm::new(transport, behavior, self.identity.peer_id.clone())
-        };
+    /// The metadata lookups that are waiting on the DHT
+    #[behaviour(ignore)]
+    pending_metadata: HashMap<QueryId, MetadataSink>,
 
-        let mut stdin = io::BufReader::new(io::stdin()).lines();
+    /// The shard holder lookups that are waiting on the DHT
+    #[behaviour(ignore)]
+    pending_holders: HashMap<QueryId, HolderSink>,
 
-        // Start listening on this node
-        Swarm::listen_on(&mut swarm, format!("/ip4/0.0.0.0/tcp/{}", port).parse()?)?;
+    /// The number of corrupted records that were received from the DHT
+    #[behaviour(ignore)]
+    corrupt_records: u64,
 
-        // Construct the future for handling lines from stdin
-        let mut listening = false;
-        let mut hi = false;
-        let fut = future::poll_fn(move |cx: &mut Context<'_>| {
-            loop {
-                if hi == false {
-                    swarm.behaviour_mut().hi();
-                    println!("\n\n SAYING HI \n\n");
-                    hi = true;
-                }
+    /// The metadata lookups of files whose replication is being checked, and
+    /// how many peers should hold each shard
+    #[behaviour(ignore)]
+    pending_replications: HashMap<QueryId, usize>,
 
-                // If this node has pending operations, execute them
-                if self.pending_ops.len() != 0 {
-                    let result = match self.pending_ops[0].clone() {
-                        Operation::PutFile {
-                            file_metadata,
-                            file_bytes,
-                            config,
-                        } => self.put_file(
-                            &mut swarm,
-                            file_metadata,
-                            file_bytes.to_vec(),
-                            &config,
-                        ),
-                        Operation::GetFile { file_id, config } => {
-                            self.get_file(&mut swarm, &file_id, &config)
-                        }
-                        Operation::TestSub => self.test_sub(&mut swarm),
-                        _ => Ok(()),
-                    };
+    /// The holder lookups of shards whose replication is being checked
+    #[behaviour(ignore)]
+    pending_replica_checks: HashMap<QueryId, ReplicaCheck>,
 
-                    match result {
-                        Ok(_) => {
-                            println!("successfully executed operation");
-                            self.pending_ops.remove(0);
-                        }
-                        Err(e) => println!("error executing operation: {:?}", e),
-                    }
-                }
+    /// The shards being fetched from a holder to be copied to more peers
+    #[behaviour(ignore)]
+    pending_copies: HashMap<(file::FileID, u32), Vec<PeerId>>,
 
-                /* testing with just stdin for sanity */
-                loop {
-                    match stdin.try_poll_next_unpin(cx)? {
-                        Poll::Ready(Some(line)) => {
-                            let sb = swarm.behaviour_mut();
-                            super::handler::handle_stdin_line(
-                                &mut sb.kademlia,
-                                &mut sb.floodsub,
-                                line,
-                            )
-                        }
-                        Poll::Ready(None) => println!("stdin broken"),
-                        Poll::Pending => break,
-                    }
-                }
+    /// The shards that this node dropped for repair and is fetching back from
+    /// a holder, by the id they should have
+    #[behaviour(ignore)]
+    pending_repairs: HashMap<(file::FileID, u32), shard::ShardID>,
 
-                /* --- This can be left alone --- */
+    /// The holder lookups of shards that are being collected by a get
+    #[behaviour(ignore)]
+    pending_races: HashMap<QueryId, (file::FileID, u32)>,
 
-                // Then poll the swarm for an event
-                match swarm.poll_next_unpin(cx) {
-                    Poll::Ready(Some(event)) => match event {
-                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
-                            println!("peer joined: {:?}", peer_id);
-                        }
+    /// How many holders of a shard a get requests it from at once
+    #[behaviour(ignore)]
+    get_fan_out: usize,
 
-                        SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                            println!("peer left: {:?}", peer_id)
-                        }
+    /// How long the holders of a shard get to deliver it before a get asks the
+    /// next ones
+    #[behaviour(ignore)]
+    shard_timeout: Option<Duration>,
 
-                        _ => println!("swarm event: {:?}", event),
-                    },
-                    Poll::Ready(None) => return Poll::Ready(Ok(())),
-                    Poll::Pending => {
-                        if !listening {
-                            if let Some(addr) = Swarm::listeners(&swarm).next() {
-                                println!("listening on {:?}", addr);
-                                listening = true;
-                            }
-                        }
-                        break;
-                    }
-                }
-            }
-            Poll::Pending
-        });
+    /// The order that the holders of a shard are asked for it in
+    #[behaviour(ignore)]
+    holder_selection: HolderSelection,
 
-        task::block_on(fut)
-    }
+    /// How many holders of a shard a get asks for it in all before it gives
+    /// up on the shard
+    #[behaviour(ignore)]
+    max_holder_attempts: Option<usize>,
 
-    /// Core node operation to put a file onto the network.
-    fn put_file(
-        &mut self,
-        swarm: &mut Swarm<MerosBehavior>,
-        mut file_metadata: file::File,
-        file_bytes: Vec<u8>,
-        _: &OperationConfig,
-    ) -> Result<(), Box<dyn Error>> {
-        /*
-           1. Find online peers, get their peerIDs, and modify the metadata to
-           include the shard locations
+    /// The shard and file that each shard request of a get is for
+    #[behaviour(ignore)]
+    pending_shard_requests: HashMap<RequestId, (file::FileID, u32)>,
 
-           2. Put the metadata into the DHT
+    /// The metadata lookups of files whose reconstruction feasibility is being
+    /// checked
+    #[behaviour(ignore)]
+    pending_feasibility: HashMap<QueryId, FeasibilitySink>,
 
-           3. Contact the peers with the shard data (the shards of the actual bytes
-              of the file).
-        */
+    /// The largest pubsub message or DHT record (in bytes) that is accepted
+    #[behaviour(ignore)]
+    max_payload_bytes: usize,
 
-        // (1) Get the online peers
-        let mut peers = swarm.behaviour_mut().get_online_peers();
-        if peers.len() > super::MAX_SHARDS {
-            peers.truncate(super::MAX_SHARDS);
-        }
+    /// Whether the metadata of the files that the node stores shards of is
+    /// kept in the node's store
+    #[behaviour(ignore)]
+    cache_metadata: bool,
 
-        if peers.len() == 0 {
-            return Err(Box::new(GeneralError::new(
-                "not enough peers to shard file",
-            )));
-        }
+    /// Whether the node stores the shards that its peers send it
+    #[behaviour(ignore)]
+    hosts_shards: bool,
 
-        // Calcualte the shards of the file and update file sharding metadata accordingly
-        file_metadata.set_shards(&peers);
-        let (shards, new_config) =
-            shard::Shard::shard(&file_bytes, file_metadata.shard_config)?;
+    /// The metadata lookups of files that the node stores shards of, whose
+    /// metadata will be kept in the node's store, and the files they are for
+    #[behaviour(ignore)]
+    pending_caches: HashMap<QueryId, file::FileID>,
 
-        file_metadata.shard_config = new_config;
+    /// The scores of the peers, used to choose which peers to place shards on
+    /// and request shards from
+    #[behaviour(ignore)]
+    scores: PeerScores,
 
-        println!("HEX: {}", file_metadata.id.to_hex());
+    /// The latest heartbeat of each peer
+    #[behaviour(ignore)]
+    capacities: Capacities,
 
-        // (2) Insert into the DHT the FileID which points to the relevant metadata.
-        let record = Record {
-            key: Key::new(&file_metadata.id.to_bytes()?),
-            value: file_metadata.to_bytes()?,
-            publisher: Some(self.identity.peer_id.clone()),
-            expires: None,
-        };
-        swarm
-            .behaviour_mut()
+    /// The latest round trip time measured to each peer
+    #[behaviour(ignore)]
+    latencies: Latencies,
+
+    /// The files whose metadata records have passed through the local DHT
+    /// store
+    #[behaviour(ignore)]
+    dht_files: DhtFiles,
+
+    /// The pings that haven't been answered yet, their nonces, and where their
+    /// round trip times are sent
+    #[behaviour(ignore)]
+    pending_pings: HashMap<RequestId, (u64, PingSink)>,
+
+    /// The shard stores of puts whose holders haven't answered yet, and the
+    /// shards they store
+    #[behaviour(ignore)]
+    pending_acks: HashMap<RequestId, (file::FileID, u32)>,
+
+    /// The shards that puts have sent, so that tests can see what was sent
+    #[cfg(test)]
+    #[behaviour(ignore)]
+    sent_shards: Vec<(file::FileID, shard::Shard)>,
+
+    /// The acknowledgements of the shards of each put so far
+    #[behaviour(ignore)]
+    pending_receipts: HashMap<file::FileID, ReceiptCollector>,
+
+    /// The metadata of the puts whose receipts aren't done yet. The node only
+    /// owns a file once its put succeeds.
+    #[behaviour(ignore)]
+    unconfirmed_puts: HashMap<file::FileID, file::File>,
+
+    /// The receipts of the puts that succeeded
+    #[behaviour(ignore)]
+    receipts: Receipts,
+
+    /// When each in-flight shard request was sent, to measure the latency of
+    /// the peer it was sent to
+    #[behaviour(ignore)]
+    request_times: HashMap<RequestId, Instant>,
+
+    /// The feasibility checks that are waiting on shard holder lookups, by the
+    /// id of their metadata lookup
+    #[behaviour(ignore)]
+    feasibility_checks: HashMap<QueryId, FeasibilityCheck>,
+
+    /// The shard holder lookups of feasibility checks, and the check each is
+    /// part of
+    #[behaviour(ignore)]
+    pending_feasibility_lookups: HashMap<QueryId, QueryId>,
+
+    /// The shard holders that the node isn't connected to and that are
+    /// waiting to be dialed
+    #[behaviour(ignore)]
+    dial_queue: VecDeque<PeerId>,
+
+    /// The shard holders that are being dialed
+    #[behaviour(ignore)]
+    dialing: HashSet<PeerId>,
+
+    /// The threads that the files of finished gets are reconstructed on
+    #[behaviour(ignore)]
+    workers: ShardWorkers,
+
+    /// How many shard holders may be dialed at once
+    #[behaviour(ignore)]
+    max_concurrent_dials: usize,
+
+    /// The shard requests that are waiting for the node to connect to their
+    /// peer
+    #[behaviour(ignore)]
+    deferred_requests: HashMap<PeerId, Vec<ShardMessage>>,
+}
+
+impl MerosBehavior {
+    /// Get a list of the alive peers in the DHT.
+    pub fn get_online_peers(&mut self) -> Vec<PeerId> {
+        let mut nodes: Vec<PeerId> = Vec::new();
+        for bucket in self.kademlia.kbuckets() {
+            for node in bucket.iter() {
+                nodes.push(node.node.key.preimage().clone());
+            }
+        }
+        distinct_remote_peers(nodes, &self.local_peer_id)
+    }
+
+    /// Put a record into the DHT. If the record can't even be stored locally,
+    /// it is scheduled to be retried instead of failing the node.
+    fn put_record(&mut self, record: Record, attempt: u32) {
+        match self.kademlia.put_record(record.clone(), Quorum::One) {
+            Ok(qid) => {
+                self.note_dht_file(&record);
+                self.put_retries.track(qid, record, attempt)
+            }
+            Err(e) => {
+                eprintln!("failed to store record locally: {:?}", e);
+                match self.put_retries.schedule(record, attempt, Instant::now()) {
+                    Some(delay) => println!("retrying put in {:?}", delay),
+                    None => {
+                        eprintln!("giving up on put after {} attempts", attempt + 1)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remember the file whose metadata a record holds, if it holds any.
+    fn note_dht_file(&self, record: &Record) {
+        if let Some(file_id) = record_file_id(record, self.max_payload_bytes) {
+            if let Ok(mut files) = self.dht_files.lock() {
+                files.insert(file_id);
+            }
+        }
+    }
+
+    /// Re-attempt the failed DHT puts whose backoff has passed.
+    fn retry_puts(&mut self) {
+        for (record, attempt) in self.put_retries.due(Instant::now()) {
+            println!("retrying put (attempt {})", attempt + 1);
+            self.put_record(record, attempt);
+        }
+    }
+
+    /// Start getting a file: look up its metadata in the DHT, and collect its
+    /// shards once the metadata arrives.
+    fn start_get(
+        &mut self,
+        file_id: file::FileID,
+        mut collector: ShardCollector,
+    ) -> Result<QueryId, Box<dyn Error>> {
+        collector.set_shard_timeout(self.shard_timeout);
+        collector.set_max_attempts(self.max_holder_attempts);
+        let qid = self
             .kademlia
-            .put_record(record, Quorum::One)
-            .unwrap();
+            .get_record(&Key::new(&file_id.to_bytes()?), Quorum::One);
+        self.pending_gets.insert(file_id, collector);
+        Ok(qid)
+    }
 
-        // (3) Then distribute the actual file bytes data across the network.
-        //for peer in &peers {
-        //    swarm.dial(peer)?;
-        //}
-        swarm.behaviour_mut().floodsub.publish(
-            floodsub::Topic::new(SHARD_CHANNEL),
-            "just put file".as_bytes(),
-        );
-        println!("end of put file");
+    /// Write the file of a get to its output once every shard has arrived, or
+    /// tell the output why the get failed. Gets without an output are ignored.
+    fn finish_output(&mut self, file_id: &file::FileID, failure: Option<&str>) {
+        if let Some(pending) = self.pending_outputs.remove(file_id) {
+            // The file is reconstructed on the workers, so that the swarm
+            // keeps being polled in the meantime
+            let file_id = file_id.clone();
+            let failure = failure.map(|reason| reason.to_string());
+            self.workers.run(move || {
+                if let Err(e) = pending.finish(failure.as_deref()) {
+                    eprintln!("failed to output {}: {}", file_id.to_hex(), e);
+                }
+            });
+        }
+    }
 
-        Ok(())
+    /// Start the queued gets of batches while fewer than `max_concurrent_gets`
+    /// gets are running.
+    fn start_queued_gets(&mut self) {
+        while self.pending_gets.len() < self.max_concurrent_gets {
+            let (file_id, sink) = match self.queued_gets.pop_front() {
+                Some(get) => get,
+                None => return,
+            };
+            if let Err(e) = file_id.to_bytes() {
+                sink.fail(format!("invalid file id: {}", e).as_str());
+                continue;
+            }
+            let collector = ShardCollector::new(Some(sink), self.get_timeout);
+            if let Err(e) = self.start_get(file_id, collector) {
+                eprintln!("failed to start a queued get: {:?}", e);
+            }
+        }
     }
 
-    fn test_sub(
+    /// Stop a put or get of a file that is in flight: stop retrying the put of
+    /// its metadata, fail its get, and forget the holder lookups of the get.
+    fn cancel_transfer(
         &mut self,
-        swarm: &mut Swarm<MerosBehavior>,
+        file_id: &file::FileID,
     ) -> Result<(), Box<dyn Error>> {
-        println!("testing sub");
-
-        let mut c = 0;
-        for _ in 0..10 {
-            println!("trial {}", c);
-            swarm.behaviour_mut().floodsub.publish(
-                floodsub::Topic::new(SHARD_CHANNEL),
-                "test message".as_bytes(),
-            );
-            c += 1;
+        let key = Key::new(&file_id.to_bytes()?);
+        self.put_retries.cancel(|record| record.key == key);
+        if let Some(mut collector) = self.pending_gets.remove(file_id) {
+            collector.fail("the get was cancelled");
         }
+        self.finish_output(file_id, Some("the get was cancelled"));
+        let (cancelled, queued): (Vec<_>, VecDeque<_>) = self
+            .queued_gets
+            .drain(..)
+            .partition(|(id, _)| id == file_id);
+        self.queued_gets = queued;
+        for (_, sink) in cancelled.into_iter() {
+            sink.fail("the get was cancelled");
+        }
+        self.pending_races.retain(|_, (id, _)| id != file_id);
+        self.pending_receipts.remove(file_id);
+        self.unconfirmed_puts.remove(file_id);
+        self.pending_acks.retain(|_, (id, _)| id != file_id);
         Ok(())
     }
 
-    /// Core node operation to get a file from the network.
-    fn get_file(
+    /// Check whether a put or get of a file is still in flight.
+    fn is_transferring(&self, file_id: &file::FileID) -> bool {
+        if self.pending_gets.contains_key(file_id)
+            || self.pending_receipts.contains_key(file_id)
+            || self.queued_gets.iter().any(|(id, _)| id == file_id)
+        {
+            return true;
+        }
+        match file_id.to_bytes() {
+            Ok(key) => {
+                let key = Key::new(&key);
+                self.put_retries.any(|record| record.key == key)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Look up the metadata of a file in the DHT without fetching its shards.
+    /// The result is sent to `sink` once the lookup completes.
+    fn get_metadata(
+        &mut self,
+        file_id: &file::FileID,
+        sink: MetadataSink,
+    ) -> Result<QueryId, Box<dyn Error>> {
+        let qid = self
+            .kademlia
+            .get_record(&Key::new(&file_id.to_bytes()?), Quorum::One);
+        self.pending_metadata.insert(qid, sink);
+        Ok(qid)
+    }
+
+    /// Keep a copy of the metadata of a file in the node's store, unless it is
+    /// already there. The metadata is taken from the node's own part of the
+    /// DHT if it is there, and looked up in the DHT otherwise, unless it is
+    /// already being looked up. Only metadata signed by its owner is kept.
+    fn cache_file_metadata(
         &mut self,
-        swarm: &mut Swarm<MerosBehavior>,
         file_id: &file::FileID,
-        config: &OperationConfig,
     ) -> Result<(), Box<dyn Error>> {
-        println!("getting file");
+        if self.store.get_metadata(file_id)?.is_some()
+            || self.pending_caches.values().any(|id| id == file_id)
+        {
+            return Ok(());
+        }
 
-        let qid = swarm
-            .behaviour_mut()
+        let key = Key::new(&file_id.to_bytes()?);
+        let local = self
+            .kademlia
+            .store_mut()
+            .get(&key)
+            .map(|record| record.value.clone());
+        match local {
+            Some(value) => {
+                let file = protocol::decode_bounded::<file::File>(
+                    &value,
+                    self.max_payload_bytes,
+                )?;
+                if &file.id != file_id {
+                    return Err(Box::new(GeneralError::new(
+                        "the record holds the metadata of another file",
+                    )));
+                }
+                file.verify_metadata()?;
+                self.store.put_metadata(&file)
+            }
+            None => {
+                let qid = self.kademlia.get_record(&key, Quorum::One);
+                self.pending_caches.insert(qid, file_id.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Announce in the DHT that this node holds a shard, using a provider
+    /// record. Kademlia keeps republishing the record while the node is up.
+    fn announce_shard(
+        &mut self,
+        shard_id: &shard::ShardID,
+    ) -> Result<(), Box<dyn Error>> {
+        self.kademlia.start_providing(provider_key(shard_id)?)?;
+        Ok(())
+    }
+
+    /// Look up the peers that currently hold a shard in the DHT. The result is
+    /// sent to `sink` once the lookup completes.
+    fn find_shard_holders(
+        &mut self,
+        shard_id: &shard::ShardID,
+        sink: HolderSink,
+    ) -> Result<QueryId, Box<dyn Error>> {
+        let qid = self.kademlia.get_providers(provider_key(shard_id)?);
+        self.pending_holders.insert(qid, sink);
+        Ok(qid)
+    }
+
+    /// Make sure that every shard of a file is held by at least `target` peers.
+    /// The file's metadata is looked up first, then the current holders of
+    /// each of its shards, and the shards held by too few peers are copied to
+    /// more peers.
+    fn ensure_replication(
+        &mut self,
+        file_id: &file::FileID,
+        target: usize,
+    ) -> Result<QueryId, Box<dyn Error>> {
+        let qid = self
             .kademlia
             .get_record(&Key::new(&file_id.to_bytes()?), Quorum::One);
+        self.pending_replications.insert(qid, target);
+        Ok(qid)
+    }
 
-        let query = swarm.behaviour_mut().kademlia.query(&qid);
-        if let Some(q) = query {
-            println!(
-                "--------------QUERY:\n{:#?}\n\n{:#?}\n------------",
-                q.info(),
-                q.stats(),
+    /// Look up the current holders of every shard of a file, to check that
+    /// each is held by `target` peers.
+    fn check_replication(&mut self, file: &file::File, target: usize) {
+        if file.shard_ids().is_empty() {
+            eprintln!(
+                "{} does not list its shards, so its replication can't be checked",
+                file.id.to_hex()
             );
+            return;
         }
 
-        let nodes = swarm.behaviour_mut().get_online_peers();
-        println!("\nONLINE NODES: {:?}\n", nodes);
-        for node in nodes {
-            match swarm.dial(&node) {
-                Ok(_) => println!("dialed {:?}", node),
-                Err(e) => println!("failed to dial {:?}: {:?}", node, e),
+        for (index, shard_id) in file.shard_ids().iter().enumerate() {
+            match provider_key(shard_id) {
+                Ok(key) => {
+                    let qid = self.kademlia.get_providers(key);
+                    self.pending_replica_checks.insert(
+                        qid,
+                        ReplicaCheck {
+                            file_id: file.id.clone(),
+                            index: index as u32,
+                            shard_id: shard_id.clone(),
+                            target,
+                        },
+                    );
+                }
+                Err(e) => eprintln!("invalid shard id: {:?}", e),
             }
         }
+    }
+
+    /// Copy a shard to more peers if fewer than its target number of peers hold
+    /// it. The copy comes from this node's store if it holds the shard, and
+    /// from another holder otherwise. Returns the peers the shard is copied to.
+    fn replicate(
+        &mut self,
+        check: ReplicaCheck,
+        holders: Vec<PeerId>,
+    ) -> Vec<PeerId> {
+        // A shard this node dropped for repair is fetched back first
+        if self
+            .store
+            .needs_repair(&check.file_id, check.index)
+            .unwrap_or(false)
+        {
+            self.repair(&check, &holders);
+        }
 
-        swarm.behaviour_mut().floodsub.publish(
-            floodsub::Topic::new(SHARD_CHANNEL),
-            "awesome postget message".as_bytes(),
+        let peers = self.get_online_peers();
+        let targets = placement::replication_targets(
+            &check.shard_id,
+            &holders,
+            &peers,
+            check.target,
         );
+        if targets.is_empty() {
+            return targets;
+        }
+        if holders.len() + targets.len() < check.target {
+            eprintln!(
+                "not enough peers to hold shard {} of {} {} times",
+                check.index,
+                check.file_id.to_hex(),
+                check.target
+            );
+        }
 
-        Ok(())
+        match self.store.get_shard(&check.file_id, check.index) {
+            Ok(Some(shard)) if shard.is_valid() => {
+                self.send_copies(&check.file_id, &shard, &targets)
+            }
+            _ => match holders.iter().find(|p| **p != self.local_peer_id) {
+                Some(holder) => {
+                    self.send_request(
+                        holder,
+                        ShardMessage::Request {
+                            file_id: check.file_id.clone(),
+                            index: check.index,
+                        },
+                    );
+                    self.pending_copies
+                        .insert((check.file_id, check.index), targets.clone());
+                }
+                None => {
+                    eprintln!(
+                        "no peer holds shard {} of {}",
+                        check.index,
+                        check.file_id.to_hex()
+                    );
+                    return Vec::new();
+                }
+            },
+        }
+        targets
+    }
+
+    /// Request a shard that this node dropped for repair from one of its other
+    /// holders. It is stored again once it arrives.
+    fn repair(&mut self, check: &ReplicaCheck, holders: &[PeerId]) {
+        match holders.iter().find(|p| **p != self.local_peer_id) {
+            Some(holder) => {
+                self.send_request(
+                    holder,
+                    ShardMessage::Request {
+                        file_id: check.file_id.clone(),
+                        index: check.index,
+                    },
+                );
+                self.pending_repairs.insert(
+                    (check.file_id.clone(), check.index),
+                    check.shard_id.clone(),
+                );
+            }
+            None => eprintln!(
+                "no other peer holds shard {} of {} to repair it from",
+                check.index,
+                check.file_id.to_hex()
+            ),
+        }
+    }
+
+    /// Store a shard that was fetched to repair this node's copy, if it is the
+    /// shard that was dropped, and clear its repair flag.
+    fn store_repaired(&mut self, file_id: &file::FileID, shard: &shard::Shard) {
+        let expected = match self
+            .pending_repairs
+            .remove(&(file_id.clone(), shard.index()))
+        {
+            Some(expected) => expected,
+            None => return,
+        };
+        if shard.id != expected {
+            eprintln!("a holder sent another shard than the one being repaired");
+            return;
+        }
+        let repaired = self
+            .store
+            .put_shard(file_id, shard)
+            .and_then(|_| self.store.clear_repair(file_id, shard.index()))
+            .and_then(|_| self.store.flush());
+        match repaired {
+            Ok(()) => {
+                println!("repaired shard {} of {}", shard.index(), file_id.to_hex());
+                self.events.emit(NodeEvent::ShardStored {
+                    file_id: file_id.clone(),
+                    index: shard.index(),
+                });
+            }
+            Err(e) => eprintln!("failed to store a repaired shard: {:?}", e),
+        }
+    }
+
+    /// Ask the holders of the shards of a file that a reshard replaced to
+    /// delete them. The ones this node held are no longer announced.
+    /// # Arguments
+    /// * `old` - the metadata of the file before it was resharded
+    /// * `new` - the metadata of the resharded file
+    fn delete_old_shards(&mut self, old: &file::File, new: &file::File) {
+        for (holder, shard_id) in old.shards().iter().zip(old.shard_ids().iter()) {
+            if new.shard_ids().contains(shard_id) {
+                continue;
+            }
+            let holder = match PeerId::from_bytes(holder) {
+                Ok(holder) => holder,
+                Err(_) => continue,
+            };
+            if holder == self.local_peer_id {
+                if let Ok(key) = provider_key(shard_id) {
+                    self.kademlia.stop_providing(&key);
+                }
+            } else {
+                self.send_request(
+                    &holder,
+                    ShardMessage::Delete {
+                        file_id: old.id.clone(),
+                        shard_id: shard_id.clone(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// The owner of a file, according to the metadata of the file that this
+    /// node keeps in its store or in its part of the DHT.
+    fn known_owner(&mut self, file_id: &file::FileID) -> Option<PeerId> {
+        let file = match self.store.get_metadata(file_id).ok()? {
+            Some(file) => file,
+            None => {
+                let key = Key::new(&file_id.to_bytes().ok()?);
+                let record = self.kademlia.store_mut().get(&key)?;
+                protocol::decode_bounded::<file::File>(
+                    &record.value,
+                    self.max_payload_bytes,
+                )
+                .ok()?
+            }
+        };
+        file.verify_metadata().ok()?;
+        file.owner().ok()
+    }
+
+    /// Delete a held shard of a file at the request of `peer`, which has to be
+    /// the owner of the file, and stop announcing it.
+    fn delete_held_shard(
+        &mut self,
+        peer: &PeerId,
+        file_id: &file::FileID,
+        shard_id: &shard::ShardID,
+    ) -> ShardMessage {
+        if self.known_owner(file_id).as_ref() != Some(peer) {
+            return ShardMessage::Error(
+                "only the owner of a file may delete its shards".to_string(),
+            );
+        }
+        let held = match self.store.get(file_id) {
+            Ok(held) => held.unwrap_or_default(),
+            Err(e) => return ShardMessage::Error(e.to_string()),
+        };
+        let index = match held.iter().find(|shard| &shard.id == shard_id) {
+            Some(shard) => shard.index(),
+            None => return ShardMessage::NotFound,
+        };
+        let deleted = self
+            .store
+            .remove_shard(file_id, index)
+            .and_then(|_| self.store.flush());
+        if let Err(e) = deleted {
+            return ShardMessage::Error(e.to_string());
+        }
+        if let Ok(key) = provider_key(shard_id) {
+            self.kademlia.stop_providing(&key);
+        }
+        ShardMessage::Deleted
+    }
+
+    /// Send a copy of a shard to each of `peers` to store.
+    fn send_copies(
+        &mut self,
+        file_id: &file::FileID,
+        shard: &shard::Shard,
+        peers: &[PeerId],
+    ) {
+        for peer in peers {
+            self.send_request(
+                peer,
+                ShardMessage::Store {
+                    file_id: file_id.clone(),
+                    shard: shard.clone(),
+                },
+            );
+        }
+    }
+
+    /// Check whether a file can currently be reconstructed. The file's metadata
+    /// is looked up first, then whether each of its shards has a holder. The
+    /// result is sent to `sink` once every lookup completes.
+    fn can_reconstruct(
+        &mut self,
+        file_id: &file::FileID,
+        sink: FeasibilitySink,
+    ) -> Result<QueryId, Box<dyn Error>> {
+        let qid = self
+            .kademlia
+            .get_record(&Key::new(&file_id.to_bytes()?), Quorum::One);
+        self.pending_feasibility.insert(qid, sink);
+        Ok(qid)
+    }
+
+    /// Look up the holders of every shard of a file for the feasibility check
+    /// started by the metadata lookup `check_id`.
+    fn check_feasibility(
+        &mut self,
+        check_id: QueryId,
+        file: &file::File,
+        sink: FeasibilitySink,
+    ) {
+        let required = file.shard_config.sizes.len();
+        if file.shard_ids().is_empty() {
+            let _ = sink.unbounded_send(if required == 0 {
+                Ok(Feasibility {
+                    reachable: 0,
+                    required,
+                })
+            } else {
+                Err(GeneralError::new(
+                    "the file does not list its shards, so they can't be found",
+                ))
+            });
+            return;
+        }
+
+        let mut outstanding = 0;
+        for shard_id in file.shard_ids() {
+            match provider_key(shard_id) {
+                Ok(key) => {
+                    let qid = self.kademlia.get_providers(key);
+                    self.pending_feasibility_lookups.insert(qid, check_id);
+                    outstanding += 1;
+                }
+                Err(e) => eprintln!("invalid shard id: {:?}", e),
+            }
+        }
+        if outstanding == 0 {
+            let _ = sink.unbounded_send(Ok(Feasibility {
+                reachable: 0,
+                required,
+            }));
+            return;
+        }
+        self.feasibility_checks.insert(
+            check_id,
+            FeasibilityCheck {
+                sink,
+                required,
+                reachable: 0,
+                outstanding,
+            },
+        );
+    }
+
+    /// Count the result of a shard holder lookup towards its feasibility check,
+    /// if it is part of one, and report the check once it is complete.
+    fn count_reachable(&mut self, qid: &QueryId, reachable: bool) {
+        let check_id = match self.pending_feasibility_lookups.remove(qid) {
+            Some(check_id) => check_id,
+            None => return,
+        };
+        let done = match self.feasibility_checks.get_mut(&check_id) {
+            Some(check) => {
+                check.outstanding -= 1;
+                if reachable {
+                    check.reachable += 1;
+                }
+                check.outstanding == 0
+            }
+            None => return,
+        };
+        if done {
+            if let Some(check) = self.feasibility_checks.remove(&check_id) {
+                let _ = check.sink.unbounded_send(Ok(Feasibility {
+                    reachable: check.reachable,
+                    required: check.required,
+                }));
+            }
+        }
+    }
+
+    /// Request every shard of a file from its holders, if the file is being
+    /// collected and its shards haven't been requested yet.
+    fn request_shards(&mut self, file: &file::File) {
+        if let Some(pending) = self.pending_outputs.get_mut(&file.id) {
+            pending.file = Some(file.clone());
+        }
+        match self.pending_gets.get_mut(&file.id) {
+            Some(collector) if !collector.has_total() => {
+                if file.shard_ids().len() == file.shards().len() {
+                    collector.set_shard_ids(file.shard_ids().clone());
+                }
+                collector.set_spare(file.shard_config.parity_shards as u32);
+                collector.set_total(file.shards().len() as u32);
+                if collector.is_complete() {
+                    self.pending_gets.remove(&file.id);
+                    self.finish_output(&file.id, None);
+                }
+            }
+            _ => return,
+        }
+
+        // Find more holders of each shard to race against (or fall back on
+        // from) the one in the metadata
+        let alternatives = self.get_fan_out > 1 || self.shard_timeout.is_some();
+        if alternatives && file.shard_ids().len() == file.shards().len() {
+            for (index, shard_id) in file.shard_ids().iter().enumerate() {
+                match provider_key(shard_id) {
+                    Ok(key) => {
+                        let qid = self.kademlia.get_providers(key);
+                        self.pending_races
+                            .insert(qid, (file.id.clone(), index as u32));
+                    }
+                    Err(e) => eprintln!("invalid shard id: {:?}", e),
+                }
+            }
+        }
+
+        for index in request_order(file) {
+            match PeerId::from_bytes(&file.shards()[index]) {
+                Ok(peer) => self.race_shard(&file.id, index as u32, &[peer]),
+                Err(e) => eprintln!("invalid holder of shard {}: {:?}", index, e),
+            }
+        }
+    }
+
+    /// Request a shard that is being collected from up to `get_fan_out` of its
+    /// holders at once, the best scoring holders first. The first valid copy
+    /// to arrive is used, and the rest are ignored.
+    fn race_shard(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+        holders: &[PeerId],
+    ) {
+        let mut holders = holders.to_vec();
+        self.scores.order(&mut holders, self.holder_selection);
+        let chosen = match self.pending_gets.get_mut(file_id) {
+            Some(collector) => collector.race(index, &holders, self.get_fan_out),
+            None => return,
+        };
+        self.send_shard_requests(file_id, index, chosen);
+    }
+
+    /// Request the shard of a file at an index from some of its holders.
+    fn send_shard_requests(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+        holders: Vec<PeerId>,
+    ) {
+        for peer in holders {
+            let request = ShardMessage::Request {
+                file_id: file_id.clone(),
+                index,
+            };
+            // The shards held by this node are read straight from its store
+            if peer == self.local_peer_id {
+                let response = self.handle_shard_request(request);
+                self.handle_shard_response(peer, response);
+            } else if self.shard_protocol.is_connected(&peer) {
+                self.send_request(&peer, request);
+            } else {
+                self.defer_request(peer, request);
+            }
+        }
+    }
+
+    /// Hold a shard request back until the node is connected to its peer,
+    /// queueing the peer to be dialed. Only the holders of the shards being
+    /// requested are ever dialed, never the whole routing table.
+    fn defer_request(&mut self, peer: PeerId, request: ShardMessage) {
+        let requests = self.deferred_requests.entry(peer.clone()).or_default();
+        if requests.is_empty() && !self.dialing.contains(&peer) {
+            self.dial_queue.push_back(peer);
+        }
+        requests.push(request);
+    }
+
+    /// Take the queued shard holders that should be dialed now, keeping at
+    /// most `max_concurrent_dials` dials in flight. Holders that got
+    /// connected in the meantime are sent their requests instead.
+    fn next_dials(&mut self) -> Vec<PeerId> {
+        let mut dials = Vec::new();
+        while self.dialing.len() < self.max_concurrent_dials {
+            let peer = match self.dial_queue.pop_front() {
+                Some(peer) => peer,
+                None => break,
+            };
+            if self.shard_protocol.is_connected(&peer) {
+                self.send_deferred(&peer);
+            } else {
+                self.dialing.insert(peer.clone());
+                dials.push(peer);
+            }
+        }
+        dials
+    }
+
+    /// Record that the dial of a shard holder is over, and send the requests
+    /// that were waiting on it. If the dial failed, the requests fail like any
+    /// other request to an unreachable peer, so the gets move on to other
+    /// holders.
+    fn dial_finished(&mut self, peer: &PeerId) {
+        if self.dialing.remove(peer) || self.shard_protocol.is_connected(peer) {
+            self.send_deferred(peer);
+        }
+    }
+
+    /// Send the requests that were held back for a peer.
+    fn send_deferred(&mut self, peer: &PeerId) {
+        for request in self.deferred_requests.remove(peer).unwrap_or_default() {
+            self.send_request(peer, request);
+        }
+    }
+
+    /// Send a request over the shard protocol, timing how long the peer takes
+    /// to answer it.
+    fn send_request(&mut self, peer: &PeerId, request: ShardMessage) -> RequestId {
+        let requested = match &request {
+            ShardMessage::Request { file_id, index } => {
+                Some((file_id.clone(), *index))
+            }
+            _ => None,
+        };
+        let id = self.shard_protocol.send_request(peer, request);
+        self.request_times.insert(id, Instant::now());
+        if let Some(requested) = requested {
+            self.pending_shard_requests.insert(id, requested);
+        }
+        id
+    }
+
+    /// Ping a peer over the shard protocol, sending the round trip time to
+    /// `sink` once it answers.
+    fn ping(&mut self, peer: &PeerId, sink: PingSink) {
+        let nonce = rand::random::<u64>();
+        let id = self.send_request(peer, ShardMessage::Ping(nonce));
+        self.pending_pings.insert(id, (nonce, sink));
+    }
+
+    /// Handle the answer to a ping (or its failure, with `None`), remembering
+    /// the round trip time of the peer. Returns `false` if the request wasn't
+    /// a ping.
+    fn pong(
+        &mut self,
+        request_id: &RequestId,
+        peer: &PeerId,
+        answer: Option<(&ShardMessage, Duration)>,
+    ) -> bool {
+        let (nonce, sink) = match self.pending_pings.remove(request_id) {
+            Some(ping) => ping,
+            None => return false,
+        };
+        let result = match answer {
+            Some((ShardMessage::Pong(n), rtt)) if *n == nonce => {
+                if let Ok(mut latencies) = self.latencies.lock() {
+                    latencies.insert(peer.clone(), rtt);
+                }
+                Ok(rtt)
+            }
+            Some(_) => Err(GeneralError::new(
+                format!("{:?} answered a ping with something else", peer).as_str(),
+            )),
+            None => Err(GeneralError::new(
+                format!("ping to {:?} failed", peer).as_str(),
+            )),
+        };
+        sink.unbounded_send(result).ok();
+        true
+    }
+
+    /// Finish a get once every shard that it needs has arrived.
+    fn complete_get(&mut self, file_id: &file::FileID) {
+        println!("got every shard of {}", file_id.to_hex());
+        self.pending_gets.remove(file_id);
+        self.finish_output(file_id, None);
+        self.events.emit(NodeEvent::FileReceived(file_id.clone()));
+        self.start_queued_gets();
+    }
+
+    /// Fall through to the next holders of a shard after one of them failed
+    /// to deliver it. The get fails once every holder it may ask has failed.
+    fn holder_failed(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+        holder: &PeerId,
+    ) {
+        let collector = match self.pending_gets.get_mut(file_id) {
+            Some(collector) => collector,
+            None => return,
+        };
+        let next = collector.holder_failed(index, holder, self.get_fan_out);
+        if let Some(index) = collector.exhausted() {
+            let failures = collector.failures();
+            let reason = format!(
+                "every holder asked for shard {} of {} failed ({} failures)",
+                index,
+                file_id.to_hex(),
+                failures
+            );
+            if let Some(mut collector) = self.pending_gets.remove(file_id) {
+                collector.fail(reason.as_str());
+            }
+            self.finish_output(file_id, Some(reason.as_str()));
+            self.start_queued_gets();
+            return;
+        }
+        if collector.is_complete() {
+            // The shard can be recovered from the parity shards
+            self.complete_get(file_id);
+            return;
+        }
+        if !next.is_empty() {
+            println!(
+                "{:?} failed to deliver shard {} of {}, asking {:?}",
+                holder,
+                index,
+                file_id.to_hex(),
+                next
+            );
+            self.send_shard_requests(file_id, index, next);
+        }
+    }
+
+    /// Send the shards of a put to their holders, and collect the holders'
+    /// acknowledgements into a receipt, which is sent to `sink` once every
+    /// holder has answered. The node owns the file once the put succeeds.
+    fn send_put_shards(
+        &mut self,
+        file_metadata: &file::File,
+        shards: Vec<(shard::Shard, PeerId)>,
+        min_acks: usize,
+        sink: Option<ReceiptSink>,
+    ) {
+        let file_id = &file_metadata.id;
+        self.unconfirmed_puts
+            .insert(file_id.clone(), file_metadata.clone());
+        let indices: Vec<u32> = shards.iter().map(|(s, _)| s.index()).collect();
+        self.pending_receipts.insert(
+            file_id.clone(),
+            ReceiptCollector::new(&indices, min_acks, sink),
+        );
+        for (shard, holder) in shards {
+            let index = shard.index();
+            #[cfg(test)]
+            self.sent_shards.push((file_id.clone(), shard.clone()));
+            let id = self.send_request(
+                &holder,
+                ShardMessage::Store {
+                    file_id: file_id.clone(),
+                    shard,
+                },
+            );
+            self.pending_acks.insert(id, (file_id.clone(), index));
+        }
+    }
+
+    /// Record whether the holder of a shard of a put stored it, finishing the
+    /// put's receipt once every holder has answered. The file of a put that
+    /// succeeded is recorded as owned by the node.
+    fn acknowledge(&mut self, request_id: &RequestId, peer: &PeerId, stored: bool) {
+        let file_id = match self.pending_acks.remove(request_id) {
+            Some((file_id, index)) => {
+                match self.pending_receipts.get_mut(&file_id) {
+                    Some(collector) => {
+                        collector.answer(index, peer, stored);
+                        if !collector.is_done() {
+                            return;
+                        }
+                        file_id
+                    }
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        if let Some(collector) = self.pending_receipts.remove(&file_id) {
+            let file = self.unconfirmed_puts.remove(&file_id);
+            if let Ok(receipt) = collector.receipt() {
+                if let Some(file) = file {
+                    if let Err(e) = self.own_file(&file) {
+                        eprintln!("failed to record an owned file: {:?}", e);
+                    }
+                }
+                if let Ok(mut receipts) = self.receipts.lock() {
+                    receipts.insert(file_id, receipt);
+                }
+            }
+            collector.finish();
+        }
+    }
+
+    /// Record that the node owns a file whose put succeeded.
+    fn own_file(&mut self, file: &file::File) -> Result<(), Box<dyn Error>> {
+        self.store.add_owned(file)?;
+        self.store.flush()
+    }
+
+    /// Take a deleted file off the DHT: remove its record from this node's
+    /// part of the DHT, and stop announcing the shards of it that this node
+    /// held. The copies of the record that other peers hold expire.
+    fn unpublish_file(&mut self, file: &file::File) -> Result<(), Box<dyn Error>> {
+        self.kademlia.remove_record(&Key::new(&file.id.to_bytes()?));
+        if let Ok(mut files) = self.dht_files.lock() {
+            files.remove(&file.id);
+        }
+        for shard_id in file.shard_ids().iter() {
+            self.kademlia.stop_providing(&provider_key(shard_id)?);
+        }
+        Ok(())
+    }
+
+    /// Fail the gets that have timed out, and move the shards whose holders
+    /// stalled on to their next holders.
+    fn expire_gets(&mut self) {
+        let now = Instant::now();
+        let mut retries = Vec::new();
+        for (file_id, collector) in self.pending_gets.iter_mut() {
+            for (index, holders) in collector.retry_stalled(now, self.get_fan_out) {
+                retries.push((file_id.clone(), index, holders));
+            }
+        }
+        for (file_id, index, holders) in retries {
+            println!(
+                "shard {} of {} stalled, asking {:?}",
+                index,
+                file_id.to_hex(),
+                holders
+            );
+            self.send_shard_requests(&file_id, index, holders);
+        }
+
+        let mut expired = Vec::new();
+        self.pending_gets.retain(|file_id, collector| {
+            if collector.is_expired(now) {
+                collector.fail(
+                    format!("timed out getting {}", file_id.to_hex()).as_str(),
+                );
+                expired.push(file_id.clone());
+                false
+            } else {
+                true
+            }
+        });
+        for file_id in expired {
+            let reason = format!("timed out getting {}", file_id.to_hex());
+            self.finish_output(&file_id, Some(reason.as_str()));
+        }
+        self.start_queued_gets();
+    }
+
+    /// Handle a request that arrived over the shard protocol, and return the
+    /// response. Stored shards are announced in the DHT. A node that doesn't
+    /// host shards refuses to store them.
+    fn handle_shard_request(&mut self, request: ShardMessage) -> ShardMessage {
+        let stored = match &request {
+            ShardMessage::Store { .. } if !self.hosts_shards => {
+                return ShardMessage::Error(
+                    "this node doesn't host shards".to_string(),
+                )
+            }
+            ShardMessage::Store { file_id, shard } => {
+                Some((file_id.clone(), shard.index(), shard.id.clone()))
+            }
+            _ => None,
+        };
+
+        let response =
+            serve_shard_request(&mut self.store, request, self.max_payload_bytes);
+        if let (Some((file_id, index, shard_id)), ShardMessage::Stored) =
+            (stored, &response)
+        {
+            if let Err(e) = self.announce_shard(&shard_id) {
+                eprintln!("failed to announce stored shard: {:?}", e);
+            }
+            if self.cache_metadata {
+                if let Err(e) = self.cache_file_metadata(&file_id) {
+                    eprintln!("failed to cache the metadata of a file: {:?}", e);
+                }
+            }
+            self.events.emit(NodeEvent::ShardStored { file_id, index });
+        }
+        response
+    }
+
+    /// Handle a response that arrived over the shard protocol.
+    fn handle_shard_response(&mut self, peer: PeerId, response: ShardMessage) {
+        match response {
+            ShardMessage::Data {
+                ref file_id,
+                ref shard,
+            } => {
+                // A copy of a corrupted shard is given up on
+                let copy_to = self
+                    .pending_copies
+                    .remove(&(file_id.clone(), shard.index()));
+                let (file_id, shard) =
+                    match response.into_shard_bounded(self.max_payload_bytes) {
+                        Some(data) => data,
+                        None => {
+                            eprintln!("{:?} sent a corrupted shard", peer);
+                            return;
+                        }
+                    };
+                if let Some(peers) = copy_to {
+                    self.send_copies(&file_id, &shard, &peers);
+                }
+                self.store_repaired(&file_id, &shard);
+                if let Some(collector) = self.pending_gets.get_mut(&file_id) {
+                    if collector.has_received(shard.index()) {
+                        // A faster holder already sent this shard
+                    } else if collector.accept_from(&shard, &peer) {
+                        if let Some(pending) = self.pending_outputs.get_mut(&file_id)
+                        {
+                            pending.shards.insert(shard.index(), shard.clone());
+                        }
+                        self.events.emit(NodeEvent::ShardReceived {
+                            file_id: file_id.clone(),
+                            index: shard.index(),
+                            holder: peer,
+                        });
+                    } else {
+                        eprintln!("rejected shard from {:?}", peer);
+                    }
+                    if collector.is_complete() {
+                        self.complete_get(&file_id);
+                    }
+                }
+            }
+            ShardMessage::Stored => println!("{:?} stored a shard", peer),
+            ShardMessage::Deleted => println!("{:?} deleted a shard", peer),
+            ShardMessage::NotFound => {
+                eprintln!("{:?} does not hold the requested shard", peer)
+            }
+            ShardMessage::Corrupt => {
+                eprintln!("{:?} holds a corrupted copy of the requested shard", peer)
+            }
+            ShardMessage::Error(e) => {
+                eprintln!("{:?} could not handle a shard request: {}", peer, e)
+            }
+            _ => eprintln!("unexpected shard response from {:?}", peer),
+        }
+    }
+
+    /// Publish the free space of the node's stores to its peers. A node that
+    /// doesn't host shards has none to offer.
+    fn send_heartbeat(&mut self) -> Result<(), Box<dyn Error>> {
+        let (free_bytes, total_bytes) = match self.hosts_shards {
+            true => self.store.capacity()?,
+            false => (0, 0),
+        };
+        let heartbeat = Heartbeat {
+            free_bytes,
+            total_bytes,
+        };
+        self.pubsub
+            .publish(STATUS_CHANNEL, bincode::serialize(&heartbeat)?);
+        Ok(())
+    }
+
+    /// Remember the free space that a peer advertised in a heartbeat.
+    fn handle_heartbeat(&mut self, peer: PeerId, data: &[u8]) {
+        let heartbeat = match protocol::decode_bounded::<Heartbeat>(
+            data,
+            self.max_payload_bytes,
+        ) {
+            Ok(heartbeat) => heartbeat,
+            Err(e) => {
+                eprintln!("invalid heartbeat from {:?}: {:?}", peer, e);
+                return;
+            }
+        };
+        self.scores.record_capacity(&peer, heartbeat.free_bytes);
+        if let Ok(mut capacities) = self.capacities.lock() {
+            capacities.insert(peer, heartbeat);
+        }
+    }
+
+    /// Say hi
+    pub fn hi(&mut self) {
+        self.pubsub.publish(SHARD_CHANNEL, "hi".as_bytes());
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for MerosBehavior {
+    /// Upon an Mdns event
+    fn inject_event(&mut self, event: MdnsEvent) {
+        // Add the discovered peers to the dht and remove stale peers
+        match event {
+            MdnsEvent::Discovered(discovered_peers) => {
+                for (peer_id, multiaddr) in discovered_peers {
+                    self.kademlia.add_address(&peer_id, multiaddr);
+                    self.pubsub.add_peer(peer_id);
+                    println!("found peer {:?}", peer_id);
+                    self.events.emit(NodeEvent::PeerDiscovered(peer_id));
+                }
+            }
+            MdnsEvent::Expired(expired_peers) => {
+                for (peer_id, _) in expired_peers {
+                    self.kademlia.remove_peer(&peer_id);
+                    self.pubsub.remove_peer(&peer_id);
+                    println!("removed peer {:?}", peer_id);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PubsubEvent> for MerosBehavior {
+    /// Upon a floodsub or gossipsub event
+    fn inject_event(&mut self, event: PubsubEvent) {
+        match event {
+            PubsubEvent::Message {
+                source,
+                topic,
+                data,
+            } => {
+                if topic == STATUS_CHANNEL {
+                    self.handle_heartbeat(source, &data);
+                    return;
+                }
+                if data.len() > self.max_payload_bytes {
+                    eprintln!(
+                        "dropping a pubsub message of {} bytes from {:?}",
+                        data.len(),
+                        source
+                    );
+                    return;
+                }
+                println!(
+                    "\n=========\nreceived msg: {:?} from {:?}\n========\n",
+                    String::from_utf8_lossy(&data),
+                    source
+                );
+                self.events.emit(NodeEvent::Message { source, data });
+
+                //std::process::Command::new("brave").output().expect("err"); // lolll
+            }
+            PubsubEvent::Other(event) => println!("PUBSUB EVENT: {}", event),
+        };
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for MerosBehavior {
+    /// Upon a Kademlia event
+    fn inject_event(&mut self, event: KademliaEvent) {
+        match event {
+            // If the event is a query
+            KademliaEvent::OutboundQueryCompleted { id, result, .. } => {
+                match result {
+                    // If the query is a GET
+                    QueryResult::GetRecord(Ok(ok)) => {
+                        let mut metadata = self.pending_metadata.remove(&id);
+                        let mut replication = self.pending_replications.remove(&id);
+                        let mut feasibility = self.pending_feasibility.remove(&id);
+                        let mut cache = self.pending_caches.remove(&id);
+                        for query in ok.records {
+                            println!(
+                                "KAD EVENT: got record {:?} {:?}",
+                                query.record.key.as_ref(),
+                                &query.record.value
+                            );
+
+                            self.note_dht_file(&query.record);
+
+                            // A peer may return garbage, which is skipped
+                            let key = query.record.key;
+                            let f = match protocol::decode_bounded::<file::File>(
+                                &query.record.value,
+                                self.max_payload_bytes,
+                            ) {
+                                Ok(f) => f,
+                                Err(e) => {
+                                    self.corrupt_records += 1;
+                                    eprintln!(
+                                        "skipping corrupted record {:?} from {:?}: {:?}",
+                                        key, query.peer, e
+                                    );
+                                    continue;
+                                }
+                            };
+
+                            println!("file: {:?}", f);
+
+                            if let Some(file_id) = cache.take() {
+                                if file_id != f.id {
+                                    eprintln!(
+                                        "skipping the metadata of another file"
+                                    );
+                                } else if let Err(e) = f.verify_metadata() {
+                                    eprintln!(
+                                        "skipping unverified metadata from {:?}: {:?}",
+                                        query.peer, e
+                                    );
+                                } else if let Err(e) = self.store.put_metadata(&f) {
+                                    eprintln!("failed to cache metadata: {:?}", e);
+                                }
+                                continue;
+                            }
+                            if let Some(target) = replication.take() {
+                                self.check_replication(&f, target);
+                                continue;
+                            }
+                            if let Some(sink) = feasibility.take() {
+                                self.check_feasibility(id, &f, sink);
+                                continue;
+                            }
+
+                            match metadata.take() {
+                                Some(sink) => {
+                                    let _ = sink.unbounded_send(Ok(f));
+                                }
+                                None => self.request_shards(&f),
+                            }
+                        }
+                        if replication.is_some() {
+                            eprintln!(
+                                "can't check the replication of a corrupted file"
+                            );
+                        }
+                        if let Some(sink) = feasibility {
+                            let _ = sink.unbounded_send(Err(GeneralError::new(
+                                "the file's metadata is corrupted",
+                            )));
+                        }
+                        if let Some(sink) = metadata {
+                            let _ = sink.unbounded_send(Err(GeneralError::new(
+                                "the file's metadata is corrupted",
+                            )));
+                        }
+                    }
+                    // If the query is a failed GET
+                    QueryResult::GetRecord(Err(err)) => {
+                        eprintln!("failed to get record: {:?}", err);
+
+                        if let Some(sink) = self.pending_feasibility.remove(&id) {
+                            let _ = sink.unbounded_send(Err(GeneralError::new(
+                                "could not find the file's metadata",
+                            )));
+                            return;
+                        }
+
+                        if self.pending_replications.remove(&id).is_some() {
+                            eprintln!(
+                                "can't check the replication of a missing file"
+                            );
+                            return;
+                        }
+
+                        if self.pending_caches.remove(&id).is_some() {
+                            eprintln!("can't cache the metadata of a missing file");
+                            return;
+                        }
+
+                        if let Some(sink) = self.pending_metadata.remove(&id) {
+                            let _ = sink.unbounded_send(Err(GeneralError::new(
+                                "could not find the file's metadata",
+                            )));
+                            return;
+                        }
+
+                        if let Ok(file_id) =
+                            file::FileID::try_from_slice(err.key().as_ref())
+                        {
+                            if let Some(mut collector) =
+                                self.pending_gets.remove(&file_id)
+                            {
+                                collector.fail("could not find the file's metadata");
+                            }
+                            self.finish_output(
+                                &file_id,
+                                Some("could not find the file's metadata"),
+                            );
+                        }
+                    }
+
+                    // If the query is a lookup of a shard's holders
+                    QueryResult::GetProviders(Ok(ok)) => {
+                        let holders =
+                            shard_holders(&mut self.kademlia, &ok.key, ok.providers);
+                        if let Some(sink) = self.pending_holders.remove(&id) {
+                            let _ = sink.unbounded_send(Ok(holders.clone()));
+                        }
+                        self.count_reachable(&id, !holders.is_empty());
+                        if let Some((file_id, index)) =
+                            self.pending_races.remove(&id)
+                        {
+                            self.race_shard(&file_id, index, &holders);
+                        }
+                        if let Some(check) = self.pending_replica_checks.remove(&id)
+                        {
+                            self.replicate(check, holders);
+                        }
+                    }
+
+                    // If the lookup of a shard's holders timed out, use the
+                    // holders that were found in time
+                    QueryResult::GetProviders(Err(GetProvidersError::Timeout {
+                        key,
+                        providers,
+                        ..
+                    })) => {
+                        let holders =
+                            shard_holders(&mut self.kademlia, &key, providers);
+                        self.count_reachable(&id, !holders.is_empty());
+                        if let Some((file_id, index)) =
+                            self.pending_races.remove(&id)
+                        {
+                            self.race_shard(&file_id, index, &holders);
+                        }
+                        if let Some(check) = self.pending_replica_checks.remove(&id)
+                        {
+                            self.replicate(check, holders.clone());
+                        }
+                        if let Some(sink) = self.pending_holders.remove(&id) {
+                            let _ = sink.unbounded_send(if holders.is_empty() {
+                                Err(GeneralError::new(
+                                    "could not find the shard's holders",
+                                ))
+                            } else {
+                                Ok(holders)
+                            });
+                        }
+                    }
+
+                    // If the query is a PUT
+                    QueryResult::PutRecord(Ok(ok)) => {
+                        self.put_retries.succeeded(&id);
+                        match file::FileID::try_from_slice(ok.key.as_ref()) {
+                            Ok(file_id) => {
+                                println!("KAD EVENT: put record {:?}", file_id);
+                                self.events
+                                    .emit(NodeEvent::MetadataPublished(file_id));
+                            }
+                            Err(e) => eprintln!(
+                                "KAD EVENT: put record with bad key: {:?}",
+                                e
+                            ),
+                        }
+                    }
+
+                    // If the query is a failed PUT
+                    QueryResult::PutRecord(Err(err)) => {
+                        eprintln!("KAD EVENT: failed to put record: {:?}", err);
+                        match self.put_retries.failed(&id, Instant::now()) {
+                            Some(delay) => println!("retrying put in {:?}", delay),
+                            None => eprintln!("giving up on put"),
+                        }
+                    }
+
+                    _ => {}
+                }
+            }
+
+            // If a peer stored a record on this node. Inbound records are
+            // filtered so that the files they describe can be noted here.
+            KademliaEvent::InboundRequestServed {
+                request:
+                    InboundRequest::PutRecord {
+                        record: Some(record),
+                        ..
+                    },
+            } => {
+                self.note_dht_file(&record);
+                if let Err(e) = self.kademlia.store_mut().put(record) {
+                    eprintln!("failed to store inbound record: {:?}", e);
+                }
+            }
+
+            // If a peer announced that it provides a key
+            KademliaEvent::InboundRequestServed {
+                request:
+                    InboundRequest::AddProvider {
+                        record: Some(record),
+                        ..
+                    },
+            } => {
+                if let Err(e) = self.kademlia.store_mut().add_provider(record) {
+                    eprintln!("failed to store inbound provider: {:?}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<ShardMessage, ShardMessage>>
+    for MerosBehavior
+{
+    /// Upon a shard protocol event
+    fn inject_event(
+        &mut self,
+        event: RequestResponseEvent<ShardMessage, ShardMessage>,
+    ) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let response = match request {
+                        ShardMessage::Delete { file_id, shard_id } => {
+                            self.delete_held_shard(&peer, &file_id, &shard_id)
+                        }
+                        request => self.handle_shard_request(request),
+                    };
+                    if let ShardMessage::Data { file_id, shard } = &response {
+                        self.events.emit(NodeEvent::ShardServed {
+                            file_id: file_id.clone(),
+                            index: shard.index(),
+                        });
+                    }
+                    if let Err(e) =
+                        self.shard_protocol.send_response(channel, response)
+                    {
+                        eprintln!("failed to respond to {:?}: {:?}", peer, e);
+                    }
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => {
+                    let rtt = self.request_times.remove(&request_id).map(|sent| {
+                        let rtt = sent.elapsed();
+                        self.scores.record_latency(&peer, rtt);
+                        rtt
+                    });
+                    let answer = rtt.map(|rtt| (&response, rtt));
+                    if self.pong(&request_id, &peer, answer) {
+                        return;
+                    }
+                    let requested = self.pending_shard_requests.remove(&request_id);
+                    let response = match &requested {
+                        Some((file_id, index)) => {
+                            bind_response(file_id, *index, response)
+                        }
+                        None => response,
+                    };
+                    let stored = match response {
+                        ShardMessage::Stored => true,
+                        _ => false,
+                    };
+                    let delivered = match &response {
+                        ShardMessage::Data { shard, .. } => shard.is_valid(),
+                        _ => false,
+                    };
+                    self.acknowledge(&request_id, &peer, stored);
+                    match &response {
+                        ShardMessage::Data { shard, .. } if shard.is_valid() => {
+                            self.scores.record_success(&peer)
+                        }
+                        ShardMessage::Stored => self.scores.record_success(&peer),
+                        ShardMessage::Data { .. }
+                        | ShardMessage::Corrupt
+                        | ShardMessage::Error(_) => {
+                            self.scores.record_failure(&peer)
+                        }
+                        _ => {}
+                    }
+                    self.handle_shard_response(peer.clone(), response);
+                    if let (Some((file_id, index)), false) = (requested, delivered) {
+                        self.holder_failed(&file_id, index, &peer);
+                    }
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                self.request_times.remove(&request_id);
+                self.scores.record_failure(&peer);
+                self.pong(&request_id, &peer, None);
+                self.acknowledge(&request_id, &peer, false);
+                eprintln!("shard request to {:?} failed: {:?}", peer, error);
+                if let Some((file_id, index)) =
+                    self.pending_shard_requests.remove(&request_id)
+                {
+                    self.holder_failed(&file_id, index, &peer);
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                eprintln!("shard request from {:?} failed: {:?}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+/// Handle a request that arrived over the shard protocol using a node's store,
+/// and return the response. Shards are only stored and served if they are
+/// valid. A corrupted stored shard is dropped from the store so that a good
+/// copy can take its place, and is never served.
+fn serve_shard_request(
+    store: &mut StoreManager,
+    request: ShardMessage,
+    max_bytes: usize,
+) -> ShardMessage {
+    match request {
+        ShardMessage::Store { .. } => {
+            let (file_id, shard) = match request.into_shard_bounded(max_bytes) {
+                Some(data) => data,
+                None => return ShardMessage::Error("invalid shard".to_string()),
+            };
+            match store
+                .put_shard(&file_id, &shard)
+                .and_then(|_| store.flush())
+            {
+                Ok(()) => ShardMessage::Stored,
+                Err(e) => ShardMessage::Error(e.to_string()),
+            }
+        }
+        ShardMessage::Request { file_id, index } => {
+            match store.get_shard(&file_id, index) {
+                Ok(Some(shard)) if shard.is_valid() => shard.to_message(&file_id),
+                Ok(Some(_)) => {
+                    eprintln!(
+                        "shard {} of {} is corrupted, dropping it for repair",
+                        index,
+                        file_id.to_hex()
+                    );
+                    if let Err(e) = drop_for_repair(store, &file_id, index) {
+                        eprintln!("failed to drop corrupted shard: {}", e);
+                    }
+                    ShardMessage::Corrupt
+                }
+                Ok(None) => ShardMessage::NotFound,
+                Err(e) => ShardMessage::Error(e.to_string()),
+            }
+        }
+        ShardMessage::Ping(nonce) => ShardMessage::Pong(nonce),
+        _ => ShardMessage::Error("unexpected request".to_string()),
+    }
+}
+
+/// Bind the response to a request for the shard of a file at an index to that
+/// request: a shard of another file, or at another index, is turned into an
+/// error, so that it is never collected as the requested shard.
+fn bind_response(
+    file_id: &file::FileID,
+    index: u32,
+    response: ShardMessage,
+) -> ShardMessage {
+    match response {
+        ShardMessage::Data {
+            file_id: ref sent,
+            ref shard,
+        } if sent != file_id || shard.index() != index => {
+            ShardMessage::Error(format!(
+                "sent shard {} of {} when asked for shard {} of {}",
+                shard.index(),
+                sent.to_hex(),
+                index,
+                file_id.to_hex()
+            ))
+        }
+        response => response,
+    }
+}
+
+/// The id of the file whose metadata a DHT record holds. Records that aren't
+/// the metadata of the file they are keyed by have none.
+fn record_file_id(record: &Record, max_bytes: usize) -> Option<file::FileID> {
+    let file =
+        protocol::decode_bounded::<file::File>(&record.value, max_bytes).ok()?;
+    match file.id.to_bytes() {
+        Ok(key) if key == record.key.to_vec() => Some(file.id),
+        _ => None,
+    }
+}
+
+/// The DHT key that the holders of a shard are announced under.
+fn provider_key(shard_id: &shard::ShardID) -> Result<Key, Box<dyn Error>> {
+    Ok(Key::new(&shard_id.to_bytes()?))
+}
+
+/// Combine the holders of a shard that a DHT lookup found with the holders
+/// known to the local DHT store (including this node).
+fn shard_holders<I>(
+    kademlia: &mut Kademlia<MemoryStore>,
+    key: &Key,
+    found: I,
+) -> Vec<PeerId>
+where
+    I: IntoIterator<Item = PeerId>,
+{
+    let mut holders: Vec<PeerId> = Vec::new();
+    let local = kademlia
+        .store_mut()
+        .providers(key)
+        .into_iter()
+        .map(|record| record.provider);
+    for peer in found.into_iter().chain(local) {
+        if !holders.contains(&peer) {
+            holders.push(peer);
+        }
+    }
+    holders
+}
+
+/// Choose a holder for each shard: the best scoring of the peers that win the
+/// shard under rendezvous hashing. Peers whose latest heartbeat leaves too
+/// little free space for the shard (counting the shards already given to
+/// them) are skipped, and a shard whose winners are all full goes to the best
+/// scoring peer with room for it. Peers that never sent a heartbeat are
+/// assumed to have room.
+fn choose_holders(
+    shards: &[shard::Shard],
+    peers: &[PeerId],
+    scores: &PeerScores,
+    capacities: &HashMap<PeerId, Heartbeat>,
+) -> Result<Vec<PeerId>, Box<dyn Error>> {
+    let shard_ids: Vec<shard::ShardID> =
+        shards.iter().map(|s| s.id.clone()).collect();
+    let placement =
+        placement::assign_shards(&shard_ids, peers, PLACEMENT_CANDIDATES);
+    let mut free: HashMap<PeerId, u64> = capacities
+        .iter()
+        .map(|(peer, heartbeat)| (peer.clone(), heartbeat.free_bytes))
+        .collect();
+
+    let mut holders = Vec::with_capacity(shards.len());
+    for shard in shards.iter() {
+        let size = shard.size() as u64;
+        let has_room = |peer: &PeerId| free.get(peer).map_or(true, |f| *f >= size);
+        let winners: Vec<PeerId> = placement[&shard.id]
+            .iter()
+            .filter(|peer| has_room(peer))
+            .cloned()
+            .collect();
+        let others: Vec<PeerId> = peers
+            .iter()
+            .filter(|peer| has_room(peer))
+            .cloned()
+            .collect();
+        let holder = match scores.best(&winners).or_else(|| scores.best(&others)) {
+            Some(peer) => peer.clone(),
+            None => {
+                return Err(Box::new(GeneralError::new(
+                    format!("no peer has room for shard {}", shard.index()).as_str(),
+                )))
+            }
+        };
+        if let Some(f) = free.get_mut(&holder) {
+            *f -= size;
+        }
+        holders.push(holder);
+    }
+    Ok(holders)
+}
+
+/// The order to request the shards of a file in: rarest first, or by index if
+/// the file doesn't say which shard is which.
+fn request_order(file: &file::File) -> Vec<usize> {
+    let shard_ids = file.shard_ids();
+    if shard_ids.len() != file.shards().len() {
+        return (0..file.shards().len()).collect();
+    }
+
+    let shard_map: HashMap<shard::ShardID, Vec<PeerId>> = shard_ids
+        .iter()
+        .zip(file.shards().iter())
+        .map(|(id, holder)| {
+            (id.clone(), PeerId::from_bytes(holder).into_iter().collect())
+        })
+        .collect();
+    if shard_map.len() != shard_ids.len() {
+        // Shards with identical contents share an id
+        return (0..shard_ids.len()).collect();
+    }
+    placement::rarest_first_order(&shard_map)
+        .iter()
+        .filter_map(|id| shard_ids.iter().position(|s| s == id))
+        .collect()
+}
+
+/// Build the Kademlia DHT of a node.
+fn build_kademlia(local: PeerId, config: &DhtConfig) -> Kademlia<MemoryStore> {
+    let store = MemoryStore::with_config(local.clone(), config.store_config());
+    Kademlia::with_config(local, store, config.kademlia_config())
+}
+
+/// Remove a corrupted shard from a store and flag it for repair.
+fn drop_for_repair(
+    store: &mut StoreManager,
+    file_id: &file::FileID,
+    index: u32,
+) -> Result<(), Box<dyn Error>> {
+    store.remove_shard(file_id, index)?;
+    store.flag_for_repair(file_id, index)
+}
+
+/// Log an event from a node's swarm, and emit it to the node's subscribers if
+/// they care about it.
+fn handle_swarm_event<TEvent, THandleErr>(
+    events: &EventBus,
+    event: SwarmEvent<TEvent, THandleErr>,
+) where
+    TEvent: std::fmt::Debug,
+    THandleErr: std::fmt::Debug,
+{
+    match event {
+        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            println!("peer joined: {:?}", peer_id);
+            events.emit(NodeEvent::PeerJoined(peer_id));
+        }
+
+        SwarmEvent::ConnectionClosed { peer_id, .. } => {
+            println!("peer left: {:?}", peer_id);
+            events.emit(NodeEvent::PeerLeft(peer_id));
+        }
+
+        SwarmEvent::IncomingConnectionError {
+            send_back_addr,
+            error,
+            ..
+        } => eprintln!("rejected connection from {:?}: {:?}", send_back_addr, error),
+
+        _ => println!("swarm event: {:?}", event),
+    }
+}
+
+/// Make a swarm listen on every one of `addrs`, once they are all validated.
+fn listen(
+    swarm: &mut Swarm<MerosBehavior>,
+    addrs: &[Multiaddr],
+) -> Result<(), Box<dyn Error>> {
+    if addrs.is_empty() {
+        return Err(Box::new(GeneralError::new("no address to listen on")));
+    }
+    for addr in addrs {
+        transport::validate_listen_addr(addr)?;
+    }
+    for addr in addrs {
+        Swarm::listen_on(swarm, addr.clone())?;
+    }
+    Ok(())
+}
+
+/// Remove the duplicates and the local node from a list of peers, keeping the
+/// first occurrence of each peer in its place.
+fn distinct_remote_peers<I>(peers: I, local: &PeerId) -> Vec<PeerId>
+where
+    I: IntoIterator<Item = PeerId>,
+{
+    let mut seen = HashSet::new();
+    peers
+        .into_iter()
+        .filter(|peer| peer != local && seen.insert(peer.clone()))
+        .collect()
+}
+
+/// A node on the Meros network. A Node stores and broadcasts shards on the network
+/// to host files.
+pub struct Node {
+    /// The local name of the node on the disk
+    name: String,
+
+    /// The node's identity and private key on the network (keypair and peer id)
+    identity: Identity,
+
+    /// The collection of shards that this node holds, across every store
+    /// registered with the node. Each store is a map from fileIDs to a Vec of
+    /// shards, using sled db.
+    shards: StoreManager, // Make Arc<RwLock<>>
+
+    /// This node's list of pending operations, and their ids.
+    pending_ops: Vec<(OperationId, Operation)>, // Make Arc<RwLock<>>
+
+    /// The id of the next operation to be pushed
+    next_operation_id: OperationId,
+
+    /// The puts and gets that were started but may still be in flight, and the
+    /// files they transfer
+    in_flight: HashMap<OperationId, file::FileID>,
+
+    /// The node's configuration
+    config: NodeConfig,
+
+    /// Whether the node only works on the local disk and never joins the network
+    local: bool,
+
+    /// Where the node's events are broadcast to its subscribers
+    events: EventBus,
+
+    /// The pubsub topics that the listening node is subscribed to
+    subscriptions: HashSet<String>,
+
+    /// The health of the node, shared with its health handles
+    health: HealthHandle,
+
+    /// The threads that CPU-bound shard work runs on
+    workers: ShardWorkers,
+
+    /// The puts whose shards are being calculated by the workers
+    sharding: Vec<ShardingPut>,
+
+    /// The puts waiting for enough peers to be online
+    waiting_puts: Vec<WaitingPut>,
+
+    /// The reshards of files held by peers, whose files are being fetched or
+    /// sharded again
+    resharding: Vec<RemoteReshard>,
+
+    /// The latest heartbeat of each peer
+    capacities: Capacities,
+
+    /// The latest round trip time measured to each peer
+    latencies: Latencies,
+
+    /// The receipt of the latest successful put of each file
+    receipts: Receipts,
+
+    /// The files whose metadata records have passed through the local DHT
+    /// store
+    dht_files: DhtFiles,
+}
+
+/// The shards of a file and the config they were made with, or why they
+/// couldn't be made.
+type ShardingResult = Result<(Vec<shard::Shard>, shard::ShardConfig), String>;
+
+/// A put whose shards are being calculated by the node's workers.
+struct ShardingPut {
+    /// The metadata of the file being put
+    file_metadata: file::File,
+
+    /// The peers that the shards can be placed on
+    peers: Vec<PeerId>,
+
+    /// Where the progress of the put is reported
+    progress: Option<ProgressSink>,
+
+    /// The fewest holders that have to acknowledge their shards
+    min_nodes: u16,
+
+    /// Where the receipt of the put is sent
+    receipt: Option<ReceiptSink>,
+
+    /// The shards, once they are calculated
+    shards: oneshot::Receiver<ShardingResult>,
+}
+
+/// The metadata of a file before and after it was resharded, and its new
+/// shards, or why the file couldn't be resharded.
+type ReshardResult = Result<(file::File, file::File, Vec<shard::Shard>), String>;
+
+/// A reshard of a file held by peers, whose file is being fetched from its
+/// holders and sharded again.
+struct RemoteReshard {
+    /// The fetch and reshard of the file
+    job: future::BoxFuture<'static, ReshardResult>,
+
+    /// Where the resharded metadata of the file is sent
+    sink: MetadataSink,
+}
+
+/// A put waiting for enough peers to be online before it is sharded.
+struct WaitingPut {
+    /// When the put stops waiting
+    deadline: Instant,
+
+    /// The metadata of the file being put
+    file_metadata: file::File,
+
+    /// The bytes of the file being put
+    file_bytes: Arc<Vec<u8>>,
+
+    /// The configuration of the put
+    config: OperationConfig,
+
+    /// Where the progress of the put is reported
+    progress: Option<ProgressSink>,
+
+    /// Where the receipt of the put is sent
+    receipt: Option<ReceiptSink>,
+}
+
+/// The id of an operation pushed to a node, used to cancel it.
+pub type OperationId = u64;
+
+/// An operation that a node on the network can perform. This enum will
+/// grow as features on the network grow.
+#[derive(Clone)]
+pub enum Operation {
+    /// Store a file on the network. Also sends the shards to all other nodes.
+    /// The bytes of the file are shared, so that queueing and running the
+    /// operation never copies them.
+    PutFile {
+        file_metadata: file::File,
+        file_bytes: Arc<Vec<u8>>,
+        config: OperationConfig,
+        progress: Option<ProgressSink>,
+        receipt: Option<ReceiptSink>,
+    },
+
+    /// Put the shards of a resharded file on the network as they are, and
+    /// delete the shards they replace.
+    Reshard {
+        old: file::File,
+        file_metadata: file::File,
+        shards: Vec<shard::Shard>,
+    },
+
+    /// Get a file held by peers, shard it again into `shard_count` shards, and
+    /// put the new shards on the network, sending the resharded metadata to
+    /// `sink`.
+    ReshardFile {
+        file_id: file::FileID,
+        shard_count: usize,
+        priv_key: Arc<SecretKey>,
+        sink: MetadataSink,
+    },
+
+    /// Poll all the necessary nodes to get a file from the network.
+    GetFile {
+        file_id: file::FileID,
+        config: OperationConfig,
+    },
+
+    /// Look up the metadata of a file, sending it to `sink`.
+    GetMetadata {
+        file_id: file::FileID,
+        sink: MetadataSink,
+    },
+
+    /// Get a file from the network, sending each shard to `sink` as it arrives.
+    StreamFile {
+        file_id: file::FileID,
+        sink: ShardSink,
+    },
+
+    /// Announce every shard in the node's store as held by this node.
+    AnnounceShards,
+
+    /// Look up the current holders of a shard, sending them to `sink`.
+    FindShardHolders {
+        shard_id: shard::ShardID,
+        sink: HolderSink,
+    },
+
+    /// Make sure every shard of a file is held by at least `target` peers.
+    EnsureReplication {
+        file_id: file::FileID,
+        target: usize,
+    },
+
+    /// Get several files from the network, sending the shards of each file to
+    /// the sink at the same position as its id.
+    GetBatch {
+        file_ids: Vec<file::FileID>,
+        config: OperationConfig,
+        sinks: Vec<ShardSink>,
+    },
+
+    /// Check whether a file can currently be reconstructed, sending the result
+    /// to `sink`.
+    CanReconstruct {
+        file_id: file::FileID,
+        sink: FeasibilitySink,
+    },
+
+    /// Measure the round trip time to a peer, sending it to `sink`.
+    Ping { peer_id: PeerId, sink: PingSink },
+
+    /// Publish the metadata of a file in the DHT.
+    PublishMetadata { file: file::File },
+
+    /// Remove the metadata of a deleted file from the DHT, and stop announcing
+    /// its shards.
+    UnpublishFile { file: file::File },
+
+    /// Stop an in-flight put or get of a file and clean up its partial state.
+    CancelTransfer { file_id: file::FileID },
+
+    /// Send a test pubsub msg.
+    TestSub,
+}
+
+/// Where the bytes of a file (or why its get failed) are sent once a get of
+/// the file is over.
+pub type OutputSink = mpsc::UnboundedSender<Result<Vec<u8>, GeneralError>>;
+
+/// Where the bytes of a file go once a get of it completes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Output {
+    /// Write the bytes to the standard output
+    Stdout,
+
+    /// Write the bytes to a file at a path
+    Path(PathBuf),
+
+    /// Throw the bytes away, only collecting the shards
+    Discard,
+
+    /// Send the bytes, or why the get failed, to a channel. A channel can't
+    /// be saved in a snapshot, so it is saved as `Discard`.
+    #[serde(skip)]
+    Channel(OutputSink),
+}
+
+impl Output {
+    /// Write the bytes of a file to the output. A channel is told about a
+    /// failed get, and every other output gets the error back.
+    fn write(
+        self,
+        bytes: Result<Vec<u8>, GeneralError>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Output::Channel(sink) = self {
+            sink.unbounded_send(bytes).ok();
+            return Ok(());
+        }
+
+        let bytes = bytes?;
+        match self {
+            Output::Stdout => {
+                std::io::Write::write_all(&mut std::io::stdout(), &bytes)?
+            }
+            Output::Path(path) => fs::write(path, bytes)?,
+            Output::Discard | Output::Channel(_) => {}
+        }
+        Ok(())
+    }
+
+    /// The output as it is saved in a snapshot.
+    fn saved(self) -> Self {
+        match self {
+            Output::Channel(_) => Output::Discard,
+            output => output,
+        }
+    }
+}
+
+impl From<&str> for Output {
+    /// `"none"` discards the bytes and `"-"` writes them to the standard
+    /// output. Anything else is the path of the file to write.
+    fn from(output: &str) -> Self {
+        match output {
+            "none" => Output::Discard,
+            "-" => Output::Stdout,
+            path => Output::Path(PathBuf::from(path)),
+        }
+    }
+}
+
+/// A get whose file is written to an `Output` once every shard has arrived.
+struct PendingOutput {
+    /// Where the file goes
+    output: Output,
+
+    /// The key the file is decrypted with, if it should be
+    private_key: Option<SecretKey>,
+
+    /// The largest the reconstructed file may be, in bytes
+    max_bytes: u64,
+
+    /// The metadata of the file, once it is known
+    file: Option<file::File>,
+
+    /// The shards that have arrived, by index
+    shards: HashMap<u32, shard::Shard>,
+}
+
+impl PendingOutput {
+    fn new(output: Output, private_key: Option<SecretKey>, max_bytes: u64) -> Self {
+        Self {
+            output,
+            private_key,
+            max_bytes,
+            file: None,
+            shards: HashMap::new(),
+        }
+    }
+
+    /// Reconstruct and validate the file and write it to the output, or tell
+    /// the output why the get failed.
+    fn finish(self, failure: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let bytes = match (failure, &self.file) {
+            (Some(reason), _) => Err(GeneralError::new(reason)),
+            (None, None) => {
+                Err(GeneralError::new("the file's metadata never arrived"))
+            }
+            (None, Some(file)) => reconstruct_output(
+                file,
+                self.shards,
+                self.private_key.as_ref(),
+                self.max_bytes,
+            ),
+        };
+        self.output.write(bytes)
+    }
+}
+
+/// Reconstruct a file of no more than `max_bytes` bytes from the shards that a
+/// get collected, and make sure the bytes are the file's.
+fn reconstruct_output(
+    file: &file::File,
+    shards: HashMap<u32, shard::Shard>,
+    private_key: Option<&SecretKey>,
+    max_bytes: u64,
+) -> Result<Vec<u8>, GeneralError> {
+    let bytes = shard::Shard::reconstruct_map(
+        shards,
+        &file.shard_config,
+        private_key,
+        max_bytes,
+    )
+    .map_err(|e| GeneralError::new(&e.to_string()))?;
+    match file.validate_bytes(&bytes) {
+        Ok(report) if report.all_ok() => Ok(bytes),
+        Ok(report) => Err(GeneralError::new(
+            format!("the reconstructed file is invalid: {:?}", report).as_str(),
+        )),
+        Err(e) => Err(GeneralError::new(&e.to_string())),
+    }
+}
+
+/// Parameters for a client operation on the network.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationConfig {
+    /// Where the file of a get goes once it has arrived
+    pub output: Output,
+
+    /// Minimum number of nodes that the operation must contact to be valid.
+    pub min_nodes: u16,
+
+    /// Should the output be automatically decrypted.
+    pub decrypt: bool,
+
+    /// How long a put may wait for `min_nodes` peers (or at least one) to be
+    /// online before it gives up, for a node that is still discovering its
+    /// peers. A put without it fails right away if no peers are known.
+    pub wait_for_peers: Option<Duration>,
+
+    /// Put a file even if this node already put it and every shard was
+    /// stored. Without it, putting the same file again is a no-op that
+    /// answers with the earlier put's receipt.
+    pub force: bool,
+}
+
+impl Node {
+    /// Initialize a new node.
+    /// # Arguments
+    /// * `name` - The local name of the node on the disk.
+    pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+        Self::with_config(name, NodeConfig::default())
+    }
+
+    /// Initialize a new node with a custom configuration.
+    /// # Arguments
+    /// * `name` - The local name of the node on the disk.
+    /// * `config` - The configuration of the node.
+    pub fn with_config(
+        name: &str,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_identity(name, Identity::new(name)?, config)
+    }
+
+    /// Initialize a new node whose identity is kept encrypted with a
+    /// passphrase on the disk (see `Identity::with_passphrase`).
+    /// # Arguments
+    /// * `name` - The local name of the node on the disk.
+    /// * `passphrase` - The passphrase that the identity is encrypted with.
+    /// * `config` - The configuration of the node.
+    pub fn with_passphrase(
+        name: &str,
+        passphrase: &str,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_identity(
+            name,
+            Identity::with_passphrase(name, passphrase)?,
+            config,
+        )
+    }
+
+    /// Initialize a new node with an identity that was already loaded.
+    /// # Arguments
+    /// * `name` - The local name of the node on the disk.
+    /// * `identity` - The identity of the node.
+    /// * `config` - The configuration of the node.
+    pub fn with_identity(
+        name: &str,
+        identity: Identity,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::with_store(name, identity, ShardStore::new(name)?, config)
+    }
+
+    /// Initialize a node whose identity and store are kept in a directory of
+    /// their own, rather than under a local name in the data directory. The
+    /// node's local name is the name of the directory.
+    /// # Arguments
+    /// * `dir` - The directory that the node is kept in.
+    /// * `config` - The configuration of the node.
+    pub fn in_dir(dir: &Path, config: NodeConfig) -> Result<Self, Box<dyn Error>> {
+        let name = dir
+            .file_name()
+            .ok_or_else(|| GeneralError::new("the node's directory has no name"))?
+            .to_string_lossy()
+            .to_string();
+        let identity = Identity::at(dir)?;
+        let store = ShardStore::open(&dir.join("shard_store"))?;
+        Self::with_store(&name, identity, store, config)
+    }
+
+    /// Initialize a node with an identity and a primary store that were
+    /// already opened.
+    fn with_store(
+        name: &str,
+        identity: Identity,
+        store: ShardStore,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let workers = ShardWorkers::new(config.cpu_threads)?;
+        let shards = StoreManager::new(PRIMARY_STORE, store);
+        Ok(Node {
+            name: name.to_string(),
+            identity,
+            health: HealthHandle::new(shards.clone()),
+            shards,
+            pending_ops: Vec::new(),
+            next_operation_id: 0,
+            in_flight: HashMap::new(),
+            config,
+            local: false,
+            events: EventBus::default(),
+            subscriptions: HashSet::new(),
+            workers,
+            sharding: Vec::new(),
+            waiting_puts: Vec::new(),
+            resharding: Vec::new(),
+            capacities: Capacities::default(),
+            latencies: Latencies::default(),
+            receipts: Receipts::default(),
+            dht_files: DhtFiles::default(),
+        })
+    }
+
+    /// Initialize a node that never touches the network. A local node shards,
+    /// stores, and reconstructs files entirely on the disk with `put_local`
+    /// and `get_local`, and can't start listening.
+    /// # Arguments
+    /// * `name` - The local name of the node on the disk.
+    /// * `config` - The configuration of the node.
+    pub fn local(name: &str, config: NodeConfig) -> Result<Self, Box<dyn Error>> {
+        let mut node = Self::with_config(name, config)?;
+        node.local = true;
+        Ok(node)
+    }
+
+    /// Shard a file and store its shards and metadata on the local disk.
+    /// # Arguments
+    /// * `path` - the path of the file to read from on the disk
+    /// * `config` - information about how the data should be sharded
+    /// * `priv_key` - the private key of the owner of the file
+    pub fn put_local(
+        &mut self,
+        path: &Path,
+        config: shard::ShardConfig,
+        priv_key: &SecretKey,
+    ) -> Result<file::File, Box<dyn Error>> {
+        let (file, shards) = file::File::new(path, config, priv_key)?;
+        self.shards.put_shards(&file.id, &shards)?;
+        self.shards.put_metadata(&file)?;
+        self.shards.flush()?;
+        Ok(file)
+    }
+
+    /// Get the metadata of every file that this node has put on the network
+    /// (and hasn't deleted since).
+    pub fn owned_files(&self) -> Result<Vec<file::File>, Box<dyn Error>> {
+        self.shards.owned_files()
+    }
+
+    /// Delete a file that this node owns: forget that it owns the file, and
+    /// remove the shards and metadata of the file held by this node's stores.
+    /// The file's record is then removed from this node's part of the DHT and
+    /// its shards are no longer announced (see `Operation::UnpublishFile`).
+    /// The copies held by other peers aren't removed, and expire along with
+    /// the file's DHT record.
+    pub fn delete_file(
+        &mut self,
+        file_id: &file::FileID,
+    ) -> Result<(), Box<dyn Error>> {
+        let file = match self.shards.owned_file(file_id)? {
+            Some(file) => file,
+            None => {
+                return Err(Box::new(GeneralError::new(
+                    "the node doesn't own the file",
+                )))
+            }
+        };
+        self.shards.remove_owned(file_id)?;
+        self.shards.remove_file(file_id)?;
+        self.shards.flush()?;
+        self.push_operation(Operation::UnpublishFile { file });
+        Ok(())
+    }
+
+    /// Change the number of shards of a file held by this node's store. The
+    /// stored shards are reconstructed, split into `shard_count` new shards
+    /// that replace the old ones, and the file's metadata is updated and signed
+    /// again. Its id stays the same. The file is reconstructed and sharded
+    /// again on the node's workers. If the node is on the network, the new
+    /// shards are then placed on peers as they are, and the holders of the old
+    /// shards are asked to delete them. Files whose shards are held by peers
+    /// are resharded with `reshard_remote`.
+    /// # Arguments
+    /// * `file_id` - the id of the file to reshard
+    /// * `shard_count` - the new number of shards
+    /// * `priv_key` - the private key of the owner of the file
+    pub async fn reshard(
+        &mut self,
+        file_id: &file::FileID,
+        shard_count: usize,
+        priv_key: &SecretKey,
+    ) -> Result<file::File, Box<dyn Error>> {
+        let file = match self.shards.get_metadata(file_id)? {
+            Some(f) => f,
+            None => {
+                return Err(Box::new(GeneralError::new(
+                    format!("no local metadata for {}", file_id.to_hex()).as_str(),
+                )))
+            }
+        };
+
+        // The holders of the old shards are known from the last put
+        let old = self
+            .shards
+            .owned_file(file_id)?
+            .unwrap_or_else(|| file.clone());
+
+        // Collect the current shards, then reconstruct and shard the file again
+        let shards = self.shards.get(file_id)?.unwrap_or_default();
+        let shards = file.canonical_shard_order(&shards)?;
+        let key = SecretKey::from_bytes(&priv_key.to_bytes())
+            .map_err(|e| GeneralError::new(&e.to_string()))?;
+        let max_bytes = self.config.max_reconstruct_bytes;
+        let job = self.workers.run(move || {
+            let mut file = file;
+            let data = shard::Shard::reconstruct_bounded(
+                &shards,
+                &file.shard_config,
+                Some(&key),
+                max_bytes,
+            )
+            .map_err(|e| e.to_string())?;
+            let new_shards = file
+                .reshard(&data, shard_count, &key)
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>((file, new_shards))
+        });
+        let (file, new_shards) = job
+            .await
+            .map_err(|_| GeneralError::new("the shard workers dropped the reshard"))?
+            .map_err(|e| GeneralError::new(&e))?;
+
+        // Replace the old shards with the new ones
+        self.shards.put(file_id, &new_shards)?;
+        self.shards.put_metadata(&file)?;
+        self.shards.flush()?;
+
+        if !self.local {
+            self.push_operation(Operation::Reshard {
+                old,
+                file_metadata: file.clone(),
+                shards: new_shards,
+            });
+        }
+        Ok(file)
+    }
+
+    /// Change the number of shards of a file whose shards are held by peers
+    /// rather than by this node's store. The file's metadata is looked up in
+    /// the DHT and its shards are fetched from their holders the way a get
+    /// fetches them. The file is then reconstructed and split into
+    /// `shard_count` new shards on the node's workers, which are placed on
+    /// peers, and the holders of the old shards are asked to delete them. The
+    /// node must be listening for the reshard to run.
+    /// # Arguments
+    /// * `file_id` - the id of the file to reshard
+    /// * `shard_count` - the new number of shards
+    /// * `priv_key` - the private key of the owner of the file
+    pub fn reshard_remote(
+        &mut self,
+        file_id: file::FileID,
+        shard_count: usize,
+        priv_key: SecretKey,
+    ) -> impl Future<Output = Result<file::File, Box<dyn Error>>> {
+        let (sink, mut results) = mpsc::unbounded();
+        self.push_operation(Operation::ReshardFile {
+            file_id,
+            shard_count,
+            priv_key: Arc::new(priv_key),
+            sink,
+        });
+        async move {
+            match results.next().await {
+                Some(result) => Ok(result?),
+                None => Err(Box::new(GeneralError::new("the reshard was dropped"))
+                    as Box<dyn Error>),
+            }
+        }
+    }
+
+    /// Check the least recently verified stored shards for corruption (bit
+    /// rot). Valid shards are marked as verified, and corrupted shards are
+    /// dropped from the store and flagged for repair. Returns the corrupted
+    /// shards. This runs periodically while the node is listening, and the
+    /// flagged shards are then fetched back from their other holders.
+    pub fn scrub(&mut self) -> Result<Vec<(file::FileID, u32)>, Box<dyn Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut corrupted = Vec::new();
+        for (file_id, index) in self
+            .shards
+            .least_recently_verified(self.config.scrub_batch)?
+        {
+            match self.shards.get_shard(&file_id, index)? {
+                Some(shard) if shard.is_valid() => {
+                    self.shards.mark_verified(&file_id, index, now)?
+                }
+                Some(_) => {
+                    eprintln!(
+                        "scrub: shard {} of {} is corrupted",
+                        index,
+                        file_id.to_hex()
+                    );
+                    drop_for_repair(&mut self.shards, &file_id, index)?;
+                    corrupted.push((file_id, index));
+                }
+                None => {}
+            }
+        }
+
+        self.shards.flush()?;
+        Ok(corrupted)
+    }
+
+    /// Reconstruct the bytes of a file stored on the local disk by `put_local`.
+    /// # Arguments
+    /// * `file_id` - the id of the file to get
+    /// * `priv_key` - If the shards are encrypted, this key will be used to decrypt them
+    pub fn get_local(
+        &self,
+        file_id: &file::FileID,
+        priv_key: Option<&SecretKey>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let file = match self.shards.get_metadata(file_id)? {
+            Some(f) => f,
+            None => {
+                return Err(Box::new(GeneralError::new(
+                    format!("no local metadata for {}", file_id.to_hex()).as_str(),
+                )))
+            }
+        };
+        let shards = self.shards.get(file_id)?.unwrap_or_default();
+        let shards = file.canonical_shard_order(&shards)?;
+        shard::Shard::reconstruct_bounded(
+            &shards,
+            &file.shard_config,
+            priv_key,
+            self.config.max_reconstruct_bytes,
+        )
+    }
+
+    /// Reconstruct a file from the shards and metadata held by this node's
+    /// stores alone, without going to the network. Unlike `get_local`, the
+    /// file is validated against its metadata (its checksum, id, and
+    /// signature) before it is returned. Only the data shards are needed:
+    /// missing ones are recovered from the parity shards if enough of those
+    /// are held, and otherwise every shard that the stores are missing is
+    /// named.
+    /// # Arguments
+    /// * `file_id` - the id of the file to reconstruct
+    /// * `priv_key` - If the shards are encrypted, this key will be used to decrypt them
+    pub fn reconstruct_local(
+        &self,
+        file_id: &file::FileID,
+        priv_key: Option<&SecretKey>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let file = match self.shards.get_metadata(file_id)? {
+            Some(f) => f,
+            None => {
+                return Err(Box::new(GeneralError::new(
+                    format!("no local metadata for {}", file_id.to_hex()).as_str(),
+                )))
+            }
+        };
+
+        let stored = self.shards.get(file_id)?.unwrap_or_default();
+        let mut held = HashMap::new();
+        let mut missing = Vec::new();
+        for (index, id) in file.shard_ids().iter().enumerate() {
+            match stored.iter().find(|s| &s.id == id && s.is_valid()) {
+                Some(shard) => {
+                    let mut shard = shard.clone();
+                    shard.set_index(index as u32);
+                    held.insert(index as u32, shard);
+                }
+                None => missing.push(index),
+            }
+        }
+        let data_count = file.shard_config.sizes.len();
+        if missing.iter().any(|i| *i < data_count) && held.len() < data_count {
+            return Err(Box::new(GeneralError::new(
+                format!(
+                    "shards {:?} of {} aren't held locally",
+                    missing,
+                    file_id.to_hex()
+                )
+                .as_str(),
+            )));
+        }
+
+        let data = shard::Shard::reconstruct_map(
+            held,
+            &file.shard_config,
+            priv_key,
+            self.config.max_reconstruct_bytes,
+        )?;
+        let report = file.validate_bytes(&data)?;
+        if !report.all_ok() {
+            return Err(Box::new(GeneralError::new(
+                format!(
+                    "the local copy of {} is invalid: {:?}",
+                    file_id.to_hex(),
+                    report
+                )
+                .as_str(),
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Register another store for the node's shards (like one on a slower,
+    /// larger disk) under a name that the placement policy can choose. The
+    /// node's own store is named `PRIMARY_STORE`. Stores have to be registered
+    /// before the node starts listening.
+    pub fn add_store(&mut self, name: &str, store: ShardStore) {
+        self.shards.add_store(name, store);
+        self.health.set_stores(self.shards.clone());
+    }
+
+    /// Set the policy that chooses which store each shard the node stores is
+    /// kept in. Without a policy, every shard is kept in `PRIMARY_STORE`.
+    pub fn set_placement_policy(&mut self, policy: PlacementPolicy) {
+        self.shards.set_policy(policy);
+        self.health.set_stores(self.shards.clone());
+    }
+
+    /// Export a file held by this node for an offline transfer (like on a USB
+    /// drive): write its signed manifest and every shard of it that this node
+    /// holds, each as its own file, into `out_dir`. Returns the number of
+    /// shards written.
+    /// # Arguments
+    /// * `file_id` - the id of the file to export
+    /// * `out_dir` - the directory to write the bundle to (created if needed)
+    pub fn export_bundle(
+        &self,
+        file_id: &file::FileID,
+        out_dir: &Path,
+    ) -> Result<usize, Box<dyn Error>> {
+        let file = match self.shards.get_metadata(file_id)? {
+            Some(f) => f,
+            None => {
+                return Err(Box::new(GeneralError::new(
+                    format!("no local metadata for {}", file_id.to_hex()).as_str(),
+                )))
+            }
+        };
+        let shards = self.shards.get(file_id)?.unwrap_or_default();
+
+        fs::create_dir_all(out_dir)?;
+        fs::write(out_dir.join(BUNDLE_MANIFEST), file.export_manifest()?)?;
+        for shard in shards.iter() {
+            fs::write(
+                out_dir.join(format!("shard_{}", shard.index())),
+                shard.to_bytes()?,
+            )?;
+        }
+        Ok(shards.len())
+    }
+
+    /// Import a bundle written by `export_bundle` into this node's store. The
+    /// manifest must be authentic and every shard must be a valid shard of the
+    /// file, or nothing is imported.
+    /// # Arguments
+    /// * `dir` - the directory holding the bundle
+    /// * `republish` - whether to publish the file's metadata and announce its
+    ///   shards on the network once the node is listening
+    pub fn import_bundle(
+        &mut self,
+        dir: &Path,
+        republish: bool,
+    ) -> Result<file::File, Box<dyn Error>> {
+        let file =
+            file::File::verify_manifest(&fs::read(dir.join(BUNDLE_MANIFEST))?)?;
+
+        let mut shards = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_shard = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.starts_with("shard_"));
+            if !is_shard {
+                continue;
+            }
+
+            let shard = shard::Shard::from_bytes(fs::read(&path)?)?;
+            let expected = file.shard_ids().get(shard.index() as usize);
+            if !shard.is_valid() || expected != Some(&shard.id) {
+                return Err(Box::new(GeneralError::new(
+                    format!("{:?} is not a valid shard of the file", path).as_str(),
+                )));
+            }
+            shards.push(shard);
+        }
+
+        self.shards.put_shards(&file.id, &shards)?;
+        self.shards.put_metadata(&file)?;
+        self.shards.flush()?;
+
+        if republish && !self.local {
+            self.push_operation(Operation::PublishMetadata { file: file.clone() });
+            self.announce_shards();
+        }
+        Ok(file)
+    }
+
+    /// Write a point-in-time snapshot of the node to a single archive at
+    /// `path`: its identity, every one of its stores (with the shards and the
+    /// metadata of its files), and its pending operations. The identity's
+    /// keypair is encrypted with `passphrase`, so the archive never holds it in
+    /// plain text. The stores are flushed before they are read. Operations
+    /// that report back through a channel (like metadata lookups) aren't saved.
+    pub fn snapshot(
+        &self,
+        path: &Path,
+        passphrase: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let keypair = match &self.identity.keypair {
+            libp2p::identity::Keypair::Ed25519(k) => {
+                identity::seal_keypair(k, passphrase)?
+            }
+            _ => {
+                return Err(Box::new(GeneralError::new(
+                    "only ed25519 identities can be snapshotted",
+                )))
+            }
+        };
+
+        let mut stores = Vec::new();
+        for name in self.shards.names() {
+            if let Some(store) = self.shards.store(&name) {
+                stores.push((name, store.dump()?));
+            }
+        }
+
+        let pending_ops = self
+            .pending_ops
+            .iter()
+            .filter_map(|(_, op)| match op.clone() {
+                Operation::PutFile {
+                    file_metadata,
+                    file_bytes,
+                    config,
+                    ..
+                } => Some(SavedOperation::PutFile {
+                    file_metadata,
+                    file_bytes: file_bytes.to_vec(),
+                    config: OperationConfig {
+                        output: config.output.saved(),
+                        ..config
+                    },
+                }),
+                Operation::GetFile { file_id, config } => {
+                    Some(SavedOperation::GetFile {
+                        file_id,
+                        config: OperationConfig {
+                            output: config.output.saved(),
+                            ..config
+                        },
+                    })
+                }
+                Operation::AnnounceShards => Some(SavedOperation::AnnounceShards),
+                Operation::EnsureReplication { file_id, target } => {
+                    Some(SavedOperation::EnsureReplication { file_id, target })
+                }
+                Operation::PublishMetadata { file } => {
+                    Some(SavedOperation::PublishMetadata { file })
+                }
+                _ => None,
+            })
+            .collect();
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            keypair,
+            stores,
+            pending_ops,
+        };
+        fs::write(path, bincode::serialize(&snapshot)?)?;
+        Ok(())
+    }
+
+    /// Rebuild a node from a snapshot written by `snapshot`, under a local name
+    /// that isn't in use yet. The primary store is restored into the node's
+    /// own store, and any other store into a store next to it. The restored
+    /// identity stays encrypted with the snapshot's passphrase (see
+    /// `with_passphrase`).
+    /// # Arguments
+    /// * `path` - the path of the snapshot archive
+    /// * `name` - the local name of the restored node on the disk
+    /// * `passphrase` - the passphrase that the snapshot was written with
+    /// * `config` - the configuration of the restored node
+    pub fn restore(
+        path: &Path,
+        name: &str,
+        passphrase: &str,
+        config: NodeConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let snapshot: Snapshot = bincode::deserialize(&fs::read(path)?)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(Box::new(GeneralError::new(
+                format!("unsupported snapshot version {}", snapshot.version)
+                    .as_str(),
+            )));
+        }
+
+        let dir = Path::new(DATADIR).join("identities").join(name);
+        if dir.exists() {
+            return Err(Box::new(GeneralError::new(
+                format!("a node named {} already exists", name).as_str(),
+            )));
+        }
+        identity::open_keypair(&snapshot.keypair, name, passphrase)?;
+        fs::create_dir_all(&dir)?;
+        identity::write_sealed_keypair(&dir, &snapshot.keypair)?;
+
+        let mut node = Self::with_passphrase(name, passphrase, config)?;
+        for (i, (store_name, dump)) in snapshot.stores.iter().enumerate() {
+            if i == 0 {
+                node.shards.primary_mut().load(dump)?;
+            } else {
+                let mut store = ShardStore::open(&dir.join(store_name))?;
+                store.load(dump)?;
+                node.add_store(store_name, store);
+            }
+        }
+
+        for op in snapshot.pending_ops {
+            node.push_operation(match op {
+                SavedOperation::PutFile {
+                    file_metadata,
+                    file_bytes,
+                    config,
+                } => Operation::PutFile {
+                    file_metadata,
+                    file_bytes: Arc::new(file_bytes),
+                    config,
+                    progress: None,
+                    receipt: None,
+                },
+                SavedOperation::GetFile { file_id, config } => {
+                    Operation::GetFile { file_id, config }
+                }
+                SavedOperation::AnnounceShards => Operation::AnnounceShards,
+                SavedOperation::EnsureReplication { file_id, target } => {
+                    Operation::EnsureReplication { file_id, target }
+                }
+                SavedOperation::PublishMetadata { file } => {
+                    Operation::PublishMetadata { file }
+                }
+            });
+        }
+        Ok(node)
+    }
+
+    /// Report the health of the node: whether it is listening, how many peers
+    /// it is connected to, whether its shard stores can be written to, and how
+    /// many operations are waiting to be run.
+    pub fn health(&self) -> HealthStatus {
+        self.health.status()
+    }
+
+    /// A handle that reports the health of the node (like `health`), and that
+    /// keeps doing so while the node is running.
+    pub fn health_handle(&self) -> HealthHandle {
+        self.health.clone()
+    }
+
+    /// The free and total space (in bytes) that a peer advertised in its
+    /// latest heartbeat, if it sent one.
+    pub fn peer_capacity(&self, peer_id: &PeerId) -> Option<(u64, u64)> {
+        let capacities = self.capacities.lock().ok()?;
+        capacities
+            .get(peer_id)
+            .map(|h| (h.free_bytes, h.total_bytes))
+    }
+
+    /// Get the metadata of a file from the node's store, if the node keeps it:
+    /// because the file was stored locally, or because the node stores shards
+    /// of it and caches their metadata (`NodeConfig::cache_metadata`).
+    pub fn local_metadata(
+        &self,
+        file_id: &file::FileID,
+    ) -> Result<Option<file::File>, Box<dyn Error>> {
+        self.shards.get_metadata(file_id)
+    }
+
+    /// Check whether the listening node is subscribed to a pubsub topic.
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscriptions.contains(topic)
+    }
+
+    /// Subscribe a swarm to a pubsub topic. Subscribing to a topic that the
+    /// node is already subscribed to does nothing, but a subscription that
+    /// fails is an error, since the node would miss the topic's messages.
+    fn subscribe_topic(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        topic: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.is_subscribed(topic) {
+            return Ok(());
+        }
+        if !swarm.behaviour_mut().pubsub.subscribe(topic)? {
+            return Err(Box::new(GeneralError::new(
+                format!("could not subscribe to {}", topic).as_str(),
+            )));
+        }
+        self.subscriptions.insert(topic.to_string());
+        Ok(())
+    }
+
+    /// Subscribe to the node's events (peers joining and leaving, shards being
+    /// stored and served, files arriving, ...). Every subscriber receives
+    /// every event emitted after it subscribed.
+    pub fn subscribe(&self) -> NodeEvents {
+        self.events.subscribe()
+    }
+
+    /// Push a network operation to this node's stack of operations. Returns
+    /// the id of the operation, which can be used to cancel it.
+    pub fn push_operation(&mut self, op: Operation) -> OperationId {
+        let id = self.next_operation_id;
+        self.next_operation_id += 1;
+        self.pending_ops.push((id, op));
+        self.count_pending_ops();
+        id
+    }
+
+    /// Report the number of operations waiting to be run to the health of the
+    /// node.
+    fn count_pending_ops(&self) {
+        let count = self.pending_ops.len();
+        self.health.update(|status| status.pending_ops = count);
+    }
+
+    /// Cancel an operation. An operation that hasn't run yet is removed from
+    /// the stack of operations. A put or get that has already started is
+    /// stopped, and its partial state is cleaned up, the next time the node
+    /// runs its operations. Returns `false` if there is no such operation (or
+    /// it has already finished).
+    pub fn cancel_operation(&mut self, id: OperationId) -> bool {
+        if let Some(i) = self.pending_ops.iter().position(|(op_id, _)| *op_id == id)
+        {
+            self.pending_ops.remove(i);
+            self.count_pending_ops();
+            return true;
+        }
+
+        match self.in_flight.remove(&id) {
+            Some(file_id) => {
+                self.push_operation(Operation::CancelTransfer { file_id });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queue a put of a file onto the network. The node must be listening for
+    /// the put to run.
+    pub fn queue_put_file(
+        &mut self,
+        file_metadata: file::File,
+        file_bytes: Vec<u8>,
+        config: OperationConfig,
+    ) -> OperationId {
+        self.push_operation(Operation::PutFile {
+            file_metadata,
+            file_bytes: Arc::new(file_bytes),
+            config,
+            progress: None,
+            receipt: None,
+        })
+    }
+
+    /// Queue a get of a file from the network, written to `config.output`. The
+    /// node must be listening for the get to run.
+    pub fn queue_get_file(
+        &mut self,
+        file_id: file::FileID,
+        config: OperationConfig,
+    ) -> OperationId {
+        self.push_operation(Operation::GetFile { file_id, config })
+    }
+
+    /// Put a file onto the network, reporting its progress as it goes. The node
+    /// must be listening for the put to run.
+    pub fn put_file_with_progress(
+        &mut self,
+        file_metadata: file::File,
+        file_bytes: Vec<u8>,
+        config: OperationConfig,
+    ) -> ProgressStream {
+        let (sink, stream) = progress::progress_channel();
+        self.push_operation(Operation::PutFile {
+            file_metadata,
+            file_bytes: Arc::new(file_bytes),
+            config,
+            progress: Some(sink),
+            receipt: None,
+        });
+        stream
+    }
+
+    /// Put a file onto the network, and get a receipt of which holders
+    /// acknowledged storing which shards once every holder has answered. The
+    /// put fails if fewer than `config.min_nodes` shards are acknowledged. The
+    /// node must be listening for the put to run.
+    pub fn put_file_with_receipt(
+        &mut self,
+        file_metadata: file::File,
+        file_bytes: Vec<u8>,
+        config: OperationConfig,
+    ) -> ReceiptStream {
+        let (sink, stream) = mpsc::unbounded();
+        self.push_operation(Operation::PutFile {
+            file_metadata,
+            file_bytes: Arc::new(file_bytes),
+            config,
+            progress: None,
+            receipt: Some(sink),
+        });
+        stream
+    }
+
+    /// Look up the metadata of a file (its name, size, shard map, ...) and its
+    /// owner in the DHT without fetching any of its shards. The node must be
+    /// listening for the lookup to run.
+    pub fn get_metadata(
+        &mut self,
+        file_id: file::FileID,
+    ) -> impl Future<Output = Result<FileMetadata, Box<dyn Error>>> {
+        let (sink, mut results) = mpsc::unbounded();
+        self.push_operation(Operation::GetMetadata { file_id, sink });
+        async move {
+            match results.next().await {
+                Some(result) => {
+                    // The owner is only reported for metadata it signed
+                    let file = result?;
+                    file.verify_metadata()?;
+                    let owner = match file.is_public() {
+                        true => None,
+                        false => Some(file.owner()?),
+                    };
+                    Ok(FileMetadata { file, owner })
+                }
+                None => {
+                    Err(Box::new(GeneralError::new("metadata lookup was dropped"))
+                        as Box<dyn Error>)
+                }
+            }
+        }
+    }
+
+    /// Announce in the DHT that this node holds every shard in its store, so
+    /// that getters can find the shards' current holders. This happens when the
+    /// node starts listening, and every shard stored afterwards is announced
+    /// as it arrives.
+    pub fn announce_shards(&mut self) {
+        self.push_operation(Operation::AnnounceShards);
+    }
+
+    /// Find the peers that currently hold a shard, using the provider records
+    /// in the DHT. Unlike the holders in a file's metadata, these stay fresh
+    /// as peers come and go. The node must be listening for the lookup to run.
+    pub fn find_shard_holders(
+        &mut self,
+        shard_id: shard::ShardID,
+    ) -> impl Future<Output = Result<Vec<PeerId>, Box<dyn Error>>> {
+        let (sink, mut results) = mpsc::unbounded();
+        self.push_operation(Operation::FindShardHolders { shard_id, sink });
+        async move {
+            match results.next().await {
+                Some(result) => Ok(result?),
+                None => Err(Box::new(GeneralError::new(
+                    "shard holder lookup was dropped",
+                )) as Box<dyn Error>),
+            }
+        }
+    }
+
+    /// Make sure that every shard of a file is held by at least `target` peers,
+    /// copying the shards held by too few peers (because holders left the
+    /// network) to more peers. Holders are counted using the provider records
+    /// in the DHT. The node must be listening for the check to run. Set
+    /// `NodeConfig::replication_target` to also do this periodically for
+    /// every file that the node holds shards of.
+    pub fn ensure_replication(&mut self, file_id: file::FileID, target: usize) {
+        self.push_operation(Operation::EnsureReplication { file_id, target });
+    }
+
+    /// Measure the round trip time to a peer over the shard protocol. The node
+    /// must be listening for the ping to be sent.
+    pub fn ping(
+        &mut self,
+        peer_id: PeerId,
+    ) -> impl Future<Output = Result<Duration, Box<dyn Error>>> {
+        let (sink, mut results) = mpsc::unbounded();
+        self.push_operation(Operation::Ping { peer_id, sink });
+        async move {
+            match results.next().await {
+                Some(result) => Ok(result?),
+                None => Err(Box::new(GeneralError::new("ping was dropped"))
+                    as Box<dyn Error>),
+            }
+        }
+    }
+
+    /// Get the latest round trip time measured to a peer with `ping`, if it
+    /// was ever pinged.
+    pub fn peer_latency(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.latencies.lock().ok()?.get(peer_id).copied()
+    }
+
+    /// Estimate how many files exist on the network: the number of distinct
+    /// files that this node knows of, from the metadata records that were put,
+    /// fetched, or stored through its part of the DHT and the files it holds
+    /// shards of or owns. This is only the node's local view, so files whose
+    /// records only ever lived on other peers aren't counted.
+    pub fn network_file_count(&self) -> Result<Estimate, Box<dyn Error>> {
+        let mut files = self
+            .dht_files
+            .lock()
+            .map_err(|_| GeneralError::new("the known DHT files are poisoned"))?
+            .clone();
+        files.extend(self.shards.file_ids()?);
+        files.extend(self.shards.owned_files()?.into_iter().map(|file| file.id));
+        Ok(files.len())
+    }
+
+    /// Check whether enough shards of a file are currently reachable to
+    /// reconstruct it, before downloading any of them. Each shard is reachable
+    /// if the DHT knows of a peer that holds it (by its provider records). The
+    /// node must be listening for the check to run.
+    pub fn can_reconstruct(
+        &mut self,
+        file_id: file::FileID,
+    ) -> impl Future<Output = Result<Feasibility, Box<dyn Error>>> {
+        let (sink, mut results) = mpsc::unbounded();
+        self.push_operation(Operation::CanReconstruct { file_id, sink });
+        async move {
+            match results.next().await {
+                Some(result) => Ok(result?),
+                None => {
+                    Err(Box::new(GeneralError::new("feasibility check was dropped"))
+                        as Box<dyn Error>)
+                }
+            }
+        }
+    }
+
+    /// Get a file from the network, yielding each validated shard (and its
+    /// index) as it arrives rather than waiting for the whole file. The stream
+    /// completes once every shard has arrived, and yields an error if the get
+    /// times out first. The node must be listening for the get to run.
+    pub fn get_file_streaming(&mut self, file_id: file::FileID) -> ShardStream {
+        let (sink, stream) = stream::shard_stream();
+        self.push_operation(Operation::StreamFile { file_id, sink });
+        stream
+    }
+
+    /// Get several files from the network at once, like when restoring many
+    /// files. The peers are discovered and dialed once for the whole batch,
+    /// and at most `NodeConfig::max_concurrent_gets` files are fetched at
+    /// once. The stream yields the shards of each file (or why getting it
+    /// failed) as each file finishes. The node must be listening for the gets
+    /// to run.
+    pub fn get_batch(
+        &mut self,
+        file_ids: Vec<file::FileID>,
+        config: OperationConfig,
+    ) -> impl Stream<Item = BatchItem> {
+        let (sinks, streams): (Vec<ShardSink>, Vec<ShardStream>) =
+            file_ids.iter().map(|_| stream::shard_stream()).unzip();
+        self.push_operation(Operation::GetBatch {
+            file_ids: file_ids.clone(),
+            config,
+            sinks,
+        });
+        file_ids
+            .into_iter()
+            .zip(streams)
+            .map(|(file_id, shards)| async move {
+                (file_id, shards.collect_shards().await)
+            })
+            .collect::<futures::stream::FuturesUnordered<_>>()
+    }
+
+    /// Build the swarm that drives this node on the network.
+    async fn build_swarm(&self) -> Result<Swarm<MerosBehavior>, Box<dyn Error>> {
+        let transport =
+            transport::build_transport(self.identity.keypair.clone()).await?;
+
+        let kademlia =
+            build_kademlia(self.identity.peer_id.clone(), &self.config.dht);
+        let mdns = Mdns::new(MdnsConfig::default()).await?;
+        let pubsub = Pubsub::new(
+            self.config.pubsub,
+            &self.identity.keypair,
+            self.config.max_payload_bytes,
+        )?;
+        let shard_protocol = RequestResponse::new(
+            ShardCodec::new(self.config.max_payload_bytes),
+            iter::once((ShardProtocol(), ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        );
+        let behavior = MerosBehavior {
+            kademlia,
+            mdns,
+            pubsub,
+            shard_protocol,
+            put_retries: RetryTracker::new(self.config.put_retry.clone()),
+            local_peer_id: self.identity.peer_id.clone(),
+            events: self.events.clone(),
+            store: self.shards.clone(),
+            pending_gets: HashMap::new(),
+            pending_outputs: HashMap::new(),
+            queued_gets: VecDeque::new(),
+            max_concurrent_gets: self.config.max_concurrent_gets,
+            get_timeout: self.config.get_timeout,
+            pending_metadata: HashMap::new(),
+            pending_holders: HashMap::new(),
+            corrupt_records: 0,
+            pending_replications: HashMap::new(),
+            pending_replica_checks: HashMap::new(),
+            pending_copies: HashMap::new(),
+            pending_repairs: HashMap::new(),
+            pending_races: HashMap::new(),
+            get_fan_out: self.config.get_fan_out,
+            holder_selection: self.config.holder_selection,
+            max_holder_attempts: self.config.max_holder_attempts,
+            pending_shard_requests: HashMap::new(),
+            shard_timeout: self.config.shard_timeout,
+            pending_feasibility: HashMap::new(),
+            max_payload_bytes: self.config.max_payload_bytes,
+            cache_metadata: self.config.cache_metadata,
+            hosts_shards: self.config.hosts_shards,
+            pending_caches: HashMap::new(),
+            scores: PeerScores::new(self.config.score_weights),
+            capacities: self.capacities.clone(),
+            latencies: self.latencies.clone(),
+            dht_files: self.dht_files.clone(),
+            receipts: self.receipts.clone(),
+            pending_pings: HashMap::new(),
+            request_times: HashMap::new(),
+            pending_acks: HashMap::new(),
+            #[cfg(test)]
+            sent_shards: Vec::new(),
+            pending_receipts: HashMap::new(),
+            unconfirmed_puts: HashMap::new(),
+            feasibility_checks: HashMap::new(),
+            pending_feasibility_lookups: HashMap::new(),
+            dial_queue: VecDeque::new(),
+            dialing: HashSet::new(),
+            workers: self.workers.clone(),
+            max_concurrent_dials: self.config.max_concurrent_dials,
+            deferred_requests: HashMap::new(),
+        };
+
+        Ok(
+            SwarmBuilder::new(transport, behavior, self.identity.peer_id.clone())
+                .connection_limits(self.config.connection_limits())
+                .build(),
+        )
+    }
+
+    /// Start listening on a node on every ipv4 interface, at a tcp port (`0`
+    /// for any free port).
+    pub async fn start_listening(
+        &mut self,
+        port: u16,
+    ) -> Result<(), Box<dyn Error>> {
+        self.start_listening_on(vec![format!("/ip4/0.0.0.0/tcp/{}", port).parse()?])
+            .await
+    }
+
+    /// Start listening on a node at one or more addresses, like a loopback-only
+    /// or ipv6 address. Every address is validated before the node listens on
+    /// any of them.
+    pub async fn start_listening_on(
+        &mut self,
+        addrs: Vec<Multiaddr>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.local {
+            return Err(Box::new(GeneralError::new(
+                "a local node can't join the network",
+            )));
+        }
+        let mut swarm = self.build_swarm().await?;
+        self.subscriptions.clear();
+        self.subscribe_topic(&mut swarm, SHARD_CHANNEL)?;
+        self.subscribe_topic(&mut swarm, STATUS_CHANNEL)?;
+        self.announce_shards();
+
+        let mut stdin = io::BufReader::new(io::stdin()).lines();
+
+        // Start listening on this node
+        listen(&mut swarm, &addrs)?;
+
+        // Construct the future for handling lines from stdin
+        self.health.update(|status| {
+            status.listening = false;
+            status.connected_peers = 0;
+        });
+        let mut stdin_open = true;
+        let mut hi = false;
+        let mut tick = task::sleep(TICK_INTERVAL).boxed();
+        let mut last_scrub = Instant::now();
+        let mut last_replication = Instant::now();
+        let mut last_heartbeat = Instant::now();
+        let fut = future::poll_fn(move |cx: &mut Context<'_>| {
+            loop {
+                // Run timed maintenance
+                while let Poll::Ready(()) = tick.poll_unpin(cx) {
+                    swarm.behaviour_mut().retry_puts();
+                    swarm.behaviour_mut().expire_gets();
+                    self.retry_waiting_puts(&mut swarm);
+                    if last_scrub.elapsed() >= self.config.scrub_interval {
+                        if let Err(e) = self.scrub() {
+                            eprintln!("scrub failed: {:?}", e);
+                        }
+                        self.repair_flagged(&mut swarm);
+                        last_scrub = Instant::now();
+                    }
+                    let behaviour = swarm.behaviour();
+                    let sharding: Vec<file::FileID> = self
+                        .sharding
+                        .iter()
+                        .map(|put| &put.file_metadata.id)
+                        .chain(
+                            self.waiting_puts
+                                .iter()
+                                .map(|put| &put.file_metadata.id),
+                        )
+                        .cloned()
+                        .collect();
+                    self.in_flight.retain(|_, file_id| {
+                        behaviour.is_transferring(file_id)
+                            || sharding.contains(file_id)
+                    });
+                    if last_heartbeat.elapsed() >= self.config.heartbeat_interval {
+                        if let Err(e) = swarm.behaviour_mut().send_heartbeat() {
+                            eprintln!("failed to send a heartbeat: {:?}", e);
+                        }
+                        last_heartbeat = Instant::now();
+                    }
+                    if let Some(target) = self.config.replication_target {
+                        if last_replication.elapsed()
+                            >= self.config.replication_interval
+                        {
+                            self.replicate_held_files(&mut swarm, target);
+                            last_replication = Instant::now();
+                        }
+                    }
+                    tick = task::sleep(TICK_INTERVAL).boxed();
+                }
+
+                // Finish the puts whose shards are ready
+                self.poll_sharding(&mut swarm, cx);
+
+                // Place the shards of the remote reshards that are ready
+                self.poll_resharding(&mut swarm, cx);
+
+                // Dial the shard holders that gets are waiting on
+                self.dial_holders(&mut swarm);
+
+                if hi == false {
+                    swarm.behaviour_mut().hi();
+                    println!("\n\n SAYING HI \n\n");
+                    hi = true;
+                }
+
+                // If this node has pending operations, execute them
+                if self.pending_ops.len() != 0 {
+                    let (id, op) = self.pending_ops[0].clone();
+                    let transfer = match &op {
+                        Operation::PutFile { file_metadata, .. }
+                        | Operation::Reshard { file_metadata, .. } => {
+                            Some(file_metadata.id.clone())
+                        }
+                        Operation::GetFile { file_id, .. }
+                        | Operation::StreamFile { file_id, .. }
+                        | Operation::ReshardFile { file_id, .. } => {
+                            Some(file_id.clone())
+                        }
+                        _ => None,
+                    };
+                    let result = match op {
+                        Operation::PutFile {
+                            file_metadata,
+                            file_bytes,
+                            config,
+                            progress,
+                            receipt,
+                        } => self.put_file(
+                            &mut swarm,
+                            file_metadata,
+                            file_bytes,
+                            &config,
+                            progress,
+                            receipt,
+                        ),
+                        Operation::Reshard {
+                            old,
+                            file_metadata,
+                            shards,
+                        } => {
+                            self.redistribute(&mut swarm, old, file_metadata, shards)
+                        }
+                        Operation::ReshardFile {
+                            file_id,
+                            shard_count,
+                            priv_key,
+                            sink,
+                        } => self.start_reshard(
+                            &mut swarm,
+                            file_id,
+                            shard_count,
+                            priv_key,
+                            sink,
+                        ),
+                        Operation::GetFile { file_id, config } => {
+                            self.get_file(&mut swarm, &file_id, &config)
+                        }
+                        Operation::GetBatch {
+                            file_ids, sinks, ..
+                        } => self.get_files(&mut swarm, file_ids, sinks),
+                        Operation::GetMetadata { file_id, sink } => swarm
+                            .behaviour_mut()
+                            .get_metadata(&file_id, sink)
+                            .map(|_| ()),
+                        Operation::StreamFile { file_id, sink } => {
+                            self.stream_file(&mut swarm, file_id, sink)
+                        }
+                        Operation::AnnounceShards => {
+                            self.announce_stored_shards(&mut swarm)
+                        }
+                        Operation::FindShardHolders { shard_id, sink } => swarm
+                            .behaviour_mut()
+                            .find_shard_holders(&shard_id, sink)
+                            .map(|_| ()),
+                        Operation::EnsureReplication { file_id, target } => swarm
+                            .behaviour_mut()
+                            .ensure_replication(&file_id, target)
+                            .map(|_| ()),
+                        Operation::CanReconstruct { file_id, sink } => swarm
+                            .behaviour_mut()
+                            .can_reconstruct(&file_id, sink)
+                            .map(|_| ()),
+                        Operation::Ping { peer_id, sink } => {
+                            swarm.behaviour_mut().ping(&peer_id, sink);
+                            Ok(())
+                        }
+                        Operation::CancelTransfer { file_id } => {
+                            self.sharding
+                                .retain(|put| put.file_metadata.id != file_id);
+                            self.waiting_puts
+                                .retain(|put| put.file_metadata.id != file_id);
+                            swarm.behaviour_mut().cancel_transfer(&file_id)
+                        }
+                        Operation::PublishMetadata { file } => {
+                            self.publish_metadata(&mut swarm, &file)
+                        }
+                        Operation::UnpublishFile { file } => {
+                            swarm.behaviour_mut().unpublish_file(&file)
+                        }
+                        Operation::TestSub => self.test_sub(&mut swarm),
+                        _ => Ok(()),
+                    };
+
+                    match result {
+                        Ok(_) => {
+                            println!("successfully executed operation");
+                            self.pending_ops.remove(0);
+                            self.count_pending_ops();
+                            if let Some(file_id) = transfer {
+                                self.in_flight.insert(id, file_id);
+                            }
+                        }
+                        Err(e) => println!("error executing operation: {:?}", e),
+                    }
+                }
+
+                /* testing with just stdin for sanity */
+                while stdin_open {
+                    match stdin.try_poll_next_unpin(cx)? {
+                        Poll::Ready(Some(line)) => {
+                            let sb = swarm.behaviour_mut();
+                            super::handler::handle_stdin_line(
+                                &mut sb.kademlia,
+                                &mut sb.pubsub,
+                                line,
+                            )
+                        }
+                        Poll::Ready(None) => {
+                            println!("stdin closed");
+                            stdin_open = false;
+                        }
+                        Poll::Pending => break,
+                    }
+                }
+
+                /* --- This can be left alone --- */
+
+                // Then poll the swarm for an event
+                match swarm.poll_next_unpin(cx) {
+                    Poll::Ready(Some(event)) => {
+                        match &event {
+                            SwarmEvent::ConnectionEstablished {
+                                peer_id, ..
+                            }
+                            | SwarmEvent::UnreachableAddr {
+                                peer_id,
+                                attempts_remaining: 0,
+                                ..
+                            } => swarm.behaviour_mut().dial_finished(peer_id),
+                            _ => {}
+                        }
+                        handle_swarm_event(&self.events, event);
+                        let peers = swarm.network_info().num_peers();
+                        self.health.update(|status| status.connected_peers = peers);
+                    }
+                    Poll::Ready(None) => {
+                        self.health.update(|status| {
+                            status.listening = false;
+                            status.connected_peers = 0;
+                        });
+                        return Poll::Ready(Ok(()));
+                    }
+                    Poll::Pending => {
+                        if !self.health.is_listening() {
+                            if let Some(addr) = Swarm::listeners(&swarm).next() {
+                                println!("listening on {:?}", addr);
+                                self.health.update(|status| status.listening = true);
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+            Poll::Pending
+        });
+
+        fut.await
+    }
+
+    /// Announce every shard in the node's store in the DHT.
+    fn announce_stored_shards(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+    ) -> Result<(), Box<dyn Error>> {
+        for shard_id in self.shards.shard_ids()? {
+            swarm.behaviour_mut().announce_shard(&shard_id)?;
+        }
+        Ok(())
+    }
+
+    /// Check the replication of every file that this node holds shards of.
+    fn replicate_held_files(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        target: usize,
+    ) {
+        let file_ids = match self.shards.file_ids() {
+            Ok(ids) => ids,
+            Err(e) => {
+                eprintln!("failed to list the held files: {:?}", e);
+                return;
+            }
+        };
+        for file_id in file_ids {
+            if let Err(e) =
+                swarm.behaviour_mut().ensure_replication(&file_id, target)
+            {
+                eprintln!("failed to check replication: {:?}", e);
+            }
+        }
+    }
+
+    /// Fetch back the shards that were dropped for repair, through the
+    /// replication check of their files (at the configured replication target,
+    /// or a single holder without one).
+    fn repair_flagged(&mut self, swarm: &mut Swarm<MerosBehavior>) {
+        let flagged = match self.shards.flagged_for_repair() {
+            Ok(flagged) => flagged,
+            Err(e) => {
+                eprintln!("failed to list the shards to repair: {:?}", e);
+                return;
+            }
+        };
+        let file_ids: HashSet<file::FileID> =
+            flagged.into_iter().map(|(file_id, _)| file_id).collect();
+        let target = self.config.replication_target.unwrap_or(1);
+        for file_id in file_ids {
+            if let Err(e) =
+                swarm.behaviour_mut().ensure_replication(&file_id, target)
+            {
+                eprintln!("failed to repair the shards of a file: {:?}", e);
+            }
+        }
+    }
+
+    /// Core node operation to put a file onto the network.
+    fn put_file(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file_metadata: file::File,
+        file_bytes: Arc<Vec<u8>>,
+        config: &OperationConfig,
+        progress: Option<ProgressSink>,
+        receipt: Option<ReceiptSink>,
+    ) -> Result<(), Box<dyn Error>> {
+        let emit = |event| {
+            if let Some(sink) = &progress {
+                sink.emit(event);
+            }
+        };
+
+        /*
+           1. Find online peers, get their peerIDs, and modify the metadata to
+           include the shard locations
+
+           2. Put the metadata into the DHT
+
+           3. Contact the peers with the shard data (the shards of the actual bytes
+              of the file).
+        */
+
+        // Don't put a file again that was already put in full
+        if !config.force {
+            if let Some(previous) = self.previous_receipt(swarm, &file_metadata.id) {
+                println!("{} was already put", file_metadata.id.to_hex());
+                if let Some(sink) = receipt {
+                    let _ = sink.unbounded_send(Ok(previous));
+                }
+                emit(PutProgress::Complete);
+                return Ok(());
+            }
+        }
+
+        // (1) Get the online peers
+        let peers = self.shard_candidates(swarm);
+
+        // Wait for peers to be discovered, if the put may
+        let needed = (config.min_nodes as usize).max(1);
+        if peers.len() < needed {
+            if let Some(wait) = config.wait_for_peers {
+                self.waiting_puts.push(WaitingPut {
+                    deadline: Instant::now() + wait,
+                    file_metadata,
+                    file_bytes,
+                    config: config.clone(),
+                    progress,
+                    receipt,
+                });
+                return Ok(());
+            }
+        }
+
+        if peers.len() == 0 {
+            return Err(Box::new(GeneralError::new(
+                "not enough peers to shard file",
+            )));
+        }
+
+        // Calculate the shards of the file on the workers, so that the swarm
+        // keeps being polled in the meantime. The put is finished by
+        // `poll_sharding` once they are ready.
+        emit(PutProgress::Sharding);
+        let shard_config = file_metadata.shard_config.clone();
+        let shards = self.workers.run(move || {
+            shard::Shard::shard(&file_bytes, shard_config).map_err(|e| e.to_string())
+        });
+        self.sharding.push(ShardingPut {
+            file_metadata,
+            peers,
+            progress,
+            min_nodes: config.min_nodes,
+            receipt,
+            shards,
+        });
+        Ok(())
+    }
+
+    /// The online peers that the shards of a put may be placed on. They are
+    /// sorted before they are cut down, so that the same known peers always
+    /// leave the same candidates for the rendezvous placement of the shards.
+    fn shard_candidates(&self, swarm: &mut Swarm<MerosBehavior>) -> Vec<PeerId> {
+        let mut peers = swarm.behaviour_mut().get_online_peers();
+        peers.sort_by_key(|peer| peer.to_bytes());
+        peers.truncate(self.config.max_shard_peers);
+        peers
+    }
+
+    /// Place the shards of a resharded file on peers without sharding the file
+    /// again, and ask the holders of the shards they replace to delete them.
+    /// # Arguments
+    /// * `old` - the metadata of the file before it was resharded
+    /// * `file_metadata` - the resharded metadata of the file
+    /// * `shards` - the new shards of the file
+    fn redistribute(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        old: file::File,
+        file_metadata: file::File,
+        shards: Vec<shard::Shard>,
+    ) -> Result<(), Box<dyn Error>> {
+        let peers = self.shard_candidates(swarm);
+        if peers.is_empty() {
+            return Err(Box::new(GeneralError::new(
+                "not enough peers to shard file",
+            )));
+        }
+
+        // The shards are ready, so `poll_sharding` distributes them right away
+        let (sink, ready) = oneshot::channel();
+        let _ = sink.send(Ok((shards, file_metadata.shard_config.clone())));
+        swarm
+            .behaviour_mut()
+            .delete_old_shards(&old, &file_metadata);
+        self.sharding.push(ShardingPut {
+            file_metadata,
+            peers,
+            progress: None,
+            min_nodes: 0,
+            receipt: None,
+            shards: ready,
+        });
+        Ok(())
+    }
+
+    /// Start resharding a file whose shards are held by peers: look up its
+    /// metadata, and get the file from its holders. Once both arrive, the file
+    /// is sharded again on the workers, and `poll_resharding` places the new
+    /// shards.
+    /// # Arguments
+    /// * `file_id` - the id of the file to reshard
+    /// * `shard_count` - the new number of shards
+    /// * `priv_key` - the private key of the owner of the file
+    /// * `sink` - where the resharded metadata is sent
+    fn start_reshard(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file_id: file::FileID,
+        shard_count: usize,
+        priv_key: Arc<SecretKey>,
+        sink: MetadataSink,
+    ) -> Result<(), Box<dyn Error>> {
+        let key = SecretKey::from_bytes(&priv_key.to_bytes())
+            .map_err(|e| GeneralError::new(&e.to_string()))?;
+        let (metadata_sink, mut metadata) = mpsc::unbounded();
+        let (bytes_sink, mut bytes) = mpsc::unbounded();
+
+        // The file is fetched and decrypted like the output of a get
+        let collector = ShardCollector::new(None, self.config.get_timeout);
+        let behaviour = swarm.behaviour_mut();
+        behaviour.get_metadata(&file_id, metadata_sink)?;
+        behaviour.start_get(file_id.clone(), collector)?;
+        behaviour.pending_outputs.insert(
+            file_id,
+            PendingOutput::new(
+                Output::Channel(bytes_sink),
+                Some(key),
+                self.config.max_reconstruct_bytes,
+            ),
+        );
+
+        let workers = self.workers.clone();
+        let job = async move {
+            // Only metadata signed by the owner is resharded
+            let old = match metadata.next().await {
+                Some(result) => result.map_err(|e| e.to_string())?,
+                None => return Err("the metadata lookup was dropped".to_string()),
+            };
+            old.verify_metadata().map_err(|e| e.to_string())?;
+            let data = match bytes.next().await {
+                Some(result) => result.map_err(|e| e.to_string())?,
+                None => return Err("the get of the file was dropped".to_string()),
+            };
+
+            let job = workers.run(move || {
+                let mut file = old.clone();
+                let shards = file
+                    .reshard(&data, shard_count, &priv_key)
+                    .map_err(|e| e.to_string())?;
+                Ok::<_, String>((old, file, shards))
+            });
+            job.await
+                .map_err(|_| "the shard workers dropped the reshard".to_string())?
+        };
+        self.resharding.push(RemoteReshard {
+            job: job.boxed(),
+            sink,
+        });
+        Ok(())
+    }
+
+    /// Place the shards of the remote reshards whose files the workers have
+    /// sharded again, and report the resharded metadata.
+    fn poll_resharding(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        cx: &mut Context<'_>,
+    ) {
+        let mut i = 0;
+        while i < self.resharding.len() {
+            let result = match self.resharding[i].job.poll_unpin(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let reshard = self.resharding.remove(i);
+            let result = result.and_then(|(old, file, shards)| {
+                self.redistribute(swarm, old, file.clone(), shards)
+                    .map(|_| file)
+                    .map_err(|e| e.to_string())
+            });
+            if let Err(e) = &result {
+                eprintln!("failed to reshard file: {}", e);
+            }
+            let _ = reshard
+                .sink
+                .unbounded_send(result.map_err(|e| GeneralError::new(&e)));
+        }
+    }
+
+    /// The receipt of an earlier put of a file, if this node still owns the
+    /// file, every shard of that put was stored, and every holder of those
+    /// shards is still among the online peers. Receipts are only kept in
+    /// memory, so a file put before the node restarted is put again.
+    fn previous_receipt(
+        &self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file_id: &file::FileID,
+    ) -> Option<PutReceipt> {
+        let receipt = self.receipts.lock().ok()?.get(file_id).cloned()?;
+        if !receipt.missing.is_empty() || !self.shards.is_owned(file_id).ok()? {
+            return None;
+        }
+        let online = swarm.behaviour_mut().get_online_peers();
+        if receipt
+            .stored
+            .values()
+            .all(|holder| online.contains(holder))
+        {
+            Some(receipt)
+        } else {
+            None
+        }
+    }
+
+    /// Start the puts that were waiting for peers once enough peers are online,
+    /// and the ones that have waited as long as they may with whatever peers
+    /// are online (failing if there are none).
+    fn retry_waiting_puts(&mut self, swarm: &mut Swarm<MerosBehavior>) {
+        if self.waiting_puts.is_empty() {
+            return;
+        }
+        let online = swarm.behaviour_mut().get_online_peers().len();
+        let now = Instant::now();
+        for put in std::mem::take(&mut self.waiting_puts) {
+            let needed = (put.config.min_nodes as usize).max(1);
+            if online < needed && now < put.deadline {
+                self.waiting_puts.push(put);
+                continue;
+            }
+            let config = OperationConfig {
+                wait_for_peers: None,
+                ..put.config
+            };
+            if let Err(e) = self.put_file(
+                swarm,
+                put.file_metadata,
+                put.file_bytes,
+                &config,
+                put.progress,
+                put.receipt,
+            ) {
+                eprintln!("failed to put file: {:?}", e);
+            }
+        }
+    }
+
+    /// Finish the puts whose shards the workers have calculated.
+    fn poll_sharding(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        cx: &mut Context<'_>,
+    ) {
+        let mut i = 0;
+        while i < self.sharding.len() {
+            let result = match self.sharding[i].shards.poll_unpin(cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let put = self.sharding.remove(i);
+            let result: Result<(), Box<dyn Error>> = match result {
+                Ok(Ok(sharded)) => self.distribute_shards(swarm, put, sharded),
+                Ok(Err(e)) => Err(Box::new(GeneralError::new(e.as_str()))),
+                Err(oneshot::Canceled) => Err(Box::new(GeneralError::new(
+                    "the shard workers dropped the put",
+                ))),
+            };
+            if let Err(e) = result {
+                eprintln!("failed to put file: {:?}", e);
+            }
+        }
+    }
+
+    /// Place the shards of a put on peers, put the file's metadata into the
+    /// DHT, and send the shards to their holders.
+    fn distribute_shards(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        put: ShardingPut,
+        (shards, new_config): (Vec<shard::Shard>, shard::ShardConfig),
+    ) -> Result<(), Box<dyn Error>> {
+        let ShardingPut {
+            mut file_metadata,
+            peers,
+            progress,
+            min_nodes,
+            receipt,
+            ..
+        } = put;
+        let emit = |event| {
+            if let Some(sink) = &progress {
+                sink.emit(event);
+            }
+        };
+
+        // Update the sharding metadata of the file. A peer may hold more than
+        // one shard.
+        file_metadata.shard_config = new_config;
+
+        let holders = {
+            let behaviour = swarm.behaviour();
+            let capacities = behaviour.capacities.lock().map_err(|_| {
+                GeneralError::new("the peer capacities are poisoned")
+            })?;
+            choose_holders(&shards, &peers, &behaviour.scores, &capacities)?
+        };
+        file_metadata.set_shards(&holders);
+        file_metadata.set_shard_ids(&shards);
+
+        println!("HEX: {}", file_metadata.id.to_hex());
+
+        // (2) Insert into the DHT the FileID which points to the relevant metadata.
+        let record = Record {
+            key: Key::new(&file_metadata.id.to_bytes()?),
+            value: file_metadata.to_bytes()?,
+            publisher: Some(self.identity.peer_id.clone()),
+            expires: None,
+        };
+        swarm.behaviour_mut().put_record(record, 0);
+        emit(PutProgress::PublishedMetadata);
+
+        // (3) Then distribute the actual file bytes data across the network,
+        // collecting the holders' acknowledgements.
+        let sent: Vec<(u32, PeerId)> = shards
+            .iter()
+            .map(|s| s.index())
+            .zip(holders.iter().cloned())
+            .collect();
+        swarm.behaviour_mut().send_put_shards(
+            &file_metadata,
+            shards.into_iter().zip(holders.into_iter()).collect(),
+            min_nodes as usize,
+            receipt,
+        );
+        for (index, peer) in sent {
+            emit(PutProgress::ShardSent { index, peer });
+        }
+        swarm
+            .behaviour_mut()
+            .pubsub
+            .publish(SHARD_CHANNEL, "just put file".as_bytes());
+        println!("end of put file");
+        emit(PutProgress::Complete);
+
+        Ok(())
+    }
+
+    /// Put the metadata of a file into the DHT.
+    fn publish_metadata(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file: &file::File,
+    ) -> Result<(), Box<dyn Error>> {
+        let record = Record {
+            key: Key::new(&file.id.to_bytes()?),
+            value: file.to_bytes()?,
+            publisher: Some(self.identity.peer_id.clone()),
+            expires: None,
+        };
+        swarm.behaviour_mut().put_record(record, 0);
+        Ok(())
+    }
+
+    fn test_sub(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("testing sub");
+
+        let mut c = 0;
+        for _ in 0..10 {
+            println!("trial {}", c);
+            swarm
+                .behaviour_mut()
+                .pubsub
+                .publish(SHARD_CHANNEL, "test message".as_bytes());
+            c += 1;
+        }
+        Ok(())
+    }
+
+    /// Core node operation to get a file from the network.
+    fn get_file(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file_id: &file::FileID,
+        config: &OperationConfig,
+    ) -> Result<(), Box<dyn Error>> {
+        println!("getting file");
+
+        // The file is decrypted with the keypair of the same name as the node
+        let private_key = match config.decrypt {
+            true => Some(encryption::load_priv_key(&encryption::KeyType::Private(
+                self.name.clone(),
+            ))?),
+            false => None,
+        };
+
+        let collector = ShardCollector::new(None, self.config.get_timeout);
+        let behaviour = swarm.behaviour_mut();
+        let qid = behaviour.start_get(file_id.clone(), collector)?;
+        match config.output {
+            Output::Discard => {}
+            ref output => {
+                behaviour.pending_outputs.insert(
+                    file_id.clone(),
+                    PendingOutput::new(
+                        output.clone(),
+                        private_key,
+                        self.config.max_reconstruct_bytes,
+                    ),
+                );
+            }
+        }
+
+        let query = swarm.behaviour_mut().kademlia.query(&qid);
+        if let Some(q) = query {
+            println!(
+                "--------------QUERY:\n{:#?}\n\n{:#?}\n------------",
+                q.info(),
+                q.stats(),
+            );
+        }
+
+        swarm
+            .behaviour_mut()
+            .pubsub
+            .publish(SHARD_CHANNEL, "awesome postget message".as_bytes());
+
+        Ok(())
+    }
+
+    /// Core node operation to get several files from the network. The gets
+    /// are queued to run a few at a time.
+    fn get_files(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file_ids: Vec<file::FileID>,
+        sinks: Vec<ShardSink>,
+    ) -> Result<(), Box<dyn Error>> {
+        if file_ids.len() != sinks.len() {
+            return Err(Box::new(GeneralError::new(
+                "every file of a batch needs its own sink",
+            )));
+        }
+
+        let behaviour = swarm.behaviour_mut();
+        behaviour
+            .queued_gets
+            .extend(file_ids.into_iter().zip(sinks));
+        behaviour.start_queued_gets();
+        Ok(())
+    }
+
+    /// Dial the shard holders that gets are waiting on, a few at a time.
+    fn dial_holders(&self, swarm: &mut Swarm<MerosBehavior>) {
+        for peer in swarm.behaviour_mut().next_dials() {
+            match swarm.dial(&peer) {
+                Ok(_) => println!("dialed {:?}", peer),
+                Err(e) => {
+                    println!("failed to dial {:?}: {:?}", peer, e);
+                    swarm.behaviour_mut().dial_finished(&peer);
+                }
+            }
+        }
+    }
+
+    /// Core node operation to get a file from the network, streaming its
+    /// shards to a sink as they arrive.
+    fn stream_file(
+        &mut self,
+        swarm: &mut Swarm<MerosBehavior>,
+        file_id: file::FileID,
+        sink: ShardSink,
+    ) -> Result<(), Box<dyn Error>> {
+        // Wait until there is someone to get the file from
+        if swarm.behaviour_mut().get_online_peers().is_empty() {
+            return Err(Box::new(GeneralError::new(
+                "no peers to get the file from",
+            )));
+        }
+
+        let collector = ShardCollector::new(Some(sink), self.config.get_timeout);
+        swarm.behaviour_mut().start_get(file_id, collector)?;
+        Ok(())
+    }
+}
+
+impl Drop for Node {
+    /// Make sure every stored shard reaches the disk before the node goes away.
+    fn drop(&mut self) {
+        if let Err(e) = self.shards.flush() {
+            eprintln!("failed to flush the shard store: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::pubsub::PubsubKind;
+    use crate::test_util::{
+        fresh_store, sample_file, sample_file_with, temp_file, test_keypair, SAMPLE,
+    };
+    use libp2p::core::connection::PendingConnectionError;
+
+    /// Drive the swarms until `until` finishes, and return what it finished
+    /// with. Panics if that takes more than ten seconds.
+    async fn drive_until<T>(
+        mut swarms: Vec<&mut Swarm<MerosBehavior>>,
+        until: impl Future<Output = T>,
+        what: &str,
+    ) -> T {
+        let drive = Box::pin(async {
+            loop {
+                future::select_all(swarms.iter_mut().map(|swarm| swarm.next()))
+                    .await;
+            }
+        });
+        match async_std::future::timeout(
+            Duration::from_secs(10),
+            future::select(Box::pin(until), drive),
+        )
+        .await
+        {
+            Ok(future::Either::Left((value, _))) => value,
+            Ok(future::Either::Right(_)) => panic!("the swarms stopped"),
+            Err(_) => panic!("{} timed out", what),
+        }
+    }
+
+    /// Create a node that is kept in a temporary directory, which is removed
+    /// (along with the node's identity and store) once it is dropped.
+    fn temp_node(config: NodeConfig) -> (Node, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let node = Node::in_dir(dir.path(), config).unwrap();
+        (node, dir)
+    }
+
+    /// Wait until the node has sharded every put it has started.
+    async fn wait_sharded(node: &mut Node, swarm: &mut Swarm<MerosBehavior>) {
+        future::poll_fn(|cx| {
+            node.poll_sharding(swarm, cx);
+            if node.sharding.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    #[test]
+    fn test_placement_skips_full_peers() {
+        let (_, shards) = sample_file(&[7u8; 4000], 4);
+        let (full, roomy, small) =
+            (PeerId::random(), PeerId::random(), PeerId::random());
+        let peers = vec![full.clone(), roomy.clone(), small.clone()];
+        let scores = PeerScores::new(Default::default());
+        let heartbeat = |free_bytes| Heartbeat {
+            free_bytes,
+            total_bytes: 1024 * 1024,
+        };
+
+        // The full peer gets nothing, and the small one gets one shard at most
+        let mut capacities = HashMap::new();
+        capacities.insert(full.clone(), heartbeat(0));
+        capacities.insert(small.clone(), heartbeat(shards[0].size() as u64));
+        let holders = choose_holders(&shards, &peers, &scores, &capacities).unwrap();
+        assert_eq!(holders.len(), shards.len());
+        assert!(!holders.contains(&full));
+        assert!(holders.iter().filter(|p| **p == small).count() <= 1);
+
+        // Without heartbeats, every peer is a candidate
+        let holders =
+            choose_holders(&shards, &peers, &scores, &HashMap::new()).unwrap();
+        assert!(holders.iter().all(|p| peers.contains(p)));
+
+        // A put fails if no peer has room
+        capacities.insert(roomy.clone(), heartbeat(0));
+        capacities.insert(small.clone(), heartbeat(0));
+        assert!(choose_holders(&shards, &peers, &scores, &capacities).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_peer_capacity() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let peer = PeerId::random();
+        assert_eq!(node.peer_capacity(&peer), None);
+
+        let heartbeat = Heartbeat {
+            free_bytes: 10,
+            total_bytes: 100,
+        };
+        NetworkBehaviourEventProcess::<PubsubEvent>::inject_event(
+            swarm.behaviour_mut(),
+            PubsubEvent::Message {
+                source: peer.clone(),
+                topic: STATUS_CHANNEL.to_string(),
+                data: bincode::serialize(&heartbeat).unwrap(),
+            },
+        );
+        assert_eq!(node.peer_capacity(&peer), Some((10, 100)));
+
+        // Garbage isn't remembered
+        let other = PeerId::random();
+        NetworkBehaviourEventProcess::<PubsubEvent>::inject_event(
+            swarm.behaviour_mut(),
+            PubsubEvent::Message {
+                source: other.clone(),
+                topic: STATUS_CHANNEL.to_string(),
+                data: vec![1, 2, 3],
+            },
+        );
+        assert_eq!(node.peer_capacity(&other), None);
+
+        // And the node's own stores are advertised
+        swarm.behaviour_mut().send_heartbeat().unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_hosts_shards() {
+        let config = NodeConfig {
+            hosts_shards: false,
+            ..NodeConfig::default()
+        };
+        let (node, _dir) = temp_node(config);
+        let mut swarm = node.build_swarm().await.unwrap();
+        let (file, shards) = sample_file(SAMPLE, 2);
+
+        let response =
+            swarm
+                .behaviour_mut()
+                .handle_shard_request(ShardMessage::Store {
+                    file_id: file.id.clone(),
+                    shard: shards[0].clone(),
+                });
+        match response {
+            ShardMessage::Error(_) => {}
+            r => panic!("expected the shard to be refused, got {:?}", r),
+        }
+        assert_eq!(node.shards.get_shard(&file.id, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_with_passphrase() {
+        let name = format!("test_with_passphrase_{}", rand::random::<u32>());
+        let node = Node::with_passphrase(&name, "passphrase", NodeConfig::default())
+            .unwrap();
+        let peer_id = node.identity.peer_id.clone();
+        drop(node);
+
+        // An encrypted identity only runs a node with its passphrase
+        assert!(Node::new(&name).is_err());
+        assert!(
+            Node::with_passphrase(&name, "wrong", NodeConfig::default()).is_err()
+        );
+        let node = Node::with_passphrase(&name, "passphrase", NodeConfig::default())
+            .unwrap();
+        assert_eq!(node.identity.peer_id, peer_id);
+
+        drop(node);
+        fs::remove_dir_all(Path::new(DATADIR).join("identities").join(&name))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_record_file_id() {
+        let record = |file: &file::File| Record {
+            key: Key::new(&file.id.to_bytes().unwrap()),
+            value: file.to_bytes().unwrap(),
+            publisher: None,
+            expires: None,
+        };
+        let max = protocol::MAX_MESSAGE_SIZE;
+        let (a, _) = sample_file(b"first file", 2);
+        assert_eq!(record_file_id(&record(&a), max), Some(a.id.clone()));
+        let garbage = Record {
+            key: Key::new(&b"not a file".to_vec()),
+            value: vec![1, 2, 3],
+            publisher: None,
+            expires: None,
+        };
+        assert_eq!(record_file_id(&garbage, max), None);
+
+        // Metadata stored under another file's key isn't counted
+        let mut misplaced = record(&a);
+        misplaced.key = Key::new(&b"misplaced".to_vec());
+        assert_eq!(record_file_id(&misplaced, max), None);
+
+        // Neither is metadata larger than the limit
+        assert_eq!(record_file_id(&record(&a), 8), None);
+    }
+
+    #[test]
+    fn test_build_kademlia() {
+        use libp2p::kad::record::store::Error;
+        let too_large = |result: Result<(), Error>| match result {
+            Err(Error::ValueTooLarge) => {}
+            r => panic!("expected the record to be too large, got {:?}", r),
+        };
+        let record = |bytes| Record {
+            key: Key::new(&b"large metadata".to_vec()),
+            value: vec![0u8; bytes],
+            publisher: None,
+            expires: None,
+        };
+
+        // The default limit is too small for the record
+        let mut kademlia = build_kademlia(PeerId::random(), &DhtConfig::default());
+        too_large(kademlia.store_mut().put(record(100 * 1024)));
+
+        let config = DhtConfig {
+            max_record_bytes: 1024 * 1024,
+            replication_interval: Some(Duration::from_secs(5 * 60)),
+            ..DhtConfig::default()
+        };
+        let mut kademlia = build_kademlia(PeerId::random(), &config);
+        assert!(kademlia.store_mut().put(record(100 * 1024)).is_ok());
+        too_large(kademlia.store_mut().put(record(2 * 1024 * 1024)));
+    }
+
+    #[test]
+    fn test_corrupt_shard_not_served() {
+        let (primary, _dir) = fresh_store();
+        let mut store = StoreManager::new(PRIMARY_STORE, primary);
+        let (file, shards) = sample_file(SAMPLE, 2);
+
+        let mut corrupted = shards[1].clone();
+        corrupted.data[0] ^= 0xFF;
+        store.put_shard(&file.id, &shards[0]).unwrap();
+        store.put_shard(&file.id, &corrupted).unwrap();
+
+        let request = |index| ShardMessage::Request {
+            file_id: file.id.clone(),
+            index,
+        };
+        match serve_shard_request(&mut store, request(0), protocol::MAX_MESSAGE_SIZE)
+        {
+            ShardMessage::Data { shard, .. } => assert_eq!(shard, shards[0]),
+            r => panic!("expected data, got {:?}", r),
+        }
+        match serve_shard_request(&mut store, request(1), protocol::MAX_MESSAGE_SIZE)
+        {
+            ShardMessage::Corrupt => {}
+            r => panic!("expected a corrupt response, got {:?}", r),
+        }
+
+        // The corrupted shard was dropped so a good copy can replace it
+        assert_eq!(store.get_shard(&file.id, 1).unwrap(), None);
+        match serve_shard_request(&mut store, request(1), protocol::MAX_MESSAGE_SIZE)
+        {
+            ShardMessage::NotFound => {}
+            r => panic!("expected not found, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_distinct_remote_peers() {
+        let local = PeerId::random();
+        let remote: Vec<PeerId> = (0..50).map(|_| PeerId::random()).collect();
+
+        // A synthetic routing table listing every peer (and the local node) twice
+        let mut table = remote.clone();
+        table.extend(remote.iter().cloned());
+        table.push(local.clone());
+        table.push(local.clone());
+
+        // Each peer is kept where it first appeared
+        let peers = distinct_remote_peers(table, &local);
+        assert!(!peers.contains(&local));
+        assert_eq!(peers, remote);
+    }
+
+    #[async_std::test]
+    async fn test_put_file_progress() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        // Make three (offline) peers known to the DHT to hold the shards
+        for _ in 0..3 {
+            swarm.behaviour_mut().kademlia.add_address(
+                &PeerId::random(),
+                "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            );
+        }
+
+        let (file, _) = sample_file(SAMPLE, 3);
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 0,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+        let (sink, stream) = progress::progress_channel();
+        node.put_file(
+            &mut swarm,
+            file,
+            Arc::new(SAMPLE.to_vec()),
+            &config,
+            Some(sink),
+            None,
+        )
+        .unwrap();
+
+        // The shards are calculated off of the swarm's thread
+        wait_sharded(&mut node, &mut swarm).await;
+
+        let events: Vec<PutProgress> = stream.collect().await;
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[0], PutProgress::Sharding);
+        assert_eq!(events[1], PutProgress::PublishedMetadata);
+        for (i, event) in events[2..5].iter().enumerate() {
+            match event {
+                PutProgress::ShardSent { index, .. } => assert_eq!(*index, i as u32),
+                e => panic!("expected a sent shard, got {:?}", e),
+            }
+        }
+        assert_eq!(events[5], PutProgress::Complete);
+    }
+
+    #[async_std::test]
+    async fn test_put_waits_for_peers() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 2,
+            decrypt: false,
+            wait_for_peers: Some(Duration::from_secs(60)),
+            force: false,
+        };
+
+        // Without peers, the put waits instead of failing
+        let (file, _) = sample_file(SAMPLE, 3);
+        let (sink, stream) = progress::progress_channel();
+        let bytes = Arc::new(SAMPLE.to_vec());
+        node.put_file(&mut swarm, file, bytes.clone(), &config, Some(sink), None)
+            .unwrap();
+        node.retry_waiting_puts(&mut swarm);
+        assert_eq!(node.waiting_puts.len(), 1);
+        assert!(node.sharding.is_empty());
+
+        // One peer isn't enough, but the put goes ahead once a second appears
+        let add_peer = |swarm: &mut Swarm<MerosBehavior>| {
+            swarm.behaviour_mut().kademlia.add_address(
+                &PeerId::random(),
+                "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            );
+        };
+        task::sleep(Duration::from_millis(50)).await;
+        add_peer(&mut swarm);
+        node.retry_waiting_puts(&mut swarm);
+        assert_eq!(node.waiting_puts.len(), 1);
+        task::sleep(Duration::from_millis(50)).await;
+        add_peer(&mut swarm);
+        node.retry_waiting_puts(&mut swarm);
+        assert!(node.waiting_puts.is_empty());
+
+        wait_sharded(&mut node, &mut swarm).await;
+        let events: Vec<PutProgress> = stream.collect().await;
+        assert_eq!(events.first(), Some(&PutProgress::Sharding));
+        assert_eq!(events.last(), Some(&PutProgress::Complete));
+
+        // A put that has waited as long as it may goes ahead with the peers
+        // there are, and one that may not wait fails right away without them
+        let (mut lonely, _lonely_dir) = temp_node(NodeConfig::default());
+        let mut lonely_swarm = lonely.build_swarm().await.unwrap();
+        let (file, _) = sample_file(SAMPLE, 3);
+        let expired = OperationConfig {
+            wait_for_peers: Some(Duration::from_millis(0)),
+            ..config.clone()
+        };
+        lonely
+            .put_file(
+                &mut lonely_swarm,
+                file.clone(),
+                bytes.clone(),
+                &expired,
+                None,
+                None,
+            )
+            .unwrap();
+        lonely.retry_waiting_puts(&mut lonely_swarm);
+        assert!(lonely.waiting_puts.is_empty());
+        assert!(lonely.sharding.is_empty());
+        let impatient = OperationConfig {
+            wait_for_peers: None,
+            ..config
+        };
+        assert!(lonely
+            .put_file(&mut lonely_swarm, file, bytes, &impatient, None, None)
+            .is_err());
+    }
+
+    /// Put a file with the node's swarm, requiring `min_nodes` acknowledged
+    /// shards, and have the holders of the shards at `stored` acknowledge them
+    /// while the others fail. Returns the put's receipt.
+    async fn put_with_receipt(
+        node: &mut Node,
+        swarm: &mut Swarm<MerosBehavior>,
+        min_nodes: u16,
+        stored: &[u32],
+    ) -> Result<progress::PutReceipt, GeneralError> {
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+        let (file, _) = sample_file(SAMPLE, 3);
+        let (sink, mut receipts) = mpsc::unbounded();
+        node.put_file(
+            swarm,
+            file,
+            Arc::new(SAMPLE.to_vec()),
+            &config,
+            None,
+            Some(sink),
+        )
+        .unwrap();
+        wait_sharded(node, swarm).await;
+
+        // Answer for the in-process holders
+        let sent: Vec<(RequestId, u32)> = swarm
+            .behaviour()
+            .pending_acks
+            .iter()
+            .map(|(id, (_, index))| (*id, *index))
+            .collect();
+        assert_eq!(sent.len(), 3);
+        for (id, index) in sent {
+            swarm.behaviour_mut().acknowledge(
+                &id,
+                &holder(index),
+                stored.contains(&index),
+            );
+        }
+        receipts.next().await.unwrap()
+    }
+
+    /// A stable peer id for the holder of a shard.
+    fn holder(index: u32) -> PeerId {
+        let mut seed = [0u8; 32];
+        seed[0] = index as u8 + 1;
+        let (sk, pk) =
+            crate::crypto::encryption::gen_keypair_from_seed("holder", &seed, false)
+                .unwrap();
+        PeerId::from_public_key(
+            crate::crypto::ecies_to_libp2p(&sk, &pk).unwrap().public(),
+        )
+    }
+
+    #[async_std::test]
+    async fn test_put_file_receipt() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        for _ in 0..3 {
+            swarm.behaviour_mut().kademlia.add_address(
+                &PeerId::random(),
+                "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            );
+        }
+
+        let receipt = put_with_receipt(&mut node, &mut swarm, 2, &[0, 2])
+            .await
+            .unwrap();
+        let mut stored: Vec<(u32, PeerId)> = receipt.stored.into_iter().collect();
+        stored.sort_by_key(|(index, _)| *index);
+        assert_eq!(stored, vec![(0, holder(0)), (2, holder(2))]);
+        assert_eq!(receipt.missing, vec![1]);
+        assert!(swarm.behaviour().pending_receipts.is_empty());
+
+        // Too few acknowledgements fail the put
+        assert!(put_with_receipt(&mut node, &mut swarm, 3, &[0, 1])
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_put_twice() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        for index in 0..3 {
+            swarm.behaviour_mut().kademlia.add_address(
+                &holder(index),
+                "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            );
+        }
+        let mut config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 3,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+        let (file, _) = sample_file(SAMPLE, 3);
+        let bytes = Arc::new(SAMPLE.to_vec());
+
+        // Every holder stores its shard the first time
+        let (sink, mut receipts) = mpsc::unbounded();
+        node.put_file(
+            &mut swarm,
+            file.clone(),
+            bytes.clone(),
+            &config,
+            None,
+            Some(sink),
+        )
+        .unwrap();
+        wait_sharded(&mut node, &mut swarm).await;
+        let sent: Vec<(RequestId, u32)> = swarm
+            .behaviour()
+            .pending_acks
+            .iter()
+            .map(|(id, (_, index))| (*id, *index))
+            .collect();
+        for (id, index) in sent {
+            swarm.behaviour_mut().acknowledge(&id, &holder(index), true);
+        }
+        let first = receipts.next().await.unwrap().unwrap();
+
+        // The second put does nothing but answer with the first one's receipt
+        let (sink, mut receipts) = mpsc::unbounded();
+        let (progress, events) = progress::progress_channel();
+        node.put_file(
+            &mut swarm,
+            file.clone(),
+            bytes.clone(),
+            &config,
+            Some(progress),
+            Some(sink),
+        )
+        .unwrap();
+        assert_eq!(receipts.next().await.unwrap().unwrap(), first);
+        assert!(node.sharding.is_empty());
+        assert!(swarm.behaviour().pending_acks.is_empty());
+        let events: Vec<PutProgress> = events.collect().await;
+        assert_eq!(events, vec![PutProgress::Complete]);
+
+        // Unless it is forced
+        config.force = true;
+        node.put_file(&mut swarm, file.clone(), bytes.clone(), &config, None, None)
+            .unwrap();
+        assert_eq!(node.sharding.len(), 1);
+        node.sharding.clear();
+
+        // Or a holder of one of its shards went away
+        config.force = false;
+        swarm.behaviour_mut().kademlia.remove_peer(&holder(0));
+        node.put_file(&mut swarm, file, bytes, &config, None, None)
+            .unwrap();
+        assert_eq!(node.sharding.len(), 1);
+    }
+
+    /// Put a file of 50 shards with a node that knows `peers`, and return the
+    /// metadata of the put file.
+    async fn put_across(
+        node: &mut Node,
+        peers: &[PeerId],
+    ) -> (file::File, HashMap<u32, shard::Shard>) {
+        let mut swarm = node.build_swarm().await.unwrap();
+        for peer in peers.iter() {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(peer, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        }
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 0,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+        let bytes: Vec<u8> = (0..50 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let (file, _) = sample_file(&bytes, 50);
+        node.put_file(
+            &mut swarm,
+            file.clone(),
+            Arc::new(bytes),
+            &config,
+            None,
+            None,
+        )
+        .unwrap();
+        wait_sharded(node, &mut swarm).await;
+
+        // Every shard is sent to its holder
+        let sent: HashMap<u32, shard::Shard> = swarm
+            .behaviour()
+            .sent_shards
+            .iter()
+            .filter(|(id, _)| *id == file.id)
+            .map(|(_, shard)| (shard.index(), shard.clone()))
+            .collect();
+        assert_eq!(sent.len(), 50);
+        let placed = swarm.behaviour().unconfirmed_puts[&file.id].clone();
+        (placed, sent)
+    }
+
+    #[async_std::test]
+    async fn test_put_many_peers() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let peers: Vec<PeerId> = (0..50).map(|_| PeerId::random()).collect();
+
+        // The shards are spread over far more peers than they used to be
+        // capped at
+        let (file, sent) = put_across(&mut node, &peers).await;
+        let holders: HashSet<&Vec<u8>> = file.shards().iter().collect();
+        assert!(holders.len() > 20);
+        assert!(holders
+            .iter()
+            .all(|holder| peers.iter().any(|peer| peer.to_bytes() == **holder)));
+
+        // Simulate every holder storing the shards that the put sent it, and
+        // get the file back from what they store
+        let mut stores: HashMap<&Vec<u8>, Vec<shard::Shard>> = HashMap::new();
+        for (index, holder) in file.shards().iter().enumerate() {
+            stores
+                .entry(holder)
+                .or_default()
+                .push(sent[&(index as u32)].clone());
+        }
+        let collected: HashMap<u32, shard::Shard> = stores
+            .values()
+            .flatten()
+            .map(|shard| (shard.index(), shard.clone()))
+            .collect();
+        let bytes: Vec<u8> = (0..50 * 1024u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(
+            reconstruct_output(&file, collected, None, u64::MAX).unwrap(),
+            bytes
+        );
+    }
+
+    #[async_std::test]
+    async fn test_max_shard_peers() {
+        let config = NodeConfig {
+            max_shard_peers: 10,
+            ..NodeConfig::default()
+        };
+        let (mut node, _dir) = temp_node(config);
+        let peers: Vec<PeerId> = (0..50).map(|_| PeerId::random()).collect();
+
+        // The same known peers always leave the same candidates, so two puts
+        // place their shards on the same 10 peers
+        let (first, _) = put_across(&mut node, &peers).await;
+        let (second, _) = put_across(&mut node, &peers).await;
+        let holders: HashSet<&Vec<u8>> = first
+            .shards()
+            .iter()
+            .chain(second.shards().iter())
+            .collect();
+        assert!(holders.len() <= 10);
+    }
+
+    #[async_std::test]
+    async fn test_owned_files() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&PeerId::random(), "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 1,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+
+        let mut ids = Vec::new();
+        let files = [
+            &b"the first file"[..],
+            &b"a second one"[..],
+            &b"a third one"[..],
+        ];
+        for bytes in files.iter() {
+            let (file, _) = sample_file(bytes, 2);
+            ids.push(file.id.clone());
+            node.put_file(
+                &mut swarm,
+                file,
+                Arc::new(bytes.to_vec()),
+                &config,
+                None,
+                None,
+            )
+            .unwrap();
+        }
+        wait_sharded(&mut node, &mut swarm).await;
+
+        let owned = |node: &Node| -> Vec<file::FileID> {
+            node.owned_files()
+                .unwrap()
+                .into_iter()
+                .map(|f| f.id)
+                .collect()
+        };
+
+        // A file is only owned once its put succeeds, and the holders of the
+        // third file's shards didn't store them
+        assert!(owned(&node).is_empty());
+        let acks: Vec<(RequestId, file::FileID)> = swarm
+            .behaviour()
+            .pending_acks
+            .iter()
+            .map(|(id, (file_id, _))| (id.clone(), file_id.clone()))
+            .collect();
+        let peer = PeerId::random();
+        for (id, file_id) in acks.iter() {
+            let stored = file_id != &ids[2];
+            swarm.behaviour_mut().acknowledge(id, &peer, stored);
+        }
+        let before = owned(&node);
+        assert_eq!(before.len(), 2);
+        assert!(ids[..2].iter().all(|id| before.contains(id)));
+
+        // Deleting a file takes its record off the DHT too
+        let key = Key::new(&ids[0].to_bytes().unwrap());
+        assert!(swarm
+            .behaviour_mut()
+            .kademlia
+            .store_mut()
+            .get(&key)
+            .is_some());
+        node.delete_file(&ids[0]).unwrap();
+        assert_eq!(owned(&node), vec![ids[1].clone()]);
+        assert!(node.delete_file(&ids[0]).is_err());
+        match node.pending_ops.last().map(|(_, op)| op.clone()) {
+            Some(Operation::UnpublishFile { file }) => {
+                assert_eq!(file.id, ids[0]);
+                swarm.behaviour_mut().unpublish_file(&file).unwrap();
+            }
+            _ => panic!("expected the file to be unpublished"),
+        }
+        assert!(swarm
+            .behaviour_mut()
+            .kademlia
+            .store_mut()
+            .get(&key)
+            .is_none());
+        assert!(!swarm
+            .behaviour()
+            .dht_files
+            .lock()
+            .unwrap()
+            .contains(&ids[0]));
+    }
+
+    #[async_std::test]
+    async fn test_get_metadata() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        let (file, _) = sample_file(SAMPLE, 3);
+        swarm.behaviour_mut().put_record(
+            Record {
+                key: Key::new(&file.id.to_bytes().unwrap()),
+                value: file.to_bytes().unwrap(),
+                publisher: None,
+                expires: None,
+            },
+            0,
+        );
+
+        let (sink, mut results) = mpsc::unbounded();
+        swarm.behaviour_mut().get_metadata(&file.id, sink).unwrap();
+        let got = drive_until(vec![&mut swarm], results.next(), "metadata lookup")
+            .await
+            .expect("metadata lookup was dropped")
+            .unwrap();
+
+        assert_eq!(got, file);
+        assert_eq!(got.size, file.size);
+        assert_eq!(got.shards(), file.shards());
+    }
+
+    /// Store `value` as the record of a file, then look up its metadata.
+    async fn lookup_record(
+        swarm: &mut Swarm<MerosBehavior>,
+        file_id: &file::FileID,
+        value: Vec<u8>,
+    ) -> Result<file::File, GeneralError> {
+        swarm.behaviour_mut().put_record(
+            Record {
+                key: Key::new(&file_id.to_bytes().unwrap()),
+                value,
+                publisher: None,
+                expires: None,
+            },
+            0,
+        );
+        let (sink, mut results) = mpsc::unbounded();
+        swarm.behaviour_mut().get_metadata(file_id, sink).unwrap();
+        drive_until(vec![swarm], results.next(), "metadata lookup")
+            .await
+            .expect("metadata lookup was dropped")
+    }
+
+    #[async_std::test]
+    async fn test_corrupted_record() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let (file, _) = sample_file(SAMPLE, 3);
+
+        // The corrupted record is skipped and counted instead of crashing
+        assert!(lookup_record(&mut swarm, &file.id, vec![0xFF; 7])
+            .await
+            .is_err());
+        assert_eq!(swarm.behaviour().corrupt_records, 1);
+
+        // And the node keeps handling records
+        let got = lookup_record(&mut swarm, &file.id, file.to_bytes().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(got, file);
+        assert_eq!(swarm.behaviour().corrupt_records, 1);
+    }
+
+    /// Publish the metadata of a file, store the given shards of it on the node,
+    /// and check whether the file can be reconstructed.
+    async fn check_feasibility(
+        swarm: &mut Swarm<MerosBehavior>,
+        file: &file::File,
+        shards: &[shard::Shard],
+    ) -> Feasibility {
+        for shard in shards {
+            swarm
+                .behaviour_mut()
+                .handle_shard_request(ShardMessage::Store {
+                    file_id: file.id.clone(),
+                    shard: shard.clone(),
+                });
+        }
+        swarm.behaviour_mut().put_record(
+            Record {
+                key: Key::new(&file.id.to_bytes().unwrap()),
+                value: file.to_bytes().unwrap(),
+                publisher: None,
+                expires: None,
+            },
+            0,
+        );
+        let (sink, mut results) = mpsc::unbounded();
+        swarm
+            .behaviour_mut()
+            .can_reconstruct(&file.id, sink)
+            .unwrap();
+        drive_until(vec![swarm], results.next(), "feasibility check")
+            .await
+            .expect("feasibility check was dropped")
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_can_reconstruct() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        // Every shard is reachable
+        let (file, shards) = sample_file(SAMPLE, 4);
+        let feasibility = check_feasibility(&mut swarm, &file, &shards).await;
+        assert_eq!(
+            feasibility,
+            Feasibility {
+                reachable: 4,
+                required: 4
+            }
+        );
+        assert!(feasibility.is_possible());
+
+        // Too many shards are missing
+        let (file, shards) = sample_file(b"another file's contents", 4);
+        let feasibility = check_feasibility(&mut swarm, &file, &shards[..1]).await;
+        assert_eq!(feasibility.reachable, 1);
+        assert!(!feasibility.is_possible());
+    }
+
+    #[async_std::test]
+    async fn test_subscribe_topics() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        assert!(!node.is_subscribed(SHARD_CHANNEL));
+
+        let mut swarm = node.build_swarm().await.unwrap();
+        node.subscribe_topic(&mut swarm, SHARD_CHANNEL).unwrap();
+        assert!(node.is_subscribed(SHARD_CHANNEL));
+
+        // Subscribing again is fine, even though floodsub would refuse it
+        node.subscribe_topic(&mut swarm, SHARD_CHANNEL).unwrap();
+        assert!(node.is_subscribed(SHARD_CHANNEL));
+
+        // A swarm that refuses the subscription fails the startup
+        let mut swarm = node.build_swarm().await.unwrap();
+        swarm.behaviour_mut().pubsub.subscribe("taken").unwrap();
+        assert!(node.subscribe_topic(&mut swarm, "taken").is_err());
+        assert!(!node.is_subscribed("taken"));
+    }
+
+    #[async_std::test]
+    async fn test_gossipsub_node() {
+        let config = NodeConfig {
+            pubsub: PubsubKind::Gossipsub,
+            ..NodeConfig::default()
+        };
+        let (mut node, _dir) = temp_node(config);
+        let mut swarm = node.build_swarm().await.unwrap();
+        assert_eq!(swarm.behaviour().pubsub.kind(), PubsubKind::Gossipsub);
+
+        node.subscribe_topic(&mut swarm, SHARD_CHANNEL).unwrap();
+        assert!(node.is_subscribed(SHARD_CHANNEL));
+        assert!(!swarm
+            .behaviour_mut()
+            .pubsub
+            .subscribe(SHARD_CHANNEL)
+            .unwrap());
+
+        // The rest of the behavior works alongside it
+        swarm.behaviour_mut().hi();
+        let (file, _) = sample_file(SAMPLE, 3);
+        let (sink, stream) = stream::shard_stream();
+        let collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        swarm
+            .behaviour_mut()
+            .start_get(file.id.clone(), collector)
+            .unwrap();
+        assert!(swarm.behaviour().is_transferring(&file.id));
+        drop(stream);
+
+        // Floodsub stays the default
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let swarm = node.build_swarm().await.unwrap();
+        assert_eq!(swarm.behaviour().pubsub.kind(), PubsubKind::Floodsub);
+    }
+
+    #[async_std::test]
+    async fn test_listen_addresses() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        // Nothing is listened on unless every address is valid
+        assert!(listen(&mut swarm, &[]).is_err());
+        let addrs: Vec<Multiaddr> = vec![
+            "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            "/dns4/localhost/tcp/0".parse().unwrap(),
+        ];
+        assert!(listen(&mut swarm, &addrs).is_err());
+
+        // A loopback-only ipv4 address and an ipv6 address
+        listen(
+            &mut swarm,
+            &[
+                "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+                "/ip6/::1/tcp/0".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+        let (mut ip4, mut ip6) = (false, false);
+        while !(ip4 && ip6) {
+            let event = async_std::future::timeout(
+                Duration::from_secs(10),
+                swarm.select_next_some(),
+            )
+            .await
+            .expect("not listening");
+            if let SwarmEvent::NewListenAddr { address, .. } = event {
+                match address.iter().next() {
+                    Some(libp2p::multiaddr::Protocol::Ip4(ip)) => {
+                        assert!(ip.is_loopback());
+                        ip4 = true;
+                    }
+                    Some(libp2p::multiaddr::Protocol::Ip6(ip)) => {
+                        assert!(ip.is_loopback());
+                        ip6 = true;
+                    }
+                    _ => panic!("listening on an unexpected address {}", address),
+                }
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_connection_limits() {
+        let config = NodeConfig {
+            max_inbound: Some(0),
+            ..NodeConfig::default()
+        };
+        let (a, _a_dir) = temp_node(config);
+        let config = NodeConfig {
+            max_outbound: Some(0),
+            ..NodeConfig::default()
+        };
+        let (b, _b_dir) = temp_node(config);
+        let (c, _c_dir) = temp_node(NodeConfig::default());
+
+        let mut swarm_a = a.build_swarm().await.unwrap();
+        let mut swarm_b = b.build_swarm().await.unwrap();
+        let mut swarm_c = c.build_swarm().await.unwrap();
+        async fn listen(swarm: &mut Swarm<MerosBehavior>) -> Multiaddr {
+            Swarm::listen_on(swarm, "/ip4/127.0.0.1/tcp/0".parse().unwrap())
+                .unwrap();
+            loop {
+                if let SwarmEvent::NewListenAddr { address, .. } =
+                    swarm.select_next_some().await
+                {
+                    return address;
+                }
+            }
+        }
+        let address_a = listen(&mut swarm_a).await;
+        let address_c = listen(&mut swarm_c).await;
+
+        // A node that takes no inbound connections turns a dialer away
+        swarm_c.dial_addr(address_a).unwrap();
+        let refused = async {
+            loop {
+                match swarm_a.select_next_some().await {
+                    SwarmEvent::IncomingConnectionError {
+                        error: PendingConnectionError::ConnectionLimit(limit),
+                        ..
+                    } => break limit,
+                    SwarmEvent::ConnectionEstablished { .. } => {
+                        panic!("the inbound connection was let in")
+                    }
+                    _ => {}
+                }
+            }
+        };
+        let limit = drive_until(vec![&mut swarm_c], refused, "inbound").await;
+        assert_eq!(limit.limit, 0);
+
+        // And a node that makes no outbound connections doesn't connect
+        swarm_b.dial_addr(address_c).unwrap();
+        let refused = async {
+            loop {
+                match swarm_b.select_next_some().await {
+                    SwarmEvent::UnknownPeerUnreachableAddr {
+                        error: PendingConnectionError::ConnectionLimit(limit),
+                        ..
+                    } => break limit,
+                    SwarmEvent::ConnectionEstablished { .. } => {
+                        panic!("the outbound connection was made")
+                    }
+                    _ => {}
+                }
+            }
+        };
+        let limit = drive_until(vec![&mut swarm_c], refused, "outbound").await;
+        assert_eq!(limit.limit, 0);
+    }
+
+    #[async_std::test]
+    async fn test_subscribe_peer_joined() {
+        let (a, _a_dir) = temp_node(NodeConfig::default());
+        let (b, _b_dir) = temp_node(NodeConfig::default());
+        let mut events = a.subscribe();
+
+        let mut swarm_a = a.build_swarm().await.unwrap();
+        let mut swarm_b = b.build_swarm().await.unwrap();
+        Swarm::listen_on(&mut swarm_a, "/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            match swarm_a.select_next_some().await {
+                SwarmEvent::NewListenAddr { address, .. } => break address,
+                event => handle_swarm_event(&a.events, event),
+            }
+        };
+        swarm_b.dial_addr(address).unwrap();
+
+        // Drive both swarms until the connection is reported
+        let drive = Box::pin(async {
+            loop {
+                match future::select(swarm_a.next(), swarm_b.next()).await {
+                    future::Either::Left((Some(event), _)) => {
+                        handle_swarm_event(&a.events, event)
+                    }
+                    _ => {}
+                }
+            }
+        });
+        let joined = Box::pin(async {
+            loop {
+                match events.next().await {
+                    Some(NodeEvent::PeerJoined(peer)) => return peer,
+                    Some(_) => {}
+                    None => panic!("the events stopped"),
+                }
+            }
+        });
+        let peer = match async_std::future::timeout(
+            Duration::from_secs(10),
+            future::select(joined, drive),
+        )
+        .await
+        .expect("no peer joined")
+        {
+            future::Either::Left((peer, _)) => peer,
+            _ => panic!("the swarms stopped"),
+        };
+        assert_eq!(peer, b.identity.peer_id);
+    }
+
+    #[async_std::test]
+    async fn test_ping() {
+        let (a, _a_dir) = temp_node(NodeConfig::default());
+        let (b, _b_dir) = temp_node(NodeConfig::default());
+        let peer_b = b.identity.peer_id.clone();
+        assert_eq!(a.peer_latency(&peer_b), None);
+
+        let mut swarm_a = a.build_swarm().await.unwrap();
+        let mut swarm_b = b.build_swarm().await.unwrap();
+        Swarm::listen_on(&mut swarm_b, "/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } =
+                swarm_b.select_next_some().await
+            {
+                break address;
+            }
+        };
+        swarm_a
+            .behaviour_mut()
+            .shard_protocol
+            .add_address(&peer_b, address);
+
+        let (sink, mut results) = mpsc::unbounded();
+        swarm_a.behaviour_mut().ping(&peer_b, sink);
+        let rtt =
+            drive_until(vec![&mut swarm_a, &mut swarm_b], results.next(), "ping")
+                .await
+                .expect("ping was dropped")
+                .unwrap();
+        assert!(rtt > Duration::from_secs(0));
+        assert!(swarm_a.behaviour().pending_pings.is_empty());
+        assert_eq!(a.peer_latency(&peer_b), Some(rtt));
+    }
+
+    #[async_std::test]
+    async fn test_put_get_round_trip() {
+        let (mut a, _a_dir) = temp_node(NodeConfig::default());
+        let (b, _b_dir) = temp_node(NodeConfig::default());
+        let peer_b = b.identity.peer_id.clone();
+
+        let mut swarm_a = a.build_swarm().await.unwrap();
+        let mut swarm_b = b.build_swarm().await.unwrap();
+        Swarm::listen_on(&mut swarm_b, "/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } =
+                swarm_b.select_next_some().await
+            {
+                break address;
+            }
+        };
+        let behaviour = swarm_a.behaviour_mut();
+        behaviour.kademlia.add_address(&peer_b, address.clone());
+        behaviour.shard_protocol.add_address(&peer_b, address);
+
+        // A signed file is sharded again and placed on the holder by the put
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 1,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+        let (file, _) = sample_file(SAMPLE, 3);
+        let (sink, mut receipts) = mpsc::unbounded();
+        a.put_file(
+            &mut swarm_a,
+            file.clone(),
+            Arc::new(SAMPLE.to_vec()),
+            &config,
+            None,
+            Some(sink),
+        )
+        .unwrap();
+        wait_sharded(&mut a, &mut swarm_a).await;
+        let receipt =
+            drive_until(vec![&mut swarm_a, &mut swarm_b], receipts.next(), "put")
+                .await
+                .expect("the put was dropped")
+                .unwrap();
+        assert!(receipt.missing.is_empty());
+
+        // The placed metadata still validates the shards that the holder got
+        let placed = a
+            .owned_files()
+            .unwrap()
+            .into_iter()
+            .find(|owned| owned.id == file.id)
+            .unwrap();
+        assert!(placed.shards().iter().all(|p| *p == peer_b.to_bytes()));
+        let stored: HashMap<u32, shard::Shard> = b
+            .shards
+            .get(&file.id)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|shard| (shard.index(), shard))
+            .collect();
+        let bytes = reconstruct_output(&placed, stored, None, u64::MAX).unwrap();
+        assert_eq!(bytes, SAMPLE);
+        assert!(placed.validate_bytes(&bytes).unwrap().all_ok());
+    }
+
+    #[async_std::test]
+    async fn test_shard_holders() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let local = node.identity.peer_id.clone();
+
+        // Storing a shard announces this node as its holder
+        let (file, shards) = sample_file(SAMPLE, 2);
+        match swarm
+            .behaviour_mut()
+            .handle_shard_request(ShardMessage::Store {
+                file_id: file.id.clone(),
+                shard: shards[0].clone(),
+            }) {
+            ShardMessage::Stored => {}
+            r => panic!("expected the shard to be stored, got {:?}", r),
+        }
+        let key = provider_key(&shards[0].id).unwrap();
+        let kademlia = &mut swarm.behaviour_mut().kademlia;
+        assert_eq!(shard_holders(kademlia, &key, vec![]), vec![local.clone()]);
+
+        // And can be discovered through the DHT
+        let (sink, mut results) = mpsc::unbounded();
+        swarm
+            .behaviour_mut()
+            .find_shard_holders(&shards[0].id, sink)
+            .unwrap();
+        let holders = drive_until(vec![&mut swarm], results.next(), "holder lookup")
+            .await
+            .expect("holder lookup was dropped")
+            .unwrap();
+        assert_eq!(holders, vec![local]);
+
+        // The other shard was never stored here
+        let key = provider_key(&shards[1].id).unwrap();
+        let kademlia = &mut swarm.behaviour_mut().kademlia;
+        assert!(shard_holders(kademlia, &key, vec![]).is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_replication() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let local = node.identity.peer_id.clone();
+
+        // Make some (offline) peers known to the DHT to hold copies
+        let peers: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        for peer in peers.iter() {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(peer, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        }
+
+        let (file, shards) = sample_file(SAMPLE, 2);
+        swarm
+            .behaviour_mut()
+            .handle_shard_request(ShardMessage::Store {
+                file_id: file.id.clone(),
+                shard: shards[0].clone(),
+            });
+        let check = || ReplicaCheck {
+            file_id: file.id.clone(),
+            index: 0,
+            shard_id: shards[0].id.clone(),
+            target: 2,
+        };
+
+        // The shard is held by enough peers
+        let holders = vec![local.clone(), peers[0].clone()];
+        assert!(swarm.behaviour_mut().replicate(check(), holders).is_empty());
+
+        // Until a holder leaves, and the shard is copied to a new peer
+        let copies = swarm
+            .behaviour_mut()
+            .replicate(check(), vec![local.clone()]);
+        assert_eq!(copies.len(), 1);
+        assert!(peers.contains(&copies[0]));
+    }
+
+    #[async_std::test]
+    async fn test_repair() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let local = node.identity.peer_id.clone();
+        let other = PeerId::random();
+
+        let (file, shards) = sample_file(SAMPLE, 2);
+        let mut store = node.shards.clone();
+        store.flag_for_repair(&file.id, 1).unwrap();
+        let check = ReplicaCheck {
+            file_id: file.id.clone(),
+            index: 1,
+            shard_id: shards[1].id.clone(),
+            target: 1,
+        };
+
+        // The dropped shard is requested from another holder
+        swarm
+            .behaviour_mut()
+            .replicate(check, vec![local.clone(), other.clone()]);
+        assert!(swarm
+            .behaviour()
+            .pending_repairs
+            .contains_key(&(file.id.clone(), 1)));
+
+        // Another shard at the same index is not taken for it
+        let (_, wrong) = sample_file(b"another file", 2);
+        let behaviour = swarm.behaviour_mut();
+        behaviour
+            .handle_shard_response(other.clone(), wrong[1].to_message(&file.id));
+        assert!(store.get_shard(&file.id, 1).unwrap().is_none());
+        assert!(store.needs_repair(&file.id, 1).unwrap());
+
+        // The shard itself is stored, and no longer needs a repair
+        behaviour
+            .pending_repairs
+            .insert((file.id.clone(), 1), shards[1].id.clone());
+        behaviour.handle_shard_response(other, shards[1].to_message(&file.id));
+        assert_eq!(
+            store.get_shard(&file.id, 1).unwrap(),
+            Some(shards[1].clone())
+        );
+        assert!(store.flagged_for_repair().unwrap().is_empty());
+        assert!(behaviour.pending_repairs.is_empty());
+    }
+
+    #[test]
+    fn test_output() {
+        let (file, shards) = sample_file(SAMPLE, 3);
+        let dir = tempfile::tempdir().unwrap();
+
+        // Written to a path once every shard has arrived
+        let path = dir.path().join("out");
+        let mut pending =
+            PendingOutput::new(Output::from(path.to_str().unwrap()), None, u64::MAX);
+        pending.file = Some(file.clone());
+        pending.shards = shards.iter().map(|s| (s.index(), s.clone())).collect();
+        pending.finish(None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), SAMPLE);
+
+        // Nor when the file is larger than allowed
+        let large = dir.path().join("large");
+        let mut pending = PendingOutput::new(
+            Output::Path(large.clone()),
+            None,
+            SAMPLE.len() as u64 - 1,
+        );
+        pending.file = Some(file.clone());
+        pending.shards = shards.iter().map(|s| (s.index(), s.clone())).collect();
+        assert!(pending.finish(None).is_err());
+        assert!(!large.exists());
+
+        // Not written at all when the get failed
+        let failed = dir.path().join("failed");
+        let pending =
+            PendingOutput::new(Output::Path(failed.clone()), None, u64::MAX);
+        assert!(pending.finish(Some("timed out")).is_err());
+        assert!(!failed.exists());
+
+        // Or thrown away
+        match Output::from("none") {
+            Output::Discard => {}
+            output => {
+                panic!("expected the output to be discarded, got {:?}", output)
+            }
+        }
+        Output::Discard.write(Ok(SAMPLE.to_vec())).unwrap();
+        assert!(Output::Discard
+            .write(Err(GeneralError::new("failed")))
+            .is_err());
+
+        // A channel hears about failures too
+        let (sink, mut results) = mpsc::unbounded();
+        let pending = PendingOutput::new(Output::Channel(sink), None, u64::MAX);
+        pending.finish(Some("timed out")).unwrap();
+        assert!(results.try_next().unwrap().unwrap().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_output_on_workers() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let (file, shards) = sample_file(SAMPLE, 3);
+
+        let (sink, mut results) = mpsc::unbounded();
+        let mut pending = PendingOutput::new(Output::Channel(sink), None, u64::MAX);
+        pending.file = Some(file.clone());
+        pending.shards = shards.iter().map(|s| (s.index(), s.clone())).collect();
+        let behaviour = swarm.behaviour_mut();
+        behaviour.pending_outputs.insert(file.id.clone(), pending);
+
+        // The file is reconstructed off the swarm's task
+        behaviour.finish_output(&file.id, None);
+        assert!(behaviour.pending_outputs.is_empty());
+        assert_eq!(results.next().await.unwrap().unwrap(), SAMPLE);
+    }
+
+    #[test]
+    fn test_cancel_operation() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let (file, _) = sample_file(SAMPLE, 3);
+
+        let announce = node.push_operation(Operation::AnnounceShards);
+        let replicate = node.push_operation(Operation::EnsureReplication {
+            file_id: file.id.clone(),
+            target: 2,
+        });
+        let test_sub = node.push_operation(Operation::TestSub);
+
+        assert!(node.cancel_operation(replicate));
+        let ids: Vec<OperationId> =
+            node.pending_ops.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![announce, test_sub]);
+        assert!(!node.cancel_operation(replicate));
+        assert!(!node.cancel_operation(42));
+
+        // A started transfer is stopped by a cancel operation of its own
+        node.in_flight.insert(7, file.id.clone());
+        assert!(node.cancel_operation(7));
+        match &node.pending_ops[2].1 {
+            Operation::CancelTransfer { file_id } => assert_eq!(file_id, &file.id),
+            _ => panic!("expected the transfer to be cancelled"),
+        }
+        assert!(!node.cancel_operation(7));
+    }
+
+    #[async_std::test]
+    async fn test_cancel_transfer() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let (file, _) = sample_file(SAMPLE, 3);
+
+        let (sink, stream) = stream::shard_stream();
+        let collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        let behaviour = swarm.behaviour_mut();
+        behaviour.start_get(file.id.clone(), collector).unwrap();
+        assert!(behaviour.is_transferring(&file.id));
+
+        behaviour.cancel_transfer(&file.id).unwrap();
+        assert!(!behaviour.is_transferring(&file.id));
+        let items: Vec<_> = stream.collect().await;
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_err());
+    }
+
+    #[async_std::test]
+    async fn test_dial_only_holders() {
+        let config = NodeConfig {
+            max_concurrent_dials: 1,
+            ..NodeConfig::default()
+        };
+        let (node, _dir) = temp_node(config);
+        let mut swarm = node.build_swarm().await.unwrap();
+        let behaviour = swarm.behaviour_mut();
+
+        // The routing table knows more peers than hold the file's shards
+        let peers: Vec<PeerId> = (0..6).map(|_| PeerId::random()).collect();
+        for peer in peers.iter() {
+            behaviour
+                .kademlia
+                .add_address(peer, "/ip4/127.0.0.1/tcp/1".parse().unwrap());
+        }
+        let holders = vec![peers[1].clone(), peers[4].clone()];
+        let (mut file, _) = sample_file(SAMPLE, 3);
+        file.set_shards(&vec![
+            holders[0].clone(),
+            holders[1].clone(),
+            holders[0].clone(),
+        ]);
+
+        let collector = ShardCollector::new(None, Duration::from_secs(60));
+        behaviour.start_get(file.id.clone(), collector).unwrap();
+        behaviour.request_shards(&file);
+
+        // Only the holders are dialed, one at a time
+        let first = behaviour.next_dials();
+        assert_eq!(first.len(), 1);
+        assert!(behaviour.next_dials().is_empty());
+        behaviour.dial_finished(&first[0]);
+        let second = behaviour.next_dials();
+        assert_eq!(second.len(), 1);
+
+        let dialed: HashSet<PeerId> = vec![first[0].clone(), second[0].clone()]
+            .into_iter()
+            .collect();
+        assert_eq!(dialed, holders.into_iter().collect());
+        behaviour.dial_finished(&second[0]);
+        assert!(behaviour.next_dials().is_empty());
+        assert!(behaviour.deferred_requests.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_rejects_other_files_shards() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let behaviour = swarm.behaviour_mut();
+
+        let (mut file, shards) = sample_file(SAMPLE, 2);
+        let (other_file, other_shards) = sample_file(b"a different file", 2);
+        let holder = PeerId::random();
+        file.set_shards(&vec![holder.clone(); shards.len()]);
+        let collector = ShardCollector::new(None, Duration::from_secs(60));
+        behaviour.start_get(file.id.clone(), collector).unwrap();
+        behaviour.request_shards(&file);
+
+        // An answer carrying another file's id is bound to the request
+        let is_error = |response| match response {
+            ShardMessage::Error(_) => true,
+            _ => false,
+        };
+        assert!(is_error(bind_response(
+            &file.id,
+            0,
+            shards[0].to_message(&other_file.id)
+        )));
+        assert!(is_error(bind_response(
+            &file.id,
+            1,
+            shards[0].to_message(&file.id)
+        )));
+        assert!(!is_error(bind_response(
+            &file.id,
+            0,
+            shards[0].to_message(&file.id)
+        )));
+
+        // A shard of another file claimed to be of this one isn't in its map
+        behaviour.handle_shard_response(
+            holder.clone(),
+            other_shards[0].to_message(&file.id),
+        );
+        assert!(!behaviour.pending_gets[&file.id].has_received(0));
+
+        // So it is never used, and the real shard still is
+        behaviour.handle_shard_response(holder, shards[0].to_message(&file.id));
+        assert!(behaviour.pending_gets[&file.id].has_received(0));
+    }
+
+    #[async_std::test]
+    async fn test_get_batch() {
+        let config = NodeConfig {
+            max_concurrent_gets: 2,
+            ..NodeConfig::default()
+        };
+        let (mut node, _dir) = temp_node(config);
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        // Three files whose records and shards are held by the node itself
+        let contents: Vec<&[u8]> = vec![b"the first file", b"a second one", b"3rd"];
+        let mut files = Vec::new();
+        for bytes in contents.iter() {
+            let (mut file, shards) = sample_file(bytes, 3);
+            file.set_shards(&vec![node.identity.peer_id.clone(); shards.len()]);
+            let behaviour = swarm.behaviour_mut();
+            behaviour.put_record(
+                Record {
+                    key: Key::new(&file.id.to_bytes().unwrap()),
+                    value: file.to_bytes().unwrap(),
+                    publisher: None,
+                    expires: None,
+                },
+                0,
+            );
+            for shard in shards {
+                behaviour.handle_shard_request(ShardMessage::Store {
+                    file_id: file.id.clone(),
+                    shard,
+                });
+            }
+            files.push(file);
+        }
+
+        let ids = files.iter().map(|f| f.id.clone()).collect();
+        let config = OperationConfig {
+            output: Output::Discard,
+            min_nodes: 1,
+            decrypt: false,
+            wait_for_peers: None,
+            force: false,
+        };
+        let results = node.get_batch(ids, config);
+        let (_, op) = node.pending_ops.remove(0);
+        match op {
+            Operation::GetBatch {
+                file_ids, sinks, ..
+            } => node.get_files(&mut swarm, file_ids, sinks).unwrap(),
+            _ => panic!("expected a batch get"),
+        }
+
+        // Only two of the gets run at once
+        assert_eq!(swarm.behaviour().pending_gets.len(), 2);
+        assert_eq!(swarm.behaviour().queued_gets.len(), 1);
+
+        // Drive the swarm until every file has arrived
+        let results = drive_until(
+            vec![&mut swarm],
+            results.collect::<Vec<BatchItem>>(),
+            "batch get",
+        )
+        .await;
+
+        assert_eq!(results.len(), files.len());
+        for (file_id, shards) in results {
+            let i = files.iter().position(|f| f.id == file_id).unwrap();
+            let mut shards: Vec<shard::Shard> = shards
+                .unwrap()
+                .into_iter()
+                .map(|(index, data)| shard::Shard::new(data, index).unwrap())
+                .collect();
+            shards.sort_by_key(|s| s.index());
+            let bytes = shard::Shard::reconstruct_bounded(
+                &shards,
+                &files[i].shard_config,
+                None,
+                u64::MAX,
+            )
+            .unwrap();
+            assert_eq!(bytes, contents[i]);
+        }
+    }
+
+    #[test]
+    fn test_put_get_local() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        node.local = true;
+        let (sk, pk) = test_keypair();
+
+        let path = temp_file(SAMPLE);
+        let file = node
+            .put_local(&path, shard::ShardConfig::new(4, &pk), &sk)
+            .unwrap();
+        assert_eq!(node.get_local(&file.id, None).unwrap(), SAMPLE);
+
+        let (other, _) = sample_file(b"never stored", 2);
+        assert!(node.get_local(&other.id, None).is_err());
+
+        // Files over the node's limit aren't reconstructed
+        node.config.max_reconstruct_bytes = SAMPLE.len() as u64 - 1;
+        assert!(node.get_local(&file.id, None).is_err());
+    }
+
+    #[test]
+    fn test_network_file_count() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        node.local = true;
+        let (sk, pk) = test_keypair();
+        assert_eq!(node.network_file_count().unwrap(), 0);
+
+        let mut files = Vec::new();
+        for i in 0..3 {
+            let path = temp_file(format!("file number {}", i).as_bytes());
+            files.push(
+                node.put_local(&path, shard::ShardConfig::new(2, &pk), &sk)
+                    .unwrap(),
+            );
+        }
+        assert_eq!(node.network_file_count().unwrap(), 3);
+
+        // A file known from the DHT as well as locally is counted once
+        let (other, _) = sample_file(b"only in the dht", 2);
+        node.dht_files.lock().unwrap().insert(files[0].id.clone());
+        node.dht_files.lock().unwrap().insert(other.id);
+        assert_eq!(node.network_file_count().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_reconstruct_local() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        node.local = true;
+        let (sk, pk) = test_keypair();
+
+        let mut config = shard::ShardConfig::new(4, &pk);
+        config.encrypt = true;
+        let path = temp_file(SAMPLE);
+        let file = node.put_local(&path, config, &sk).unwrap();
+        assert_eq!(node.reconstruct_local(&file.id, Some(&sk)).unwrap(), SAMPLE);
+
+        // Metadata that doesn't match the shards is refused
+        let mut tampered = file.clone();
+        tampered.filename = "renamed.txt".to_string();
+        node.shards.put_metadata(&tampered).unwrap();
+        assert!(node.reconstruct_local(&file.id, Some(&sk)).is_err());
+        node.shards.put_metadata(&file).unwrap();
+
+        // Every missing shard is named
+        node.shards.remove_shard(&file.id, 1).unwrap();
+        node.shards.remove_shard(&file.id, 3).unwrap();
+        let e = node.reconstruct_local(&file.id, Some(&sk)).unwrap_err();
+        assert!(e.to_string().contains("shards [1, 3]"), "{}", e);
+
+        // Unless the parity shards make up for them
+        let mut config = shard::ShardConfig::new(4, &pk);
+        config.parity_shards = 1;
+        let without_parity = node.put_local(&path, config.clone(), &sk).unwrap();
+        node.shards.remove_shard(&without_parity.id, 4).unwrap();
+        assert_eq!(
+            node.reconstruct_local(&without_parity.id, None).unwrap(),
+            SAMPLE
+        );
+        let recovered: &[u8] = b"recovered from the parity shard";
+        let without_data =
+            node.put_local(&temp_file(recovered), config, &sk).unwrap();
+        node.shards.remove_shard(&without_data.id, 0).unwrap();
+        assert_eq!(
+            node.reconstruct_local(&without_data.id, None).unwrap(),
+            recovered
+        );
+        node.shards.remove_shard(&without_data.id, 4).unwrap();
+        assert!(node.reconstruct_local(&without_data.id, None).is_err());
+    }
+
+    #[async_std::test]
+    async fn test_cache_metadata() {
+        let config = NodeConfig {
+            cache_metadata: true,
+            ..NodeConfig::default()
+        };
+        let (node, _dir) = temp_node(config);
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        // The node stores every shard of a file whose record it holds
+        let (file, shards) = sample_file(b"cached metadata", 3);
+        assert!(node.local_metadata(&file.id).unwrap().is_none());
+        swarm.behaviour_mut().put_record(
+            Record {
+                key: Key::new(&file.id.to_bytes().unwrap()),
+                value: file.to_bytes().unwrap(),
+                publisher: None,
+                expires: None,
+            },
+            0,
+        );
+        for shard in shards.iter() {
+            swarm
+                .behaviour_mut()
+                .handle_shard_request(ShardMessage::Store {
+                    file_id: file.id.clone(),
+                    shard: shard.clone(),
+                });
+        }
+        assert_eq!(node.local_metadata(&file.id).unwrap(), Some(file.clone()));
+
+        // So the file can be reconstructed without the network
+        drop(swarm);
+        assert_eq!(node.get_local(&file.id, None).unwrap(), b"cached metadata");
+
+        // A node that doesn't cache metadata only stores the shards
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        swarm
+            .behaviour_mut()
+            .handle_shard_request(ShardMessage::Store {
+                file_id: file.id.clone(),
+                shard: shards[0].clone(),
+            });
+        assert!(node.local_metadata(&file.id).unwrap().is_none());
+    }
+
+    #[async_std::test]
+    async fn test_cache_unverified_metadata() {
+        let config = NodeConfig {
+            cache_metadata: true,
+            ..NodeConfig::default()
+        };
+        let (node, _dir) = temp_node(config);
+        let mut swarm = node.build_swarm().await.unwrap();
+
+        // A record whose metadata its owner didn't sign isn't cached
+        let (file, shards) = sample_file(b"forged metadata", 3);
+        let mut forged = file.clone();
+        forged.filename = "forged.txt".to_string();
+        let key = Key::new(&file.id.to_bytes().unwrap());
+        swarm.behaviour_mut().put_record(
+            Record {
+                key: key.clone(),
+                value: forged.to_bytes().unwrap(),
+                publisher: None,
+                expires: None,
+            },
+            0,
+        );
+        let behaviour = swarm.behaviour_mut();
+        assert!(behaviour.cache_file_metadata(&file.id).is_err());
+        assert!(node.local_metadata(&file.id).unwrap().is_none());
+
+        // Without a record, the metadata is looked up once per file
+        behaviour.kademlia.store_mut().remove(&key);
+        for shard in shards.iter() {
+            behaviour.handle_shard_request(ShardMessage::Store {
+                file_id: file.id.clone(),
+                shard: shard.clone(),
+            });
+        }
+        assert_eq!(behaviour.pending_caches.len(), 1);
+        assert_eq!(behaviour.pending_caches.values().next(), Some(&file.id));
+    }
+
+    #[test]
+    fn test_health() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let health = node.health();
+        assert!(!health.listening);
+        assert_eq!(health.connected_peers, 0);
+        assert!(health.store_writable);
+        assert_eq!(health.pending_ops, 0);
+        assert!(!health.is_ready());
+
+        // A handle keeps reporting the node's health as it changes
+        let handle = node.health_handle();
+        node.push_operation(Operation::AnnounceShards);
+        assert_eq!(node.health().pending_ops, 1);
+        assert_eq!(handle.clone().status(), node.health());
+        let id = node.push_operation(Operation::AnnounceShards);
+        node.cancel_operation(id);
+        assert_eq!(handle.status().pending_ops, 1);
+    }
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let (mut a, _a_dir) = temp_node(NodeConfig::default());
+        a.local = true;
+        let (sk, pk) = test_keypair();
+        let file = a
+            .put_local(&temp_file(SAMPLE), shard::ShardConfig::new(3, &pk), &sk)
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(a.export_bundle(&file.id, dir.path()).unwrap(), 3);
+
+        let (mut b, _b_dir) = temp_node(NodeConfig::default());
+        b.local = true;
+        let imported = b.import_bundle(dir.path(), true).unwrap();
+        assert_eq!(imported.id, file.id);
+        assert_eq!(b.get_local(&file.id, None).unwrap(), SAMPLE);
+        assert!(b.pending_ops.is_empty());
+
+        // A tampered shard is refused
+        let shard_path = dir.path().join("shard_1");
+        let mut bytes = fs::read(&shard_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&shard_path, bytes).unwrap();
+        assert!(b.import_bundle(dir.path(), false).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        node.local = true;
+        let (sk, pk) = test_keypair();
+        let file = node
+            .put_local(&temp_file(SAMPLE), shard::ShardConfig::new(3, &pk), &sk)
+            .unwrap();
+        node.push_operation(Operation::EnsureReplication {
+            file_id: file.id.clone(),
+            target: 2,
+        });
+        let (sink, _) = mpsc::unbounded();
+        node.push_operation(Operation::GetMetadata {
+            file_id: file.id.clone(),
+            sink,
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("snapshot");
+        node.snapshot(&archive, "passphrase").unwrap();
+
+        // The keypair is only restored with the passphrase
+        let name = format!("test_snapshot_restored_{}", rand::random::<u32>());
+        assert!(
+            Node::restore(&archive, &name, "wrong", NodeConfig::default()).is_err()
+        );
+        let restored =
+            Node::restore(&archive, &name, "passphrase", NodeConfig::default())
+                .unwrap();
+        assert_eq!(restored.identity.peer_id, node.identity.peer_id);
+        assert!(Identity::is_encrypted(&name));
+        assert_eq!(restored.get_local(&file.id, None).unwrap(), SAMPLE);
+        assert!(restored.shards.get_metadata(&file.id).unwrap().is_some());
+
+        // Only the operation that can outlive the process is restored
+        assert_eq!(restored.pending_ops.len(), 1);
+        match &restored.pending_ops[0].1 {
+            Operation::EnsureReplication { file_id, target } => {
+                assert_eq!(file_id, &file.id);
+                assert_eq!(*target, 2);
+            }
+            _ => panic!("expected the replication to be restored"),
+        }
+
+        // A node can't be restored over an existing one
+        assert!(
+            Node::restore(&archive, &name, "passphrase", NodeConfig::default())
+                .is_err()
+        );
+
+        drop(restored);
+        fs::remove_dir_all(Path::new(DATADIR).join("identities").join(&name))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_scrub() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        node.local = true;
+        let (sk, pk) = test_keypair();
+
+        let path = temp_file(SAMPLE);
+        let file = node
+            .put_local(&path, shard::ShardConfig::new(3, &pk), &sk)
+            .unwrap();
+
+        // Nothing to repair yet
+        assert!(node.scrub().unwrap().is_empty());
+
+        // Rot a stored shard
+        let mut rotten = node.shards.get_shard(&file.id, 1).unwrap().unwrap();
+        rotten.data[0] ^= 0xFF;
+        node.shards.put_shard(&file.id, &rotten).unwrap();
+
+        assert_eq!(node.scrub().unwrap(), vec![(file.id.clone(), 1)]);
+        assert_eq!(node.shards.get_shard(&file.id, 1).unwrap(), None);
+        assert_eq!(
+            node.shards.flagged_for_repair().unwrap(),
+            vec![(file.id.clone(), 1)]
+        );
+        assert!(node.shards.get_shard(&file.id, 0).unwrap().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_reshard() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        node.local = true;
+        let (sk, pk) = test_keypair();
+
+        let path = temp_file(SAMPLE);
+        let file = node
+            .put_local(&path, shard::ShardConfig::new(5, &pk), &sk)
+            .unwrap();
+        assert_eq!(file.shard_ids().len(), 5);
+
+        let resharded = node.reshard(&file.id, 12, &sk).await.unwrap();
+        assert_eq!(resharded.id, file.id);
+        assert_eq!(resharded.shard_config.shard_count, 12);
+        assert_eq!(resharded.shard_ids().len(), 12);
+        assert_eq!(node.shards.get(&file.id).unwrap().unwrap().len(), 12);
+        assert_eq!(node.get_local(&file.id, None).unwrap(), SAMPLE);
+
+        // The new metadata is signed by the owner
+        let manifest = resharded.export_manifest().unwrap();
+        assert!(file::File::verify_manifest(&manifest).is_ok());
+
+        // Only the owner can reshard
+        let (other_sk, _) = test_keypair();
+        assert!(node.reshard(&file.id, 3, &other_sk).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_reshard_redistributes() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        for index in 0..3 {
+            swarm.behaviour_mut().kademlia.add_address(
+                &holder(index),
+                "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            );
+        }
+        let (sk, pk) = test_keypair();
+        let path = temp_file(b"a file that is resharded on the network");
+        let mut file = node
+            .put_local(&path, shard::ShardConfig::new(3, &pk), &sk)
+            .unwrap();
+        // As if its shards had been put on the holders
+        file.set_shards(&(0..3).map(holder).collect());
+        node.shards.add_owned(&file).unwrap();
+
+        let resharded = node.reshard(&file.id, 2, &sk).await.unwrap();
+        let (old, file_metadata, shards) = match node.pending_ops.pop() {
+            Some((
+                _,
+                Operation::Reshard {
+                    old,
+                    file_metadata,
+                    shards,
+                },
+            )) => (old, file_metadata, shards),
+            _ => panic!("the reshard wasn't queued"),
+        };
+        assert_eq!(old.shard_ids(), file.shard_ids());
+        node.redistribute(&mut swarm, old, file_metadata, shards)
+            .unwrap();
+        wait_sharded(&mut node, &mut swarm).await;
+
+        // The shards of the reshard are sent as they are, not sharded again
+        let behaviour = swarm.behaviour();
+        let sent: Vec<&shard::ShardID> = behaviour
+            .sent_shards
+            .iter()
+            .map(|(_, shard)| &shard.id)
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|id| resharded.shard_ids().contains(id)));
+
+        // And each holder of an old shard is asked to delete it
+        assert_eq!(behaviour.request_times.len(), sent.len() + 3);
+        let owned = node.shards.owned_file(&file.id).unwrap().unwrap();
+        assert_eq!(owned.shard_ids(), resharded.shard_ids());
+    }
+
+    #[async_std::test]
+    async fn test_reshard_remote() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let (sk, pk) = test_keypair();
+        let (mut file, shards) =
+            sample_file_with(SAMPLE, shard::ShardConfig::new(3, &pk), &sk);
+
+        // The shards are only on the holders, and the metadata in the DHT
+        file.set_shards(&(0..3).map(holder).collect());
+        swarm.behaviour_mut().put_record(
+            Record {
+                key: Key::new(&file.id.to_bytes().unwrap()),
+                value: file.to_bytes().unwrap(),
+                publisher: None,
+                expires: None,
+            },
+            0,
+        );
+        assert!(node.shards.get(&file.id).unwrap().is_none());
+
+        let key = SecretKey::from_bytes(&sk.to_bytes()).unwrap();
+        let resharding = node.reshard_remote(file.id.clone(), 2, key);
+        match node.pending_ops.pop() {
+            Some((
+                _,
+                Operation::ReshardFile {
+                    file_id,
+                    shard_count,
+                    priv_key,
+                    sink,
+                },
+            )) => node
+                .start_reshard(&mut swarm, file_id, shard_count, priv_key, sink)
+                .unwrap(),
+            _ => panic!("the reshard wasn't queued"),
+        }
+
+        // The holders answer the get of the file
+        let behaviour = swarm.behaviour_mut();
+        behaviour.request_shards(&file);
+        for shard in shards.iter() {
+            behaviour.handle_shard_response(
+                holder(shard.index()),
+                shard.to_message(&file.id),
+            );
+        }
+
+        // Once the metadata arrives too, the file is sharded again for the
+        // online peers
+        for index in 3..5 {
+            swarm.behaviour_mut().kademlia.add_address(
+                &holder(index),
+                "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            );
+        }
+        let finished = future::poll_fn(|cx| {
+            while let Poll::Ready(Some(_)) = swarm.poll_next_unpin(cx) {}
+            node.poll_resharding(&mut swarm, cx);
+            if node.resharding.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        async_std::future::timeout(Duration::from_secs(10), finished)
+            .await
+            .expect("the reshard timed out");
+        let resharded = resharding.await.unwrap();
+        assert_eq!(resharded.id, file.id);
+        assert_eq!(resharded.shard_config.shard_count, 2);
+        assert!(resharded.verify_metadata().is_ok());
+
+        // And the new shards are placed on the peers as they are
+        wait_sharded(&mut node, &mut swarm).await;
+        let sent: Vec<&shard::ShardID> = swarm
+            .behaviour()
+            .sent_shards
+            .iter()
+            .map(|(_, shard)| &shard.id)
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|id| resharded.shard_ids().contains(id)));
+    }
+
+    #[async_std::test]
+    async fn test_get_metadata_verified() {
+        let (mut node, _dir) = temp_node(NodeConfig::default());
+        let (file, _) = sample_file(SAMPLE, 2);
+        let answer = |node: &mut Node, file: file::File| {
+            let lookup = node.get_metadata(file.id.clone());
+            match node.pending_ops.pop() {
+                Some((_, Operation::GetMetadata { sink, .. })) => {
+                    sink.unbounded_send(Ok(file)).unwrap()
+                }
+                _ => panic!("the lookup wasn't queued"),
+            }
+            lookup
+        };
+
+        let found = answer(&mut node, file.clone()).await.unwrap();
+        assert_eq!(found.owner, Some(file.owner().unwrap()));
+
+        // Metadata signed by someone other than its owner is refused
+        let (_, other_pk) = test_keypair();
+        let mut forged = file.clone();
+        forged.shard_config.pub_key = other_pk;
+        assert!(answer(&mut node, forged).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_delete_held_shard() {
+        let (node, _dir) = temp_node(NodeConfig::default());
+        let mut swarm = node.build_swarm().await.unwrap();
+        let (file, shards) = sample_file(SAMPLE, 2);
+        let owner = file.owner().unwrap();
+
+        let behaviour = swarm.behaviour_mut();
+        behaviour.store.put_metadata(&file).unwrap();
+        behaviour.handle_shard_request(ShardMessage::Store {
+            file_id: file.id.clone(),
+            shard: shards[0].clone(),
+        });
+
+        // Only the owner of the file may delete its shards
+        match behaviour.delete_held_shard(&PeerId::random(), &file.id, &shards[0].id)
+        {
+            ShardMessage::Error(_) => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert!(behaviour.store.get_shard(&file.id, 0).unwrap().is_some());
+
+        match behaviour.delete_held_shard(&owner, &file.id, &shards[0].id) {
+            ShardMessage::Deleted => {}
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert!(behaviour.store.get_shard(&file.id, 0).unwrap().is_none());
+        match behaviour.delete_held_shard(&owner, &file.id, &shards[0].id) {
+            ShardMessage::NotFound => {}
+            other => panic!("unexpected response {:?}", other),
+        }
     }
 }
@@ -0,0 +1,198 @@
+use crate::{crypto::hash, primitives::shard::ShardID};
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// Calculate the rendezvous (highest random weight) score of a peer for a shard.
+fn score(shard_id: &ShardID, peer: &PeerId) -> hash::Hash {
+    hash::hash_bytes([shard_id.as_bytes(), &peer.to_bytes()[..]].concat())
+}
+
+/// Assign every shard to the `k` candidate peers that score the highest for
+/// that shard under rendezvous hashing. The assignment only depends on the
+/// shard ids and the set of peers (not their order), so a getter can predict
+/// the holders of a shard, and a peer joining or leaving only moves the shards
+/// that it wins or held.
+///
+/// # Arguments
+/// * `shard_ids` - The ids of the shards to place
+/// * `peers` - The candidate peers that can hold shards
+/// * `k` - The number of peers each shard should be assigned to
+pub fn assign_shards(
+    shard_ids: &[ShardID],
+    peers: &[PeerId],
+    k: usize,
+) -> HashMap<ShardID, Vec<PeerId>> {
+    shard_ids
+        .iter()
+        .map(|shard_id| {
+            let mut ranked: Vec<(hash::Hash, &PeerId)> = peers
+                .iter()
+                .map(|peer| (score(shard_id, peer), peer))
+                .collect();
+            ranked.sort_by(|a, b| b.0.cmp(&a.0));
+
+            let holders = ranked
+                .into_iter()
+                .take(k)
+                .map(|(_, peer)| peer.clone())
+                .collect();
+            (shard_id.clone(), holders)
+        })
+        .collect()
+}
+
+/// Choose the peers that a shard should be copied to so that it is held by
+/// `target` peers, using the same rendezvous hashing as `assign_shards`. Peers
+/// that already hold the shard are never chosen. Fewer peers are returned if
+/// there aren't enough candidates.
+///
+/// # Arguments
+/// * `shard_id` - The id of the shard to replicate
+/// * `holders` - The peers that currently hold the shard
+/// * `peers` - The candidate peers that can hold shards
+/// * `target` - The number of peers that should hold the shard
+pub fn replication_targets(
+    shard_id: &ShardID,
+    holders: &[PeerId],
+    peers: &[PeerId],
+    target: usize,
+) -> Vec<PeerId> {
+    let candidates: Vec<PeerId> = peers
+        .iter()
+        .filter(|peer| !holders.contains(peer))
+        .cloned()
+        .collect();
+    let missing = target.saturating_sub(holders.len());
+    assign_shards(&[shard_id.clone()], &candidates, missing)
+        .remove(shard_id)
+        .unwrap_or_default()
+}
+
+/// Order shards so that the ones held by the fewest peers come first, so that
+/// a getter can request the shards most likely to vanish before the others.
+/// Shards held by the same number of peers are ordered by id.
+pub fn rarest_first_order(
+    shard_map: &HashMap<ShardID, Vec<PeerId>>,
+) -> Vec<ShardID> {
+    let mut shard_ids: Vec<&ShardID> = shard_map.keys().collect();
+    shard_ids.sort_by(|a, b| {
+        shard_map[*a]
+            .len()
+            .cmp(&shard_map[*b].len())
+            .then_with(|| a.as_bytes().cmp(b.as_bytes()))
+    });
+    shard_ids.into_iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard_ids(n: u8) -> Vec<ShardID> {
+        (0..n).map(|i| ShardID::from_bytes([i; 32])).collect()
+    }
+
+    fn peers(n: usize) -> Vec<PeerId> {
+        (0..n).map(|_| PeerId::random()).collect()
+    }
+
+    #[test]
+    fn test_assign_deterministic() {
+        let ids = shard_ids(10);
+        let mut peers = peers(8);
+
+        let a = assign_shards(&ids, &peers, 3);
+        peers.reverse();
+        let b = assign_shards(&ids, &peers, 3);
+
+        assert_eq!(a, b);
+        for holders in a.values() {
+            assert_eq!(holders.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_assign_k_larger_than_peers() {
+        let ids = shard_ids(4);
+        let peers = peers(2);
+
+        for holders in assign_shards(&ids, &peers, 5).values() {
+            assert_eq!(holders.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_assign_stable_under_leave() {
+        let ids = shard_ids(32);
+        let mut peers = peers(10);
+        let before = assign_shards(&ids, &peers, 1);
+
+        // Only shards held by the departed peer should move
+        let gone = peers.remove(4);
+        let after = assign_shards(&ids, &peers, 1);
+        for id in ids.iter() {
+            if before[id][0] != gone {
+                assert_eq!(before[id], after[id]);
+            } else {
+                assert_ne!(after[id][0], gone);
+            }
+        }
+    }
+
+    #[test]
+    fn test_assign_stable_under_join() {
+        let ids = shard_ids(32);
+        let mut peers = peers(10);
+        let before = assign_shards(&ids, &peers, 1);
+
+        // Only shards won by the new peer should move
+        let joined = PeerId::random();
+        peers.push(joined.clone());
+        let after = assign_shards(&ids, &peers, 1);
+        for id in ids.iter() {
+            if after[id][0] != joined {
+                assert_eq!(before[id], after[id]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_replication_targets() {
+        let id = &shard_ids(1)[0];
+        let peers = peers(6);
+        let holders = peers[..3].to_vec();
+
+        assert!(replication_targets(id, &holders, &peers, 3).is_empty());
+
+        // Losing a holder means one new peer must get a copy
+        let targets = replication_targets(id, &holders[..2], &peers, 3);
+        assert_eq!(targets.len(), 1);
+        assert!(!holders[..2].contains(&targets[0]));
+
+        // There are only four candidates that don't hold the shard
+        assert_eq!(replication_targets(id, &holders[..2], &peers, 10).len(), 4);
+    }
+
+    #[test]
+    fn test_rarest_first_order() {
+        let ids = shard_ids(4);
+        let replicas = [3, 1, 2, 1];
+
+        let shard_map: HashMap<ShardID, Vec<PeerId>> = ids
+            .iter()
+            .zip(replicas.iter())
+            .map(|(id, n)| (id.clone(), peers(*n)))
+            .collect();
+
+        assert_eq!(
+            rarest_first_order(&shard_map),
+            vec![
+                ids[1].clone(),
+                ids[3].clone(),
+                ids[2].clone(),
+                ids[0].clone()
+            ]
+        );
+        assert!(rarest_first_order(&HashMap::new()).is_empty());
+    }
+}
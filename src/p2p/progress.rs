@@ -0,0 +1,169 @@
+use crate::GeneralError;
+use futures::channel::mpsc;
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+
+/// An event emitted as a put makes progress.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PutProgress {
+    /// The file is being split into shards.
+    Sharding,
+
+    /// The file's metadata was handed to the DHT.
+    PublishedMetadata,
+
+    /// A shard was sent to the peer that will hold it.
+    ShardSent { index: u32, peer: PeerId },
+
+    /// Every shard was sent.
+    Complete,
+}
+
+/// The receiving half of a put's progress events. It completes once the put
+/// is done.
+pub type ProgressStream = mpsc::UnboundedReceiver<PutProgress>;
+
+/// The sending half of a put's progress events.
+#[derive(Clone)]
+pub struct ProgressSink(mpsc::UnboundedSender<PutProgress>);
+
+impl ProgressSink {
+    /// Emit an event. Events sent after the receiver is dropped are discarded.
+    pub fn emit(&self, event: PutProgress) {
+        let _ = self.0.unbounded_send(event);
+    }
+}
+
+/// Create a connected sink and stream.
+pub fn progress_channel() -> (ProgressSink, ProgressStream) {
+    let (tx, rx) = mpsc::unbounded();
+    (ProgressSink(tx), rx)
+}
+
+/// Which holders acknowledged storing the shards of a put.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PutReceipt {
+    /// The holder that stored each acknowledged shard, by index
+    pub stored: HashMap<u32, PeerId>,
+
+    /// The indices of the shards that no holder acknowledged, in order
+    pub missing: Vec<u32>,
+}
+
+/// Where the receipt of a put is sent once every holder has answered. A put
+/// acknowledged by fewer holders than its `min_nodes` fails instead.
+pub type ReceiptSink = mpsc::UnboundedSender<Result<PutReceipt, GeneralError>>;
+
+/// The receiving half of a put's receipt.
+pub type ReceiptStream = mpsc::UnboundedReceiver<Result<PutReceipt, GeneralError>>;
+
+/// Collects the acknowledgements of the shards of a put into a receipt.
+pub(crate) struct ReceiptCollector {
+    /// The shards acknowledged so far, and their holders
+    stored: HashMap<u32, PeerId>,
+
+    /// The shards whose holders haven't answered yet
+    outstanding: HashSet<u32>,
+
+    /// The fewest acknowledged shards that make the put a success
+    min_acks: usize,
+
+    /// Where the receipt is sent
+    sink: Option<ReceiptSink>,
+}
+
+impl ReceiptCollector {
+    pub(crate) fn new(
+        indices: &[u32],
+        min_acks: usize,
+        sink: Option<ReceiptSink>,
+    ) -> Self {
+        Self {
+            stored: HashMap::new(),
+            outstanding: indices.iter().cloned().collect(),
+            min_acks,
+            sink,
+        }
+    }
+
+    /// Record the answer of the holder of a shard: whether it stored it.
+    pub(crate) fn answer(&mut self, index: u32, peer: &PeerId, stored: bool) {
+        if self.outstanding.remove(&index) && stored {
+            self.stored.insert(index, peer.clone());
+        }
+    }
+
+    /// Check whether the holder of every shard has answered.
+    pub(crate) fn is_done(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// Make the receipt of the answers so far, treating the shards that have
+    /// no answer as missing, or say why the put failed.
+    pub(crate) fn receipt(&self) -> Result<PutReceipt, GeneralError> {
+        if self.stored.len() < self.min_acks {
+            return Err(GeneralError::new(
+                format!(
+                    "only {} of the {} required shards were stored",
+                    self.stored.len(),
+                    self.min_acks
+                )
+                .as_str(),
+            ));
+        }
+        let mut missing: Vec<u32> = self.outstanding.iter().cloned().collect();
+        missing.sort();
+        Ok(PutReceipt {
+            stored: self.stored.clone(),
+            missing,
+        })
+    }
+
+    /// Send the receipt (or why the put failed) to the sink.
+    pub(crate) fn finish(self) {
+        let result = self.receipt();
+        match self.sink {
+            Some(sink) => {
+                let _ = sink.unbounded_send(result);
+            }
+            None => {
+                if let Err(e) = result {
+                    eprintln!("put failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[async_std::test]
+    async fn test_receipt() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (sink, mut receipts) = mpsc::unbounded();
+        let mut collector = ReceiptCollector::new(&[0, 1, 2], 2, Some(sink));
+
+        collector.answer(0, &a, true);
+        collector.answer(2, &b, false);
+        assert!(!collector.is_done());
+        collector.answer(1, &b, true);
+        collector.answer(1, &a, true); // A second answer doesn't count
+        assert!(collector.is_done());
+
+        let receipt = collector.receipt().unwrap();
+        collector.finish();
+        assert_eq!(receipts.next().await.unwrap().unwrap(), receipt);
+        assert_eq!(receipt.stored.len(), 2);
+        assert_eq!(receipt.stored[&0], a);
+        assert_eq!(receipt.stored[&1], b);
+        assert_eq!(receipt.missing, vec![2]);
+
+        // Too few acknowledgements fail the put
+        let mut collector = ReceiptCollector::new(&[0, 1], 2, None);
+        collector.answer(0, &a, true);
+        assert!(collector.receipt().is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::primitives::{
+    file::FileID,
+    shard::{Shard, ShardID},
+};
+use async_trait::async_trait;
+use bincode::Options;
+use futures::prelude::*;
+use libp2p::{
+    core::{
+        upgrade::{read_length_prefixed, write_length_prefixed},
+        ProtocolName,
+    },
+    request_response::RequestResponseCodec,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io;
+
+/// The largest message (in bytes) that will be read from the shard protocol by
+/// default.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The status that a node periodically publishes on the status channel, so
+/// that its peers can place shards on the nodes with room for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// The free space of the node's shard stores, in bytes
+    pub free_bytes: u64,
+
+    /// The total space of the node's shard stores, in bytes (`u64::MAX` if
+    /// the stores aren't limited)
+    pub total_bytes: u64,
+}
+
+/// A message exchanged between nodes over the shard protocol. Every message is
+/// used as either a request or a response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ShardMessage {
+    /// Ask a node to store a shard of a file (request).
+    Store { file_id: FileID, shard: Shard },
+
+    /// Ask a node for the shard of a file at an index (request).
+    Request { file_id: FileID, index: u32 },
+
+    /// A shard of a file (response to `Request`).
+    Data { file_id: FileID, shard: Shard },
+
+    /// The shard was stored (response to `Store`).
+    Stored,
+
+    /// The requested shard is not held by this node (response to `Request`).
+    NotFound,
+
+    /// The requested shard is held by this node but is corrupted, so it won't
+    /// be served (response to `Request`).
+    Corrupt,
+
+    /// The request could not be handled (response).
+    Error(String),
+
+    /// Ask a node to answer as soon as it can, to measure the round trip time
+    /// to it (request). The nonce is echoed back in the `Pong`.
+    Ping(u64),
+
+    /// The answer to a `Ping`, carrying its nonce (response).
+    Pong(u64),
+
+    /// Ask a node to delete the shard of a file with an id, because the
+    /// owner of the file replaced it (request). Only the owner may ask.
+    Delete { file_id: FileID, shard_id: ShardID },
+
+    /// The shard was deleted (response to `Delete`).
+    Deleted,
+}
+
+impl Shard {
+    /// Wrap the shard of a file in a `Data` message, to answer a request for
+    /// it.
+    pub fn to_message(&self, file_id: &FileID) -> ShardMessage {
+        ShardMessage::Data {
+            file_id: file_id.clone(),
+            shard: self.clone(),
+        }
+    }
+}
+
+impl ShardMessage {
+    /// Unwrap the shard carried by a `Store` or `Data` message, along with the
+    /// id of its file. Messages that don't carry a shard, and shards that are
+    /// corrupted or larger than `MAX_MESSAGE_SIZE`, give `None`.
+    pub fn into_shard(self) -> Option<(FileID, Shard)> {
+        self.into_shard_bounded(MAX_MESSAGE_SIZE)
+    }
+
+    /// Like `into_shard`, but with a custom limit on the size of the shard's
+    /// data (in bytes).
+    pub fn into_shard_bounded(self, max_bytes: usize) -> Option<(FileID, Shard)> {
+        match self {
+            ShardMessage::Store { file_id, shard }
+            | ShardMessage::Data { file_id, shard }
+                if shard.data.len() <= max_bytes && shard.is_valid() =>
+            {
+                Some((file_id, shard))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The name of the shard protocol.
+#[derive(Debug, Clone)]
+pub struct ShardProtocol();
+
+impl ProtocolName for ShardProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/meros/shard/1.0.0"
+    }
+}
+
+/// Reads and writes length-prefixed, bincode encoded `ShardMessage`s.
+#[derive(Clone)]
+pub struct ShardCodec {
+    /// The largest message (in bytes) that will be read
+    max_message_bytes: usize,
+}
+
+impl ShardCodec {
+    pub fn new(max_message_bytes: usize) -> Self {
+        Self { max_message_bytes }
+    }
+}
+
+impl Default for ShardCodec {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_SIZE)
+    }
+}
+
+/// Deserialize a payload that arrived from the network (like a message or a
+/// DHT record), refusing payloads over `max_bytes` bytes. Nothing is decoded
+/// from a payload that is too large, and lengths inside the payload that would
+/// make it decode to more than `max_bytes` bytes are refused before anything
+/// is allocated for them.
+pub fn decode_bounded<T: DeserializeOwned>(
+    bytes: &[u8],
+    max_bytes: usize,
+) -> bincode::Result<T> {
+    if bytes.len() > max_bytes {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+
+    // The same encoding as `bincode::deserialize`, with a limit
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(max_bytes as u64)
+        .deserialize(bytes)
+}
+
+/// Read a single message from a stream.
+async fn read_message<T>(io: &mut T, max_bytes: usize) -> io::Result<ShardMessage>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let bytes = read_length_prefixed(io, max_bytes).await?;
+    if bytes.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    decode_bounded(&bytes, max_bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Write a single message to a stream and close it.
+async fn write_message<T>(io: &mut T, msg: ShardMessage) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let bytes = bincode::serialize(&msg)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    write_length_prefixed(io, bytes).await?;
+    io.close().await
+}
+
+#[async_trait]
+impl RequestResponseCodec for ShardCodec {
+    type Protocol = ShardProtocol;
+    type Request = ShardMessage;
+    type Response = ShardMessage;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &ShardProtocol,
+        io: &mut T,
+    ) -> io::Result<ShardMessage>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, self.max_message_bytes).await
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &ShardProtocol,
+        io: &mut T,
+    ) -> io::Result<ShardMessage>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, self.max_message_bytes).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &ShardProtocol,
+        io: &mut T,
+        req: ShardMessage,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, req).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &ShardProtocol,
+        io: &mut T,
+        res: ShardMessage,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::file::File;
+    use crate::test_util::{fuzzed, sample_file};
+    use crate::CanSerialize;
+
+    #[test]
+    fn test_message_helpers() {
+        let (file, shards) = sample_file(&vec![7u8; 4096], 2);
+        let msg = shards[1].to_message(&file.id);
+        let bytes = bincode::serialize(&msg).unwrap();
+        let decoded = decode_bounded::<ShardMessage>(&bytes, bytes.len()).unwrap();
+        assert_eq!(
+            decoded.into_shard(),
+            Some((file.id.clone(), shards[1].clone()))
+        );
+
+        // Store requests carry shards too
+        let store = ShardMessage::Store {
+            file_id: file.id.clone(),
+            shard: shards[0].clone(),
+        };
+        assert_eq!(
+            store.clone().into_shard(),
+            Some((file.id.clone(), shards[0].clone()))
+        );
+
+        // But shards that are too large or corrupted are refused
+        assert_eq!(store.into_shard_bounded(1024), None);
+        let mut corrupted = shards[0].clone();
+        corrupted.data[0] ^= 1;
+        assert_eq!(corrupted.to_message(&file.id).into_shard(), None);
+        assert_eq!(ShardMessage::Stored.into_shard(), None);
+    }
+
+    #[test]
+    fn test_decode_bounded() {
+        let (file, shards) = sample_file(&vec![7u8; 4096], 1);
+        let msg = ShardMessage::Data {
+            file_id: file.id.clone(),
+            shard: shards[0].clone(),
+        };
+        let bytes = bincode::serialize(&msg).unwrap();
+
+        match decode_bounded::<ShardMessage>(&bytes, bytes.len()).unwrap() {
+            ShardMessage::Data { shard, .. } => assert_eq!(shard, shards[0]),
+            m => panic!("decoded the wrong message: {:?}", m),
+        }
+
+        // An oversized payload is refused before it is decoded
+        match *decode_bounded::<ShardMessage>(&bytes, 1024).unwrap_err() {
+            bincode::ErrorKind::SizeLimit => {}
+            e => panic!("expected a size limit error, got {:?}", e),
+        }
+
+        // So is a small payload claiming a huge string
+        let mut claim =
+            bincode::serialize(&ShardMessage::Error(String::new())).unwrap();
+        let len = claim.len();
+        claim[len - 8..].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        match *decode_bounded::<ShardMessage>(&claim, 1024).unwrap_err() {
+            bincode::ErrorKind::SizeLimit => {}
+            e => panic!("expected a size limit error, got {:?}", e),
+        }
+
+        // The same limit applies to DHT records
+        let record = file.to_bytes().unwrap();
+        assert!(decode_bounded::<File>(&record, record.len()).is_ok());
+        assert!(decode_bounded::<File>(&record, record.len() - 1).is_err());
+    }
+
+    #[test]
+    fn test_decode_fuzzed() {
+        let (file, shards) = sample_file(&vec![7u8; 256], 1);
+        let messages = vec![
+            shards[0].to_message(&file.id),
+            ShardMessage::Request {
+                file_id: file.id.clone(),
+                index: 0,
+            },
+            ShardMessage::Error("refused".to_string()),
+        ];
+
+        // Malformed messages are refused (or decode to something harmless)
+        // without panicking
+        for msg in messages {
+            for bytes in fuzzed(&bincode::serialize(&msg).unwrap(), 300) {
+                if let Ok(msg) = decode_bounded::<ShardMessage>(&bytes, 1024 * 1024)
+                {
+                    let _ = msg.into_shard();
+                }
+            }
+        }
+        let heartbeat = Heartbeat {
+            free_bytes: 1,
+            total_bytes: 2,
+        };
+        for bytes in fuzzed(&bincode::serialize(&heartbeat).unwrap(), 100) {
+            let _ = decode_bounded::<Heartbeat>(&bytes, 1024);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::GeneralError;
+use libp2p::{
+    floodsub::{self, Floodsub, FloodsubEvent},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic,
+        MessageAuthenticity,
+    },
+    identity::Keypair,
+    swarm::toggle::Toggle,
+    NetworkBehaviour, PeerId,
+};
+use std::error::Error;
+
+/// The publish-subscribe protocol that a node exchanges topic messages (like
+/// shard announcements) over. Both ends have to use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PubsubKind {
+    /// Floodsub, which sends every message to every known peer
+    Floodsub,
+
+    /// Gossipsub, which sends messages along a mesh of peers, so that it
+    /// scales to larger networks
+    Gossipsub,
+}
+
+impl Default for PubsubKind {
+    fn default() -> Self {
+        PubsubKind::Floodsub
+    }
+}
+
+/// An event of a node's publish-subscribe protocol, whichever protocol it is.
+#[derive(Debug)]
+pub enum PubsubEvent {
+    /// A message arrived on a subscribed topic.
+    Message {
+        source: PeerId,
+        topic: String,
+        data: Vec<u8>,
+    },
+
+    /// Something else happened, like a peer subscribing to a topic.
+    Other(String),
+}
+
+impl From<FloodsubEvent> for PubsubEvent {
+    fn from(event: FloodsubEvent) -> Self {
+        match event {
+            FloodsubEvent::Message(msg) => PubsubEvent::Message {
+                source: msg.source,
+                topic: msg
+                    .topics
+                    .first()
+                    .map_or(String::new(), |t| t.id().to_string()),
+                data: msg.data,
+            },
+            event => PubsubEvent::Other(format!("{:?}", event)),
+        }
+    }
+}
+
+impl From<GossipsubEvent> for PubsubEvent {
+    fn from(event: GossipsubEvent) -> Self {
+        match event {
+            GossipsubEvent::Message {
+                propagation_source,
+                message,
+                ..
+            } => PubsubEvent::Message {
+                source: message.source.unwrap_or(propagation_source),
+                topic: message.topic.into_string(),
+                data: message.data,
+            },
+            event => PubsubEvent::Other(format!("{:?}", event)),
+        }
+    }
+}
+
+/// The publish-subscribe protocol of a node. Exactly one of floodsub and
+/// gossipsub is enabled, as chosen by `NodeConfig::pubsub`.
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "PubsubEvent", event_process = false)]
+pub struct Pubsub {
+    floodsub: Toggle<Floodsub>,
+    gossipsub: Toggle<Gossipsub>,
+}
+
+impl Pubsub {
+    /// Build the protocol of a node. Gossipsub messages are signed with
+    /// `keypair`, and messages larger than `max_message_bytes` aren't sent or
+    /// accepted.
+    pub fn new(
+        kind: PubsubKind,
+        keypair: &Keypair,
+        max_message_bytes: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        match kind {
+            PubsubKind::Floodsub => Ok(Self {
+                floodsub: Some(Floodsub::new(keypair.public().into_peer_id()))
+                    .into(),
+                gossipsub: None.into(),
+            }),
+            PubsubKind::Gossipsub => {
+                let config = GossipsubConfigBuilder::default()
+                    .max_transmit_size(max_message_bytes)
+                    .build()
+                    .map_err(GeneralError::new)?;
+                let gossipsub = Gossipsub::new(
+                    MessageAuthenticity::Signed(keypair.clone()),
+                    config,
+                )
+                .map_err(GeneralError::new)?;
+                Ok(Self {
+                    floodsub: None.into(),
+                    gossipsub: Some(gossipsub).into(),
+                })
+            }
+        }
+    }
+
+    /// The protocol that is enabled.
+    pub fn kind(&self) -> PubsubKind {
+        if self.gossipsub.is_enabled() {
+            PubsubKind::Gossipsub
+        } else {
+            PubsubKind::Floodsub
+        }
+    }
+
+    /// Subscribe to a topic. Returns `false` if the node was already
+    /// subscribed to it.
+    pub fn subscribe(&mut self, topic: &str) -> Result<bool, Box<dyn Error>> {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            return Ok(floodsub.subscribe(floodsub::Topic::new(topic)));
+        }
+        match self.gossipsub.as_mut() {
+            Some(gossipsub) => gossipsub.subscribe(&IdentTopic::new(topic)).map_err(
+                |e| -> Box<dyn Error> {
+                    Box::new(GeneralError::new(
+                        format!("could not subscribe to {}: {:?}", topic, e)
+                            .as_str(),
+                    ))
+                },
+            ),
+            None => Ok(false),
+        }
+    }
+
+    /// Publish a message on a topic. Messages that can't be sent (like when
+    /// gossipsub has no peers to send them to) are dropped.
+    pub fn publish(&mut self, topic: &str, data: impl Into<Vec<u8>>) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.publish(floodsub::Topic::new(topic), data);
+        } else if let Some(gossipsub) = self.gossipsub.as_mut() {
+            if let Err(e) = gossipsub.publish(IdentTopic::new(topic), data) {
+                eprintln!("could not publish to {}: {:?}", topic, e);
+            }
+        }
+    }
+
+    /// Start sending the messages of subscribed topics to a discovered peer.
+    /// Gossipsub learns its peers from the node's connections instead, and
+    /// keeps its mesh from them (explicit peers would bypass the mesh and its
+    /// scoring), so this only matters to floodsub.
+    pub fn add_peer(&mut self, peer: PeerId) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.add_node_to_partial_view(peer);
+        }
+    }
+
+    /// Stop sending messages to a peer that went away. Like `add_peer`, this
+    /// only matters to floodsub.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        if let Some(floodsub) = self.floodsub.as_mut() {
+            floodsub.remove_node_from_partial_view(peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pubsub_kinds() {
+        let keypair = Keypair::generate_ed25519();
+        for kind in [PubsubKind::Floodsub, PubsubKind::Gossipsub].iter() {
+            let mut pubsub = Pubsub::new(*kind, &keypair, 1024).unwrap();
+            assert_eq!(pubsub.kind(), *kind);
+            assert!(pubsub.subscribe("topic").unwrap());
+            assert!(!pubsub.subscribe("topic").unwrap());
+
+            // Publishing without peers isn't an error
+            pubsub.publish("topic", "hello".as_bytes());
+            pubsub.add_peer(PeerId::random());
+        }
+    }
+
+    #[test]
+    fn test_floodsub_messages() {
+        let source = PeerId::random();
+        let event = FloodsubEvent::Message(floodsub::FloodsubMessage {
+            source: source.clone(),
+            data: b"announcement".to_vec(),
+            sequence_number: vec![1],
+            topics: vec![floodsub::Topic::new("topic")],
+        });
+        match PubsubEvent::from(event) {
+            PubsubEvent::Message {
+                source: s,
+                topic,
+                data,
+            } => {
+                assert_eq!(s, source);
+                assert_eq!(topic, "topic");
+                assert_eq!(data, b"announcement");
+            }
+            e => panic!("expected a message, got {:?}", e),
+        }
+    }
+}
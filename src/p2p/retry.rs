@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// How a failed network operation should be retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts (including the first one)
+    pub max_attempts: u32,
+
+    /// The delay before the first retry. Every retry after that doubles it.
+    pub base_delay: Duration,
+
+    /// The upper bound on the delay between two attempts
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Calculate the delay to wait after the given (zero-indexed) attempt failed.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        match self.base_delay.checked_mul(factor) {
+            Some(d) if d < self.max_delay => d,
+            _ => self.max_delay,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Keeps track of in-flight operations (keyed by something like a query id) and
+/// schedules the ones that failed to be re-attempted with exponential backoff.
+pub struct RetryTracker<K, T> {
+    policy: RetryPolicy,
+
+    /// The operations that are currently in flight, and how many attempts
+    /// were made before them
+    in_flight: HashMap<K, (T, u32)>,
+
+    /// The operations waiting to be re-attempted, and when
+    scheduled: Vec<(Instant, T, u32)>,
+}
+
+impl<K: Hash + Eq, T> RetryTracker<K, T> {
+    /// Create a new tracker with the given policy.
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            in_flight: HashMap::new(),
+            scheduled: Vec::new(),
+        }
+    }
+
+    /// Start tracking an in-flight operation.
+    pub fn track(&mut self, key: K, item: T, attempt: u32) {
+        self.in_flight.insert(key, (item, attempt));
+    }
+
+    /// Mark an in-flight operation as successful and stop tracking it.
+    pub fn succeeded(&mut self, key: &K) -> Option<T> {
+        self.in_flight.remove(key).map(|(item, _)| item)
+    }
+
+    /// Mark an in-flight operation as failed. Returns the delay until it will be
+    /// re-attempted, or `None` if it has run out of attempts (or is unknown).
+    pub fn failed(&mut self, key: &K, now: Instant) -> Option<Duration> {
+        let (item, attempt) = self.in_flight.remove(key)?;
+        self.schedule(item, attempt, now)
+    }
+
+    /// Schedule an operation whose given attempt failed before it could be
+    /// tracked. Returns the delay until the next attempt, or `None` if it has run
+    /// out of attempts.
+    pub fn schedule(
+        &mut self,
+        item: T,
+        attempt: u32,
+        now: Instant,
+    ) -> Option<Duration> {
+        if attempt + 1 >= self.policy.max_attempts {
+            return None;
+        }
+
+        let delay = self.policy.delay(attempt);
+        self.scheduled.push((now + delay, item, attempt + 1));
+        Some(delay)
+    }
+
+    /// Stop tracking every operation (in flight or waiting to be re-attempted)
+    /// that matches a predicate. Returns how many were dropped.
+    pub fn cancel<F: Fn(&T) -> bool>(&mut self, matches: F) -> usize {
+        let before = self.in_flight.len() + self.scheduled.len();
+        self.in_flight.retain(|_, (item, _)| !matches(item));
+        self.scheduled.retain(|(_, item, _)| !matches(item));
+        before - self.in_flight.len() - self.scheduled.len()
+    }
+
+    /// Check whether any tracked operation matches a predicate.
+    pub fn any<F: Fn(&T) -> bool>(&self, matches: F) -> bool {
+        self.in_flight.values().any(|(item, _)| matches(item))
+            || self.scheduled.iter().any(|(_, item, _)| matches(item))
+    }
+
+    /// Take all of the operations that are due to be re-attempted, along with
+    /// the attempt number they are on.
+    pub fn due(&mut self, now: Instant) -> Vec<(T, u32)> {
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < self.scheduled.len() {
+            if self.scheduled[i].0 <= now {
+                let (_, item, attempt) = self.scheduled.remove(i);
+                due.push((item, attempt));
+            } else {
+                i += 1;
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(250),
+        }
+    }
+
+    #[test]
+    fn test_delay_backoff() {
+        let p = policy();
+        assert_eq!(p.delay(0), Duration::from_millis(100));
+        assert_eq!(p.delay(1), Duration::from_millis(200));
+        assert_eq!(p.delay(2), Duration::from_millis(250));
+        assert_eq!(p.delay(40), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_transient_failure_retried() {
+        let mut tracker: RetryTracker<u64, &str> = RetryTracker::new(policy());
+        let now = Instant::now();
+
+        // The first attempt fails
+        tracker.track(1, "record", 0);
+        let delay = tracker.failed(&1, now).unwrap();
+        assert!(tracker.due(now).is_empty());
+
+        // It is re-attempted once the backoff has passed, and then succeeds
+        let due = tracker.due(now + delay);
+        assert_eq!(due, vec![("record", 1)]);
+        tracker.track(2, "record", 1);
+        assert_eq!(tracker.succeeded(&2), Some("record"));
+        assert!(tracker.due(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_gives_up() {
+        let mut tracker: RetryTracker<u64, &str> = RetryTracker::new(policy());
+        let now = Instant::now();
+
+        tracker.track(1, "record", 2);
+        assert_eq!(tracker.failed(&1, now), None);
+        assert_eq!(tracker.schedule("other", 2, now), None);
+        assert!(tracker.due(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut tracker: RetryTracker<u64, &str> = RetryTracker::new(policy());
+        let now = Instant::now();
+        tracker.track(1, "a", 0);
+        tracker.track(2, "b", 0);
+        tracker.track(3, "a", 0);
+        tracker.failed(&3, now);
+
+        assert!(tracker.any(|item| *item == "a"));
+        assert_eq!(tracker.cancel(|item| *item == "a"), 2);
+        assert!(!tracker.any(|item| *item == "a"));
+        assert!(tracker.due(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(tracker.succeeded(&2), Some("b"));
+    }
+}
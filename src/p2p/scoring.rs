@@ -0,0 +1,307 @@
+use libp2p::PeerId;
+use rand::seq::SliceRandom;
+use std::{collections::HashMap, time::Duration};
+
+/// The round-trip time that scores halfway between an instant peer and an
+/// infinitely slow one
+const REFERENCE_LATENCY: Duration = Duration::from_millis(200);
+
+/// The free capacity (in bytes) that scores halfway between a full peer and
+/// an infinitely large one
+const REFERENCE_CAPACITY: u64 = 1024 * 1024 * 1024;
+
+/// How far a new round-trip time moves the average round-trip time of a peer
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+/// How much each input counts towards the score of a peer. Only the ratios
+/// between the weights matter, and a weight of zero ignores its input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    /// The weight of how often the peer handled shard requests correctly
+    pub trust: f64,
+
+    /// The weight of how quickly the peer answers shard requests
+    pub latency: f64,
+
+    /// The weight of how much free space the peer has
+    pub capacity: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            trust: 0.5,
+            latency: 0.3,
+            capacity: 0.2,
+        }
+    }
+}
+
+/// The order that the holders of a shard are asked for it in. Holders that
+/// fail (or stall) are fallen through to the next ones in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolderSelection {
+    /// The highest blended score (see `ScoreWeights`) first
+    Score,
+
+    /// The fastest holders first
+    Latency,
+
+    /// The holders that handled the most requests correctly first
+    Trust,
+
+    /// A random order, to spread the requests over the holders
+    Random,
+}
+
+impl Default for HolderSelection {
+    fn default() -> Self {
+        HolderSelection::Score
+    }
+}
+
+/// What is known about a peer. Every input of a peer that nothing is known
+/// about scores 0.5.
+#[derive(Debug, Clone, Default)]
+struct PeerStats {
+    /// The shard requests that the peer handled correctly
+    successes: u64,
+
+    /// The shard requests that the peer failed, or answered with a corrupted
+    /// shard
+    failures: u64,
+
+    /// The smoothed round-trip time of the peer's shard requests
+    latency: Option<Duration>,
+
+    /// The free space the peer has, in bytes
+    free_bytes: Option<u64>,
+}
+
+impl PeerStats {
+    fn trust(&self) -> f64 {
+        (self.successes as f64 + 1.0)
+            / ((self.successes + self.failures) as f64 + 2.0)
+    }
+
+    fn latency(&self) -> f64 {
+        let reference = REFERENCE_LATENCY.as_secs_f64();
+        match self.latency {
+            Some(rtt) => reference / (reference + rtt.as_secs_f64()),
+            None => 0.5,
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        match self.free_bytes {
+            Some(free) => free as f64 / (free as f64 + REFERENCE_CAPACITY as f64),
+            None => 0.5,
+        }
+    }
+}
+
+/// Scores peers by blending how trustworthy, fast, and spacious they are, to
+/// choose which peers shards are placed on and requested from.
+pub struct PeerScores {
+    weights: ScoreWeights,
+    peers: HashMap<PeerId, PeerStats>,
+}
+
+impl PeerScores {
+    pub fn new(weights: ScoreWeights) -> Self {
+        Self {
+            weights,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record that a peer handled a shard request correctly.
+    pub fn record_success(&mut self, peer: &PeerId) {
+        self.peers.entry(peer.clone()).or_default().successes += 1;
+    }
+
+    /// Record that a peer failed a shard request.
+    pub fn record_failure(&mut self, peer: &PeerId) {
+        self.peers.entry(peer.clone()).or_default().failures += 1;
+    }
+
+    /// Record the round-trip time of a shard request to a peer.
+    pub fn record_latency(&mut self, peer: &PeerId, rtt: Duration) {
+        let stats = self.peers.entry(peer.clone()).or_default();
+        stats.latency = Some(match stats.latency {
+            Some(average) => Duration::from_secs_f64(
+                average.as_secs_f64() * (1.0 - LATENCY_SMOOTHING)
+                    + rtt.as_secs_f64() * LATENCY_SMOOTHING,
+            ),
+            None => rtt,
+        });
+    }
+
+    /// Record the free space (in bytes) that a peer advertised.
+    pub fn record_capacity(&mut self, peer: &PeerId, free_bytes: u64) {
+        self.peers.entry(peer.clone()).or_default().free_bytes = Some(free_bytes);
+    }
+
+    /// Score a peer between 0 (the worst) and 1 (the best).
+    pub fn score_peer(&self, peer: &PeerId) -> f64 {
+        let weights = &self.weights;
+        let total = weights.trust.max(0.0)
+            + weights.latency.max(0.0)
+            + weights.capacity.max(0.0);
+        if total == 0.0 {
+            return 0.5;
+        }
+
+        let stats = self.peers.get(peer).cloned().unwrap_or_default();
+        (stats.trust() * weights.trust.max(0.0)
+            + stats.latency() * weights.latency.max(0.0)
+            + stats.capacity() * weights.capacity.max(0.0))
+            / total
+    }
+
+    /// Order peers from the highest score to the lowest. Peers with the same
+    /// score keep their order.
+    pub fn rank(&self, peers: &mut [PeerId]) {
+        peers.sort_by(|a, b| {
+            self.score_peer(b)
+                .partial_cmp(&self.score_peer(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+    }
+
+    /// Order the holders of a shard in the order they should be asked for it.
+    /// Holders that rank the same keep their order.
+    pub fn order(&self, holders: &mut [PeerId], selection: HolderSelection) {
+        match selection {
+            HolderSelection::Score => self.rank(holders),
+            HolderSelection::Latency => {
+                self.sort_by_input(holders, PeerStats::latency)
+            }
+            HolderSelection::Trust => self.sort_by_input(holders, PeerStats::trust),
+            HolderSelection::Random => holders.shuffle(&mut rand::thread_rng()),
+        }
+    }
+
+    /// Order peers by a single input, from the best to the worst.
+    fn sort_by_input(&self, peers: &mut [PeerId], input: fn(&PeerStats) -> f64) {
+        let value = |peer: &PeerId| {
+            input(&self.peers.get(peer).cloned().unwrap_or_default())
+        };
+        peers.sort_by(|a, b| {
+            value(b)
+                .partial_cmp(&value(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+    }
+
+    /// Choose the peer with the highest score. The first of the peers with
+    /// the highest score wins.
+    pub fn best<'a>(&self, peers: &'a [PeerId]) -> Option<&'a PeerId> {
+        peers
+            .iter()
+            .fold(None, |best: Option<&PeerId>, peer| match best {
+                Some(b) if self.score_peer(b) >= self.score_peer(peer) => Some(b),
+                _ => Some(peer),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_peers_are_neutral() {
+        let scores = PeerScores::new(ScoreWeights::default());
+        let peers = vec![PeerId::random(), PeerId::random()];
+        assert!((scores.score_peer(&peers[0]) - 0.5).abs() < 1e-9);
+
+        let mut ranked = peers.clone();
+        scores.rank(&mut ranked);
+        assert_eq!(ranked, peers);
+        assert_eq!(scores.best(&peers), Some(&peers[0]));
+        assert_eq!(scores.best(&[]), None);
+    }
+
+    #[test]
+    fn test_ranking_by_each_input() {
+        let mut scores = PeerScores::new(ScoreWeights::default());
+        let (a, b) = (PeerId::random(), PeerId::random());
+
+        // A peer that fails requests falls behind
+        scores.record_failure(&a);
+        let mut ranked = vec![a.clone(), b.clone()];
+        scores.rank(&mut ranked);
+        assert_eq!(ranked, vec![b.clone(), a.clone()]);
+
+        // Until it is much faster
+        scores.record_latency(&a, Duration::from_millis(5));
+        scores.record_latency(&b, Duration::from_secs(2));
+        scores.rank(&mut ranked);
+        assert_eq!(ranked, vec![a.clone(), b.clone()]);
+
+        // Or has a lot more space
+        let mut scores = PeerScores::new(ScoreWeights::default());
+        scores.record_failure(&a);
+        scores.record_capacity(&a, 100 * REFERENCE_CAPACITY);
+        scores.record_capacity(&b, 0);
+        assert_eq!(scores.best(&[b.clone(), a.clone()]), Some(&a));
+
+        // The more requests a peer handles, the more it is trusted
+        let before = scores.score_peer(&b);
+        scores.record_success(&b);
+        assert!(scores.score_peer(&b) > before);
+    }
+
+    #[test]
+    fn test_weights() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let weights = ScoreWeights {
+            trust: 0.0,
+            latency: 1.0,
+            capacity: 0.0,
+        };
+        let mut scores = PeerScores::new(weights);
+
+        // Inputs with no weight don't matter
+        scores.record_failure(&a);
+        scores.record_capacity(&a, 0);
+        assert!((scores.score_peer(&a) - scores.score_peer(&b)).abs() < 1e-9);
+
+        scores.record_latency(&a, Duration::from_millis(10));
+        scores.record_latency(&b, Duration::from_millis(500));
+        assert!(scores.score_peer(&a) > scores.score_peer(&b));
+
+        // A slow round trip only moves the average part of the way
+        scores.record_latency(&a, Duration::from_secs(1));
+        assert!(scores.score_peer(&a) > scores.score_peer(&b));
+
+        let none = PeerScores::new(ScoreWeights {
+            trust: 0.0,
+            latency: 0.0,
+            capacity: 0.0,
+        });
+        assert!((none.score_peer(&a) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_holder_selection() {
+        let mut scores = PeerScores::new(ScoreWeights::default());
+        let (fast, trusted) = (PeerId::random(), PeerId::random());
+        scores.record_latency(&fast, Duration::from_millis(5));
+        scores.record_failure(&fast);
+        scores.record_latency(&trusted, Duration::from_secs(2));
+        scores.record_success(&trusted);
+
+        let mut holders = vec![trusted.clone(), fast.clone()];
+        scores.order(&mut holders, HolderSelection::Latency);
+        assert_eq!(holders, vec![fast.clone(), trusted.clone()]);
+        scores.order(&mut holders, HolderSelection::Trust);
+        assert_eq!(holders, vec![trusted.clone(), fast.clone()]);
+
+        // A random order still holds every holder
+        scores.order(&mut holders, HolderSelection::Random);
+        assert_eq!(holders.len(), 2);
+        assert!(holders.contains(&fast) && holders.contains(&trusted));
+    }
+}
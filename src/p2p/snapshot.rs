@@ -0,0 +1,49 @@
+use super::node::OperationConfig;
+use super::store::StoreDump;
+use crate::primitives::file;
+use serde::{Deserialize, Serialize};
+
+/// The version of the snapshot format written by `Node::snapshot`.
+pub(crate) const SNAPSHOT_VERSION: u8 = 2;
+
+/// A point-in-time copy of everything a node keeps on the disk, written to a
+/// single archive by `Node::snapshot` and read back by `Node::restore`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub version: u8,
+
+    /// The node's encoded ed25519 keypair, encrypted with the passphrase of
+    /// the snapshot the way an encrypted identity keeps it
+    pub keypair: Vec<u8>,
+
+    /// Every store of the node (the primary one first), with their contents:
+    /// the shards, the metadata of the node's files, and their bookkeeping
+    pub stores: Vec<(String, StoreDump)>,
+
+    /// The operations that were waiting to run
+    pub pending_ops: Vec<SavedOperation>,
+}
+
+/// A pending operation that can be saved in a snapshot. Operations that
+/// report back to a caller through a channel (like metadata lookups) can't
+/// outlive the process, so they aren't saved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum SavedOperation {
+    PutFile {
+        file_metadata: file::File,
+        file_bytes: Vec<u8>,
+        config: OperationConfig,
+    },
+    GetFile {
+        file_id: file::FileID,
+        config: OperationConfig,
+    },
+    AnnounceShards,
+    EnsureReplication {
+        file_id: file::FileID,
+        target: usize,
+    },
+    PublishMetadata {
+        file: file::File,
+    },
+}
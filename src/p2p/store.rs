@@ -1,17 +1,42 @@
 use crate::{
     common::DATADIR,
+    database::TypedTree,
     primitives::{file, shard},
     CanSerialize, GeneralError,
 };
-use sled;
-use std::error::Error;
+use sled::{
+    self,
+    transaction::{ConflictableTransactionError, Transactional},
+};
+use std::{
+    error::Error,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The key of a single shard of a file in the trees that track shards.
+fn shard_key(file_id: &file::FileID, index: u32) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok([&file_id.to_bytes()?[..], &index.to_be_bytes()[..]].concat())
+}
+
+/// The current time, in seconds since the unix epoch.
+fn now() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Every tree of a store (by name) and its entries, as read by
+/// `ShardStore::dump`.
+pub(crate) type StoreDump = Vec<(Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>)>;
 
 /// A node's local storage of shards. This is essentially just a
 /// map from FileID to Vec<Shard>
+#[derive(Clone)]
 pub struct ShardStore(sled::Db);
 
 impl ShardStore {
-    /// Load the database at `name` if it exists, create it if it doesn't
+    /// Load the database at `name` if it exists, create it if it doesn't. The
+    /// database is locked while it is open, so the same store can't be opened
+    /// again (by this or any other process) until it is closed.
     pub fn new(name: &str) -> Result<Self, Box<dyn Error>> {
         Ok(Self(sled::open(format!(
             "{}/{}/{}/{}",
@@ -19,8 +44,15 @@ impl ShardStore {
         ))?))
     }
 
+    /// Load the database at an arbitrary path (like a mount point of a
+    /// particular disk) if it exists, create it if it doesn't. Like with `new`,
+    /// the store can't be opened again until it is closed.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Self(sled::open(path)?))
+    }
+
     /// Store an entire vec of shards.
-    fn put(
+    pub(crate) fn put(
         &mut self,
         file_id: &file::FileID,
         shards: &Vec<shard::Shard>,
@@ -32,42 +64,509 @@ impl ShardStore {
             .map_err(|e| e.into())
     }
 
-    /// Get all the shards attached to a file id
-    fn get(
+    /// Get all the shards attached to a file id, or `None` if the store holds
+    /// no shards of the file. Errors are reserved for failures of the database
+    /// and for stored shards that can't be decoded.
+    pub fn get(
         &self,
         file_id: &file::FileID,
     ) -> Result<Option<Vec<shard::Shard>>, Box<dyn Error>> {
-        let shards_bytes = self.0.get(file_id.to_bytes()?)?;
-        match shards_bytes {
-            Some(bytes) => {
-                Ok(Some(bincode::deserialize::<Vec<shard::Shard>>(&bytes)?))
-            }
-            None => Err(Box::new(GeneralError::new(
-                format!("no shards in shardstore for {:?}", file_id).as_str(),
+        let bytes = match self.0.get(file_id.to_bytes()?)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        match bincode::deserialize::<Vec<shard::Shard>>(&bytes) {
+            Ok(shards) => Ok(Some(shards)),
+            Err(e) => Err(Box::new(GeneralError::new(
+                format!(
+                    "corrupted shards of {} ({} bytes): {:?}",
+                    file_id.to_hex(),
+                    bytes.len(),
+                    e
+                )
+                .as_str(),
             ))),
         }
     }
+
+    /// Store a single shard of a file, replacing any stored shard of that file
+    /// with the same index.
+    pub fn put_shard(
+        &mut self,
+        file_id: &file::FileID,
+        shard: &shard::Shard,
+    ) -> Result<(), Box<dyn Error>> {
+        self.put_shards(file_id, &[shard.clone()])
+    }
+
+    /// Store several shards of a file at once, replacing any stored shards of
+    /// that file with the same indices. Either every shard is stored (and
+    /// marked as verified) or none are, even if the node crashes midway.
+    pub fn put_shards(
+        &mut self,
+        file_id: &file::FileID,
+        shards: &[shard::Shard],
+    ) -> Result<(), Box<dyn Error>> {
+        self.put_shards_atomically(file_id, shards, || Ok(()))
+    }
+
+    /// Store several shards of a file in a single transaction. `before_commit`
+    /// runs after every write is staged, and aborts the whole transaction if
+    /// it fails.
+    fn put_shards_atomically<F>(
+        &mut self,
+        file_id: &file::FileID,
+        shards: &[shard::Shard],
+        before_commit: F,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn() -> Result<(), GeneralError>,
+    {
+        let key = file_id.to_bytes()?;
+        let time = now()?;
+
+        let mut verified = sled::Batch::default();
+        for shard in shards {
+            verified
+                .insert(shard_key(file_id, shard.index())?, &time.to_be_bytes()[..]);
+        }
+
+        let abort = |e: bincode::Error| {
+            ConflictableTransactionError::Abort(GeneralError::new(
+                format!("corrupted shards: {:?}", e).as_str(),
+            ))
+        };
+        (&*self.0, &self.0.open_tree("verified")?).transaction(
+            |(main, verified_tree)| {
+                let mut stored = match main.get(&key)? {
+                    Some(bytes) => bincode::deserialize::<Vec<shard::Shard>>(&bytes)
+                        .map_err(abort)?,
+                    None => Vec::new(),
+                };
+                stored.retain(|s| shards.iter().all(|n| n.index() != s.index()));
+                stored.extend(shards.iter().cloned());
+                stored.sort_by_key(|s| s.index());
+
+                main.insert(&key[..], bincode::serialize(&stored).map_err(abort)?)?;
+                verified_tree.apply_batch(&verified)?;
+
+                before_commit().map_err(ConflictableTransactionError::Abort)?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Record that the shard of a file at an index was found to be valid at
+    /// `time` (in seconds since the unix epoch).
+    pub fn mark_verified(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+        time: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0
+            .open_tree("verified")?
+            .insert(shard_key(file_id, index)?, &time.to_be_bytes()[..])?;
+        Ok(())
+    }
+
+    /// Get the `n` stored shards that were verified the longest time ago (or
+    /// never), oldest first.
+    pub fn least_recently_verified(
+        &self,
+        n: usize,
+    ) -> Result<Vec<(file::FileID, u32)>, Box<dyn Error>> {
+        Ok(self
+            .verification_times()?
+            .into_iter()
+            .take(n)
+            .map(|(_, file_id, index)| (file_id, index))
+            .collect())
+    }
+
+    /// Get every stored shard with the time it was last verified at (0 if it
+    /// never was), oldest first.
+    pub(crate) fn verification_times(
+        &self,
+    ) -> Result<Vec<(u64, file::FileID, u32)>, Box<dyn Error>> {
+        let verified = self.0.open_tree("verified")?;
+
+        let mut shards = Vec::new();
+        for entry in self.0.iter() {
+            let (key, value) = entry?;
+            let file_id = file::FileID::try_from_slice(&key)?;
+            for shard in bincode::deserialize::<Vec<shard::Shard>>(&value)? {
+                let time = match verified.get(shard_key(&file_id, shard.index())?)? {
+                    Some(t) if t.len() == 8 => {
+                        let mut time = [0u8; 8];
+                        time.copy_from_slice(&t);
+                        u64::from_be_bytes(time)
+                    }
+                    _ => 0,
+                };
+                shards.push((time, file_id.clone(), shard.index()));
+            }
+        }
+
+        shards.sort_by_key(|(time, _, _)| *time);
+        Ok(shards)
+    }
+
+    /// Get the ids of every file that has stored shards.
+    pub fn file_ids(&self) -> Result<Vec<file::FileID>, Box<dyn Error>> {
+        let mut ids = Vec::new();
+        for key in self.0.iter().keys() {
+            ids.push(file::FileID::try_from_slice(&key?)?);
+        }
+        Ok(ids)
+    }
+
+    /// Get the ids of every stored shard.
+    pub fn shard_ids(&self) -> Result<Vec<shard::ShardID>, Box<dyn Error>> {
+        let mut ids = Vec::new();
+        for entry in self.0.iter() {
+            let (_, value) = entry?;
+            for shard in bincode::deserialize::<Vec<shard::Shard>>(&value)? {
+                ids.push(shard.id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Flag the shard of a file at an index as needing a repair.
+    pub fn flag_for_repair(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0
+            .open_tree("repair")?
+            .insert(shard_key(file_id, index)?, Vec::<u8>::new())?;
+        Ok(())
+    }
+
+    /// Get the shards that are flagged as needing a repair.
+    pub fn flagged_for_repair(
+        &self,
+    ) -> Result<Vec<(file::FileID, u32)>, Box<dyn Error>> {
+        let mut flagged = Vec::new();
+        for entry in self.0.open_tree("repair")?.iter() {
+            let (key, _) = entry?;
+            if key.len() < 4 {
+                continue;
+            }
+            let (file_id, index) = key.split_at(key.len() - 4);
+            let mut index_bytes = [0u8; 4];
+            index_bytes.copy_from_slice(index);
+            flagged.push((
+                file::FileID::try_from_slice(file_id)?,
+                u32::from_be_bytes(index_bytes),
+            ));
+        }
+        Ok(flagged)
+    }
+
+    /// Check whether the shard of a file at an index is flagged as needing a
+    /// repair.
+    pub fn needs_repair(
+        &self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(self
+            .0
+            .open_tree("repair")?
+            .contains_key(shard_key(file_id, index)?)?)
+    }
+
+    /// Clear the repair flag of the shard of a file at an index, once it is
+    /// held again.
+    pub fn clear_repair(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        self.0
+            .open_tree("repair")?
+            .remove(shard_key(file_id, index)?)?;
+        Ok(())
+    }
+
+    /// Remove the shard of a file at an index, if this store holds it.
+    pub fn remove_shard(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(mut shards) = self.get(file_id)? {
+            shards.retain(|s| s.index() != index);
+            if shards.is_empty() {
+                self.0.remove(file_id.to_bytes()?)?;
+            } else {
+                self.put(file_id, &shards)?;
+            }
+        }
+        self.0
+            .open_tree("verified")?
+            .remove(shard_key(file_id, index)?)?;
+        Ok(())
+    }
+
+    /// Store the metadata of a file.
+    pub fn put_metadata(&mut self, file: &file::File) -> Result<(), Box<dyn Error>> {
+        self.0
+            .open_tree("metadata")?
+            .insert(file.id.to_bytes()?, file.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Get the metadata of a file, if this store holds it.
+    pub fn get_metadata(
+        &self,
+        file_id: &file::FileID,
+    ) -> Result<Option<file::File>, Box<dyn Error>> {
+        match self.0.open_tree("metadata")?.get(file_id.to_bytes()?)? {
+            Some(bytes) => Ok(Some(file::File::from_bytes(bytes.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove every shard of a file held by the store, along with its
+    /// metadata.
+    pub fn remove_file(
+        &mut self,
+        file_id: &file::FileID,
+    ) -> Result<(), Box<dyn Error>> {
+        for shard in self.get(file_id)?.unwrap_or_default() {
+            self.remove_shard(file_id, shard.index())?;
+        }
+        self.0.open_tree("metadata")?.remove(file_id.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Record that the node owns a file (it put the file on the network),
+    /// keeping a copy of the file's metadata.
+    pub fn add_owned(&mut self, file: &file::File) -> Result<(), Box<dyn Error>> {
+        self.owned()?.insert(&file.id, file)?;
+        Ok(())
+    }
+
+    /// Forget that the node owns a file. Returns `false` if it didn't.
+    pub fn remove_owned(
+        &mut self,
+        file_id: &file::FileID,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(self.owned()?.remove(file_id)?.is_some())
+    }
+
+    /// Check whether the node owns a file.
+    pub fn is_owned(&self, file_id: &file::FileID) -> Result<bool, Box<dyn Error>> {
+        self.owned()?.contains_key(file_id)
+    }
+
+    /// Get the metadata of a file that the node owns, as it was last put.
+    pub fn owned_file(
+        &self,
+        file_id: &file::FileID,
+    ) -> Result<Option<file::File>, Box<dyn Error>> {
+        self.owned()?.get(file_id)
+    }
+
+    /// Get the metadata of every file the node owns.
+    pub fn owned_files(&self) -> Result<Vec<file::File>, Box<dyn Error>> {
+        let mut files = Vec::new();
+        for entry in self.owned()?.iter() {
+            files.push(entry?.1);
+        }
+        Ok(files)
+    }
+
+    /// The index of the files the node owns, by id.
+    fn owned(&self) -> Result<TypedTree<file::FileID, file::File>, Box<dyn Error>> {
+        TypedTree::open(&self.0, "owned")
+    }
+
+    /// The number of bytes of shards held by the store.
+    pub fn usage(&self) -> Result<u64, Box<dyn Error>> {
+        let mut total = 0;
+        for entry in self.0.iter() {
+            total += entry?.1.len() as u64;
+        }
+        Ok(total)
+    }
+
+    /// The number of bytes of shards the store may hold, if it is limited.
+    pub fn quota(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.0.open_tree("settings")?.get("quota")? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Limit (or stop limiting, with `None`) the number of bytes of shards the
+    /// store may hold. The limit is enforced by `evict_to_quota`.
+    pub fn set_quota(&mut self, quota: Option<u64>) -> Result<(), Box<dyn Error>> {
+        let settings = self.0.open_tree("settings")?;
+        match quota {
+            Some(q) => settings.insert("quota", bincode::serialize(&q)?)?,
+            None => settings.remove("quota")?,
+        };
+        Ok(())
+    }
+
+    /// Exempt the shards of a file from eviction.
+    pub fn pin(&mut self, file_id: &file::FileID) -> Result<(), Box<dyn Error>> {
+        self.0
+            .open_tree("pins")?
+            .insert(file_id.to_bytes()?, Vec::<u8>::new())?;
+        Ok(())
+    }
+
+    /// Allow the shards of a file to be evicted again.
+    pub fn unpin(&mut self, file_id: &file::FileID) -> Result<(), Box<dyn Error>> {
+        self.0.open_tree("pins")?.remove(file_id.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Check whether the shards of a file are exempt from eviction.
+    pub fn is_pinned(&self, file_id: &file::FileID) -> Result<bool, Box<dyn Error>> {
+        Ok(self
+            .0
+            .open_tree("pins")?
+            .contains_key(file_id.to_bytes()?)?)
+    }
+
+    /// Evict the shards of unpinned files until the store fits in its quota,
+    /// and return the ids of the evicted files. Nothing is evicted if the
+    /// pinned shards alone don't fit in the quota.
+    pub fn evict_to_quota(&mut self) -> Result<Vec<file::FileID>, Box<dyn Error>> {
+        let quota = match self.quota()? {
+            Some(q) => q,
+            None => return Ok(Vec::new()),
+        };
+
+        // Split the stored files into the pinned and the evictable
+        let pins = self.0.open_tree("pins")?;
+        let mut pinned_bytes = 0;
+        let mut evictable = Vec::new();
+        for entry in self.0.iter() {
+            let (key, value) = entry?;
+            if pins.contains_key(&key)? {
+                pinned_bytes += value.len() as u64;
+            } else {
+                evictable.push((key, value.len() as u64));
+            }
+        }
+
+        if pinned_bytes > quota {
+            return Err(Box::new(GeneralError::new(
+                format!(
+                    "cannot fit {} pinned bytes into a quota of {} bytes",
+                    pinned_bytes, quota
+                )
+                .as_str(),
+            )));
+        }
+
+        let mut usage =
+            pinned_bytes + evictable.iter().map(|(_, len)| len).sum::<u64>();
+        let mut evicted = Vec::new();
+        for (key, len) in evictable {
+            if usage <= quota {
+                break;
+            }
+            self.0.remove(&key)?;
+            usage -= len;
+            evicted.push(file::FileID::try_from_slice(&key)?);
+        }
+        Ok(evicted)
+    }
+
+    /// Open a tree of the database by name, including the default tree.
+    fn tree(&self, name: &[u8]) -> Result<sled::Tree, Box<dyn Error>> {
+        if name == &self.0.name()[..] {
+            return Ok((*self.0).clone());
+        }
+        Ok(self.0.open_tree(name)?)
+    }
+
+    /// Flush the store to the disk, then read every entry of every tree in it
+    /// (shards, metadata, verification times, ...).
+    pub(crate) fn dump(&self) -> Result<StoreDump, Box<dyn Error>> {
+        self.flush()?;
+        let mut trees = Vec::new();
+        for name in self.0.tree_names() {
+            let mut entries = Vec::new();
+            for entry in self.tree(&name)?.iter() {
+                let (key, value) = entry?;
+                entries.push((key.to_vec(), value.to_vec()));
+            }
+            trees.push((name.to_vec(), entries));
+        }
+        Ok(trees)
+    }
+
+    /// Write the entries read by `dump` into this store, and flush it.
+    pub(crate) fn load(&mut self, dump: &StoreDump) -> Result<(), Box<dyn Error>> {
+        for (name, entries) in dump {
+            let mut batch = sled::Batch::default();
+            for (key, value) in entries {
+                batch.insert(&key[..], &value[..]);
+            }
+            self.tree(name)?.apply_batch(batch)?;
+        }
+        self.flush()
+    }
+
+    /// Check that the store can still be written to the disk, by writing a
+    /// probe to it and removing it again.
+    pub fn is_writable(&self) -> bool {
+        let probe = || -> Result<(), Box<dyn Error>> {
+            let settings = self.0.open_tree("settings")?;
+            settings.insert("health_probe", Vec::<u8>::new())?;
+            settings.remove("health_probe")?;
+            self.flush()
+        };
+        probe().is_ok()
+    }
+
+    /// Write any buffered changes to the disk, blocking until they are durable.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    /// Flush the store and close it, releasing the lock on its database so
+    /// that it can be opened again. The lock is shared by every clone of the
+    /// store, so it is only released once the last clone is closed (or
+    /// dropped).
+    pub fn close(self) -> Result<(), Box<dyn Error>> {
+        self.flush()?;
+        drop(self.0);
+        Ok(())
+    }
+
+    /// Get the shard of a file at an index, if this store holds it.
+    pub fn get_shard(
+        &self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<Option<shard::Shard>, Box<dyn Error>> {
+        Ok(self
+            .get(file_id)?
+            .and_then(|shards| shards.into_iter().find(|s| s.index() == index)))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::primitives::file::File;
-    use crate::primitives::shard::ShardConfig;
-    use ecies_ed25519::*;
-    use std::path::Path;
-
-    fn keypair() -> (SecretKey, PublicKey) {
-        let mut csprng = rand::thread_rng();
-        generate_keypair(&mut csprng)
-    }
+    use crate::test_util::{fresh_store, sample_file, SAMPLE};
 
     #[test]
     fn test_put_get() {
-        let (sk, pk) = keypair();
-        let (file, shards) =
-            &File::new(Path::new("./testfile.txt"), ShardConfig::new(5, &pk), &sk)
-                .unwrap();
+        let (file, shards) = &sample_file(SAMPLE, 5);
 
         let mut store = ShardStore::new("test_db").unwrap();
         store.put(&file.id, &shards).unwrap();
@@ -77,4 +576,143 @@ mod tests {
             None => panic!(),
         }
     }
+
+    #[test]
+    fn test_get_missing_and_corrupted() {
+        let (mut store, _dir) = fresh_store();
+        let (file, shards) = sample_file(SAMPLE, 2);
+
+        // A file that was never stored is a miss, not an error
+        assert!(store.get(&file.id).unwrap().is_none());
+        assert!(store.get_shard(&file.id, 0).unwrap().is_none());
+
+        // But shards that can't be decoded are, naming the file and the size
+        store.put(&file.id, &shards).unwrap();
+        store
+            .0
+            .insert(file.id.to_bytes().unwrap(), vec![0xFF; 5])
+            .unwrap();
+        let e = store.get(&file.id).unwrap_err().to_string();
+        assert!(e.contains(&file.id.to_hex()), "{}", e);
+        assert!(e.contains("5 bytes"), "{}", e);
+        assert!(store.get_shard(&file.id, 0).is_err());
+    }
+
+    #[test]
+    fn test_put_get_shard() {
+        let (file, shards) = &sample_file(SAMPLE, 3);
+
+        let (mut store, _dir) = fresh_store();
+        store.put_shard(&file.id, &shards[2]).unwrap();
+        store.put_shard(&file.id, &shards[0]).unwrap();
+        store.put_shard(&file.id, &shards[0]).unwrap();
+
+        assert_eq!(
+            store.get_shard(&file.id, 0).unwrap(),
+            Some(shards[0].clone())
+        );
+        assert_eq!(store.get_shard(&file.id, 1).unwrap(), None);
+        assert_eq!(
+            store.get_shard(&file.id, 2).unwrap(),
+            Some(shards[2].clone())
+        );
+        assert_eq!(
+            store.get(&file.id).unwrap(),
+            Some(vec![shards[0].clone(), shards[2].clone()])
+        );
+    }
+
+    #[test]
+    fn test_put_shards_all_or_nothing() {
+        let (mut store, _dir) = fresh_store();
+        let (file, shards) = sample_file(SAMPLE, 4);
+
+        // A failure after the writes are staged leaves nothing behind
+        let crash = || Err(GeneralError::new("simulated crash"));
+        assert!(store
+            .put_shards_atomically(&file.id, &shards, crash)
+            .is_err());
+        assert!(store.get(&file.id).unwrap().is_none());
+        assert!(store.0.open_tree("verified").unwrap().is_empty());
+
+        // And without the failure, everything is written
+        store.put_shards(&file.id, &shards).unwrap();
+        assert_eq!(store.get(&file.id).unwrap().unwrap(), shards);
+        assert_eq!(store.least_recently_verified(10).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_flush_persists() {
+        let (file, shards) = &sample_file(SAMPLE, 3);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store");
+
+        {
+            let mut store = ShardStore::open(&path).unwrap();
+            store.put_shard(&file.id, &shards[1]).unwrap();
+            store.flush().unwrap();
+        }
+
+        let store = ShardStore::open(&path).unwrap();
+        assert_eq!(
+            store.get_shard(&file.id, 1).unwrap(),
+            Some(shards[1].clone())
+        );
+    }
+
+    #[test]
+    fn test_close_reopen() {
+        let (file, shards) = &sample_file(SAMPLE, 3);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store");
+
+        let mut store = ShardStore::open(&path).unwrap();
+        store.put_shard(&file.id, &shards[0]).unwrap();
+
+        // The store is locked until it is closed
+        assert!(ShardStore::open(&path).is_err());
+        store.close().unwrap();
+
+        let store = ShardStore::open(&path).unwrap();
+        assert_eq!(
+            store.get_shard(&file.id, 0).unwrap(),
+            Some(shards[0].clone())
+        );
+        store.close().unwrap();
+    }
+
+    #[test]
+    fn test_pinned_survive_eviction() {
+        let (mut store, _dir) = fresh_store();
+        let (pinned, pinned_shards) = &sample_file(SAMPLE, 3);
+        let (loose, loose_shards) = &sample_file(b"some unpinned data", 3);
+
+        store.put(&pinned.id, pinned_shards).unwrap();
+        store.put(&loose.id, loose_shards).unwrap();
+        store.pin(&pinned.id).unwrap();
+        assert!(store.is_pinned(&pinned.id).unwrap());
+        assert!(!store.is_pinned(&loose.id).unwrap());
+
+        // Without a quota, nothing is evicted
+        assert!(store.evict_to_quota().unwrap().is_empty());
+
+        // Only room for the pinned file
+        let pinned_bytes = bincode::serialize(pinned_shards).unwrap().len() as u64;
+        store.set_quota(Some(pinned_bytes)).unwrap();
+        assert_eq!(store.evict_to_quota().unwrap(), vec![loose.id.clone()]);
+        assert_eq!(store.usage().unwrap(), pinned_bytes);
+        assert_eq!(store.get(&pinned.id).unwrap(), Some(pinned_shards.clone()));
+        assert!(store.get(&loose.id).unwrap().is_none());
+
+        // Not even enough room for the pinned file
+        store.set_quota(Some(pinned_bytes - 1)).unwrap();
+        assert!(store.evict_to_quota().is_err());
+        assert_eq!(store.get(&pinned.id).unwrap(), Some(pinned_shards.clone()));
+
+        // Unpinned files can be evicted
+        store.unpin(&pinned.id).unwrap();
+        assert_eq!(store.evict_to_quota().unwrap(), vec![pinned.id.clone()]);
+        assert_eq!(store.usage().unwrap(), 0);
+    }
 }
@@ -0,0 +1,443 @@
+use super::store::ShardStore;
+use crate::primitives::{file, shard};
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+/// Chooses the name of the store a shard of a file should be kept in. A name
+/// that isn't registered routes the shard to the primary store.
+pub type PlacementPolicy =
+    Arc<dyn Fn(&file::FileID, &shard::Shard) -> String + Send + Sync>;
+
+/// Several named shard stores (like a fast and a slow disk) used as one. Every
+/// shard is written to the store chosen by the placement policy, and reads
+/// look through every store. The first store registered is the primary one:
+/// it holds the metadata of files, the shards the policy doesn't place, and
+/// the flags of shards that need a repair.
+#[derive(Clone)]
+pub struct StoreManager {
+    /// The stores and their names, the primary one first
+    stores: Vec<(String, ShardStore)>,
+
+    /// Where new shards go (`None` to keep everything in the primary store)
+    policy: Option<PlacementPolicy>,
+}
+
+impl StoreManager {
+    /// Manage a single primary store.
+    pub fn new(name: &str, primary: ShardStore) -> Self {
+        Self {
+            stores: vec![(name.to_string(), primary)],
+            policy: None,
+        }
+    }
+
+    /// Register another store under a name, replacing any store registered
+    /// under that name before.
+    pub fn add_store(&mut self, name: &str, store: ShardStore) {
+        match self.stores.iter_mut().find(|(n, _)| n == name) {
+            Some(entry) => entry.1 = store,
+            None => self.stores.push((name.to_string(), store)),
+        }
+    }
+
+    /// The names of the registered stores, the primary one first.
+    pub fn names(&self) -> Vec<String> {
+        self.stores.iter().map(|(n, _)| n.clone()).collect()
+    }
+
+    /// Get a registered store by its name.
+    pub fn store(&self, name: &str) -> Option<&ShardStore> {
+        self.stores.iter().find(|(n, _)| n == name).map(|(_, s)| s)
+    }
+
+    /// Set the policy that chooses where new shards are stored.
+    pub fn set_policy(&mut self, policy: PlacementPolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// The position of the store that a shard should be kept in.
+    fn route(&self, file_id: &file::FileID, shard: &shard::Shard) -> usize {
+        let name = match &self.policy {
+            Some(policy) => policy(file_id, shard),
+            None => return 0,
+        };
+        match self.stores.iter().position(|(n, _)| *n == name) {
+            Some(i) => i,
+            None => {
+                eprintln!("no store named {}, using the primary store", name);
+                0
+            }
+        }
+    }
+
+    /// The primary store.
+    pub(crate) fn primary_mut(&mut self) -> &mut ShardStore {
+        &mut self.stores[0].1
+    }
+
+    /// Store a single shard of a file in the store the policy chooses.
+    pub fn put_shard(
+        &mut self,
+        file_id: &file::FileID,
+        shard: &shard::Shard,
+    ) -> Result<(), Box<dyn Error>> {
+        self.put_shards(file_id, &[shard.clone()])
+    }
+
+    /// Store several shards of a file, each in the store the policy chooses.
+    /// Any copy of one of the shards in another store is removed, so a shard
+    /// that the policy moves isn't kept twice. The shards going to the same
+    /// store are stored all or nothing, but not across stores.
+    pub fn put_shards(
+        &mut self,
+        file_id: &file::FileID,
+        shards: &[shard::Shard],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut routed: HashMap<usize, Vec<shard::Shard>> = HashMap::new();
+        for shard in shards {
+            routed
+                .entry(self.route(file_id, shard))
+                .or_insert_with(Vec::new)
+                .push(shard.clone());
+        }
+
+        for (target, shards) in routed {
+            self.stores[target].1.put_shards(file_id, &shards)?;
+            for (i, (_, store)) in self.stores.iter_mut().enumerate() {
+                if i == target {
+                    continue;
+                }
+                for shard in shards.iter() {
+                    if store.get_shard(file_id, shard.index())?.is_some() {
+                        store.remove_shard(file_id, shard.index())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace every stored shard of a file with new shards.
+    pub(crate) fn put(
+        &mut self,
+        file_id: &file::FileID,
+        shards: &Vec<shard::Shard>,
+    ) -> Result<(), Box<dyn Error>> {
+        for (_, store) in self.stores.iter_mut() {
+            if let Some(old) = store.get(file_id)? {
+                for shard in old {
+                    store.remove_shard(file_id, shard.index())?;
+                }
+            }
+        }
+        self.put_shards(file_id, shards)
+    }
+
+    /// Get all the shards of a file, from every store, in order, or `None` if
+    /// no store holds any.
+    pub(crate) fn get(
+        &self,
+        file_id: &file::FileID,
+    ) -> Result<Option<Vec<shard::Shard>>, Box<dyn Error>> {
+        let mut shards: Vec<shard::Shard> = Vec::new();
+        for (_, store) in self.stores.iter() {
+            if let Some(held) = store.get(file_id)? {
+                shards.extend(held);
+            }
+        }
+        if shards.is_empty() {
+            return Ok(None);
+        }
+        shards.sort_by_key(|s| s.index());
+        Ok(Some(shards))
+    }
+
+    /// Get the shard of a file at an index, if any store holds it.
+    pub fn get_shard(
+        &self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<Option<shard::Shard>, Box<dyn Error>> {
+        for (_, store) in self.stores.iter() {
+            if let Some(shard) = store.get_shard(file_id, index)? {
+                return Ok(Some(shard));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remove the shard of a file at an index from whichever stores hold it.
+    pub fn remove_shard(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        for (_, store) in self.stores.iter_mut() {
+            store.remove_shard(file_id, index)?;
+        }
+        Ok(())
+    }
+
+    /// Record that the shard of a file at an index was found to be valid at
+    /// `time`, in the stores that hold it.
+    pub fn mark_verified(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+        time: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        for (_, store) in self.stores.iter_mut() {
+            if store.get_shard(file_id, index)?.is_some() {
+                store.mark_verified(file_id, index, time)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the `n` stored shards, across every store, that were verified the
+    /// longest time ago (or never), oldest first.
+    pub fn least_recently_verified(
+        &self,
+        n: usize,
+    ) -> Result<Vec<(file::FileID, u32)>, Box<dyn Error>> {
+        let mut shards = Vec::new();
+        for (_, store) in self.stores.iter() {
+            shards.extend(store.verification_times()?);
+        }
+        shards.sort_by_key(|(time, _, _)| *time);
+        Ok(shards
+            .into_iter()
+            .take(n)
+            .map(|(_, file_id, index)| (file_id, index))
+            .collect())
+    }
+
+    /// Get the ids of every file that has shards in any store.
+    pub fn file_ids(&self) -> Result<Vec<file::FileID>, Box<dyn Error>> {
+        let mut ids: Vec<file::FileID> = Vec::new();
+        for (_, store) in self.stores.iter() {
+            for id in store.file_ids()? {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Get the ids of every shard in every store.
+    pub fn shard_ids(&self) -> Result<Vec<shard::ShardID>, Box<dyn Error>> {
+        let mut ids = Vec::new();
+        for (_, store) in self.stores.iter() {
+            ids.extend(store.shard_ids()?);
+        }
+        Ok(ids)
+    }
+
+    /// Flag the shard of a file at an index as needing a repair.
+    pub fn flag_for_repair(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        self.primary_mut().flag_for_repair(file_id, index)
+    }
+
+    /// Get the shards that are flagged as needing a repair.
+    pub fn flagged_for_repair(
+        &self,
+    ) -> Result<Vec<(file::FileID, u32)>, Box<dyn Error>> {
+        self.stores[0].1.flagged_for_repair()
+    }
+
+    /// Check whether the shard of a file at an index is flagged as needing a
+    /// repair.
+    pub fn needs_repair(
+        &self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.stores[0].1.needs_repair(file_id, index)
+    }
+
+    /// Clear the repair flag of the shard of a file at an index.
+    pub fn clear_repair(
+        &mut self,
+        file_id: &file::FileID,
+        index: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        self.primary_mut().clear_repair(file_id, index)
+    }
+
+    /// Store the metadata of a file in the primary store.
+    pub fn put_metadata(&mut self, file: &file::File) -> Result<(), Box<dyn Error>> {
+        self.primary_mut().put_metadata(file)
+    }
+
+    /// Get the metadata of a file, if the primary store holds it.
+    pub fn get_metadata(
+        &self,
+        file_id: &file::FileID,
+    ) -> Result<Option<file::File>, Box<dyn Error>> {
+        self.stores[0].1.get_metadata(file_id)
+    }
+
+    /// Remove every shard of a file from whichever stores hold them, along with
+    /// its metadata.
+    pub fn remove_file(
+        &mut self,
+        file_id: &file::FileID,
+    ) -> Result<(), Box<dyn Error>> {
+        for (_, store) in self.stores.iter_mut() {
+            store.remove_file(file_id)?;
+        }
+        Ok(())
+    }
+
+    /// Record in the primary store that the node owns a file.
+    pub fn add_owned(&mut self, file: &file::File) -> Result<(), Box<dyn Error>> {
+        self.primary_mut().add_owned(file)
+    }
+
+    /// Forget that the node owns a file. Returns `false` if it didn't.
+    pub fn remove_owned(
+        &mut self,
+        file_id: &file::FileID,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.primary_mut().remove_owned(file_id)
+    }
+
+    /// Check whether the node owns a file.
+    pub fn is_owned(&self, file_id: &file::FileID) -> Result<bool, Box<dyn Error>> {
+        self.stores[0].1.is_owned(file_id)
+    }
+
+    /// Get the metadata of a file that the node owns, as it was last put.
+    pub fn owned_file(
+        &self,
+        file_id: &file::FileID,
+    ) -> Result<Option<file::File>, Box<dyn Error>> {
+        self.stores[0].1.owned_file(file_id)
+    }
+
+    /// Get the metadata of every file the node owns.
+    pub fn owned_files(&self) -> Result<Vec<file::File>, Box<dyn Error>> {
+        self.stores[0].1.owned_files()
+    }
+
+    /// The free and total space (in bytes) of the stores. A store without a
+    /// quota counts as unlimited.
+    pub fn capacity(&self) -> Result<(u64, u64), Box<dyn Error>> {
+        let (mut used, mut total) = (0u64, 0u64);
+        for (_, store) in self.stores.iter() {
+            used = used.saturating_add(store.usage()?);
+            total = total.saturating_add(store.quota()?.unwrap_or(u64::MAX));
+        }
+        Ok((total.saturating_sub(used), total))
+    }
+
+    /// Check that every store can still be written to the disk.
+    pub fn is_writable(&self) -> bool {
+        self.stores.iter().all(|(_, store)| store.is_writable())
+    }
+
+    /// Write any buffered changes in every store to the disk.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        for (_, store) in self.stores.iter() {
+            store.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fresh_store, sample_file, SAMPLE};
+
+    #[test]
+    fn test_routes_by_policy() {
+        let (hot, _hot_dir) = fresh_store();
+        let (cold, _cold_dir) = fresh_store();
+        let mut manager = StoreManager::new("hot", hot);
+        manager.add_store("cold", cold);
+        manager.set_policy(Arc::new(|_, shard| {
+            if shard.index() == 0 {
+                "hot".to_string()
+            } else {
+                "cold".to_string()
+            }
+        }));
+
+        let (file, shards) = sample_file(SAMPLE, 3);
+        manager.put_shards(&file.id, &shards).unwrap();
+
+        let hot = manager.store("hot").unwrap();
+        let cold = manager.store("cold").unwrap();
+        assert_eq!(hot.get(&file.id).unwrap().unwrap(), vec![shards[0].clone()]);
+        assert_eq!(cold.get(&file.id).unwrap().unwrap(), shards[1..].to_vec());
+        assert_eq!(manager.get(&file.id).unwrap().unwrap(), shards);
+        assert_eq!(
+            manager.get_shard(&file.id, 2).unwrap(),
+            Some(shards[2].clone())
+        );
+        assert_eq!(manager.least_recently_verified(10).unwrap().len(), 3);
+        assert_eq!(manager.file_ids().unwrap(), vec![file.id.clone()]);
+
+        // A shard the policy moves leaves its old store
+        manager.set_policy(Arc::new(|_, _| "cold".to_string()));
+        manager.put_shard(&file.id, &shards[0]).unwrap();
+        assert!(manager
+            .store("hot")
+            .unwrap()
+            .get(&file.id)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            manager
+                .store("cold")
+                .unwrap()
+                .get(&file.id)
+                .unwrap()
+                .unwrap(),
+            shards
+        );
+
+        // Unknown stores fall back to the primary one
+        let (other, other_shards) = sample_file(b"other data", 1);
+        assert!(manager.get(&other.id).unwrap().is_none());
+        manager.set_policy(Arc::new(|_, _| "missing".to_string()));
+        manager.put_shards(&other.id, &other_shards).unwrap();
+        assert_eq!(
+            manager
+                .store("hot")
+                .unwrap()
+                .get(&other.id)
+                .unwrap()
+                .unwrap(),
+            other_shards
+        );
+    }
+
+    #[test]
+    fn test_capacity() {
+        let (mut hot, _hot_dir) = fresh_store();
+        hot.set_quota(Some(1024 * 1024)).unwrap();
+        let mut manager = StoreManager::new("hot", hot);
+        let (file, shards) = sample_file(SAMPLE, 2);
+        manager.put_shards(&file.id, &shards).unwrap();
+
+        let used = manager.store("hot").unwrap().usage().unwrap();
+        assert!(used > 0);
+        assert_eq!(
+            manager.capacity().unwrap(),
+            (1024 * 1024 - used, 1024 * 1024)
+        );
+
+        // A store without a quota is unlimited
+        let (cold, _cold_dir) = fresh_store();
+        manager.add_store("cold", cold);
+        let (free, total) = manager.capacity().unwrap();
+        assert_eq!(total, u64::MAX);
+        assert_eq!(free, u64::MAX - used);
+    }
+}
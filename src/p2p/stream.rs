@@ -0,0 +1,665 @@
+use crate::{
+    primitives::{
+        file::FileID,
+        shard::{Shard, ShardID},
+    },
+    GeneralError,
+};
+use futures::{
+    channel::mpsc,
+    stream::{Stream, StreamExt},
+};
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// An item yielded by a `ShardStream`: the index of a shard and its data.
+pub type ShardItem = Result<(u32, Vec<u8>), GeneralError>;
+
+/// The result of getting one file of a batch: the file's id, and every shard
+/// of the file (with its index) in the order they arrived.
+pub type BatchItem = (FileID, Result<Vec<(u32, Vec<u8>)>, GeneralError>);
+
+/// The receiving half of a streaming get. It yields every shard of a file
+/// (with its index) as it arrives, and completes once all of them have (or
+/// every holder failed to deliver a shard that the file can do without).
+pub struct ShardStream(mpsc::UnboundedReceiver<ShardItem>);
+
+impl Stream for ShardStream {
+    type Item = ShardItem;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx)
+    }
+}
+
+impl ShardStream {
+    /// Wait for every shard of the file, and collect them (with their indices)
+    /// in the order they arrived. Fails with the first error of the get.
+    pub async fn collect_shards(
+        mut self,
+    ) -> Result<Vec<(u32, Vec<u8>)>, GeneralError> {
+        let mut shards = Vec::new();
+        while let Some(item) = self.next().await {
+            shards.push(item?);
+        }
+        Ok(shards)
+    }
+}
+
+/// The sending half of a streaming get.
+#[derive(Clone)]
+pub struct ShardSink(mpsc::UnboundedSender<ShardItem>);
+
+impl ShardSink {
+    /// Fail the get, sending the reason to the stream and closing it.
+    pub fn fail(self, reason: &str) {
+        let _ = self.0.unbounded_send(Err(GeneralError::new(reason)));
+    }
+}
+
+/// Create a connected sink and stream.
+pub fn shard_stream() -> (ShardSink, ShardStream) {
+    let (tx, rx) = mpsc::unbounded();
+    (ShardSink(tx), ShardStream(rx))
+}
+
+/// The requests for a shard that hasn't arrived yet. The shard is requested
+/// in rounds: a few holders at a time, moving on to the next holders if none
+/// of them delivered the shard by the round's deadline.
+struct ShardAttempt {
+    /// The holders the shard was requested from
+    asked: Vec<PeerId>,
+
+    /// The known holders that haven't been asked yet
+    waiting: Vec<PeerId>,
+
+    /// The asked holders that failed to deliver the shard
+    failed: Vec<PeerId>,
+
+    /// How many holders were asked in the current round
+    in_round: usize,
+
+    /// When the current round is abandoned, if it has a deadline
+    deadline: Option<Instant>,
+}
+
+impl ShardAttempt {
+    fn new() -> Self {
+        Self {
+            asked: Vec::new(),
+            waiting: Vec::new(),
+            failed: Vec::new(),
+            in_round: 0,
+            deadline: None,
+        }
+    }
+
+    /// Ask waiting holders until `fan_out` holders were asked in this round
+    /// (or `max_attempts` holders in all), and return them. The round's
+    /// deadline starts with its first request.
+    fn ask(
+        &mut self,
+        fan_out: usize,
+        max_attempts: Option<usize>,
+        timeout: Option<Duration>,
+        now: Instant,
+    ) -> Vec<PeerId> {
+        let mut chosen = Vec::new();
+        while self.in_round < fan_out
+            && !self.waiting.is_empty()
+            && max_attempts.map_or(true, |max| self.asked.len() < max)
+        {
+            let holder = self.waiting.remove(0);
+            self.asked.push(holder.clone());
+            chosen.push(holder);
+            self.in_round += 1;
+        }
+        if !chosen.is_empty() && self.deadline.is_none() {
+            self.deadline = timeout.map(|t| now + t);
+        }
+        chosen
+    }
+}
+
+/// Keeps track of the shards of a file that have arrived during a get, and
+/// forwards each new valid shard to a sink (if there is one).
+pub struct ShardCollector {
+    /// The number of shards of the file, once its metadata is known
+    total: Option<u32>,
+
+    /// The id of the shard at each index, once the metadata of the file is
+    /// known
+    shard_ids: Option<Vec<ShardID>>,
+
+    /// How many shards the get may give up on (the parity shards of the
+    /// file), since the file can be reconstructed without them
+    spare: u32,
+
+    /// The indices of the shards that have arrived
+    received: HashSet<u32>,
+
+    /// The requests for each shard that hasn't arrived yet
+    racing: HashMap<u32, ShardAttempt>,
+
+    /// When the get times out
+    deadline: Instant,
+
+    /// How long the holders of a shard get to deliver it before the next
+    /// holders are asked (`None` to only ever ask the first holders)
+    shard_timeout: Option<Duration>,
+
+    /// How many holders of a shard may be asked for it in all (`None` for no
+    /// limit)
+    max_attempts: Option<usize>,
+
+    /// How many times a holder failed to deliver a shard
+    failures: u32,
+
+    /// The holder that delivered each shard that has arrived
+    served_by: HashMap<u32, PeerId>,
+
+    /// Where to send the shards. Dropping it completes the stream.
+    sink: Option<ShardSink>,
+}
+
+impl ShardCollector {
+    /// Start collecting shards.
+    pub fn new(sink: Option<ShardSink>, timeout: Duration) -> Self {
+        Self {
+            total: None,
+            shard_ids: None,
+            spare: 0,
+            received: HashSet::new(),
+            racing: HashMap::new(),
+            deadline: Instant::now() + timeout,
+            shard_timeout: None,
+            max_attempts: None,
+            failures: 0,
+            served_by: HashMap::new(),
+            sink,
+        }
+    }
+
+    /// Check whether the number of shards of the file is known yet.
+    pub fn has_total(&self) -> bool {
+        self.total.is_some()
+    }
+
+    /// Set the number of shards of the file.
+    pub fn set_total(&mut self, total: u32) {
+        self.total = Some(total);
+        self.close_if_complete();
+    }
+
+    /// Set how many shards the get may give up on once every holder of them
+    /// failed, and still complete without them.
+    pub fn set_spare(&mut self, spare: u32) {
+        self.spare = spare;
+    }
+
+    /// Set the id of the shard at each index, from the shard map of the file.
+    /// Shards whose id isn't the one at their index are rejected from then on.
+    pub fn set_shard_ids(&mut self, shard_ids: Vec<ShardID>) {
+        self.shard_ids = Some(shard_ids);
+    }
+
+    /// Set how long the holders of a shard get to deliver it before the get
+    /// gives up on them and asks the next holders of that shard.
+    pub fn set_shard_timeout(&mut self, timeout: Option<Duration>) {
+        self.shard_timeout = timeout;
+    }
+
+    /// Set how many holders of a shard may be asked for it in all, counting
+    /// the holders that are fallen through to after others fail or stall.
+    pub fn set_max_attempts(&mut self, max_attempts: Option<usize>) {
+        self.max_attempts = max_attempts;
+    }
+
+    /// Race the holders of a shard: choose which of `holders` to request the
+    /// shard at `index` from, so that it is requested from up to `fan_out`
+    /// holders at once. Holders that were already asked aren't chosen again,
+    /// and nothing is chosen once the shard has arrived. The first valid copy
+    /// to arrive is used, and the copies from the slower holders are ignored.
+    /// The holders that aren't chosen are kept for `retry_stalled`.
+    pub fn race(
+        &mut self,
+        index: u32,
+        holders: &[PeerId],
+        fan_out: usize,
+    ) -> Vec<PeerId> {
+        if self.received.contains(&index) {
+            return Vec::new();
+        }
+
+        let attempt = self.racing.entry(index).or_insert_with(ShardAttempt::new);
+        for holder in holders {
+            if !attempt.asked.contains(holder) && !attempt.waiting.contains(holder) {
+                attempt.waiting.push(holder.clone());
+            }
+        }
+        attempt.ask(
+            fan_out,
+            self.max_attempts,
+            self.shard_timeout,
+            Instant::now(),
+        )
+    }
+
+    /// Record that a holder that was asked for the shard at an index failed to
+    /// deliver it (it doesn't hold the shard, sent a corrupted copy, or can't
+    /// be reached), and fall through to the next holders of the shard right
+    /// away instead of waiting for the shard timeout. Returns the holders to
+    /// request the shard from next.
+    pub fn holder_failed(
+        &mut self,
+        index: u32,
+        holder: &PeerId,
+        fan_out: usize,
+    ) -> Vec<PeerId> {
+        let attempt = match self.racing.get_mut(&index) {
+            Some(attempt) => attempt,
+            None => return Vec::new(),
+        };
+        if !attempt.asked.contains(holder) || attempt.failed.contains(holder) {
+            return Vec::new();
+        }
+        attempt.failed.push(holder.clone());
+        self.failures += 1;
+
+        // The failed holder no longer counts towards the current round
+        attempt.in_round = attempt.in_round.saturating_sub(1);
+        let next = attempt.ask(
+            fan_out,
+            self.max_attempts,
+            self.shard_timeout,
+            Instant::now(),
+        );
+        self.close_if_complete();
+        next
+    }
+
+    /// The first shard that every holder allowed by `max_attempts` was asked
+    /// for and failed to deliver, once more shards than the get may give up on
+    /// (see `set_spare`) were. The get can't finish without it.
+    pub fn exhausted(&self) -> Option<u32> {
+        let given_up = self.given_up();
+        if given_up.len() as u32 <= self.spare {
+            return None;
+        }
+        given_up.first().copied()
+    }
+
+    /// The shards that every holder allowed by `max_attempts` was asked for
+    /// and failed to deliver, in order.
+    fn given_up(&self) -> Vec<u32> {
+        let max = match self.max_attempts {
+            Some(max) => max,
+            None => return Vec::new(),
+        };
+        let mut given_up: Vec<u32> = self
+            .racing
+            .iter()
+            .filter(|(_, a)| a.asked.len() >= max && a.failed.len() >= a.asked.len())
+            .map(|(index, _)| *index)
+            .collect();
+        given_up.sort();
+        given_up
+    }
+
+    /// How many times a holder failed to deliver a shard during the get.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// The holder that delivered the shard at an index, if it has arrived
+    /// from a holder.
+    pub fn served_by(&self, index: u32) -> Option<&PeerId> {
+        self.served_by.get(&index)
+    }
+
+    /// Give up on the holders of every shard that didn't deliver it before the
+    /// shard timeout, and choose up to `fan_out` of the next holders of each of
+    /// those shards to request it from instead. Returns the shards to request
+    /// again and their new holders.
+    pub fn retry_stalled(
+        &mut self,
+        now: Instant,
+        fan_out: usize,
+    ) -> Vec<(u32, Vec<PeerId>)> {
+        let (timeout, max_attempts) = (self.shard_timeout, self.max_attempts);
+        let mut retries = Vec::new();
+        for (index, attempt) in self.racing.iter_mut() {
+            if attempt.deadline.map_or(true, |d| now < d) {
+                continue;
+            }
+            attempt.in_round = 0;
+            attempt.deadline = None;
+            let chosen = attempt.ask(fan_out, max_attempts, timeout, now);
+            if !chosen.is_empty() {
+                retries.push((*index, chosen));
+            }
+        }
+        retries.sort_by_key(|(index, _)| *index);
+        retries
+    }
+
+    /// Check whether the shard at an index has already arrived.
+    pub fn has_received(&self, index: u32) -> bool {
+        self.received.contains(&index)
+    }
+
+    /// Accept a shard that arrived from the network. Returns `false` if the
+    /// shard was invalid, out of range, not in the file's shard map, or a
+    /// duplicate.
+    pub fn accept(&mut self, shard: &Shard) -> bool {
+        let index = shard.index();
+        let valid = match self.total {
+            Some(total) => shard.is_valid_for(total),
+            None => shard.is_valid(),
+        };
+        let mapped = match &self.shard_ids {
+            Some(ids) => ids.get(index as usize) == Some(&shard.id),
+            None => true,
+        };
+        if !valid || !mapped {
+            return false;
+        }
+        if !self.received.insert(index) {
+            return false;
+        }
+        self.racing.remove(&index);
+
+        if let Some(ShardSink(tx)) = &self.sink {
+            let _ = tx.unbounded_send(Ok((index, shard.data.clone())));
+        }
+        self.close_if_complete();
+        true
+    }
+
+    /// Like `accept`, but remember which holder delivered the shard.
+    pub fn accept_from(&mut self, shard: &Shard, holder: &PeerId) -> bool {
+        if !self.accept(shard) {
+            return false;
+        }
+        self.served_by.insert(shard.index(), holder.clone());
+        true
+    }
+
+    /// Check whether every shard of the file has arrived, other than the
+    /// spare shards that the get gave up on.
+    pub fn is_complete(&self) -> bool {
+        let given_up = self.given_up().len() as u32;
+        given_up <= self.spare
+            && self
+                .total
+                .map_or(false, |t| self.received.len() as u32 + given_up >= t)
+    }
+
+    /// Check whether the get has timed out.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        !self.is_complete() && now >= self.deadline
+    }
+
+    /// Fail the get, sending the reason to the stream and closing it.
+    pub fn fail(&mut self, reason: &str) {
+        if let Some(sink) = self.sink.take() {
+            sink.fail(reason);
+        }
+    }
+
+    /// Close the stream once every shard (that wasn't given up on) has
+    /// arrived.
+    fn close_if_complete(&mut self) {
+        if self.is_complete() {
+            self.sink = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_stream_yields_each_index_once() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+
+        let shards: Vec<Shard> = (0..3)
+            .map(|i| Shard::new(vec![i as u8; 4], i).unwrap())
+            .collect();
+
+        // Shards can arrive before the metadata, out of order, and repeatedly
+        assert!(collector.accept(&shards[2]));
+        assert!(!collector.accept(&shards[2]));
+        collector.set_total(3);
+        assert!(!collector.accept(&Shard::new(vec![9], 7).unwrap()));
+        assert!(collector.accept(&shards[0]));
+        assert!(!collector.accept(&shards[0]));
+        assert!(!collector.is_complete());
+        assert!(collector.accept(&shards[1]));
+        assert!(collector.is_complete());
+
+        let mut items: Vec<(u32, Vec<u8>)> = block_on(stream.collect::<Vec<_>>())
+            .into_iter()
+            .map(|item| item.unwrap())
+            .collect();
+        items.sort();
+        assert_eq!(
+            items,
+            vec![(0, vec![0; 4]), (1, vec![1; 4]), (2, vec![2; 4])]
+        );
+    }
+
+    #[test]
+    fn test_race_uses_first_valid_shard() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        collector.set_total(1);
+
+        let (fast, slow, bad) =
+            (PeerId::random(), PeerId::random(), PeerId::random());
+        let holders =
+            vec![bad.clone(), slow.clone(), fast.clone(), PeerId::random()];
+        assert_eq!(collector.race(0, &holders, 3), &holders[..3]);
+        assert!(collector.race(0, &holders, 3).is_empty());
+
+        // The corrupted copy arrives first, then the fast holder's copy
+        let mut corrupted = Shard::new(vec![0; 4], 0).unwrap();
+        corrupted.data[0] = 1;
+        assert!(!collector.accept(&corrupted));
+        let fast_copy = Shard::new(vec![1; 4], 0).unwrap();
+        assert!(collector.accept(&fast_copy));
+
+        // The slow holder's copy is ignored
+        assert!(collector.has_received(0));
+        assert!(!collector.accept(&Shard::new(vec![2; 4], 0).unwrap()));
+        assert!(collector.race(0, &[PeerId::random()], 3).is_empty());
+
+        let items = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].as_ref().unwrap(), &(0, vec![1; 4]));
+    }
+
+    #[test]
+    fn test_stalled_holder_replaced() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        collector.set_shard_timeout(Some(Duration::from_secs(5)));
+        collector.set_total(1);
+
+        let (stuck, responsive) = (PeerId::random(), PeerId::random());
+        let start = Instant::now();
+        assert_eq!(
+            collector.race(0, &[stuck.clone(), responsive.clone()], 1),
+            vec![stuck]
+        );
+
+        // The stuck holder keeps the shard until its deadline passes
+        assert!(collector.retry_stalled(start, 1).is_empty());
+        let later = start + Duration::from_secs(6);
+        assert_eq!(
+            collector.retry_stalled(later, 1),
+            vec![(0, vec![responsive.clone()])]
+        );
+
+        // The responsive holder delivers, well within the get's own timeout
+        assert!(collector.accept(&Shard::new(vec![3; 4], 0).unwrap()));
+        assert!(collector.is_complete());
+        assert!(!collector.is_expired(later));
+        assert!(collector
+            .retry_stalled(later + Duration::from_secs(6), 1)
+            .is_empty());
+
+        let items = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].as_ref().unwrap(), &(0, vec![3; 4]));
+    }
+
+    #[test]
+    fn test_failed_holders_fall_through() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        collector.set_max_attempts(Some(3));
+        collector.set_total(1);
+
+        let holders: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        assert_eq!(collector.race(0, &holders, 1), vec![holders[0].clone()]);
+
+        // The first two holders fail, so the next ones are asked at once
+        assert_eq!(
+            collector.holder_failed(0, &holders[0], 1),
+            vec![holders[1].clone()]
+        );
+        assert!(collector.holder_failed(0, &holders[0], 1).is_empty());
+        assert_eq!(
+            collector.holder_failed(0, &holders[1], 1),
+            vec![holders[2].clone()]
+        );
+        assert_eq!(collector.exhausted(), None);
+
+        // And the third delivers
+        let shard = Shard::new(vec![5; 4], 0).unwrap();
+        assert!(collector.accept_from(&shard, &holders[2]));
+        assert_eq!(collector.served_by(0), Some(&holders[2]));
+        assert_eq!(collector.failures(), 2);
+        assert!(collector.is_complete());
+
+        let items = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].as_ref().unwrap(), &(0, vec![5; 4]));
+    }
+
+    #[test]
+    fn test_holder_attempts_exhausted() {
+        let mut collector = ShardCollector::new(None, Duration::from_secs(60));
+        collector.set_max_attempts(Some(2));
+        collector.set_total(1);
+
+        let holders: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        collector.race(0, &holders, 1);
+        collector.holder_failed(0, &holders[0], 1);
+
+        // The third holder is never asked
+        assert!(collector.holder_failed(0, &holders[1], 1).is_empty());
+        assert_eq!(collector.exhausted(), Some(0));
+        assert_eq!(collector.failures(), 2);
+    }
+
+    #[test]
+    fn test_spare_shards_given_up() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        collector.set_max_attempts(Some(1));
+        collector.set_spare(1);
+        collector.set_total(3);
+
+        let shards: Vec<Shard> = (0..2)
+            .map(|i| Shard::new(vec![i as u8; 4], i).unwrap())
+            .collect();
+        assert!(collector.accept(&shards[0]));
+        assert!(collector.accept(&shards[1]));
+
+        // The get completes without the one shard that it may give up on
+        let holder = PeerId::random();
+        collector.race(2, &[holder.clone()], 1);
+        assert!(!collector.is_complete());
+        assert!(collector.holder_failed(2, &holder, 1).is_empty());
+        assert_eq!(collector.exhausted(), None);
+        assert!(collector.is_complete());
+        assert_eq!(block_on(stream.collect::<Vec<_>>()).len(), 2);
+
+        // But not without two of them
+        let mut collector = ShardCollector::new(None, Duration::from_secs(60));
+        collector.set_max_attempts(Some(1));
+        collector.set_spare(1);
+        collector.set_total(3);
+        let holders: Vec<PeerId> = (0..2).map(|_| PeerId::random()).collect();
+        for (index, holder) in holders.iter().enumerate() {
+            collector.race(index as u32, &[holder.clone()], 1);
+            collector.holder_failed(index as u32, holder, 1);
+        }
+        assert_eq!(collector.exhausted(), Some(0));
+        assert!(!collector.is_complete());
+    }
+
+    #[test]
+    fn test_rejects_unmapped_shards() {
+        let mut collector = ShardCollector::new(None, Duration::from_secs(60));
+        let ours = Shard::new(vec![1, 2, 3], 0).unwrap();
+        let theirs = Shard::new(vec![4, 5, 6], 0).unwrap();
+        collector.set_total(1);
+        collector.set_shard_ids(vec![ours.id.clone()]);
+
+        // A valid shard of another file is not ours
+        assert!(!collector.accept(&theirs));
+        assert!(!collector.has_received(0));
+        assert!(collector.accept(&ours));
+        assert!(collector.is_complete());
+    }
+
+    #[test]
+    fn test_stream_rejects_invalid_shards() {
+        let (sink, _stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+
+        let mut shard = Shard::new(vec![1, 2, 3], 0).unwrap();
+        shard.data[0] = 0;
+        assert!(!collector.accept(&shard));
+    }
+
+    #[test]
+    fn test_stream_times_out() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(0));
+        collector.set_total(2);
+        assert!(collector.accept(&Shard::new(vec![1], 0).unwrap()));
+
+        assert!(collector.is_expired(Instant::now()));
+        collector.fail("timed out");
+
+        let items = block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items.len(), 2);
+        assert!(items[0].is_ok());
+        assert!(items[1].is_err());
+    }
+
+    #[test]
+    fn test_empty_file_completes() {
+        let (sink, stream) = shard_stream();
+        let mut collector = ShardCollector::new(Some(sink), Duration::from_secs(60));
+        collector.set_total(0);
+
+        assert!(collector.is_complete());
+        assert!(block_on(stream.collect::<Vec<_>>()).is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+use crate::GeneralError;
+use libp2p::{
+    core::{muxing::StreamMuxerBox, transport::Boxed},
+    identity::Keypair,
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use std::error::Error;
+
+/// The transport of a node's swarm: authenticated, multiplexed connections to
+/// other peers.
+pub type NodeTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+/// Build a node's transport, secured with the Noise XX handshake. The peer id
+/// of the node is derived from `keypair`. TLS (libp2p-tls) first shipped with
+/// libp2p 0.51, so it can't be offered until the node is ported to it.
+pub async fn build_transport(
+    keypair: Keypair,
+) -> Result<NodeTransport, Box<dyn Error>> {
+    Ok(libp2p::development_transport(keypair).await?)
+}
+
+/// Check that a node's transport can listen on an address: an ip4 or ip6
+/// address followed by a tcp port, optionally over websockets.
+pub fn validate_listen_addr(addr: &Multiaddr) -> Result<(), Box<dyn Error>> {
+    let invalid = |reason: &str| -> Result<(), Box<dyn Error>> {
+        Err(Box::new(GeneralError::new(
+            format!("can't listen on {}: {}", addr, reason).as_str(),
+        )))
+    };
+
+    let mut protocols = addr.iter();
+    match protocols.next() {
+        Some(Protocol::Ip4(_)) | Some(Protocol::Ip6(_)) => {}
+        _ => return invalid("it doesn't start with an ip4 or ip6 address"),
+    }
+    match protocols.next() {
+        Some(Protocol::Tcp(_)) => {}
+
+        // QUIC first shipped with libp2p 0.50
+        Some(Protocol::Udp(_)) => {
+            return invalid("QUIC isn't supported by this version of libp2p")
+        }
+        _ => return invalid("it has no tcp port"),
+    }
+    match (protocols.next(), protocols.next()) {
+        (None, _) | (Some(Protocol::Ws(_)), None) => Ok(()),
+        _ => invalid("only websockets may follow the tcp port"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_build_transport() {
+        let keypair = Keypair::generate_ed25519();
+        assert!(build_transport(keypair).await.is_ok());
+    }
+
+    #[test]
+    fn test_validate_listen_addr() {
+        for valid in [
+            "/ip4/0.0.0.0/tcp/0",
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip6/::1/tcp/0",
+            "/ip4/127.0.0.1/tcp/0/ws",
+        ]
+        .iter()
+        {
+            assert!(validate_listen_addr(&valid.parse().unwrap()).is_ok());
+        }
+
+        for invalid in [
+            "/dns4/localhost/tcp/0",
+            "/ip4/127.0.0.1",
+            "/ip4/127.0.0.1/udp/0/quic",
+            "/ip4/127.0.0.1/tcp/0/tcp/1",
+        ]
+        .iter()
+        {
+            assert!(validate_listen_addr(&invalid.parse().unwrap()).is_err());
+        }
+    }
+}
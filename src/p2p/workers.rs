@@ -0,0 +1,72 @@
+use futures::channel::oneshot;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::{error::Error, sync::Arc};
+
+/// A pool of threads for CPU-bound shard work (sharding, compression,
+/// encryption, and erasure coding), so that the work doesn't block the
+/// executor that polls the swarm.
+#[derive(Clone)]
+pub struct ShardWorkers {
+    pool: Arc<ThreadPool>,
+}
+
+impl ShardWorkers {
+    /// Start a pool of `threads` threads (`None` for one per CPU).
+    pub fn new(threads: Option<usize>) -> Result<Self, Box<dyn Error>> {
+        let mut builder =
+            ThreadPoolBuilder::new().thread_name(|i| format!("meros-shard-{}", i));
+        if let Some(n) = threads {
+            builder = builder.num_threads(n);
+        }
+        Ok(Self {
+            pool: Arc::new(builder.build()?),
+        })
+    }
+
+    /// Run a job on the pool, and get a future of its result. A job whose
+    /// result is dropped still runs to completion.
+    pub fn run<T, F>(&self, job: F) -> oneshot::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.pool.spawn(move || {
+            let _ = sender.send(job());
+        });
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::shard::{Shard, ShardConfig};
+    use crate::test_util::test_keypair;
+    use async_std::task;
+    use std::time::{Duration, Instant};
+
+    #[async_std::test]
+    async fn test_large_sharding_does_not_block() {
+        let workers = ShardWorkers::new(Some(1)).unwrap();
+        let (_, pk) = test_keypair();
+        let bytes: Vec<u8> = (0..64 * 1024 * 1024u32).map(|i| i as u8).collect();
+        let mut config = ShardConfig::new(8, &pk);
+        config.parity_shards = 4;
+
+        let start = Instant::now();
+        let job = workers.run(move || {
+            Shard::shard(&bytes, config)
+                .map(|(shards, _)| shards.len())
+                .map_err(|e| e.to_string())
+        });
+
+        // Small tasks keep running while the shards are calculated
+        for _ in 0..5 {
+            task::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        assert_eq!(job.await.unwrap().unwrap(), 12);
+    }
+}
@@ -1,13 +1,14 @@
-use super::shard::*;
+use super::{shard::*, PrimitiveError};
 use crate::crypto;
 use crate::GeneralError;
 use crate::{crypto::hash, CanSerialize};
-use crc32fast::Hasher;
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     clone::Clone,
     cmp::PartialEq,
+    collections::BTreeMap,
     error::Error,
     fmt::Write,
     fs,
@@ -31,25 +32,34 @@ impl FileID {
         bytes: &Vec<u8>,
     ) -> Result<(Self, u128), SystemTimeError> {
         let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u128;
+        Ok((Self::new_at(filename, bytes, time), time))
+    }
 
-        let data = [filename.as_bytes(), &bytes[..], time.to_string().as_bytes()]
+    /// Calculate the FileID of a file created at the given time (in seconds
+    /// since the unix epoch). The time is mixed in as its 16 big-endian bytes.
+    pub fn new_at(filename: &str, bytes: &Vec<u8>, time: u128) -> Self {
+        let data = [filename.as_bytes(), &bytes[..], &time.to_be_bytes()[..]]
             .concat()
             .to_vec();
-        Ok((
-            Self {
-                id: hash::hash_bytes(data),
-            },
-            time,
-        ))
+        Self {
+            id: hash::hash_bytes(data),
+        }
     }
 
-    /// Check that this FileID matches that of the information given.
-    pub fn matches(&self, filename: &str, bytes: &Vec<u8>, time: u128) -> bool {
-        let a = [filename.as_bytes(), &bytes[..], time.to_string().as_bytes()]
-            .concat()
-            .to_vec();
+    /// Calculate the content address of a file: a FileID of only its name and
+    /// bytes, so that the same file always gets the same id.
+    pub fn new_content_addressed(filename: &str, bytes: &Vec<u8>) -> Self {
+        let data = [filename.as_bytes(), &bytes[..]].concat().to_vec();
+        Self {
+            id: hash::hash_bytes(data),
+        }
+    }
 
-        true
+    /// Check that this FileID matches that of the information given, whether it
+    /// is timestamped or content addressed.
+    pub fn matches(&self, filename: &str, bytes: &Vec<u8>, time: u128) -> bool {
+        self == &Self::new_at(filename, bytes, time)
+            || self == &Self::new_content_addressed(filename, bytes)
     }
 
     /// Peek at the internal hash
@@ -64,11 +74,18 @@ impl FileID {
 
     /// Convert from a hex string
     pub fn from_hex(s: &str) -> Result<Self, hex::FromHexError> {
-        let decoded = hex::decode(s)?;
-        let mut id = [0u8; 32];
-        for (i, byte) in decoded.into_iter().enumerate() {
-            id[i] = byte;
+        Self::try_from_slice(&hex::decode(s)?)
+            .map_err(|_| hex::FromHexError::InvalidStringLength)
+    }
+
+    /// Construct a FileID from a slice of bytes (like a DHT key), making sure
+    /// that the slice is exactly as long as a FileID.
+    pub fn try_from_slice(bytes: &[u8]) -> Result<Self, PrimitiveError> {
+        if bytes.len() != hash::HASH_SIZE {
+            return Err(PrimitiveError::InvalidLength(bytes.len()));
         }
+        let mut id = [0u8; hash::HASH_SIZE];
+        id.copy_from_slice(bytes);
         Ok(Self { id })
     }
 }
@@ -79,6 +96,8 @@ impl PartialEq for FileID {
     }
 }
 
+impl Eq for FileID {}
+
 impl CanSerialize for FileID {
     type S = Self;
     fn to_bytes(&self) -> bincode::Result<Vec<u8>> {
@@ -89,6 +108,171 @@ impl CanSerialize for FileID {
     }
 }
 
+/// The algorithm used to checksum the bytes of a file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32: fast, but only catches accidental corruption
+    Crc32,
+
+    /// BLAKE3: a cryptographic hash, so the bytes can't be forged
+    Blake3,
+
+    /// SHA-256: a cryptographic hash, for when a standard one is required
+    Sha256,
+}
+
+impl Default for ChecksumAlgorithm {
+    fn default() -> Self {
+        ChecksumAlgorithm::Crc32
+    }
+}
+
+impl ChecksumAlgorithm {
+    /// Calculate the checksum of some bytes.
+    pub fn checksum(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut checksummer = self.checksummer();
+        checksummer.update(bytes);
+        checksummer.finalize()
+    }
+
+    /// Start a checksum that is fed the bytes a piece at a time.
+    fn checksummer(&self) -> Checksummer {
+        match self {
+            ChecksumAlgorithm::Crc32 => Checksummer::Crc32(crc32fast::Hasher::new()),
+            ChecksumAlgorithm::Blake3 => Checksummer::Blake3(blake3::Hasher::new()),
+            ChecksumAlgorithm::Sha256 => Checksummer::Sha256(Sha256::new()),
+        }
+    }
+}
+
+/// A checksum being calculated over bytes as they arrive.
+enum Checksummer {
+    Crc32(crc32fast::Hasher),
+    Blake3(blake3::Hasher),
+    Sha256(Sha256),
+}
+
+impl Checksummer {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Checksummer::Crc32(hasher) => hasher.update(bytes),
+            Checksummer::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Checksummer::Sha256(hasher) => Digest::update(hasher, bytes),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Checksummer::Crc32(hasher) => hasher.finalize().to_be_bytes().to_vec(),
+            Checksummer::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Checksummer::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// The size (in bytes) of the chunks of a file that are the leaves of its
+/// merkle tree.
+pub const MERKLE_LEAF_SIZE: usize = 4096;
+
+/// Calculate the merkle root of the bytes of a file, over chunks of
+/// `MERKLE_LEAF_SIZE` bytes.
+pub fn merkle_root(bytes: &[u8]) -> hash::Hash {
+    hash::merkle_root(&bytes.chunks(MERKLE_LEAF_SIZE).collect::<Vec<_>>())
+}
+
+/// A writer that calculates the checksum and merkle root of the bytes written
+/// through it, so that reconstructed data can be checked without being held
+/// in memory.
+struct ChecksumWriter<W> {
+    inner: W,
+    checksummer: Checksummer,
+
+    /// The hashes of the merkle leaves written so far
+    leaves: Vec<hash::Hash>,
+
+    /// The bytes of the merkle leaf being written
+    leaf: Vec<u8>,
+}
+
+impl<W: std::io::Write> ChecksumWriter<W> {
+    fn new(inner: W, algorithm: ChecksumAlgorithm) -> Self {
+        Self {
+            inner,
+            checksummer: algorithm.checksummer(),
+            leaves: Vec::new(),
+            leaf: Vec::with_capacity(MERKLE_LEAF_SIZE),
+        }
+    }
+
+    /// The checksum and merkle root of everything that was written.
+    fn finish(mut self) -> (Vec<u8>, hash::Hash) {
+        if !self.leaf.is_empty() {
+            self.leaves.push(hash::merkle_leaf(&self.leaf));
+        }
+        (
+            self.checksummer.finalize(),
+            hash::merkle_root_of_hashes(self.leaves),
+        )
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksummer.update(&buf[..written]);
+
+        let mut rest = &buf[..written];
+        while !rest.is_empty() {
+            let take = rest.len().min(MERKLE_LEAF_SIZE - self.leaf.len());
+            self.leaf.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.leaf.len() == MERKLE_LEAF_SIZE {
+                self.leaves.push(hash::merkle_leaf(&self.leaf));
+                self.leaf.clear();
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The outcome of every check of a file against some shards (see
+/// `File::validate`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// Whether the checksum of the reconstructed bytes matches the file's
+    pub checksum_ok: bool,
+
+    /// Whether the merkle root of the reconstructed bytes matches the file's
+    pub merkle_ok: bool,
+
+    /// Whether the file's id matches its name, creation date, and the
+    /// reconstructed bytes
+    pub file_id_ok: bool,
+
+    /// Whether the file is signed by its owner over the reconstructed bytes
+    /// (or, for a public file, whether it really is unsigned)
+    pub signature_ok: bool,
+
+    /// How many bytes were reconstructed from the shards
+    pub reconstructed_len: u64,
+}
+
+impl ValidationReport {
+    /// Whether every check passed.
+    pub fn all_ok(&self) -> bool {
+        self.checksum_ok && self.merkle_ok && self.file_id_ok && self.signature_ok
+    }
+}
+
+/// The most bytes that the labels of a file (every key and value) may take up.
+pub const MAX_METADATA_BYTES: usize = 4 * 1024;
+
 /// The byte representation of a libp2p::PeerId. This alias exists for readability.
 type PeerIdSerial = Vec<u8>;
 
@@ -107,13 +291,32 @@ pub struct File {
     /// The date of creation
     pub creation_date: u128,
 
-    /// A checksum of the bytes of the file
-    checksum: u32,
+    /// The size of the file's data, in bytes (of the plaintext, if the file is
+    /// encrypted)
+    pub size: u64,
+
+    /// The algorithm that `checksum` was calculated with
+    checksum_algorithm: ChecksumAlgorithm,
+
+    /// A checksum of the bytes of the file (of the plaintext, if the file is
+    /// encrypted)
+    checksum: Vec<u8>,
+
+    /// The root of a merkle tree over the bytes of the file (of the plaintext,
+    /// if the file is encrypted). Unlike the checksum, it is a cryptographic
+    /// hash that depends on the order of every chunk of the file. It doesn't
+    /// depend on how the file is sharded.
+    merkle_root: hash::Hash,
 
     /// Ed25519 digital signature of the entire file struct. When calculated,
     /// this field is empty.
     signature: Vec<u8>,
 
+    /// Ed25519 digital signature of only the file struct (not the bytes of the
+    /// file), so that the metadata can be verified offline. When calculated,
+    /// both signature fields are empty.
+    metadata_signature: Vec<u8>,
+
     /// The original owner of the file.
     owner: PeerIdSerial,
 
@@ -122,6 +325,18 @@ pub struct File {
 
     // The locations of the shards on the network
     shards: Vec<PeerIdSerial>, // For scalability: Make this a ShardID-Vec<PeerId> map
+
+    /// The ids of the current shards of the file, in order
+    shard_ids: Vec<ShardID>,
+
+    /// Whether the file is public: unsigned and without an owner (the owner is
+    /// the null peer id). Only the checksum and id of a public file can be
+    /// verified, and a signed file can't claim to be public.
+    public: bool,
+
+    /// Labels that the owner tagged the file with (like "project: alpha").
+    /// They are covered by the signatures.
+    metadata: BTreeMap<String, String>,
 }
 
 impl File {
@@ -141,6 +356,49 @@ impl File {
         config: ShardConfig,
         priv_key: &ecies_ed25519::SecretKey,
     ) -> Result<(Self, Vec<Shard>), Box<dyn Error>> {
+        let (mut file, shards, file_data) = Self::unsigned(path, config)?;
+
+        // Construct the libp2p keypair
+        let pub_key = ecies_ed25519::PublicKey::from_secret(priv_key);
+        let keypair = crypto::ecies_to_libp2p(priv_key, &pub_key)?;
+        file.owner = PeerId::from_public_key(keypair.public()).to_bytes();
+
+        file.resign(&file_data, priv_key)?;
+
+        Ok((file, shards))
+    }
+
+    /// Generate the metadata of a public file: one that has no owner and isn't
+    /// signed, so that it can be published without a key. Public files can't
+    /// be encrypted.
+    ///
+    /// # Arguments
+    /// * `path` - the path of the file to read from on the disk
+    /// * `config` - information about how the data should be sharded
+    pub fn new_public(
+        path: &path::Path,
+        config: ShardConfig,
+    ) -> Result<(Self, Vec<Shard>), Box<dyn Error>> {
+        if config.encrypt {
+            return Err(Box::new(GeneralError::new(
+                "a public file can't be encrypted",
+            )));
+        }
+
+        let (mut file, shards, _) = Self::unsigned(path, config)?;
+        file.public = true;
+        Ok((file, shards))
+    }
+
+    /// Read a file from the disk, shard it, and generate its metadata without
+    /// an owner or signatures. Returns the bytes of the file too.
+    fn unsigned(
+        path: &path::Path,
+        mut config: ShardConfig,
+    ) -> Result<(Self, Vec<Shard>, Vec<u8>), Box<dyn Error>> {
+        let metadata = std::mem::take(&mut config.metadata);
+        check_metadata(&metadata)?;
+
         // Read the file from the disk to generate validation metadata
         let mut fd = fs::File::open(path)?;
         let mut file_data = Vec::new(); // The contents of the file
@@ -155,41 +413,136 @@ impl File {
         };
 
         // Generate a file id and get the time of hashing
-        let (file_id, hash_date) = FileID::new(filename, &file_data)?;
-
-        // Construct the libp2p keypair
-        let pub_key = ecies_ed25519::PublicKey::from_secret(priv_key);
-        let keypair = crypto::ecies_to_libp2p(priv_key, &pub_key);
+        let (mut file_id, hash_date) = FileID::new(filename, &file_data)?;
+        if config.content_addressed {
+            file_id = FileID::new_content_addressed(filename, &file_data);
+        }
 
         // Calculate the actual shards
+        let checksum_algorithm = config.checksum_algorithm;
         let (shards, new_config) = Shard::shard(&file_data, config)?;
 
         // Construct the file
-        let mut file = Self {
+        let file = Self {
             filename: filename.to_string(),
             id: file_id,
             creation_date: hash_date,
-            checksum: {
-                let mut hasher = Hasher::new();
-                hasher.update(&file_data);
-                hasher.finalize()
-            },
+            size: file_data.len() as u64,
+            checksum_algorithm,
+            checksum: checksum_algorithm.checksum(&file_data),
+            merkle_root: merkle_root(&file_data),
             signature: Vec::new(), // Temporary so that the entire file can be signed
-            owner: PeerId::from_public_key(keypair.public()).to_bytes(),
+            metadata_signature: Vec::new(),
+            owner: Vec::new(),
             shard_config: new_config,
             shards: Vec::new(), // Empty because the network will handle this part
+            shard_ids: shards.iter().map(|s| s.id.clone()).collect(),
+            public: false,
+            metadata,
         };
 
+        Ok((file, shards, file_data))
+    }
+
+    /// Sign the file and its bytes again as the owner of the file, replacing
+    /// both signatures. This has to be done whenever the metadata of the file
+    /// changes (like after an append, a reshard, or a key rotation).
+    /// # Arguments
+    /// * `file_data` - the bytes of the file
+    /// * `priv_key` - the private key of the owner of the file
+    pub fn resign(
+        &mut self,
+        file_data: &[u8],
+        priv_key: &ecies_ed25519::SecretKey,
+    ) -> Result<(), PrimitiveError> {
+        let pub_key = ecies_ed25519::PublicKey::from_secret(priv_key);
+        let keypair = crypto::ecies_to_libp2p(priv_key, &pub_key)
+            .map_err(|e| PrimitiveError::CryptoError(e))?;
+        if PeerId::from_public_key(keypair.public()).to_bytes() != self.owner {
+            return Err(PrimitiveError::InvalidOwner);
+        }
+
         // Calc digital signature of the file and the file bytes
-        let sig_data = [&file.to_bytes()?[..], &file_data[..]].concat().to_vec();
-        //println!("sigdata on creation: {:?}", sig_data);
-        file.signature = keypair.sign(&sig_data)?;
-        //println!("sig on creation: {:?}", file.signature);
+        self.signature = Vec::new();
+        self.metadata_signature = Vec::new();
+        let unsigned = self
+            .unsigned_bytes()
+            .map_err(|e| PrimitiveError::SerializationError(e))?;
+        let sig_data = [&unsigned[..], file_data].concat().to_vec();
+        self.signature = keypair
+            .sign(&sig_data)
+            .map_err(|_| PrimitiveError::InvalidSignature)?;
 
-        Ok((file, shards))
+        // Sign the metadata on its own so that it can be verified offline
+        self.metadata_signature = keypair
+            .sign(&unsigned)
+            .map_err(|_| PrimitiveError::InvalidSignature)?;
+        Ok(())
+    }
+
+    /// Split the bytes of the file into a different number of shards, update
+    /// the metadata to describe the new shards, and sign it again. The id of
+    /// the file stays the same. The new shards aren't assigned to any nodes.
+    /// # Arguments
+    /// * `file_data` - the bytes of the file
+    /// * `shard_count` - the number of shards to split the file into
+    /// * `priv_key` - the private key of the owner of the file
+    pub fn reshard(
+        &mut self,
+        file_data: &Vec<u8>,
+        shard_count: usize,
+        priv_key: &ecies_ed25519::SecretKey,
+    ) -> Result<Vec<Shard>, Box<dyn Error>> {
+        if self.checksum_algorithm.checksum(file_data) != self.checksum {
+            return Err(Box::new(GeneralError::new(
+                "the bytes given are not the bytes of the file",
+            )));
+        }
+
+        let mut config = self.shard_config.clone();
+        config.shard_count = shard_count;
+        config.sizes = Vec::new();
+        let (shards, new_config) = Shard::shard(file_data, config)?;
+
+        self.shard_config = new_config;
+        self.shards = Vec::new();
+        self.set_shard_ids(&shards);
+        self.resign(file_data, priv_key)?;
+        Ok(shards)
     }
 
-    /// Check that a file is valid against some shards.
+    /// Replace the labels of the file, and sign it again (unless it is public).
+    /// # Arguments
+    /// * `metadata` - the new labels of the file
+    /// * `file_data` - the bytes of the file
+    /// * `priv_key` - the private key of the owner of the file
+    pub fn set_metadata(
+        &mut self,
+        metadata: BTreeMap<String, String>,
+        file_data: &[u8],
+        priv_key: &ecies_ed25519::SecretKey,
+    ) -> Result<(), PrimitiveError> {
+        check_metadata(&metadata)?;
+        let old = std::mem::replace(&mut self.metadata, metadata);
+        if self.public {
+            return Ok(());
+        }
+        if let Err(e) = self.resign(file_data, priv_key) {
+            self.metadata = old;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The labels that the file is tagged with.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Check that a file is valid against some shards. The shards are
+    /// reconstructed (and decrypted, if the file is encrypted) first, so the
+    /// checksum, merkle root, id, and signature are all checked against the
+    /// plaintext.
     /// # Arguments
     /// * `shards` - The shards that the file will be compared to
     /// * `priv_key` - If the shards are encrypted, this key will be used to decrypt them
@@ -198,50 +551,246 @@ impl File {
         shards: &Vec<Shard>,
         priv_key: Option<&ecies_ed25519::SecretKey>,
     ) -> bool {
-        // Reconstruct the shards
-        let data = match Shard::reconstruct(shards, &self.shard_config, priv_key) {
-            Ok(d) => d,
+        match self.validate(shards, priv_key) {
+            Ok(report) => report.all_ok(),
             Err(e) => {
                 eprintln!("invalid file/shard pair: {:?}", e);
-                return false;
+                false
             }
-        };
+        }
+    }
 
-        // Check the checksum
-        let checksum = {
-            let mut hasher = Hasher::new();
-            hasher.update(&data);
-            hasher.finalize()
-        } == self.checksum;
-
-        println!("checksum: {}", checksum);
-
-        // Check the file id
-        let file_id =
-            self.id
-                .matches(self.filename.as_str(), &data, self.creation_date);
-
-        println!("file id: {}", file_id);
-
-        // Check the signature
-        let check_sig = self.signature.clone(); // Copy the signature
-        let libp2p_pk = crypto::ecies_pub_to_libp2p(&self.shard_config.pub_key); // Convert key
-        self.signature = Vec::new(); // Clear the sig (this is how the sig was originally calcd)
-        let self_bytes = match self.to_bytes() {
-            // Serialize self
-            Ok(b) => b,
-            Err(e) => {
-                eprintln!("could not serialize file: {:?}", e);
-                return false;
+    /// Check a file against some shards, reporting the outcome of every check
+    /// instead of stopping at the first one that fails. Only shards that can't
+    /// be reconstructed (or decrypted) at all are an error.
+    /// # Arguments
+    /// * `shards` - The shards that the file will be compared to
+    /// * `priv_key` - If the shards are encrypted, this key will be used to decrypt them
+    pub fn validate(
+        &self,
+        shards: &Vec<Shard>,
+        priv_key: Option<&ecies_ed25519::SecretKey>,
+    ) -> Result<ValidationReport, Box<dyn Error>> {
+        let data = Shard::reconstruct(shards, &self.shard_config, priv_key)?;
+        Ok(self.validate_bytes(&data)?)
+    }
+
+    /// Like `validate`, but check the file against bytes that were already
+    /// reconstructed (and decrypted).
+    pub fn validate_bytes(
+        &self,
+        data: &Vec<u8>,
+    ) -> Result<ValidationReport, PrimitiveError> {
+        Ok(ValidationReport {
+            checksum_ok: self.checksum_algorithm.checksum(data) == self.checksum,
+            merkle_ok: merkle_root(data) == self.merkle_root,
+            file_id_ok: self.id.matches(
+                self.filename.as_str(),
+                data,
+                self.creation_date,
+            ),
+            signature_ok: self.signature_matches(data)?,
+            reconstructed_len: data.len() as u64,
+        })
+    }
+
+    /// Check the signature of the file over its bytes. A public file has no
+    /// signatures to check, but it must really be unsigned.
+    fn signature_matches(&self, data: &[u8]) -> Result<bool, PrimitiveError> {
+        if self.public {
+            return Ok(self.signature.is_empty()
+                && self.metadata_signature.is_empty()
+                && self.owner.is_empty());
+        }
+
+        // The signature is over the file without its signatures (this is how
+        // it was originally calculated) and the data
+        let libp2p_pk = crypto::ecies_pub_to_libp2p(&self.shard_config.pub_key)
+            .map_err(|e| PrimitiveError::CryptoError(e))?;
+        let unsigned = self
+            .unsigned_bytes()
+            .map_err(|e| PrimitiveError::SerializationError(e))?;
+        let sig_data = [&unsigned[..], data].concat().to_vec();
+        Ok(libp2p_pk.verify(&sig_data, &self.signature))
+    }
+
+    /// Like `is_valid`, but say why a file isn't valid against some shards. A
+    /// checksum that doesn't match is a `ChecksumMismatch`, and a merkle root
+    /// that doesn't match (like when chunks of the data were reordered without
+    /// the checksum noticing) is a `MerkleMismatch`.
+    /// # Arguments
+    /// * `shards` - The shards that the file will be compared to
+    /// * `priv_key` - If the shards are encrypted, this key will be used to decrypt them
+    pub fn verify(
+        &self,
+        shards: &Vec<Shard>,
+        priv_key: Option<&ecies_ed25519::SecretKey>,
+    ) -> Result<(), Box<dyn Error>> {
+        let report = self.validate(shards, priv_key)?;
+        if !report.checksum_ok {
+            return Err(Box::new(PrimitiveError::ChecksumMismatch));
+        }
+        if !report.merkle_ok {
+            return Err(Box::new(PrimitiveError::MerkleMismatch));
+        }
+        if !report.file_id_ok {
+            return Err(Box::new(GeneralError::new(
+                "the file id does not match the data",
+            )));
+        }
+        if !report.signature_ok {
+            if self.public {
+                return Err(Box::new(GeneralError::new(
+                    "a signed file can't be public",
+                )));
             }
-        };
-        let sig_data = [&self_bytes, &data[..]].concat().to_vec(); // The data to check
-        println!("sigdata in verify: {:?}", sig_data);
-        let signature = libp2p_pk.verify(&sig_data, &check_sig); // Verify the sig
-        self.signature = check_sig; // Set the file's sig back
-        println!("sig in verify: {}", signature);
+            return Err(Box::new(PrimitiveError::InvalidSignature));
+        }
+        Ok(())
+    }
+
+    /// Reconstruct the file from its shards into a new file at `path`, and
+    /// return how many bytes were written. Every shard is checked against the
+    /// file's shard ids right before it is written, and the checksum and
+    /// merkle root are calculated as the bytes are written. If a shard is
+    /// corrupted (a `CorruptShard` naming it) or the written bytes don't match
+    /// the file, the partial output is deleted. The bytes are written to a
+    /// temporary file next to `path`, which only replaces `path` once the whole
+    /// file has been checked, so a failed reconstruction leaves `path` as it
+    /// was. No more bytes than the size of the file are ever written.
+    /// # Arguments
+    /// * `shards` - The data and parity shards of the file, in order
+    /// * `priv_key` - If the shards are encrypted, this key will be used to decrypt them
+    /// * `path` - Where to write the file
+    pub fn reconstruct_to_path(
+        &self,
+        shards: &[Shard],
+        priv_key: Option<&ecies_ed25519::SecretKey>,
+        path: &path::Path,
+    ) -> Result<u64, Box<dyn Error>> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| GeneralError::new("the output path has no file name"))?;
+        let temp = path.with_file_name(format!(
+            ".{}.{:08x}.part",
+            name.to_string_lossy(),
+            rand::random::<u32>()
+        ));
+        let out = fs::File::create(&temp)?;
+        let result = (|| -> Result<u64, Box<dyn Error>> {
+            let mut writer = ChecksumWriter::new(
+                std::io::BufWriter::new(out),
+                self.checksum_algorithm,
+            );
+            let written = Shard::reconstruct_to_writer_checked(
+                shards,
+                &self.shard_config,
+                priv_key,
+                &mut writer,
+                self.size,
+                |shard| match self.shard_ids.get(shard.index() as usize) {
+                    Some(id) if *id != shard.id => {
+                        Err(Box::new(PrimitiveError::CorruptShard(shard.index())))
+                    }
+                    _ => Ok(()),
+                },
+            )?;
+            writer.flush()?;
+
+            let (checksum, root) = writer.finish();
+            if checksum != self.checksum {
+                return Err(Box::new(PrimitiveError::ChecksumMismatch));
+            }
+            if root != self.merkle_root {
+                return Err(Box::new(PrimitiveError::MerkleMismatch));
+            }
+            fs::rename(&temp, path)?;
+            Ok(written)
+        })();
 
-        checksum && file_id && signature
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    /// Serialize the file with both of its signatures cleared. This is what the
+    /// signatures are calculated over. The placement of the file's shards (the
+    /// holders, and the number, ids, sizes, compression and wrapped key of the
+    /// shards) is cleared too, since a put shards and places the file again
+    /// after it is signed. The bytes of the file are still covered by the
+    /// signature, so shards that don't reconstruct them are caught.
+    fn unsigned_bytes(&self) -> bincode::Result<Vec<u8>> {
+        let mut unsigned = self.clone();
+        unsigned.signature = Vec::new();
+        unsigned.metadata_signature = Vec::new();
+        unsigned.shards = Vec::new();
+        unsigned.shard_ids = Vec::new();
+        unsigned.shard_config.shard_count = 0;
+        unsigned.shard_config.sizes = Vec::new();
+        unsigned.shard_config.compressed = Vec::new();
+        unsigned.shard_config.wrapped_key = None;
+        unsigned.to_bytes()
+    }
+
+    /// Export a self-contained, signed bundle of this file's metadata (id,
+    /// checksum, shard map, owner public key, and signatures). The bundle can
+    /// be checked with `verify_manifest` without any network access or the
+    /// data of the file. The shard map isn't signed (see `unsigned_bytes`).
+    pub fn export_manifest(&self) -> Result<Vec<u8>, PrimitiveError> {
+        bincode::serialize(&Manifest {
+            version: MANIFEST_VERSION,
+            file: self.clone(),
+        })
+        .map_err(|e| PrimitiveError::SerializationError(e))
+    }
+
+    /// Verify a bundle produced by `export_manifest` offline, and return the
+    /// file it describes if it is authentic.
+    pub fn verify_manifest(bytes: &[u8]) -> Result<Self, PrimitiveError> {
+        let manifest: Manifest = bincode::deserialize(bytes)
+            .map_err(|e| PrimitiveError::SerializationError(e))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(PrimitiveError::InvalidManifest(GeneralError::new(
+                format!("unsupported manifest version {}", manifest.version)
+                    .as_str(),
+            )));
+        }
+        let file = manifest.file;
+        check_metadata(&file.metadata)?;
+        file.verify_metadata()?;
+        Ok(file)
+    }
+
+    /// Check the metadata signature of the file without its bytes: that its
+    /// owner holds the key the file was signed with, and that the metadata is
+    /// what the owner signed. A public file passes as long as it is unsigned
+    /// and has no owner.
+    pub fn verify_metadata(&self) -> Result<(), PrimitiveError> {
+        if self.public {
+            if !self.owner.is_empty()
+                || !self.signature.is_empty()
+                || !self.metadata_signature.is_empty()
+            {
+                return Err(PrimitiveError::InvalidSignature);
+            }
+            return Ok(());
+        }
+
+        // The owner must be the holder of the key that signed the file
+        let libp2p_pk = crypto::ecies_pub_to_libp2p(&self.shard_config.pub_key)
+            .map_err(|e| PrimitiveError::CryptoError(e))?;
+        if PeerId::from_public_key(libp2p_pk.clone()).to_bytes() != self.owner {
+            return Err(PrimitiveError::InvalidOwner);
+        }
+
+        let unsigned = self
+            .unsigned_bytes()
+            .map_err(|e| PrimitiveError::SerializationError(e))?;
+        if !libp2p_pk.verify(&unsigned, &self.metadata_signature) {
+            return Err(PrimitiveError::InvalidSignature);
+        }
+        Ok(())
     }
 
     /// Set the numer of shards and shard addresses given a list of PeerIds.
@@ -250,10 +799,138 @@ impl File {
         self.shards = peers.iter().map(|p| p.to_bytes()).collect();
     }
 
+    /// Decode the peer id of the owner of the file: the peer whose key signed
+    /// it. Fails for a public file, which has no owner.
+    pub fn owner(&self) -> Result<PeerId, PrimitiveError> {
+        PeerId::from_bytes(&self.owner).map_err(|_| PrimitiveError::InvalidOwner)
+    }
+
+    /// Check whether the file is public (unsigned and without an owner).
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
     /// Read the shards of this file as a reference.
     pub fn shards(&self) -> &Vec<PeerIdSerial> {
         &self.shards
     }
+
+    /// Set the ids of the file's current shards (after the file is resharded).
+    pub fn set_shard_ids(&mut self, shards: &[Shard]) {
+        self.shard_ids = shards.iter().map(|s| s.id.clone()).collect();
+    }
+
+    /// Read the ids of the file's current shards, in order.
+    pub fn shard_ids(&self) -> &Vec<ShardID> {
+        &self.shard_ids
+    }
+
+    /// The size (in bytes) of each of the file's shards, in order.
+    pub fn shard_sizes(&self) -> &[usize] {
+        &self.shard_config.sizes
+    }
+
+    /// The number of shards the file was split into.
+    pub fn shard_count(&self) -> usize {
+        self.shard_config.shard_count
+    }
+
+    /// Put a mix of shards (possibly from several sharding passes, with stale,
+    /// duplicate, or renumbered shards) into the order described by this file's
+    /// shard ids. Only the valid shards that belong to the current set are
+    /// kept, and each is given its correct index.
+    pub fn canonical_shard_order(
+        &self,
+        shards: &[Shard],
+    ) -> Result<Vec<Shard>, PrimitiveError> {
+        self.shard_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let mut shard = shards
+                    .iter()
+                    .find(|s| &s.id == id && s.is_valid())
+                    .cloned()
+                    .ok_or(PrimitiveError::MissingShard(i))?;
+                shard.set_index(i as u32);
+                Ok(shard)
+            })
+            .collect()
+    }
+
+    /// Calculate how many bytes this file takes up once it is stored on the
+    /// network, accounting for compression, encryption, parity, and replication.
+    pub fn storage_overhead(&self) -> StorageOverhead {
+        let config = &self.shard_config;
+
+        // The config may have come from anywhere, so the sizes saturate instead
+        // of overflowing
+        let data_bytes = config
+            .sizes
+            .iter()
+            .fold(0u64, |sum, size| sum.saturating_add(*size as u64));
+        let parity_bytes = (config.parity_shards as u64)
+            .saturating_mul(config.sizes.iter().max().copied().unwrap_or(0) as u64);
+        let stored_bytes = data_bytes
+            .saturating_add(parity_bytes)
+            .saturating_mul(config.replicas as u64);
+
+        StorageOverhead {
+            original_bytes: self.size,
+            stored_bytes,
+            expansion_ratio: if self.size == 0 {
+                0.0
+            } else {
+                stored_bytes as f64 / self.size as f64
+            },
+            redundancy_factor: if data_bytes == 0 {
+                0.0
+            } else {
+                stored_bytes as f64 / data_bytes as f64
+            },
+        }
+    }
+}
+
+/// Check that labels fit in `MAX_METADATA_BYTES`.
+fn check_metadata(
+    metadata: &BTreeMap<String, String>,
+) -> Result<(), PrimitiveError> {
+    let bytes = metadata
+        .iter()
+        .fold(0usize, |sum, (k, v)| sum.saturating_add(k.len() + v.len()));
+    if bytes > MAX_METADATA_BYTES {
+        return Err(PrimitiveError::MetadataTooLarge(bytes));
+    }
+    Ok(())
+}
+
+/// The version of the manifest format produced by `File::export_manifest`.
+const MANIFEST_VERSION: u8 = 1;
+
+/// The bundle produced by `File::export_manifest`.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u8,
+    file: File,
+}
+
+/// A breakdown of the on-network footprint of a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageOverhead {
+    /// The size of the original file
+    pub original_bytes: u64,
+
+    /// The total number of bytes stored across all shards and their replicas
+    pub stored_bytes: u64,
+
+    /// How much larger the stored data is than the original file
+    /// (`stored_bytes / original_bytes`, or 0 for an empty file)
+    pub expansion_ratio: f64,
+
+    /// How many times over the sharded data is stored, counting parity shards
+    /// and replicas (or 0 if there is no data)
+    pub redundancy_factor: f64,
 }
 
 impl PartialEq for File {
@@ -281,34 +958,649 @@ impl CanSerialize for File {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::crypto::encryption;
     use crate::primitives::shard::ShardConfig;
-    use std::path::Path;
+    use crate::test_util::{
+        fuzzed, sample_file, sample_file_with, temp_file, test_keypair, SAMPLE,
+    };
 
     #[test]
     /// Test File::is_valid()
     fn is_valid() {
         // Test that it works when it should
-        let (sk1, pk1) = encryption::gen_keypair("testkey", false).unwrap();
-
-        let (mut file1, shards1) =
-            File::new(Path::new("testfile.txt"), ShardConfig::new(5, &pk1), &sk1)
-                .unwrap();
-
+        let (mut file1, shards1) = sample_file(SAMPLE, 5);
         assert!(file1.is_valid(&shards1, None));
 
         /*
         // Test that it doesn't work when the shards are wrong
-        let (sk2, pk2) = encryption::gen_keypair("testkey", false).unwrap();
-        let (mut file2, shards2) =
-            File::new(Path::new("Cargo.toml"), ShardConfig::new(5, &pk2), &sk2)
-                .unwrap();
+        let (mut file2, shards2) = sample_file(b"some other file", 5);
 
         assert_eq!(file2.is_valid(&shards1, None), false);
         assert_eq!(file1.is_valid(&shards2, None), false);
         */
     }
 
+    #[test]
+    fn validation_report() {
+        let (file, shards) = sample_file(SAMPLE, 3);
+        let good = ValidationReport {
+            checksum_ok: true,
+            merkle_ok: true,
+            file_id_ok: true,
+            signature_ok: true,
+            reconstructed_len: SAMPLE.len() as u64,
+        };
+        assert_eq!(file.validate(&shards, None).unwrap(), good);
+        assert!(good.all_ok());
+
+        let mut wrong_checksum = file.clone();
+        wrong_checksum.checksum[0] ^= 1;
+        let report = wrong_checksum.validate(&shards, None).unwrap();
+        assert_eq!(
+            report,
+            ValidationReport {
+                checksum_ok: false,
+                // The checksum is part of what is signed
+                signature_ok: false,
+                ..good
+            }
+        );
+        assert!(!report.all_ok());
+
+        let mut wrong_merkle = file.clone();
+        wrong_merkle.merkle_root[0] ^= 1;
+        assert_eq!(
+            wrong_merkle.validate(&shards, None).unwrap(),
+            ValidationReport {
+                merkle_ok: false,
+                signature_ok: false,
+                ..good
+            }
+        );
+
+        let mut wrong_id = file.clone();
+        wrong_id.creation_date += 1;
+        assert_eq!(
+            wrong_id.validate(&shards, None).unwrap(),
+            ValidationReport {
+                file_id_ok: false,
+                signature_ok: false,
+                ..good
+            }
+        );
+
+        let mut wrong_signature = file.clone();
+        wrong_signature.signature[0] ^= 1;
+        assert_eq!(
+            wrong_signature.validate(&shards, None).unwrap(),
+            ValidationReport {
+                signature_ok: false,
+                ..good
+            }
+        );
+
+        // Shards that can't be reconstructed at all are an error
+        assert!(file.validate(&shards[..2].to_vec(), None).is_err());
+    }
+
+    #[test]
+    fn edge_case_sizes() {
+        for bytes in [vec![], vec![7u8], vec![1u8, 2, 3]].iter() {
+            let (mut file, shards) = sample_file(bytes, 5);
+            assert_eq!(file.size, bytes.len() as u64);
+            assert_eq!(shards.len(), bytes.len());
+            assert!(file.is_valid(&shards, None));
+        }
+    }
+
+    #[test]
+    fn shard_accessors() {
+        let (file, shards) = sample_file(SAMPLE, 5);
+        assert_eq!(file.shard_count(), file.shard_config.shard_count);
+        assert_eq!(file.shard_sizes(), &file.shard_config.sizes[..]);
+        assert_eq!(file.shard_count(), shards.len());
+        let sizes: Vec<usize> = shards.iter().map(|s| s.size()).collect();
+        assert_eq!(file.shard_sizes(), &sizes[..]);
+
+        // Fewer bytes than shards get one shard per byte
+        let (file, _) = sample_file(b"ab", 5);
+        assert_eq!(file.shard_count(), 2);
+        assert_eq!(file.shard_sizes(), &[1, 1]);
+    }
+
+    #[test]
+    fn encrypted_is_valid() {
+        use crate::crypto::encryption::{EncryptionScheme, KeyDerivation};
+
+        let (sk, pk) = test_keypair();
+        let (other_sk, _) = test_keypair();
+        let schemes = [
+            (EncryptionScheme::Ecies, KeyDerivation::None),
+            (EncryptionScheme::Aes256Gcm, KeyDerivation::None),
+            (EncryptionScheme::Aes256Gcm, KeyDerivation::HkdfPerShard),
+        ];
+        for (scheme, key_derivation) in schemes.iter() {
+            let mut config = ShardConfig::new(4, &pk);
+            config.encrypt = true;
+            config.scheme = *scheme;
+            config.key_derivation = *key_derivation;
+            config.parity_shards = 1;
+            let (mut file, shards) = sample_file_with(SAMPLE, config, &sk);
+
+            // The shards hold the ciphertext, but the size and checksum
+            // describe the plaintext
+            let sizes = &file.shard_config.sizes;
+            assert!(sizes.iter().sum::<usize>() > SAMPLE.len());
+            for (shard, size) in shards.iter().zip(sizes.iter()) {
+                assert_eq!(shard.data.len(), *size);
+            }
+            assert_eq!(file.size, SAMPLE.len() as u64);
+            assert_eq!(file.checksum, file.checksum_algorithm.checksum(SAMPLE));
+
+            assert!(file.is_valid(&shards, Some(&sk)), "{:?}", scheme);
+            assert!(!file.is_valid(&shards, None));
+            assert!(!file.is_valid(&shards, Some(&other_sk)));
+        }
+    }
+
+    #[test]
+    fn reconstruct_to_path() {
+        let bytes: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let (file, shards) = sample_file(&bytes, 5);
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+
+        let written = file.reconstruct_to_path(&shards, None, &out).unwrap();
+        assert_eq!(written, bytes.len() as u64);
+        assert!(fs::read(&out).unwrap() == bytes);
+
+        // A failed reconstruction leaves an existing output as it was
+        let existing = dir.path().join("existing");
+        fs::write(&existing, b"existing").unwrap();
+        let mut wrong = file.clone();
+        wrong.checksum[0] ^= 1;
+        assert!(wrong.reconstruct_to_path(&shards, None, &existing).is_err());
+        assert_eq!(fs::read(&existing).unwrap(), b"existing");
+        let out = dir.path().join("failed");
+
+        let corrupt_shard = |e: Box<dyn Error>| match e.downcast_ref() {
+            Some(PrimitiveError::CorruptShard(index)) => *index,
+            _ => panic!("expected a corrupt shard, got {:?}", e),
+        };
+
+        // A corrupted shard in the middle of the file is named, and nothing of
+        // the output remains
+        let mut corrupted = shards.clone();
+        corrupted[2].data[0] ^= 1;
+        let e = file
+            .reconstruct_to_path(&corrupted, None, &out)
+            .unwrap_err();
+        assert_eq!(corrupt_shard(e), 2);
+        assert!(!out.exists());
+
+        // So is a shard that is valid on its own but isn't the file's
+        let mut forged = shards.clone();
+        let mut data = forged[2].data.clone();
+        data[0] ^= 1;
+        forged[2] = Shard::new_at(data, 2, forged[2].timestamp());
+        assert!(forged[2].is_valid());
+        let e = file.reconstruct_to_path(&forged, None, &out).unwrap_err();
+        assert_eq!(corrupt_shard(e), 2);
+        assert!(!out.exists());
+
+        // Bytes that don't match the checksum are deleted once it is known
+        let mut wrong = file.clone();
+        wrong.checksum[0] ^= 1;
+        let e = wrong.reconstruct_to_path(&shards, None, &out).unwrap_err();
+        match e.downcast_ref() {
+            Some(PrimitiveError::ChecksumMismatch) => {}
+            _ => panic!("expected a checksum mismatch, got {:?}", e),
+        }
+        assert!(!out.exists());
+
+        // No temporary file is left behind either
+        let mut left: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["existing", "out"]);
+    }
+
+    #[test]
+    fn storage_overhead_plain() {
+        let (file, _) = sample_file(SAMPLE, 5);
+
+        let overhead = file.storage_overhead();
+        assert_eq!(overhead.original_bytes, file.size);
+        assert_eq!(overhead.stored_bytes, file.size);
+        assert_eq!(overhead.expansion_ratio, 1.0);
+        assert_eq!(overhead.redundancy_factor, 1.0);
+    }
+
+    #[test]
+    fn storage_overhead_compressed() {
+        let (sk, pk) = test_keypair();
+        let mut config = ShardConfig::new(5, &pk);
+        config.compress = true;
+        let (mut file, _) = sample_file_with(SAMPLE, config, &sk);
+
+        // Pretend that the data compressed down to half of its size
+        file.shard_config.sizes = vec![10; 5];
+        file.size = 100;
+
+        let overhead = file.storage_overhead();
+        assert_eq!(overhead.stored_bytes, 50);
+        assert_eq!(overhead.expansion_ratio, 0.5);
+        assert_eq!(overhead.redundancy_factor, 1.0);
+    }
+
+    #[test]
+    fn storage_overhead_encrypted() {
+        let (sk, pk) = test_keypair();
+        let mut config = ShardConfig::new(5, &pk);
+        config.encrypt = true;
+        let (file, _) = sample_file_with(SAMPLE, config, &sk);
+
+        // The ciphertext is larger than the plaintext
+        let overhead = file.storage_overhead();
+        assert!(overhead.stored_bytes > file.size);
+        assert!(overhead.expansion_ratio > 1.0);
+        assert_eq!(overhead.redundancy_factor, 1.0);
+    }
+
+    #[test]
+    fn storage_overhead_erasure_coded() {
+        let (mut file, _) = sample_file(SAMPLE, 5);
+
+        file.size = 100;
+        file.shard_config.sizes = vec![20, 20, 20, 20, 20];
+        file.shard_config.parity_shards = 2;
+        file.shard_config.replicas = 3;
+
+        let overhead = file.storage_overhead();
+        assert_eq!(overhead.stored_bytes, (100 + 2 * 20) * 3);
+        assert_eq!(overhead.expansion_ratio, 4.2);
+        assert_eq!(overhead.redundancy_factor, 4.2);
+    }
+
+    #[test]
+    fn file_id_try_from_slice() {
+        let (fid, _) = FileID::new("filename", &vec![1u8, 2u8, 3u8]).unwrap();
+        let key = fid.to_bytes().unwrap();
+        assert_eq!(FileID::try_from_slice(&key).unwrap(), fid);
+
+        for len in [0, 31, 33, 64].iter() {
+            match FileID::try_from_slice(&vec![1u8; *len]) {
+                Err(PrimitiveError::InvalidLength(l)) => assert_eq!(l, *len),
+                r => panic!("expected an invalid length, got {:?}", r),
+            }
+        }
+        assert!(FileID::from_hex("abcd").is_err());
+        assert!(FileID::from_hex(&"ab".repeat(40)).is_err());
+    }
+
+    #[test]
+    fn public_file() {
+        let (_, pk) = test_keypair();
+        let path = temp_file(SAMPLE);
+
+        let (mut public, shards) =
+            File::new_public(&path, ShardConfig::new(3, &pk)).unwrap();
+        assert!(public.public);
+        assert!(public.owner.is_empty());
+        assert!(public.is_valid(&shards, None));
+
+        // A public file whose contents were changed is still caught
+        let (_, other_shards) = sample_file(b"other contents", 3);
+        assert!(!public.is_valid(&other_shards, None));
+
+        // A signed file can't claim to be public to skip its signature check
+        let (mut signed, signed_shards) = sample_file(SAMPLE, 3);
+        signed.public = true;
+        assert!(!signed.is_valid(&signed_shards, None));
+
+        // And a public file can't pretend to be signed
+        public.public = false;
+        assert!(!public.is_valid(&shards, None));
+
+        let mut config = ShardConfig::new(3, &pk);
+        config.encrypt = true;
+        assert!(File::new_public(&path, config).is_err());
+    }
+
+    #[test]
+    fn checksum_algorithms() {
+        let (sk, pk) = test_keypair();
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::Blake3,
+            ChecksumAlgorithm::Sha256,
+        ]
+        .iter()
+        {
+            let mut config = ShardConfig::new(3, &pk);
+            config.checksum_algorithm = *algorithm;
+            let (mut file, shards) = sample_file_with(SAMPLE, config, &sk);
+            assert_eq!(file.checksum_algorithm, *algorithm);
+            assert_eq!(file.checksum, algorithm.checksum(SAMPLE));
+            assert!(file.is_valid(&shards, None));
+
+            // The algorithm survives serialization
+            let decoded = File::from_bytes(file.to_bytes().unwrap()).unwrap();
+            assert_eq!(decoded.checksum_algorithm, *algorithm);
+            assert_eq!(decoded.checksum, file.checksum);
+        }
+        assert_eq!(
+            ShardConfig::new(3, &pk).checksum_algorithm,
+            ChecksumAlgorithm::Crc32
+        );
+    }
+
+    #[test]
+    fn merkle_detects_swapped_shards() {
+        let (_, pk) = test_keypair();
+        let bytes: Vec<u8> = (0..4 * MERKLE_LEAF_SIZE as u32)
+            .map(|i| (i / MERKLE_LEAF_SIZE as u32) as u8)
+            .collect();
+        let path = temp_file(&bytes);
+        let (mut file, shards) =
+            File::new_public(&path, ShardConfig::new(4, &pk)).unwrap();
+        assert!(file.verify(&shards, None).is_ok());
+
+        // Swap the data of the first two shards, keeping every byte. Even if
+        // the checksum coincidentally matched, the merkle root wouldn't.
+        let swapped = [
+            &shards[1].data[..],
+            &shards[0].data[..],
+            &bytes[2 * MERKLE_LEAF_SIZE..],
+        ]
+        .concat();
+        assert_eq!(swapped.len(), bytes.len());
+        let (swapped_shards, _) =
+            Shard::shard(&swapped, file.shard_config.clone()).unwrap();
+        match file.verify(&swapped_shards, None) {
+            Err(e) => match e.downcast_ref::<PrimitiveError>() {
+                Some(PrimitiveError::ChecksumMismatch) => (),
+                other => panic!("unexpected error: {:?}", other),
+            },
+            Ok(()) => panic!("swapped shards were accepted"),
+        }
+
+        file.checksum = file.checksum_algorithm.checksum(&swapped);
+        match file.verify(&swapped_shards, None) {
+            Err(e) => match e.downcast_ref::<PrimitiveError>() {
+                Some(PrimitiveError::MerkleMismatch) => (),
+                other => panic!("unexpected error: {:?}", other),
+            },
+            Ok(()) => panic!("swapped shards were accepted"),
+        }
+        assert!(!file.is_valid(&swapped_shards, None));
+    }
+
+    #[test]
+    fn blake3_detects_bit_flip() {
+        let (sk, pk) = test_keypair();
+        let mut config = ShardConfig::new(1, &pk);
+        config.checksum_algorithm = ChecksumAlgorithm::Blake3;
+        let (mut file, mut shards) = sample_file_with(SAMPLE, config, &sk);
+
+        let mut flipped = SAMPLE.to_vec();
+        flipped[0] ^= 1;
+        assert_ne!(ChecksumAlgorithm::Blake3.checksum(&flipped), file.checksum);
+
+        // A shard rebuilt around the flipped bit is itself valid, but the file
+        // still isn't
+        shards[0] = Shard::new(flipped, 0).unwrap();
+        assert!(shards[0].is_valid());
+        assert!(!file.is_valid(&shards, None));
+    }
+
+    #[test]
+    fn owner() {
+        let (sk, pk) = test_keypair();
+        let (file, _) = sample_file_with(SAMPLE, ShardConfig::new(3, &pk), &sk);
+
+        let keypair = crypto::ecies_to_libp2p(&sk, &pk).unwrap();
+        assert_eq!(
+            file.owner().unwrap(),
+            PeerId::from_public_key(keypair.public())
+        );
+
+        let (public, _) =
+            File::new_public(&temp_file(SAMPLE), ShardConfig::new(3, &pk)).unwrap();
+        assert!(public.is_public());
+        match public.owner() {
+            Err(PrimitiveError::InvalidOwner) => {}
+            r => panic!("expected no owner, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn resign() {
+        let (sk, pk) = test_keypair();
+        let (mut file, shards) =
+            sample_file_with(SAMPLE, ShardConfig::new(3, &pk), &sk);
+        let old = file.clone();
+
+        file.filename = "renamed.txt".to_string();
+        assert!(!file.is_valid(&shards, None));
+        file.resign(SAMPLE, &sk).unwrap();
+        assert!(file.is_valid(&shards, None));
+        assert_ne!(file.signature, old.signature);
+        assert!(File::verify_manifest(&file.export_manifest().unwrap()).is_ok());
+
+        // The old signatures don't cover the edited metadata
+        let mut stale = file.clone();
+        stale.signature = old.signature.clone();
+        stale.metadata_signature = old.metadata_signature.clone();
+        assert!(!stale.is_valid(&shards, None));
+        match File::verify_manifest(&stale.export_manifest().unwrap()) {
+            Err(PrimitiveError::InvalidSignature) => {}
+            r => panic!("expected an invalid signature, got {:?}", r),
+        }
+
+        // Only the owner can sign the file
+        let (other_sk, _) = test_keypair();
+        match file.resign(SAMPLE, &other_sk) {
+            Err(PrimitiveError::InvalidOwner) => {}
+            r => panic!("expected an invalid owner, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn placement_unsigned() {
+        let (sk, pk) = test_keypair();
+        let (mut file, _) = sample_file_with(SAMPLE, ShardConfig::new(3, &pk), &sk);
+
+        // A put shards and places the file again without signing it
+        let mut config = file.shard_config.clone();
+        config.shard_count = 4;
+        let (shards, config) = Shard::shard(&SAMPLE.to_vec(), config).unwrap();
+        file.shard_config = config;
+        file.set_shards(&vec![PeerId::random(); shards.len()]);
+        file.set_shard_ids(&shards);
+        assert!(file.is_valid(&shards, None));
+        assert!(File::verify_manifest(&file.export_manifest().unwrap()).is_ok());
+
+        // How the file is sharded is still signed
+        file.shard_config.parity_shards = 1;
+        match File::verify_manifest(&file.export_manifest().unwrap()) {
+            Err(PrimitiveError::InvalidSignature) => {}
+            r => panic!("expected an invalid signature, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let (sk, pk) = test_keypair();
+        let mut config = ShardConfig::new(3, &pk);
+        config
+            .metadata
+            .insert("project".to_string(), "alpha".to_string());
+        let (mut file, shards) = sample_file_with(SAMPLE, config, &sk);
+        assert_eq!(file.metadata().get("project").unwrap(), "alpha");
+        assert!(file.shard_config.metadata.is_empty());
+        assert!(file.is_valid(&shards, None));
+
+        // The labels survive a round trip through the DHT and a manifest
+        let decoded = File::from_bytes(file.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.metadata(), file.metadata());
+        let verified = File::verify_manifest(&file.export_manifest().unwrap());
+        assert_eq!(verified.unwrap().metadata(), file.metadata());
+
+        // Setting them signs the file again
+        let mut labels = BTreeMap::new();
+        labels.insert("archived".to_string(), "true".to_string());
+        file.set_metadata(labels.clone(), SAMPLE, &sk).unwrap();
+        assert_eq!(file.metadata(), &labels);
+        assert!(file.is_valid(&shards, None));
+
+        // Labels changed by anyone else invalidate the signatures
+        let mut tampered = file.clone();
+        tampered
+            .metadata
+            .insert("archived".to_string(), "false".to_string());
+        assert!(!tampered.is_valid(&shards, None));
+        match File::verify_manifest(&tampered.export_manifest().unwrap()) {
+            Err(PrimitiveError::InvalidSignature) => {}
+            r => panic!("expected an invalid signature, got {:?}", r),
+        }
+        let (other_sk, _) = test_keypair();
+        assert!(file
+            .set_metadata(BTreeMap::new(), SAMPLE, &other_sk)
+            .is_err());
+        assert_eq!(file.metadata(), &labels);
+
+        // The labels are bounded
+        let mut huge = BTreeMap::new();
+        huge.insert("notes".to_string(), "x".repeat(MAX_METADATA_BYTES));
+        match file.set_metadata(huge.clone(), SAMPLE, &sk) {
+            Err(PrimitiveError::MetadataTooLarge(_)) => {}
+            r => panic!("expected too large metadata, got {:?}", r),
+        }
+        let mut config = ShardConfig::new(3, &pk);
+        config.metadata = huge;
+        assert!(File::new(&temp_file(SAMPLE), config, &sk).is_err());
+    }
+
+    #[test]
+    fn content_addressed_ids() {
+        let data = SAMPLE.to_vec();
+
+        let a = FileID::new_content_addressed("sample.txt", &data);
+        assert_eq!(a, FileID::new_content_addressed("sample.txt", &data));
+        assert_ne!(a, FileID::new_content_addressed("other.txt", &data));
+
+        let t1 = FileID::new_at("sample.txt", &data, 1_600_000_000);
+        let t2 = FileID::new_at("sample.txt", &data, 1_600_000_001);
+        assert_ne!(t1, t2);
+        assert_ne!(t1, a);
+        assert!(t1.matches("sample.txt", &data, 1_600_000_000));
+        assert!(!t1.matches("sample.txt", &data, 1_600_000_001));
+        let time = 1_600_000_000u128.to_be_bytes();
+        let input = [&b"sample.txt"[..], &data[..], &time[..]].concat();
+        assert_eq!(t1.id, hash::hash_bytes(input));
+
+        // The same file put twice gets the same content address
+        let (sk, pk) = test_keypair();
+        let mut config = ShardConfig::new(3, &pk);
+        config.content_addressed = true;
+        let path = temp_file(SAMPLE);
+        let (mut first, shards) = File::new(&path, config.clone(), &sk).unwrap();
+        let (second, _) = File::new(&path, config, &sk).unwrap();
+        assert_eq!(first.id, second.id);
+        assert!(first.is_valid(&shards, None));
+    }
+
+    #[test]
+    fn canonical_shard_order() {
+        let (mut file, current) = sample_file(SAMPLE, 5);
+
+        // Shards from an older sharding pass of the same data
+        let mut old_config = file.shard_config.clone();
+        old_config.shard_count = 3;
+        let (stale, _) = Shard::shard(&SAMPLE.to_vec(), old_config).unwrap();
+
+        // Mix them up, renumber one, and duplicate another
+        let mut mixed = stale.clone();
+        mixed.extend(current.iter().rev().cloned());
+        mixed[stale.len()].set_index(42);
+        mixed.push(current[0].clone());
+
+        let ordered = file.canonical_shard_order(&mixed).unwrap();
+        assert_eq!(ordered, current);
+        assert!(file.is_valid(&ordered, None));
+
+        // A missing shard of the current set can't be made up for
+        match file.canonical_shard_order(&mixed[..mixed.len() - 2]) {
+            Err(PrimitiveError::MissingShard(i)) => assert_eq!(i, 0),
+            r => panic!("expected a missing shard, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn manifest() {
+        let (file, _) = sample_file(SAMPLE, 5);
+
+        let manifest = file.export_manifest().unwrap();
+        let verified = File::verify_manifest(&manifest).unwrap();
+        assert_eq!(verified, file);
+        assert_eq!(verified.checksum, file.checksum);
+        assert_eq!(verified.owner, file.owner);
+    }
+
+    #[test]
+    fn manifest_tampered() {
+        let (file, _) = sample_file(SAMPLE, 5);
+
+        // Tampered metadata
+        let mut tampered = file.clone();
+        tampered.checksum[0] ^= 1;
+        match File::verify_manifest(&tampered.export_manifest().unwrap()) {
+            Err(PrimitiveError::InvalidSignature) => {}
+            r => panic!("expected an invalid signature, got {:?}", r),
+        }
+
+        // Someone else's key
+        let (_, other_pk) = test_keypair();
+        let mut tampered = file.clone();
+        tampered.shard_config.pub_key = other_pk;
+        assert!(File::verify_manifest(&tampered.export_manifest().unwrap()).is_err());
+
+        // Garbage
+        let mut manifest = file.export_manifest().unwrap();
+        manifest.truncate(manifest.len() / 2);
+        assert!(File::verify_manifest(&manifest).is_err());
+        assert!(File::verify_manifest(&[]).is_err());
+    }
+
+    #[test]
+    fn verify_metadata() {
+        let (file, _) = sample_file(SAMPLE, 3);
+        assert!(file.verify_metadata().is_ok());
+
+        // Metadata that its owner didn't sign
+        let mut tampered = file.clone();
+        tampered.filename = "tampered.txt".to_string();
+        match tampered.verify_metadata() {
+            Err(PrimitiveError::InvalidSignature) => {}
+            r => panic!("expected an invalid signature, got {:?}", r),
+        }
+
+        // Metadata that claims someone else as its owner
+        let mut tampered = file.clone();
+        tampered.owner = PeerId::random().to_bytes();
+        match tampered.verify_metadata() {
+            Err(PrimitiveError::InvalidOwner) => {}
+            r => panic!("expected an invalid owner, got {:?}", r),
+        }
+
+        // The placement of the shards isn't signed
+        let mut placed = file.clone();
+        placed.set_shards(&vec![PeerId::random(); 3]);
+        assert!(placed.verify_metadata().is_ok());
+    }
+
     #[test]
     fn hex() {
         let (fid, _) = FileID::new("filename", &vec![1u8, 2u8, 3u8]).unwrap();
@@ -325,4 +1617,36 @@ mod tests {
 
         assert_eq!(fid, new_fid);
     }
+
+    #[test]
+    fn decode_fuzzed() {
+        let (file, shards) = sample_file(SAMPLE, 3);
+
+        // Whatever a malformed file decodes to can be checked without panicking
+        for bytes in fuzzed(&file.to_bytes().unwrap(), 500) {
+            if let Ok(file) = File::from_bytes(bytes) {
+                let _ = file.owner();
+                let _ = file.storage_overhead();
+                let _ = file.canonical_shard_order(&shards);
+                let _ = file.verify(&shards, None);
+            }
+        }
+        for bytes in fuzzed(&file.export_manifest().unwrap(), 500) {
+            let _ = File::verify_manifest(&bytes);
+        }
+        for bytes in fuzzed(&file.id.to_bytes().unwrap(), 100) {
+            let _ = FileID::from_bytes(bytes.clone());
+            let _ = FileID::try_from_slice(&bytes);
+            let _ = FileID::from_hex(&String::from_utf8_lossy(&bytes));
+        }
+        for bytes in fuzzed(file.id.to_hex().as_bytes(), 100) {
+            let _ = FileID::from_hex(&String::from_utf8_lossy(&bytes));
+        }
+
+        // Sizes too large to add up saturate
+        let mut huge = file.clone();
+        huge.shard_config.sizes = vec![usize::MAX; 2];
+        huge.shard_config.parity_shards = usize::MAX;
+        assert_eq!(huge.storage_overhead().stored_bytes, u64::MAX);
+    }
 }
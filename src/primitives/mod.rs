@@ -1,5 +1,51 @@
 pub mod file;
 pub mod shard;
+pub mod sharding;
+
+use std::error::Error;
+use std::fmt;
+
+/// All of the errors that can be thrown by the Primitives module.
+#[derive(Debug)]
+pub enum PrimitiveError {
+    SerializationError(bincode::Error),
+    CryptoError(crate::crypto::CryptoError),
+    InvalidSignature,
+    InvalidOwner,
+    InvalidManifest(crate::GeneralError),
+    MissingShard(usize),
+    InvalidLength(usize),
+
+    /// The data would be larger (in bytes) than the reconstruction limit
+    TooLarge(u64),
+
+    /// The labels of a file take up more bytes than `file::MAX_METADATA_BYTES`
+    MetadataTooLarge(usize),
+
+    /// The checksum of the reconstructed data doesn't match that of the file
+    ChecksumMismatch,
+
+    /// The merkle root of the reconstructed data doesn't match that of the file
+    MerkleMismatch,
+
+    /// The shard at this index is corrupted, or isn't the shard that the file
+    /// has at this index
+    CorruptShard(u32),
+
+    /// A different number of shards was given than the config describes
+    ShardCountMismatch {
+        expected: usize,
+        got: usize,
+    },
+}
+
+impl fmt::Display for PrimitiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for PrimitiveError {}
 
 /// A trait given to types that are able to be hashed.
 trait Hashable: crate::CanSerialize {
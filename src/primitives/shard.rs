@@ -1,17 +1,26 @@
+use super::file::ChecksumAlgorithm;
+use super::sharding::Sharding;
+use super::PrimitiveError;
 use crate::{
-    crypto::{encryption, hash, hash::HASH_SIZE},
+    crypto::{encryption, hash, hash::HASH_SIZE, CryptoError},
     CanSerialize, GeneralError,
 };
 
 use ecies_ed25519::{PublicKey, SecretKey};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use math::round::floor;
+use rayon::prelude::*;
+use reed_solomon_erasure::galois_8::ReedSolomon;
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
     clone::Clone,
     cmp::PartialEq,
+    collections::{BTreeMap, HashMap},
     error::Error,
     fmt,
     hash::Hash,
+    io::{Read, Write},
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -26,14 +35,17 @@ impl ShardID {
     // Calculate a ShardID of the data in a shard.
     pub fn new(data: &Vec<u8>) -> Result<(Self, u128), Box<dyn Error>> {
         let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u128;
+        Ok((Self::new_at(data, time), time))
+    }
 
-        let data = [&data[..], time.to_string().as_bytes()].concat().to_vec();
-        Ok((
-            Self {
-                id: hash::hash_bytes(data),
-            },
-            time,
-        ))
+    /// Calculate the ShardID of the data in a shard created at the given time
+    /// (in seconds since the unix epoch). The time is mixed in as its 16
+    /// big-endian bytes.
+    pub fn new_at(data: &Vec<u8>, time: u128) -> Self {
+        let data = [&data[..], &time.to_be_bytes()[..]].concat().to_vec();
+        Self {
+            id: hash::hash_bytes(data),
+        }
     }
 
     /// Construct a ShardID from the bytes of a ShardID. This does not
@@ -42,11 +54,26 @@ impl ShardID {
         ShardID { id: bytes }
     }
 
+    /// Construct a ShardID from a slice of bytes, making sure that the slice is
+    /// exactly as long as a ShardID. This does not guarantee that the ShardID
+    /// is a valid ShardID.
+    pub fn try_from_slice(bytes: &[u8]) -> Result<Self, PrimitiveError> {
+        if bytes.len() != HASH_SIZE {
+            return Err(PrimitiveError::InvalidLength(bytes.len()));
+        }
+        let mut id = [0u8; HASH_SIZE];
+        id.copy_from_slice(bytes);
+        Ok(ShardID { id })
+    }
+
     /// Check that this ShardID matches that of the data and timestamp given.
     pub fn matches(&self, data: &Vec<u8>, time: u128) -> bool {
-        &ShardID::from_bytes(hash::hash_bytes(
-            [&data[..], time.to_string().as_bytes()].concat().to_vec(),
-        )) == self
+        &ShardID::new_at(data, time) == self
+    }
+
+    /// Peek at the raw bytes of the internal hash.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.id
     }
 }
 
@@ -89,14 +116,61 @@ pub struct ShardConfig {
     /// The owner's public key.
     pub pub_key: PublicKey,
 
-    /// Whether the shard is compressed or not
+    /// Whether the shards are compressed or not. Each data shard is only
+    /// compressed if that makes it smaller (see `compressed`).
     pub compress: bool,
 
+    /// Which of the data shards were compressed, in order. Set when the data
+    /// is sharded; a data shard past the end of it was not compressed.
+    pub compressed: Vec<bool>,
+
     /// Whether the shard is encrypted or not
     pub encrypt: bool,
 
-    /// The sizes of the shards, in order
+    /// How the data is encrypted, if it is
+    pub scheme: encryption::EncryptionScheme,
+
+    /// With the `Aes256Gcm` scheme, the symmetric key of the data encrypted to
+    /// the owner's public key. Set when the data is sharded.
+    pub wrapped_key: Option<Vec<u8>>,
+
+    /// How the keys of the shards are derived. With `HkdfPerShard`,
+    /// `wrapped_key` is the master key that the shard keys are derived from.
+    pub key_derivation: encryption::KeyDerivation,
+
+    /// The sizes of the shards, in order. When the data is encrypted, these
+    /// are the sizes of the stored ciphertext, not of the plaintext.
     pub sizes: Vec<usize>,
+
+    /// The number of parity shards stored in addition to the data shards.
+    /// Each parity shard is as large as the largest data shard. As long as no
+    /// more than this many shards are lost or corrupted, the data can be
+    /// recovered with `Shard::reconstruct_tolerant`.
+    pub parity_shards: usize,
+
+    /// The number of copies of every shard stored on the network
+    pub replicas: usize,
+
+    /// Whether the id of the file is derived from only its name and bytes (so
+    /// that the same file always gets the same id) instead of also a timestamp
+    pub content_addressed: bool,
+
+    /// The largest a single shard may be, in bytes. When set, the data is split
+    /// into more shards than `shard_count` if it has to be.
+    pub max_shard_bytes: Option<usize>,
+
+    /// The algorithm used to checksum the bytes of the file
+    pub checksum_algorithm: ChecksumAlgorithm,
+
+    /// How the bytes are split into shards. The shards are joined back
+    /// together with the same strategy.
+    pub sharding: Sharding,
+
+    /// The labels to tag a file made with this config with (see
+    /// `File::metadata`). They are moved into the file, so they are never
+    /// stored with the config.
+    #[serde(skip)]
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl fmt::Debug for ShardConfig {
@@ -105,25 +179,120 @@ impl fmt::Debug for ShardConfig {
             .field("shard_count", &self.shard_count)
             .field("pub_key", &self.pub_key.to_bytes())
             .field("compress", &self.compress)
+            .field("compressed", &self.compressed)
             .field("encrypt", &self.encrypt)
+            .field("scheme", &self.scheme)
+            .field("wrapped_key", &self.wrapped_key)
+            .field("key_derivation", &self.key_derivation)
             .field("sizes", &self.sizes)
+            .field("parity_shards", &self.parity_shards)
+            .field("replicas", &self.replicas)
+            .field("content_addressed", &self.content_addressed)
+            .field("max_shard_bytes", &self.max_shard_bytes)
+            .field("checksum_algorithm", &self.checksum_algorithm)
+            .field("sharding", &self.sharding)
+            .field("metadata", &self.metadata)
             .finish()
     }
 }
 
 impl ShardConfig {
+    /// The number of data and parity shards that the config describes (a
+    /// config without data shards has no parity shards either). Fails for a
+    /// config claiming more shards than can be counted.
+    pub fn total_shards(&self) -> Result<usize, PrimitiveError> {
+        match self.sizes.len() {
+            0 => Ok(0),
+            n => n
+                .checked_add(self.parity_shards)
+                .ok_or(PrimitiveError::InvalidLength(self.parity_shards)),
+        }
+    }
+
+    /// Whether the data shard at `index` was compressed.
+    pub fn is_compressed(&self, index: usize) -> bool {
+        self.compressed.get(index).copied().unwrap_or(false)
+    }
+
     /// Create the default shard config (will be overwritten by file::new())
     pub fn new(n_shards: usize, pk: &PublicKey) -> Self {
         Self {
             shard_count: n_shards,
             pub_key: pk.clone(),
             compress: false,
+            compressed: Vec::new(),
             encrypt: false,
+            scheme: encryption::EncryptionScheme::Ecies,
+            wrapped_key: None,
+            key_derivation: encryption::KeyDerivation::None,
             sizes: Vec::new(),
+            parity_shards: 0,
+            replicas: 1,
+            content_addressed: false,
+            max_shard_bytes: None,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            sharding: Sharding::default(),
+            metadata: BTreeMap::new(),
         }
     }
+
+    /// Recommend a config for a file of `size` bytes with the given MIME type.
+    ///
+    /// The heuristics are:
+    /// * Files under 64 KiB are kept in a single shard, since splitting them
+    ///   only adds overhead.
+    /// * Media (`image/*`, `video/*`, `audio/*`) aims for 16 MiB shards and
+    ///   allows shards of up to 64 MiB. Everything else aims for 1 MiB shards
+    ///   and allows shards of up to 16 MiB. The shard count never exceeds
+    ///   `MAX_SHARDS` unless the shard size limit requires it.
+    /// * Data is compressed if it is at least 1 KiB and its type isn't already
+    ///   compressed (media and archives). Data of an unknown type is
+    ///   compressed.
+    pub fn recommended_for(
+        size: u64,
+        content_type: Option<&str>,
+        pk: &PublicKey,
+    ) -> Self {
+        let content_type = content_type.unwrap_or("").to_lowercase();
+        let media = ["image/", "video/", "audio/"]
+            .iter()
+            .any(|t| content_type.starts_with(t));
+        let archive = [
+            "application/zip",
+            "application/gzip",
+            "application/x-7z-compressed",
+            "application/x-bzip2",
+            "application/x-xz",
+            "application/zstd",
+        ]
+        .contains(&content_type.as_str());
+
+        let (target_bytes, max_bytes) = if media {
+            (16 * MIB, 64 * MIB)
+        } else {
+            (MIB, 16 * MIB)
+        };
+
+        let shard_count = if size < 64 * KIB {
+            1
+        } else {
+            let count = (size + target_bytes - 1) / target_bytes;
+            count.max(1).min(crate::p2p::MAX_SHARDS as u64) as usize
+        };
+
+        let mut config = Self::new(shard_count, pk);
+        config.compress = size >= KIB && !media && !archive;
+        config.max_shard_bytes = Some(max_bytes as usize);
+        config
+    }
 }
 
+/// The number of bytes in a kibibyte.
+const KIB: u64 = 1024;
+
+/// The number of bytes in a mebibyte.
+const MIB: u64 = 1024 * KIB;
+
 impl CanSerialize for ShardConfig {
     type S = Self;
     fn to_bytes(&self) -> bincode::Result<Vec<u8>> {
@@ -152,6 +321,9 @@ pub struct Shard {
 
     // The index of the shard in a larger vector of shards
     index: u32,
+
+    // Whether the data of the shard is compressed
+    compressed: bool,
 }
 
 impl Shard {
@@ -165,34 +337,182 @@ impl Shard {
             timestamp,
             index,
             id,
+            compressed: false,
         })
     }
 
+    /// Create a new shard as if it were created at the given time (in seconds
+    /// since the unix epoch).
+    pub fn new_at(data: Vec<u8>, index: u32, timestamp: u128) -> Shard {
+        Shard {
+            id: ShardID::new_at(&data, timestamp),
+            size: data.len(),
+            data,
+            timestamp,
+            index,
+            compressed: false,
+        }
+    }
+
+    /// The size of the data in the shard.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The time at which the shard was created.
+    pub fn timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    /// The index of the shard in a larger vector of shards.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Whether the data of the shard is compressed.
+    pub fn compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// The bytes held by the shard, without copying them.
+    pub fn data_ref(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Take the bytes held by the shard, without copying them.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Move the shard to a different position in a larger vector of shards.
+    pub(crate) fn set_index(&mut self, index: u32) {
+        self.index = index;
+    }
+
     // Run various checks to determine if a shard is valid.
     pub fn is_valid(&self) -> bool {
         // Check the size and the fileID
         self.size == self.data.len() && self.id.matches(&self.data, self.timestamp)
     }
 
+    /// Like `is_valid`, but also check that the shard's index is in range for
+    /// a file of `total_shards` shards.
+    pub fn is_valid_for(&self, total_shards: u32) -> bool {
+        self.index < total_shards && self.is_valid()
+    }
+
     /// Given some bytes, split the bytes and return a vector of `Shard`s.
+    ///
+    /// Encrypted data is encrypted before it is split (or, with per-shard
+    /// keys, each piece right after it is split), so the shards hold the
+    /// ciphertext, which is larger than the plaintext. The `sizes` of the
+    /// returned config are the sizes of the stored shards, and so of the
+    /// ciphertext. Anything calculated over the plaintext (like the checksum
+    /// and id of a file) has to be calculated from the bytes given here, and is
+    /// checked against the output of `reconstruct`, which decrypts.
+    ///
+    /// With `config.compress`, each data shard is compressed on its own (before
+    /// it is encrypted with its own key), and only kept compressed if that
+    /// makes it smaller. Which shards were compressed is recorded both in the
+    /// shards and in the `compressed` flags of the returned config.
     pub fn shard(
         bytes: &Vec<u8>,
         config: ShardConfig,
     ) -> Result<(Vec<Shard>, ShardConfig), Box<dyn Error>> {
         // Encrypt the bytes
+        let mut config = config;
         let mut b = bytes;
         let mut a: Vec<u8> = Vec::new();
-        if config.encrypt {
-            a = encryption::encrypt_bytes(&config.pub_key, &b)?;
+        let mut master_key = None;
+        if config.encrypt
+            && config.key_derivation == encryption::KeyDerivation::HkdfPerShard
+        {
+            // Each shard is encrypted on its own once the bytes are split
+            if config.scheme != encryption::EncryptionScheme::Aes256Gcm {
+                return Err(Box::new(GeneralError::new(
+                    "per-shard keys need the Aes256Gcm scheme",
+                )));
+            }
+            let key = encryption::gen_symmetric_key();
+            config.wrapped_key =
+                Some(encryption::wrap_symmetric_key(&config.pub_key, &key)?);
+            master_key = Some(key);
+        } else if config.encrypt {
+            a = match config.scheme {
+                encryption::EncryptionScheme::Ecies => {
+                    encryption::encrypt_bytes(&config.pub_key, &b)?
+                }
+                encryption::EncryptionScheme::Aes256Gcm => {
+                    let key = encryption::gen_symmetric_key();
+                    config.wrapped_key =
+                        Some(encryption::wrap_symmetric_key(&config.pub_key, &key)?);
+                    encryption::encrypt_symmetric(&key, &b)?
+                }
+            };
         }
         // Clean this up, very hacky
         if a.len() > 0 {
             b = &a;
         }
 
-        // Shard the bytes
-        let sizes = calculate_shard_sizes(b.len(), config.shard_count)?;
-        let shards = split_bytes(&b, &sizes)?;
+        // Shard the bytes, using more shards if they would otherwise be too big
+        // (leaving room for the encryption of each shard, if it is encrypted)
+        let strategy = config.sharding.strategy();
+        let mut shards = strategy.split(b, config.shard_count)?;
+        if let Some(max) = config.max_shard_bytes {
+            let overhead = match master_key {
+                Some(_) => encryption::SYMMETRIC_OVERHEAD,
+                None => 0,
+            };
+            if max <= overhead {
+                return Err(Box::new(GeneralError::new(
+                    format!(
+                        "the maximum shard size must be at least {} bytes",
+                        overhead + 1
+                    )
+                    .as_str(),
+                )));
+            }
+            let max = max - overhead;
+            let mut n = config.shard_count.max((b.len() + max - 1) / max);
+            while shards.iter().any(|s| s.data.len() > max) {
+                shards = strategy.split(b, n)?;
+                n += 1;
+            }
+        }
+
+        // Compress the shards that get smaller when compressed
+        config.compressed = Vec::new();
+        if config.compress {
+            for shard in shards.iter_mut() {
+                let compressed = deflate(&shard.data)?;
+                if compressed.len() < shard.data.len() {
+                    *shard = Shard::new_at(compressed, shard.index, shard.timestamp);
+                    shard.compressed = true;
+                }
+            }
+            config.compressed = shards.iter().map(|s| s.compressed).collect();
+        }
+        let mut sizes: Vec<usize> = shards.iter().map(|s| s.data.len()).collect();
+
+        // Encrypt every shard with its own key
+        if let Some(master_key) = &master_key {
+            let time =
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u128;
+            shards = encrypt_shards(
+                &shards,
+                master_key,
+                time,
+                cfg!(feature = "parallel"),
+            )?;
+            sizes = shards.iter().map(|s| s.data.len()).collect();
+        }
+
+        // Add the Reed-Solomon parity shards after the data shards
+        if config.parity_shards > 0 && !shards.is_empty() {
+            let parity = parity_shards(&shards, config.parity_shards)?;
+            shards.extend(parity);
+        }
 
         // Update the config
         let mut new_config = config.clone();
@@ -209,43 +529,540 @@ impl Shard {
         config: &ShardConfig,
         private_key: Option<&SecretKey>,
     ) -> Result<Vec<u8>, Box<dyn Error>> {
-        // Reconstruct
-        let mut data: Vec<u8> = Vec::new();
-        let mut counter = 0;
+        Self::reconstruct_bounded(shards, config, private_key, u64::MAX)
+    }
+
+    /// Like `reconstruct_bounded`, but take the shards keyed by their index,
+    /// the way they are collected as they arrive from the network. The map
+    /// may only hold indices of the data and parity shards that `config`
+    /// describes, each with the shard of that index. Only the data shards are
+    /// needed: if any of them are missing or corrupted, they are recovered
+    /// from the parity shards with `reconstruct_tolerant`.
+    pub fn reconstruct_map(
+        mut shards: HashMap<u32, Shard>,
+        config: &ShardConfig,
+        private_key: Option<&SecretKey>,
+        max_bytes: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let expected = config.total_shards()?;
+        if shards.len() > expected {
+            return Err(Box::new(PrimitiveError::ShardCountMismatch {
+                expected,
+                got: shards.len(),
+            }));
+        }
+
+        let data_count = config.sizes.len() as u32;
+        let complete = (0..data_count).all(|index| {
+            shards
+                .get(&index)
+                .map_or(false, |shard| shard.index == index && shard.is_valid())
+        });
+        if !complete && config.parity_shards > 0 {
+            let placed: Vec<Shard> = shards
+                .into_iter()
+                .filter(|(index, shard)| shard.index == *index)
+                .map(|(_, shard)| shard)
+                .collect();
+            return Self::reconstruct_tolerant(
+                &placed,
+                config,
+                private_key,
+                max_bytes,
+            );
+        }
+
+        let mut ordered = Vec::with_capacity(data_count as usize);
+        for index in 0..data_count {
+            match shards.remove(&index) {
+                Some(shard) if shard.index == index => ordered.push(shard),
+                Some(_) => {
+                    return Err(Box::new(PrimitiveError::CorruptShard(index)))
+                }
+                None => {
+                    return Err(Box::new(PrimitiveError::MissingShard(
+                        index as usize,
+                    )))
+                }
+            }
+        }
+        Self::reconstruct_bounded(&ordered, config, private_key, max_bytes)
+    }
+
+    /// Like `reconstruct`, but refuse to reconstruct more than `max_bytes`
+    /// bytes. Both the sizes declared by `config` and the sizes of the given
+    /// shards are checked before anything is allocated, so metadata claiming
+    /// enormous shards can't exhaust the memory of the node, and compressed
+    /// shards are never decompressed past `max_bytes`. The shards must start
+    /// with the data shards that `config` describes, in order, and may be
+    /// followed by some or all of its parity shards.
+    pub fn reconstruct_bounded(
+        shards: &Vec<Shard>,
+        config: &ShardConfig,
+        private_key: Option<&SecretKey>,
+        max_bytes: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        check_declared(config, max_bytes)?;
+
+        // Reconstruct, ignoring the parity shards. Every data shard is the
+        // size that the config declares, so the data is too.
+        let data: Vec<&[u8]> = data_shards(shards, config)?
+            .iter()
+            .map(Shard::data_ref)
+            .collect();
+        join_and_decrypt(&data, config, private_key, max_bytes)
+    }
+
+    /// Like `reconstruct`, but write the bytes to `writer` as they are
+    /// reconstructed instead of returning them, and return how many bytes were
+    /// written. Encrypted shards are decrypted one at a time, so the whole
+    /// plaintext is never held next to the whole ciphertext. This needs the
+    /// shards to have been encrypted with per-shard keys: data encrypted as a
+    /// whole can only be decrypted as a whole. Striped shards are interleaved
+    /// once all of them are decrypted.
+    pub fn reconstruct_to_writer<W: Write>(
+        shards: &[Shard],
+        config: &ShardConfig,
+        private_key: Option<&SecretKey>,
+        writer: &mut W,
+    ) -> Result<u64, Box<dyn Error>> {
+        Self::reconstruct_to_writer_checked(
+            shards,
+            config,
+            private_key,
+            writer,
+            u64::MAX,
+            |_| Ok(()),
+        )
+    }
+
+    /// Like `reconstruct_to_writer`, but call `check` on every data shard
+    /// right before its bytes are written. The first error returned by
+    /// `check` stops the reconstruction, so nothing of the failing shard (or
+    /// of the shards after it) is written. Fails instead of writing more than
+    /// `max_bytes` bytes, without decompressing any shard past that.
+    pub fn reconstruct_to_writer_checked<W, F>(
+        shards: &[Shard],
+        config: &ShardConfig,
+        private_key: Option<&SecretKey>,
+        writer: &mut W,
+        max_bytes: u64,
+        mut check: F,
+    ) -> Result<u64, Box<dyn Error>>
+    where
+        W: Write,
+        F: FnMut(&Shard) -> Result<(), Box<dyn Error>>,
+    {
+        let data_shards = data_shards(shards, config)?;
+        let master_key = if !config.encrypt {
+            None
+        } else if config.key_derivation == encryption::KeyDerivation::HkdfPerShard {
+            Some(master_key(config, private_key)?)
+        } else {
+            return Err(Box::new(GeneralError::new(
+                "data encrypted as a whole can't be decrypted incrementally",
+            )));
+        };
+
+        // Whether a shard is compressed is taken from the config, not from the
+        // shard, which whoever sent it could have flagged as anything
+        let mut written = 0u64;
+        let mut remaining = max_bytes;
+        if config.sharding == Sharding::Contiguous {
+            for (index, shard) in data_shards.iter().enumerate() {
+                check(shard)?;
+                let bytes = plaintext_piece(
+                    shard,
+                    config.is_compressed(index),
+                    master_key.as_ref(),
+                    &mut remaining,
+                )?;
+                writer.write_all(&bytes)?;
+                written += bytes.len() as u64;
+            }
+        } else {
+            let mut pieces = Vec::with_capacity(data_shards.len());
+            for (index, shard) in data_shards.iter().enumerate() {
+                check(shard)?;
+                pieces.push(plaintext_piece(
+                    shard,
+                    config.is_compressed(index),
+                    master_key.as_ref(),
+                    &mut remaining,
+                )?);
+            }
+            let pieces: Vec<&[u8]> = pieces.iter().map(|p| p.as_ref()).collect();
+            let bytes = config.sharding.strategy().join(&pieces);
+            writer.write_all(&bytes)?;
+            written = bytes.len() as u64;
+        }
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Like `reconstruct`, but instead of failing on the first invalid shard,
+    /// treat the invalid and missing shards as erasures and recover them from
+    /// the parity shards. This works as long as at least as many valid shards
+    /// as there are data shards remain. The shards may be given in any order.
+    /// Like `reconstruct_bounded`, refuses to reconstruct more than
+    /// `max_bytes` bytes.
+    pub fn reconstruct_tolerant(
+        shards: &[Shard],
+        config: &ShardConfig,
+        private_key: Option<&SecretKey>,
+        max_bytes: u64,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data_shards = config.sizes.len();
+        let total = config.total_shards()?;
+        if data_shards == 0 {
+            return join_and_decrypt(&[], config, private_key, max_bytes);
+        }
+        check_declared(config, max_bytes)?;
+
+        // Nothing is allocated for a config claiming more shards (or larger
+        // shards) than it can have: the codec refuses too many parity shards,
+        // and a shard as large as the padding must have been given (either the
+        // largest data shard or a parity shard)
+        let codec = match config.parity_shards {
+            0 => None,
+            n => Some(ReedSolomon::new(data_shards, n)?),
+        };
+        let len = config.sizes.iter().copied().max().unwrap_or(0);
+        if !shards.iter().any(|shard| shard.data.len() == len) {
+            return Err(Box::new(GeneralError::new(
+                "none of the shards is as large as the largest shard of the config",
+            )));
+        }
+
+        // Place every valid shard in its slot, padded like it was for the parity
+        let mut slots: Vec<Option<Vec<u8>>> = vec![None; total];
         for shard in shards.iter() {
-            // For each shard
-            // Validate the shard
-            if shard.is_valid() && shard.index == counter {
-                // Extract the shard data
-                for byte in shard.data.iter() {
-                    data.push(*byte);
+            if shard.is_valid_for(total as u32) && shard.data.len() <= len {
+                slots[shard.index as usize] = Some(padded(&shard.data, len));
+            }
+        }
+
+        let valid = slots.iter().filter(|s| s.is_some()).count();
+        if valid < data_shards {
+            return Err(Box::new(GeneralError::new(
+                format!(
+                    "only {} of the {} shards needed to reconstruct are valid",
+                    valid, data_shards
+                )
+                .as_str(),
+            )));
+        }
+        if valid < total {
+            if let Some(codec) = codec {
+                codec.reconstruct_data(&mut slots)?;
+            }
+        }
+
+        // Remove the padding from the data shards
+        let mut data: Vec<Vec<u8>> = Vec::with_capacity(data_shards);
+        for (slot, size) in slots.into_iter().zip(config.sizes.iter()) {
+            match slot {
+                Some(mut bytes) => {
+                    bytes.truncate(*size);
+                    data.push(bytes);
+                }
+                None => {
+                    return Err(Box::new(GeneralError::new(
+                        "a data shard could not be recovered",
+                    )))
                 }
-            } else {
-                return Err(Box::new(GeneralError::new(
-                    "invalid shard; cannot use it to reconstruct",
-                )));
             }
-            counter += 1;
         }
 
-        // Decrypt if encrypted
-        if config.encrypt {
-            return match private_key {
-                Some(key) => Ok(encryption::decrypt_bytes(&key, &data).unwrap()),
-                None => Err(Box::new(GeneralError::new(
-                    "private key not given, cannot decrypt shard data",
-                ))),
-            };
+        join_and_decrypt(&slices(&data), config, private_key, max_bytes)
+    }
+}
+
+/// Check that the shards that `config` declares take no more than `max_bytes`
+/// bytes in total.
+fn check_declared(
+    config: &ShardConfig,
+    max_bytes: u64,
+) -> Result<(), PrimitiveError> {
+    let declared = config
+        .sizes
+        .iter()
+        .fold(0u64, |sum, size| sum.saturating_add(*size as u64));
+    if declared > max_bytes {
+        return Err(PrimitiveError::TooLarge(declared));
+    }
+    Ok(())
+}
+
+/// Check that `shards` are the data shards that `config` describes, followed
+/// by any of its parity shards, and return the data shards. Every data shard
+/// must be valid, in its place, and of the size that the config declares.
+fn data_shards<'a>(
+    shards: &'a [Shard],
+    config: &ShardConfig,
+) -> Result<&'a [Shard], Box<dyn Error>> {
+    let needed = config.sizes.len();
+    let expected = config.total_shards()?;
+    if shards.len() < needed || shards.len() > expected {
+        return Err(Box::new(PrimitiveError::ShardCountMismatch {
+            expected: if shards.len() < needed {
+                needed
+            } else {
+                expected
+            },
+            got: shards.len(),
+        }));
+    }
+
+    let data_shards = &shards[..config.sizes.len()];
+    for (index, (shard, size)) in
+        data_shards.iter().zip(config.sizes.iter()).enumerate()
+    {
+        if shard.data.len() != *size {
+            return Err(Box::new(PrimitiveError::InvalidLength(shard.data.len())));
+        }
+        if !shard.is_valid_for(expected as u32)
+            || shard.index != index as u32
+            || shard.compressed != config.is_compressed(index)
+        {
+            return Err(Box::new(PrimitiveError::CorruptShard(index as u32)));
+        }
+    }
+    Ok(data_shards)
+}
+
+/// Join the data of the data shards back together with the config's sharding
+/// strategy, decrypting it if the config says that it was encrypted. Shards
+/// that were each encrypted with their own key are decrypted before they are
+/// joined, and data that was encrypted as a whole after. The shards that the
+/// config says were compressed are decompressed right before they are joined,
+/// to no more than `max_bytes` bytes in total.
+fn join_and_decrypt(
+    shards: &[&[u8]],
+    config: &ShardConfig,
+    private_key: Option<&SecretKey>,
+    max_bytes: u64,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !config.encrypt {
+        return inflate_and_join(shards, config, max_bytes);
+    }
+    let key = match private_key {
+        Some(key) => key,
+        None => {
+            return Err(Box::new(GeneralError::new(
+                "private key not given, cannot decrypt shard data",
+            )))
+        }
+    };
+
+    if config.key_derivation == encryption::KeyDerivation::HkdfPerShard {
+        let decrypted = decrypt_per_shard(shards, config, key)?;
+        return inflate_and_join(&slices(&decrypted), config, max_bytes);
+    }
+
+    let data = inflate_and_join(shards, config, max_bytes)?;
+    match (config.scheme, &config.wrapped_key) {
+        (encryption::EncryptionScheme::Ecies, _) => {
+            Ok(encryption::decrypt_bytes(key, &data)?)
+        }
+        (encryption::EncryptionScheme::Aes256Gcm, Some(wrapped)) => {
+            let symmetric = encryption::unwrap_symmetric_key(key, wrapped)?;
+            Ok(encryption::decrypt_symmetric(&symmetric, &data)?)
+        }
+        (encryption::EncryptionScheme::Aes256Gcm, None) => Err(Box::new(
+            GeneralError::new("the symmetric key of the data is missing"),
+        )),
+    }
+}
+
+/// Unwrap the master key of shards that were each encrypted with their own
+/// key.
+fn master_key(
+    config: &ShardConfig,
+    private_key: Option<&SecretKey>,
+) -> Result<encryption::SymmetricKey, Box<dyn Error>> {
+    let private_key = match private_key {
+        Some(key) => key,
+        None => {
+            return Err(Box::new(GeneralError::new(
+                "private key not given, cannot decrypt shard data",
+            )))
+        }
+    };
+    match &config.wrapped_key {
+        Some(wrapped) => Ok(encryption::unwrap_symmetric_key(private_key, wrapped)?),
+        None => Err(Box::new(GeneralError::new(
+            "the master key of the shards is missing",
+        ))),
+    }
+}
+
+/// Decrypt the data of shards that were each encrypted with their own key,
+/// re-deriving every shard's key from the master key.
+fn decrypt_per_shard(
+    shards: &[&[u8]],
+    config: &ShardConfig,
+    private_key: &SecretKey,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let master_key = master_key(config, Some(private_key))?;
+
+    let mut decrypted = Vec::with_capacity(shards.len());
+    for (index, shard) in shards.iter().enumerate() {
+        let key = encryption::derive_shard_key(&master_key, index as u32);
+        decrypted.push(encryption::decrypt_symmetric(&key, shard)?);
+    }
+    Ok(decrypted)
+}
+
+/// Encrypt each shard with its own key derived from `master_key`, stamping the
+/// encrypted shards with `timestamp`. The shards are independent, so with
+/// `parallel` they are encrypted concurrently; either way the output is the
+/// same bytes in the same order.
+fn encrypt_shards(
+    shards: &[Shard],
+    master_key: &encryption::SymmetricKey,
+    timestamp: u128,
+    parallel: bool,
+) -> Result<Vec<Shard>, CryptoError> {
+    let encrypt = |shard: &Shard| -> Result<Shard, CryptoError> {
+        let key = encryption::derive_shard_key(master_key, shard.index);
+        let data = encryption::encrypt_symmetric_once(&key, &shard.data)?;
+        let mut encrypted = Shard::new_at(data, shard.index, timestamp);
+        encrypted.compressed = shard.compressed;
+        Ok(encrypted)
+    };
+    if parallel {
+        shards.par_iter().map(encrypt).collect()
+    } else {
+        shards.iter().map(encrypt).collect()
+    }
+}
+
+/// The plaintext of a shard that was encrypted with its own key derived from
+/// `master_key`, or the shard's bytes themselves (uncopied) if it wasn't
+/// encrypted.
+fn plaintext<'a>(
+    shard: &'a Shard,
+    master_key: Option<&encryption::SymmetricKey>,
+) -> Result<Cow<'a, [u8]>, Box<dyn Error>> {
+    match master_key {
+        Some(master) => {
+            let key = encryption::derive_shard_key(master, shard.index);
+            Ok(Cow::Owned(encryption::decrypt_symmetric(
+                &key,
+                &shard.data,
+            )?))
         }
-        Ok(data)
+        None => Ok(Cow::Borrowed(shard.data_ref())),
     }
 }
 
+/// The decrypted and decompressed bytes of a data shard, taking them out of
+/// the `remaining` bytes that may still be reconstructed.
+fn plaintext_piece<'a>(
+    shard: &'a Shard,
+    compressed: bool,
+    master_key: Option<&encryption::SymmetricKey>,
+    remaining: &mut u64,
+) -> Result<Cow<'a, [u8]>, Box<dyn Error>> {
+    let piece = inflate(plaintext(shard, master_key)?, compressed, *remaining)?;
+    *remaining = remaining
+        .checked_sub(piece.len() as u64)
+        .ok_or(PrimitiveError::TooLarge(piece.len() as u64))?;
+    Ok(piece)
+}
+
+/// Compress bytes with deflate.
+fn deflate(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompress bytes compressed with `deflate` if they are `compressed`, or
+/// leave them (uncopied) as they are. Fails instead of decompressing them to
+/// more than `max_bytes` bytes.
+fn inflate(
+    bytes: Cow<[u8]>,
+    compressed: bool,
+    max_bytes: u64,
+) -> Result<Cow<[u8]>, Box<dyn Error>> {
+    if !compressed {
+        return Ok(bytes);
+    }
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(bytes.as_ref())
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut inflated)?;
+    if inflated.len() as u64 > max_bytes {
+        return Err(Box::new(PrimitiveError::TooLarge(inflated.len() as u64)));
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+/// Decompress the data shards that the config says were compressed, and join
+/// them back together with the config's sharding strategy. Fails if they
+/// would take more than `max_bytes` bytes.
+fn inflate_and_join(
+    shards: &[&[u8]],
+    config: &ShardConfig,
+    max_bytes: u64,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut remaining = max_bytes;
+    let mut pieces = Vec::with_capacity(shards.len());
+    for (index, shard) in shards.iter().enumerate() {
+        let piece = inflate(
+            Cow::Borrowed(*shard),
+            config.is_compressed(index),
+            remaining,
+        )?;
+        remaining = remaining
+            .checked_sub(piece.len() as u64)
+            .ok_or(PrimitiveError::TooLarge(piece.len() as u64))?;
+        pieces.push(piece);
+    }
+    let pieces: Vec<&[u8]> = pieces.iter().map(|p| p.as_ref()).collect();
+    Ok(config.sharding.strategy().join(&pieces))
+}
+
+/// Borrow the bytes of each of some owned shards.
+fn slices(shards: &[Vec<u8>]) -> Vec<&[u8]> {
+    shards.iter().map(Vec::as_slice).collect()
+}
+
+/// Pad bytes with zeros to `len` bytes.
+fn padded(bytes: &[u8], len: usize) -> Vec<u8> {
+    let mut padded = bytes.to_vec();
+    padded.resize(len, 0);
+    padded
+}
+
+/// Calculate `n` Reed-Solomon parity shards for the given data shards. The
+/// data shards are padded with zeros to the size of the largest one, so every
+/// parity shard is that size.
+fn parity_shards(
+    data_shards: &[Shard],
+    n: usize,
+) -> Result<Vec<Shard>, Box<dyn Error>> {
+    let len = data_shards.iter().map(|s| s.data.len()).max().unwrap_or(0);
+    let mut blocks: Vec<Vec<u8>> =
+        data_shards.iter().map(|s| padded(&s.data, len)).collect();
+    blocks.extend((0..n).map(|_| vec![0u8; len]));
+    ReedSolomon::new(data_shards.len(), n)?.encode(&mut blocks)?;
+
+    blocks
+        .into_iter()
+        .enumerate()
+        .skip(data_shards.len())
+        .map(|(i, block)| Shard::new(block, i as u32))
+        .collect()
+}
+
 /// Split a vector of bytes as described by the `sizes` parameter and
 /// return properly distributed `Shard`s.
-fn split_bytes(
-    bytes: &Vec<u8>,
-    sizes: &Vec<usize>,
+pub(super) fn split_bytes(
+    bytes: &[u8],
+    sizes: &[usize],
 ) -> Result<Vec<Shard>, Box<dyn Error>> {
     // Validate the `sizes` vector
     if sizes.iter().sum::<usize>() != bytes.len() || sizes.contains(&0) {
@@ -272,19 +1089,36 @@ fn split_bytes(
 
 /// Calculate a vector of recommended shard data sizes for a given
 /// length of data and number of partitions. This algorithm calculates
-/// the most equal distribution of shard sizes.
-fn calculate_shard_sizes(
+/// the most equal distribution of shard sizes. Empty data has no shards, and
+/// data with fewer bytes than partitions gets one shard per byte.
+pub(super) fn calculate_shard_sizes(
     n_bytes: usize,
     n_partitions: usize,
 ) -> Result<Vec<usize>, Box<dyn Error>> {
     // Validate the inputs
-    if n_bytes == 0 || n_partitions == 0 || n_partitions > n_bytes {
+    if n_partitions == 0 {
         println!("n bytes: {}, n partitions: {}", n_bytes, n_partitions);
         return Err(Box::new(GeneralError::new(
             "invalid parameters to calculate shard sizes",
         )));
     }
 
+    // Empty data has no shards
+    if n_bytes == 0 {
+        return Ok(Vec::new());
+    }
+
+    // Every shard must hold at least one byte
+    let n_partitions = if n_partitions > n_bytes {
+        eprintln!(
+            "warning: cannot split {} bytes into {} shards, using {} shards",
+            n_bytes, n_partitions, n_bytes
+        );
+        n_bytes
+    } else {
+        n_partitions
+    };
+
     // The average byte size of each partition
     let avg = floor((n_bytes / n_partitions) as f64, 0) as usize;
 
@@ -328,6 +1162,751 @@ impl CanSerialize for Shard {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{fuzzed, test_keypair};
+
+    fn config(shard_count: usize) -> ShardConfig {
+        let (_, pk) = test_keypair();
+        ShardConfig::new(shard_count, &pk)
+    }
+
+    #[test]
+    fn test_shard_id_fixed_time() {
+        let data = vec![1u8, 2, 3];
+        let id = ShardID::new_at(&data, 1_600_000_000);
+
+        let mut input = data.clone();
+        input.extend_from_slice(&1_600_000_000u128.to_be_bytes());
+        let expected = hash::hash_bytes(input);
+        assert_eq!(id, ShardID::from_bytes(expected));
+        assert_eq!(id, ShardID::new_at(&data, 1_600_000_000));
+        assert_ne!(id, ShardID::new_at(&data, 1_600_000_001));
+        assert!(id.matches(&data, 1_600_000_000));
+
+        let shard = Shard::new_at(data, 0, 1_600_000_000);
+        assert_eq!(shard.id, id);
+        assert!(shard.is_valid());
+    }
+
+    #[test]
+    fn test_is_valid_for() {
+        let shard = Shard::new_at(vec![1, 2, 3], 2, 1_600_000_000);
+        assert!(shard.is_valid_for(3));
+        assert!(shard.is_valid_for(u32::MAX));
+        assert!(!shard.is_valid_for(2));
+        assert!(!shard.is_valid_for(0));
+
+        // An index far out of range is still fine on its own
+        let far = Shard::new_at(vec![1, 2, 3], u32::MAX, 1_600_000_000);
+        assert!(far.is_valid());
+        assert!(!far.is_valid_for(u32::MAX));
+    }
+
+    #[test]
+    fn test_shard_id_time_encoding() {
+        // Times that print the same digits in a different place don't collide
+        let data = vec![1u8];
+        assert_ne!(ShardID::new_at(&data, 1), ShardID::new_at(&vec![], 11));
+        assert_eq!(ShardID::new_at(&vec![1u8, 1], 0), {
+            let mut input = vec![1u8, 1];
+            input.extend_from_slice(&[0u8; 16]);
+            ShardID::from_bytes(hash::hash_bytes(input))
+        });
+
+        // The encoding is stable across calls
+        let (id, time) = ShardID::new(&data).unwrap();
+        assert_eq!(id, ShardID::new_at(&data, time));
+        assert!(id.matches(&data, time));
+        assert!(!id.matches(&data, time + 1));
+        assert!(ShardID::new_at(&data, u128::MAX).matches(&data, u128::MAX));
+    }
+
+    #[test]
+    fn test_shard_getters() {
+        let shard = Shard::new_at(vec![1u8, 2, 3, 4], 7, 1_600_000_000);
+        assert_eq!(shard.size(), 4);
+        assert_eq!(shard.timestamp(), 1_600_000_000);
+        assert_eq!(shard.index(), 7);
+    }
+
+    /// Shard some bytes into 4 data shards and `parity` parity shards.
+    fn shard_with_parity(parity: usize) -> (Vec<u8>, Vec<Shard>, ShardConfig) {
+        let bytes: Vec<u8> = (0..103u8).collect();
+        let mut c = config(4);
+        c.parity_shards = parity;
+        let (shards, new_config) = Shard::shard(&bytes, c).unwrap();
+        (bytes, shards, new_config)
+    }
+
+    #[test]
+    fn test_shard_symmetric_encryption() {
+        let (sk, pk) = test_keypair();
+        let bytes: Vec<u8> = (0..200u8).collect();
+        let mut c = ShardConfig::new(3, &pk);
+        c.encrypt = true;
+        c.scheme = encryption::EncryptionScheme::Aes256Gcm;
+
+        let (shards, new_config) = Shard::shard(&bytes, c).unwrap();
+        assert!(new_config.wrapped_key.is_some());
+        let data = Shard::reconstruct(&shards, &new_config, Some(&sk)).unwrap();
+        assert_eq!(data, bytes);
+
+        // Only the owner can unwrap the key
+        let (other_sk, _) = test_keypair();
+        assert!(Shard::reconstruct(&shards, &new_config, Some(&other_sk)).is_err());
+    }
+
+    #[test]
+    fn test_shard_per_shard_keys() {
+        let (sk, pk) = test_keypair();
+        let bytes: Vec<u8> = (0..200u8).collect();
+        let mut c = ShardConfig::new(4, &pk);
+        c.encrypt = true;
+        c.scheme = encryption::EncryptionScheme::Aes256Gcm;
+        c.key_derivation = encryption::KeyDerivation::HkdfPerShard;
+        c.parity_shards = 1;
+
+        let (shards, new_config) = Shard::shard(&bytes, c.clone()).unwrap();
+        let data = Shard::reconstruct(&shards, &new_config, Some(&sk)).unwrap();
+        assert_eq!(data, bytes);
+
+        // Each shard only opens with its own key
+        let wrapped = new_config.wrapped_key.as_ref().unwrap();
+        let master = encryption::unwrap_symmetric_key(&sk, wrapped).unwrap();
+        let key_0 = encryption::derive_shard_key(&master, 0);
+        let key_1 = encryption::derive_shard_key(&master, 1);
+        assert_ne!(key_0, key_1);
+        assert_eq!(
+            encryption::decrypt_symmetric(&key_0, &shards[0].data).unwrap(),
+            bytes[..50].to_vec()
+        );
+        assert!(encryption::decrypt_symmetric(&key_1, &shards[0].data).is_err());
+
+        // A lost shard is still recovered from the parity
+        let data = Shard::reconstruct_tolerant(
+            &shards[1..],
+            &new_config,
+            Some(&sk),
+            u64::MAX,
+        )
+        .unwrap();
+        assert_eq!(data, bytes);
+
+        // The shards leave room for their encryption under a size limit
+        c.max_shard_bytes = Some(60);
+        let (shards, _) = Shard::shard(&bytes, c.clone()).unwrap();
+        assert!(shards.iter().all(|s| s.size() <= 60));
+        c.max_shard_bytes = Some(encryption::SYMMETRIC_OVERHEAD);
+        assert!(Shard::shard(&bytes, c.clone()).is_err());
+
+        c.scheme = encryption::EncryptionScheme::Ecies;
+        c.max_shard_bytes = None;
+        assert!(Shard::shard(&bytes, c).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_shards_parallel() {
+        let master = [7u8; 32];
+        let bytes: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let shards =
+            split_bytes(&bytes, &calculate_shard_sizes(bytes.len(), 256).unwrap())
+                .unwrap();
+
+        let serial = encrypt_shards(&shards, &master, 1_600_000_000, false).unwrap();
+        let parallel =
+            encrypt_shards(&shards, &master, 1_600_000_000, true).unwrap();
+        assert_eq!(parallel, serial);
+        assert_eq!(
+            encryption::decrypt_symmetric(
+                &encryption::derive_shard_key(&master, 3),
+                &parallel[3].data
+            )
+            .unwrap(),
+            shards[3].data
+        );
+
+        // Encrypting many shards at once is no slower than one at a time (with
+        // plenty of slack for a busy machine)
+        let time = |parallel: bool| {
+            let start = std::time::Instant::now();
+            for _ in 0..8 {
+                encrypt_shards(&shards, &master, 0, parallel).unwrap();
+            }
+            start.elapsed()
+        };
+        let serial = time(false);
+        assert!(time(true) < serial * 4);
+    }
+
+    #[test]
+    fn test_reconstruct_to_writer() {
+        use std::io::Read;
+
+        let (sk, pk) = test_keypair();
+        let bytes: Vec<u8> = (0..4 * 1024 * 1024u32)
+            .map(|i| (i * 31 % 251) as u8)
+            .collect();
+        let mut c = ShardConfig::new(8, &pk);
+        c.encrypt = true;
+        c.scheme = encryption::EncryptionScheme::Aes256Gcm;
+        c.key_derivation = encryption::KeyDerivation::HkdfPerShard;
+        c.parity_shards = 2;
+
+        let (shards, new_config) = Shard::shard(&bytes, c.clone()).unwrap();
+        let mut out = tempfile::tempfile().unwrap();
+        let written =
+            Shard::reconstruct_to_writer(&shards, &new_config, Some(&sk), &mut out)
+                .unwrap();
+        assert_eq!(written, bytes.len() as u64);
+
+        let mut streamed = Vec::new();
+        std::io::Seek::seek(&mut out, std::io::SeekFrom::Start(0)).unwrap();
+        out.read_to_end(&mut streamed).unwrap();
+        assert!(streamed == bytes);
+
+        // Without the key, or with data encrypted as a whole, nothing streams
+        let mut sink = Vec::new();
+        assert!(
+            Shard::reconstruct_to_writer(&shards, &new_config, None, &mut sink)
+                .is_err()
+        );
+        c.key_derivation = encryption::KeyDerivation::None;
+        let (shards, whole) = Shard::shard(&bytes, c).unwrap();
+        assert!(
+            Shard::reconstruct_to_writer(&shards, &whole, Some(&sk), &mut sink)
+                .is_err()
+        );
+        assert!(sink.is_empty());
+
+        // Plain shards stream whatever their strategy
+        let mut c = ShardConfig::new(3, &pk);
+        c.sharding = Sharding::Striped;
+        let small: Vec<u8> = (0..100u8).collect();
+        let (shards, striped) = Shard::shard(&small, c).unwrap();
+        Shard::reconstruct_to_writer(&shards, &striped, None, &mut sink).unwrap();
+        assert_eq!(sink, small);
+    }
+
+    #[test]
+    fn test_striped_sharding() {
+        let (sk, pk) = test_keypair();
+        let bytes: Vec<u8> = (0..200u8).collect();
+        let mut c = ShardConfig::new(4, &pk);
+        c.sharding = Sharding::Striped;
+        c.parity_shards = 1;
+
+        let (shards, new_config) = Shard::shard(&bytes, c.clone()).unwrap();
+        assert_eq!(new_config.sharding, Sharding::Striped);
+        assert_eq!(shards[1].data[..3].to_vec(), vec![1, 5, 9]);
+        assert_eq!(
+            Shard::reconstruct(&shards, &new_config, None).unwrap(),
+            bytes
+        );
+        assert_eq!(
+            Shard::reconstruct_tolerant(&shards[1..], &new_config, None, u64::MAX)
+                .unwrap(),
+            bytes
+        );
+
+        // The shards are joined with the strategy recorded in the config
+        let mut contiguous = new_config.clone();
+        contiguous.sharding = Sharding::Contiguous;
+        assert_ne!(
+            Shard::reconstruct(&shards, &contiguous, None).unwrap(),
+            bytes
+        );
+
+        // Encrypted data is striped too, as a whole or shard by shard
+        c.encrypt = true;
+        c.scheme = encryption::EncryptionScheme::Aes256Gcm;
+        for key_derivation in [
+            encryption::KeyDerivation::None,
+            encryption::KeyDerivation::HkdfPerShard,
+        ]
+        .iter()
+        {
+            c.key_derivation = *key_derivation;
+            let (shards, new_config) = Shard::shard(&bytes, c.clone()).unwrap();
+            assert_eq!(
+                Shard::reconstruct(&shards, &new_config, Some(&sk)).unwrap(),
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_bounded() {
+        let bytes: Vec<u8> = (0..100u8).collect();
+        let (shards, mut c) = Shard::shard(&bytes, config(4)).unwrap();
+        assert_eq!(
+            Shard::reconstruct_bounded(&shards, &c, None, 100).unwrap(),
+            bytes
+        );
+        assert!(Shard::reconstruct_bounded(&shards, &c, None, 99).is_err());
+
+        // Metadata claiming enormous shards is refused before anything is
+        // allocated for them
+        c.sizes = vec![usize::MAX / 2, usize::MAX / 2, 1, 1];
+        match Shard::reconstruct_bounded(&shards, &c, None, 1 << 30) {
+            Err(e) => match e.downcast_ref::<PrimitiveError>() {
+                Some(PrimitiveError::TooLarge(size)) => {
+                    assert!(*size > 1 << 30)
+                }
+                _ => panic!("expected a too large error, got {:?}", e),
+            },
+            Ok(_) => panic!("an oversized file was reconstructed"),
+        }
+    }
+
+    #[test]
+    fn test_compress_per_shard() {
+        // A compressible header in the first two shards, and a random tail in
+        // the last two
+        let mut bytes = vec![7u8; 2048];
+        bytes.extend((0..2048).map(|_| rand::random::<u8>()));
+        let mut c = config(4);
+        c.compress = true;
+        c.parity_shards = 1;
+        let (shards, c) = Shard::shard(&bytes, c).unwrap();
+
+        let flags: Vec<bool> =
+            shards.iter().take(4).map(Shard::compressed).collect();
+        assert_eq!(flags, vec![true, true, false, false]);
+        assert_eq!(c.compressed, flags);
+        assert!(c.sizes[0] < 1024 && c.sizes[3] == 1024);
+        assert_eq!(Shard::reconstruct(&shards, &c, None).unwrap(), bytes);
+
+        // A compressed shard recovered from the parity is still decompressed
+        let missing: Vec<Shard> = shards[1..].to_vec();
+        assert_eq!(
+            Shard::reconstruct_tolerant(&missing, &c, None, u64::MAX).unwrap(),
+            bytes
+        );
+        let mut streamed = Vec::new();
+        Shard::reconstruct_to_writer(&shards, &c, None, &mut streamed).unwrap();
+        assert_eq!(streamed, bytes);
+
+        // The data is never decompressed past the bound
+        assert!(Shard::reconstruct_bounded(&shards, &c, None, 4095).is_err());
+        assert!(Shard::reconstruct_tolerant(&missing, &c, None, 4095).is_err());
+        let mut sink = Vec::new();
+        assert!(Shard::reconstruct_to_writer_checked(
+            &shards,
+            &c,
+            None,
+            &mut sink,
+            4095,
+            |_| Ok(())
+        )
+        .is_err());
+
+        // A shard whose flag disagrees with the config is refused
+        let mut flipped = shards.clone();
+        flipped[0].compressed = false;
+        assert!(Shard::reconstruct(&flipped, &c, None).is_err());
+
+        // With per-shard keys, the shards are compressed before they are
+        // encrypted
+        let (sk, pk) = test_keypair();
+        let mut e = ShardConfig::new(4, &pk);
+        e.compress = true;
+        e.encrypt = true;
+        e.scheme = encryption::EncryptionScheme::Aes256Gcm;
+        e.key_derivation = encryption::KeyDerivation::HkdfPerShard;
+        let (shards, e) = Shard::shard(&bytes, e).unwrap();
+        assert_eq!(e.compressed, vec![true, true, false, false]);
+        assert_eq!(Shard::reconstruct(&shards, &e, Some(&sk)).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_reconstruct_large() {
+        let bytes: Vec<u8> =
+            (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        for sharding in [Sharding::Contiguous, Sharding::Striped].iter() {
+            let mut c = config(8);
+            c.sharding = *sharding;
+            let (shards, c) = Shard::shard(&bytes, c).unwrap();
+            assert!(Shard::reconstruct(&shards, &c, None).unwrap() == bytes);
+            let mut out = Vec::new();
+            Shard::reconstruct_to_writer(&shards, &c, None, &mut out).unwrap();
+            assert!(out == bytes);
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_map() {
+        let bytes: Vec<u8> = (0..100u8).collect();
+        let (shards, c) = Shard::shard(&bytes, config(4)).unwrap();
+        let map: HashMap<u32, Shard> =
+            shards.iter().map(|s| (s.index(), s.clone())).collect();
+
+        // Complete
+        assert_eq!(
+            Shard::reconstruct_map(map.clone(), &c, None, u64::MAX).unwrap(),
+            bytes
+        );
+
+        // A missing index
+        let mut missing = map.clone();
+        missing.remove(&2);
+        match Shard::reconstruct_map(missing, &c, None, u64::MAX)
+            .unwrap_err()
+            .downcast_ref::<PrimitiveError>()
+        {
+            Some(PrimitiveError::MissingShard(2)) => (),
+            e => panic!("expected shard 2 to be missing, got {:?}", e),
+        }
+
+        // An extra index
+        let mut extra = map.clone();
+        extra.insert(4, Shard::new(vec![0u8; 25], 4).unwrap());
+        match Shard::reconstruct_map(extra, &c, None, u64::MAX)
+            .unwrap_err()
+            .downcast_ref::<PrimitiveError>()
+        {
+            Some(PrimitiveError::ShardCountMismatch {
+                expected: 4,
+                got: 5,
+            }) => (),
+            e => panic!("expected a shard count mismatch, got {:?}", e),
+        }
+
+        // A shard under the key of another index
+        let mut swapped = map.clone();
+        swapped.insert(1, shards[3].clone());
+        swapped.insert(3, shards[1].clone());
+        assert!(Shard::reconstruct_map(swapped, &c, None, u64::MAX).is_err());
+
+        // More bytes than allowed
+        assert!(Shard::reconstruct_map(map, &c, None, 99).is_err());
+
+        // With parity, only the data shards are needed, and missing or
+        // corrupted data shards are recovered from the parity shards
+        let mut p = config(4);
+        p.parity_shards = 2;
+        let (shards, p) = Shard::shard(&bytes, p).unwrap();
+        let map: HashMap<u32, Shard> =
+            shards.iter().map(|s| (s.index(), s.clone())).collect();
+        let mut no_parity = map.clone();
+        no_parity.remove(&4);
+        no_parity.remove(&5);
+        assert_eq!(
+            Shard::reconstruct_map(no_parity, &p, None, u64::MAX).unwrap(),
+            bytes
+        );
+        let mut lost = map.clone();
+        lost.remove(&1);
+        lost.get_mut(&2).unwrap().data[0] ^= 1;
+        assert_eq!(
+            Shard::reconstruct_map(lost.clone(), &p, None, u64::MAX).unwrap(),
+            bytes
+        );
+        lost.remove(&5);
+        assert!(Shard::reconstruct_map(lost, &p, None, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_reconstruct_shard_count_mismatch() {
+        let bytes: Vec<u8> = (0..100u8).collect();
+        let (mut shards, c) = Shard::shard(&bytes, config(4)).unwrap();
+        let expect_mismatch =
+            |shards: &Vec<Shard>, got: usize| match Shard::reconstruct(
+                shards, &c, None,
+            ) {
+                Err(e) => match e.downcast_ref::<PrimitiveError>() {
+                    Some(PrimitiveError::ShardCountMismatch {
+                        expected,
+                        got: g,
+                    }) => {
+                        assert_eq!(*expected, 4);
+                        assert_eq!(*g, got);
+                    }
+                    _ => panic!("expected a shard count mismatch, got {:?}", e),
+                },
+                Ok(_) => panic!("reconstructed from {} shards", got),
+            };
+
+        // Too few shards
+        let truncated = shards[..3].to_vec();
+        expect_mismatch(&truncated, 3);
+
+        // Too many shards
+        let mut padded = shards.clone();
+        padded.push(Shard::new(vec![0u8; 25], 4).unwrap());
+        expect_mismatch(&padded, 5);
+
+        // A shard of a different size than the config declares
+        shards[1] = Shard::new(shards[1].data[..20].to_vec(), 1).unwrap();
+        match Shard::reconstruct(&shards, &c, None) {
+            Err(e) => match e.downcast_ref::<PrimitiveError>() {
+                Some(PrimitiveError::InvalidLength(l)) => assert_eq!(*l, 20),
+                _ => panic!("expected an invalid length, got {:?}", e),
+            },
+            Ok(_) => panic!("reconstructed from a truncated shard"),
+        }
+    }
+
+    #[test]
+    fn test_parity_shards() {
+        let (bytes, shards, new_config) = shard_with_parity(2);
+        assert_eq!(shards.len(), 6);
+        assert_eq!(new_config.shard_count, 4);
+        assert_eq!(shards[4].size(), 28);
+        assert_eq!(shards[5].index(), 5);
+
+        // The parity shards don't get in the way of a normal reconstruction
+        let data = Shard::reconstruct(&shards, &new_config, None).unwrap();
+        assert_eq!(data, bytes);
+        let data = Shard::reconstruct_tolerant(&shards, &new_config, None, u64::MAX)
+            .unwrap();
+        assert_eq!(data, bytes);
+    }
+
+    #[test]
+    fn test_reconstruct_tolerant() {
+        // One corrupted shard is recovered from the parity
+        let (bytes, mut shards, new_config) = shard_with_parity(1);
+        shards[2].data[0] ^= 0xFF;
+        assert!(Shard::reconstruct(&shards, &new_config, None).is_err());
+        let data = Shard::reconstruct_tolerant(&shards, &new_config, None, u64::MAX)
+            .unwrap();
+        assert_eq!(data, bytes);
+
+        // Even when the shards are out of order and one is missing
+        let (bytes, mut shards, new_config) = shard_with_parity(2);
+        shards[0].data[0] ^= 0xFF;
+        shards.remove(3);
+        shards.reverse();
+        let data = Shard::reconstruct_tolerant(&shards, &new_config, None, u64::MAX)
+            .unwrap();
+        assert_eq!(data, bytes);
+
+        // But two corrupted shards are too many for one parity shard
+        let (_, mut shards, new_config) = shard_with_parity(1);
+        shards[0].data[0] ^= 0xFF;
+        shards[4].data[0] ^= 0xFF;
+        let err = Shard::reconstruct_tolerant(&shards, &new_config, None, u64::MAX)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("only 3 of the 4 shards"));
+    }
+
+    #[test]
+    fn test_decode_fuzzed() {
+        let (_, shards, config) = shard_with_parity(1);
+
+        // Whatever malformed shards and configs decode to can be reconstructed
+        // (or refused) without panicking
+        for bytes in fuzzed(&shards[0].to_bytes().unwrap(), 500) {
+            if let Ok(shard) = Shard::from_bytes(bytes) {
+                let _ = shard.is_valid_for(5);
+            }
+        }
+        for bytes in fuzzed(&config.to_bytes().unwrap(), 500) {
+            if let Ok(config) = ShardConfig::from_bytes(bytes) {
+                let _ = Shard::reconstruct(&shards, &config, None);
+                let _ =
+                    Shard::reconstruct_tolerant(&shards, &config, None, u64::MAX);
+            }
+        }
+        for bytes in fuzzed(&shards[0].id.to_bytes().unwrap(), 100) {
+            let _ = <ShardID as CanSerialize>::from_bytes(bytes.clone());
+            let _ = ShardID::try_from_slice(&bytes);
+        }
+
+        // A config claiming more shards than can be counted is refused
+        let mut claim = config.clone();
+        claim.parity_shards = usize::MAX;
+        assert!(claim.total_shards().is_err());
+        assert!(Shard::reconstruct(&shards, &claim, None).is_err());
+        assert!(
+            Shard::reconstruct_tolerant(&shards, &claim, None, u64::MAX).is_err()
+        );
+
+        // So is one claiming a shard larger than any that was given
+        let mut claim = config.clone();
+        claim.sizes[0] = usize::MAX;
+        assert!(
+            Shard::reconstruct_tolerant(&shards, &claim, None, u64::MAX).is_err()
+        );
+    }
+
+    #[test]
+    fn test_shard_id_try_from_slice() {
+        let bytes = [7u8; HASH_SIZE];
+        assert_eq!(
+            ShardID::try_from_slice(&bytes).unwrap(),
+            ShardID::from_bytes(bytes)
+        );
+
+        for len in [0, HASH_SIZE - 1, HASH_SIZE + 1].iter() {
+            match ShardID::try_from_slice(&vec![7u8; *len]) {
+                Err(PrimitiveError::InvalidLength(l)) => assert_eq!(l, *len),
+                r => panic!("expected an invalid length, got {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_recommended_for() {
+        let (_, pk) = test_keypair();
+
+        // A tiny config file: one compressed shard
+        let c = ShardConfig::recommended_for(2 * KIB, Some("application/json"), &pk);
+        assert_eq!(c.shard_count, 1);
+        assert!(c.compress);
+        assert_eq!(c.max_shard_bytes, Some(16 * MIB as usize));
+
+        // An empty file
+        let c = ShardConfig::recommended_for(0, None, &pk);
+        assert_eq!(c.shard_count, 1);
+        assert!(!c.compress);
+
+        // A document of an unknown type: 1 MiB shards, compressed
+        let c = ShardConfig::recommended_for(10 * MIB, None, &pk);
+        assert_eq!(c.shard_count, 10);
+        assert!(c.compress);
+
+        // A video: big, uncompressed shards
+        let c = ShardConfig::recommended_for(100 * MIB, Some("video/mp4"), &pk);
+        assert_eq!(c.shard_count, 7);
+        assert!(!c.compress);
+        assert_eq!(c.max_shard_bytes, Some(64 * MIB as usize));
+
+        // Archives aren't compressed again, and huge files are capped
+        let c = ShardConfig::recommended_for(
+            10 * 1024 * MIB,
+            Some("application/zip"),
+            &pk,
+        );
+        assert_eq!(c.shard_count, crate::p2p::MAX_SHARDS);
+        assert!(!c.compress);
+    }
+
+    #[test]
+    fn test_shard_max_shard_bytes() {
+        let mut c = config(1);
+        c.max_shard_bytes = Some(10);
+
+        let bytes = vec![7u8; 72];
+        let (shards, new_config) = Shard::shard(&bytes, c.clone()).unwrap();
+        assert!(shards.iter().all(|s| s.data.len() <= 10));
+        assert_eq!(new_config.shard_count, shards.len());
+        assert_eq!(
+            Shard::reconstruct(&shards, &new_config, None).unwrap(),
+            bytes
+        );
+
+        c.max_shard_bytes = Some(0);
+        assert!(Shard::shard(&bytes, c).is_err());
+    }
+
+    #[test]
+    fn test_calc_shard_sizes_edge_cases() {
+        assert_eq!(calculate_shard_sizes(0, 5).unwrap(), Vec::<usize>::new());
+        assert_eq!(calculate_shard_sizes(1, 5).unwrap(), vec![1]);
+        assert_eq!(calculate_shard_sizes(3, 5).unwrap(), vec![1, 1, 1]);
+        assert_eq!(calculate_shard_sizes(5, 5).unwrap(), vec![1, 1, 1, 1, 1]);
+        assert!(calculate_shard_sizes(10, 0).is_err());
+    }
+
+    #[test]
+    fn test_shard_empty() {
+        let (shards, new_config) = Shard::shard(&Vec::new(), config(5)).unwrap();
+        assert!(shards.is_empty());
+        assert_eq!(new_config.shard_count, 0);
+        assert!(new_config.sizes.is_empty());
+
+        let reconstructed = Shard::reconstruct(&shards, &new_config, None).unwrap();
+        assert!(reconstructed.is_empty());
+    }
+
+    #[test]
+    fn test_shard_single_byte() {
+        let (shards, new_config) = Shard::shard(&vec![42u8], config(5)).unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(new_config.shard_count, 1);
+        assert_eq!(new_config.sizes, vec![1]);
+
+        let reconstructed = Shard::reconstruct(&shards, &new_config, None).unwrap();
+        assert_eq!(reconstructed, vec![42u8]);
+    }
+
+    #[test]
+    fn test_shard_fewer_bytes_than_shards() {
+        let bytes = vec![1u8, 2, 3];
+        let (shards, new_config) = Shard::shard(&bytes, config(5)).unwrap();
+        assert_eq!(shards.len(), 3);
+        assert_eq!(new_config.shard_count, 3);
+
+        let reconstructed = Shard::reconstruct(&shards, &new_config, None).unwrap();
+        assert_eq!(reconstructed, bytes);
+    }
+
+    #[test]
+    fn test_reconstruct_no_encrypt() {
+        let b: Vec<u8> = (0..0xFFu8).collect();
+        let (_, pub_key) = test_keypair();
+        let shard_count = 6;
+
+        let config = ShardConfig {
+            shard_count,
+            pub_key: pub_key.clone(),
+            compress: false,
+            encrypt: false,
+            sizes: Vec::new(),
+            ..ShardConfig::new(shard_count, &pub_key)
+        };
+        let (shards, new_config) = Shard::shard(&b, config).unwrap();
+        assert_eq!(new_config.sizes.len(), shard_count);
+
+        let reconstructed = Shard::reconstruct(&shards, &new_config, None).unwrap();
+        assert_eq!(b, reconstructed);
+    }
+
+    #[test]
+    fn test_reconstruct_encrypt() {
+        let b: Vec<u8> = (0..0xFFu8).collect();
+        let (priv_key, pub_key) = test_keypair();
+        let sc = 11; // Whatever (shard count)
+
+        // Shard with encryption
+        let config = ShardConfig {
+            shard_count: sc,
+            pub_key: pub_key.clone(),
+            compress: false,
+            encrypt: true,
+            sizes: Vec::new(),
+            ..ShardConfig::new(sc, &pub_key)
+        };
+        let (shards, new_config) = Shard::shard(&b, config).unwrap();
+
+        // Reconstruct with the matching key
+        let reconstructed_b =
+            Shard::reconstruct(&shards, &new_config, Some(&priv_key)).unwrap();
+        assert_eq!(b, reconstructed_b);
+
+        // Encrypted shards can't be reconstructed without a key
+        assert!(Shard::reconstruct(&shards, &new_config, None).is_err());
+    }
+
+    #[test]
+    fn test_reconstruct_encrypt_wrong_key() {
+        let b: Vec<u8> = (0..0xFFu8).collect();
+        let (_, pub_key) = test_keypair();
+        let (wrong_key, _) = test_keypair();
+
+        let mut config = ShardConfig::new(5, &pub_key);
+        config.encrypt = true;
+        config.compress = true;
+        let (shards, new_config) = Shard::shard(&b, config).unwrap();
+
+        assert!(Shard::reconstruct(&shards, &new_config, Some(&wrong_key)).is_err());
+    }
+}
+
 /*
 #[cfg(test)]
 mod tests {
@@ -435,88 +2014,5 @@ mod tests {
             test_shard_case(b, rng.gen_range(1, len));
         }
     }
-
-    #[test]
-    fn test_reconstruct_no_encrypt() {
-        let mut b: Vec<u8> = Vec::new();
-        for byte in 0..0xFF {
-            b.push(byte);
-        }
-
-        let shard_count = 6;
-
-        let (shards, _) = Shard::shard(
-            b.clone(),
-            ShardConfig {
-                shard_count,
-                public_key: None,
-                private_key: None,
-                compress: false,
-            },
-        )
-        .unwrap();
-
-        let reconstructed = Shard::reconstruct(
-            &shards,
-            ShardConfig {
-                shard_count,
-                public_key: None,
-                private_key: None,
-                compress: false,
-            },
-        )
-        .unwrap();
-
-        assert_eq!(b, reconstructed);
-    }
-
-    #[test]
-    fn test_reconstruct_encrypt() {
-        let mut b: Vec<u8> = Vec::new();
-        for byte in 0..0xFF {
-            b.push(byte);
-        }
-
-        let (pub_key, priv_key) = (
-            encryption::load_pub_key(&encryption::KeyType::Public(
-                "testkey".to_string(),
-            ))
-            .unwrap(),
-            encryption::load_priv_key(&encryption::KeyType::Private(
-                "testkey".to_string(),
-            ))
-            .unwrap(),
-        );
-
-        let sc = 11; // Whatever (shard count)
-
-        // Shard with encryption
-        let (shards, _) = Shard::shard(
-            b.clone(),
-            ShardConfig {
-                shard_count: sc,
-                // public_key: None,
-                public_key: Some(pub_key),
-                private_key: None,
-                compress: false,
-            },
-        )
-        .unwrap();
-
-        // Reconstruct
-        let reconstructed_b = Shard::reconstruct(
-            &shards, // The shards themselves
-            ShardConfig {
-                shard_count: sc,
-                public_key: None,
-                // private_key: None,
-                private_key: Some(priv_key),
-                compress: false,
-            },
-        )
-        .unwrap();
-
-        assert_eq!(b, reconstructed_b);
-    }
 }
 */
@@ -0,0 +1,147 @@
+use super::shard::{calculate_shard_sizes, split_bytes, Shard};
+use crate::GeneralError;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// A way of splitting bytes into shards, and of joining them back together.
+pub trait ShardingStrategy {
+    /// Split bytes into `n` shards, indexed in order. Empty bytes have no
+    /// shards, and fewer bytes than `n` get one shard per byte.
+    fn split(&self, bytes: &[u8], n: usize) -> Result<Vec<Shard>, Box<dyn Error>>;
+
+    /// Join the data of the shards made by `split` (in order) back into the
+    /// bytes that were split.
+    fn join(&self, shards: &[&[u8]]) -> Vec<u8>;
+}
+
+/// Splits bytes into contiguous runs of (nearly) equal size. The last shard
+/// holds the bytes that are left over.
+pub struct ContiguousStrategy;
+
+impl ShardingStrategy for ContiguousStrategy {
+    fn split(&self, bytes: &[u8], n: usize) -> Result<Vec<Shard>, Box<dyn Error>> {
+        split_bytes(bytes, &calculate_shard_sizes(bytes.len(), n)?)
+    }
+
+    fn join(&self, shards: &[&[u8]]) -> Vec<u8> {
+        shards.concat()
+    }
+}
+
+/// Deals bytes out to the shards one at a time, so that the shard at index
+/// `i` of `n` holds the bytes at `i`, `i + n`, `i + 2n`, and so on. Reading
+/// the shards in parallel then reads the bytes from the front to the back.
+pub struct StripedStrategy;
+
+impl ShardingStrategy for StripedStrategy {
+    fn split(&self, bytes: &[u8], n: usize) -> Result<Vec<Shard>, Box<dyn Error>> {
+        if n == 0 {
+            return Err(Box::new(GeneralError::new(
+                "can't split bytes into zero shards",
+            )));
+        }
+        if bytes.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Every shard must hold at least one byte
+        let n = n.min(bytes.len());
+        let mut stripes = vec![Vec::with_capacity(bytes.len() / n + 1); n];
+        for (i, byte) in bytes.iter().enumerate() {
+            stripes[i % n].push(*byte);
+        }
+        stripes
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| Shard::new(data, i as u32))
+            .collect()
+    }
+
+    fn join(&self, shards: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(shards.iter().map(|s| s.len()).sum());
+        let rows = shards.iter().map(|s| s.len()).max().unwrap_or(0);
+        for row in 0..rows {
+            for shard in shards.iter() {
+                if let Some(byte) = shard.get(row) {
+                    bytes.push(*byte);
+                }
+            }
+        }
+        bytes
+    }
+}
+
+/// The strategy that the bytes of a file are split into shards with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharding {
+    /// Contiguous runs of bytes (`ContiguousStrategy`)
+    Contiguous,
+
+    /// Interleaved bytes (`StripedStrategy`)
+    Striped,
+}
+
+impl Default for Sharding {
+    fn default() -> Self {
+        Sharding::Contiguous
+    }
+}
+
+impl Sharding {
+    /// The implementation of the strategy.
+    pub fn strategy(&self) -> &'static dyn ShardingStrategy {
+        match self {
+            Sharding::Contiguous => &ContiguousStrategy,
+            Sharding::Striped => &StripedStrategy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(sharding: Sharding, bytes: &[u8], n: usize) -> Vec<Shard> {
+        let strategy = sharding.strategy();
+        let shards = strategy.split(bytes, n).unwrap();
+        assert_eq!(shards.len(), n.min(bytes.len()));
+        for (i, shard) in shards.iter().enumerate() {
+            assert_eq!(shard.index(), i as u32);
+            assert!(!shard.data.is_empty());
+        }
+
+        let data: Vec<&[u8]> = shards.iter().map(Shard::data_ref).collect();
+        assert_eq!(strategy.join(&data), bytes);
+        shards
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes: Vec<u8> = (0..=255u8).collect();
+        for sharding in [Sharding::Contiguous, Sharding::Striped].iter() {
+            for len in [0, 1, 2, 7, 100, 256].iter() {
+                for n in [1, 3, 5, 16, 300].iter() {
+                    round_trip(*sharding, &bytes[..*len], *n);
+                }
+            }
+            assert!(sharding.strategy().split(&bytes, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn test_layouts() {
+        let bytes: Vec<u8> = (0..10u8).collect();
+        let data = |shards: Vec<Shard>| -> Vec<Vec<u8>> {
+            shards.into_iter().map(|s| s.data).collect()
+        };
+
+        assert_eq!(
+            data(round_trip(Sharding::Contiguous, &bytes, 3)),
+            vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8, 9]]
+        );
+        assert_eq!(
+            data(round_trip(Sharding::Striped, &bytes, 3)),
+            vec![vec![0, 3, 6, 9], vec![1, 4, 7], vec![2, 5, 8]]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! Fixtures shared by the tests of every module.
+
+use crate::{
+    p2p::store::ShardStore,
+    primitives::{
+        file::File,
+        shard::{Shard, ShardConfig},
+    },
+};
+use ecies_ed25519::{PublicKey, SecretKey};
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+use std::io::Write;
+use tempfile::{NamedTempFile, TempDir, TempPath};
+
+/// Some bytes to build sample files out of.
+pub const SAMPLE: &[u8] =
+    b"The quick brown fox jumps over the lazy dog. Meros shards this sentence.";
+
+/// Write `contents` to a new temporary file. The file is deleted when the
+/// returned path is dropped.
+pub fn temp_file(contents: &[u8]) -> TempPath {
+    let mut file = NamedTempFile::new().expect("could not create temp file");
+    file.write_all(contents).expect("could not write temp file");
+    file.into_temp_path()
+}
+
+/// Open a shard store in a new temporary directory. The store is deleted
+/// along with the directory when the returned `TempDir` is dropped.
+pub fn fresh_store() -> (ShardStore, TempDir) {
+    let dir = tempfile::tempdir().expect("could not create temp dir");
+    let store =
+        ShardStore::open(&dir.path().join("store")).expect("could not open store");
+    (store, dir)
+}
+
+/// Generate a fresh keypair without writing it to the disk.
+pub fn test_keypair() -> (SecretKey, PublicKey) {
+    ecies_ed25519::generate_keypair(&mut rand::thread_rng())
+}
+
+/// Create a file (and its shards) holding `bytes`, split into `shards` shards
+/// and signed by a fresh key.
+pub fn sample_file(bytes: &[u8], shards: usize) -> (File, Vec<Shard>) {
+    let (sk, pk) = test_keypair();
+    sample_file_with(bytes, ShardConfig::new(shards, &pk), &sk)
+}
+
+/// Malformed inputs to feed a decoder, derived from a valid encoding: every
+/// truncation of `valid`, `n` copies of it with a few bytes changed at random,
+/// and `n` strings of random bytes. The inputs are the same on
+/// every run, so a failure can be reproduced.
+pub fn fuzzed(valid: &[u8], n: usize) -> Vec<Vec<u8>> {
+    let mut rng = StdRng::seed_from_u64(valid.len() as u64);
+    let mut inputs: Vec<Vec<u8>> =
+        (0..valid.len()).map(|i| valid[..i].to_vec()).collect();
+    for _ in 0..n {
+        if !valid.is_empty() {
+            let mut mutated = valid.to_vec();
+            let changed = rng.gen_range(1, 4).min(valid.len());
+            for i in index::sample(&mut rng, valid.len(), changed).into_iter() {
+                mutated[i] ^= rng.gen_range(1, 256) as u8;
+            }
+            inputs.push(mutated);
+        }
+
+        let len = rng.gen_range(0, 2 * valid.len() + 2);
+        inputs.push((0..len).map(|_| rng.gen()).collect());
+    }
+    inputs
+}
+
+/// Create a file (and its shards) holding `bytes`, using the given config and
+/// signing key.
+pub fn sample_file_with(
+    bytes: &[u8],
+    config: ShardConfig,
+    sk: &SecretKey,
+) -> (File, Vec<Shard>) {
+    let path = temp_file(bytes);
+    File::new(&path, config, sk).expect("could not create sample file")
+}
@@ -0,0 +1,103 @@
+//! End-to-end tests that run several in-process nodes which find each other
+//! over mdns. They need a network that allows multicast, so they only run with
+//! `cargo test --features network-tests`.
+#![cfg(feature = "network-tests")]
+
+use async_std::{future::timeout, task};
+use futures::{future, FutureExt, StreamExt};
+use rust_meros::{
+    p2p::{
+        config::NodeConfig,
+        node::{Node, OperationConfig, Output},
+        progress::PutProgress,
+    },
+    primitives::{file::File, shard::ShardConfig},
+};
+use std::{io::Write, time::Duration};
+
+/// Create a node that is kept in a temporary directory, which is removed
+/// (along with the node's identity and store) once it is dropped.
+fn test_node() -> (Node, tempfile::TempDir) {
+    let dir = tempfile::tempdir().expect("could not create a temporary directory");
+    let node = Node::in_dir(dir.path(), NodeConfig::default())
+        .expect("could not create node");
+    (node, dir)
+}
+
+fn operation_config() -> OperationConfig {
+    OperationConfig {
+        output: Output::Discard,
+        min_nodes: 0,
+        decrypt: false,
+        wait_for_peers: None,
+        force: false,
+    }
+}
+
+#[async_std::test]
+async fn test_put_then_get() {
+    let bytes: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let mut source = tempfile::NamedTempFile::new().unwrap();
+    source.write_all(&bytes).unwrap();
+
+    let (sk, pk) = ecies_ed25519::generate_keypair(&mut rand::thread_rng());
+    let (file, _) = File::new(source.path(), ShardConfig::new(4, &pk), &sk).unwrap();
+
+    // The putter and a node to hold the shards
+    let (mut putter, _putter_dir) = test_node();
+    let (mut holder, _holder_dir) = test_node();
+    let mut progress = putter.put_file_with_progress(
+        file.clone(),
+        bytes.clone(),
+        operation_config(),
+    );
+
+    // A third node that joins once the file is on the network and gets it
+    let (mut getter, _getter_dir) = test_node();
+    let mut shards = getter.get_file_streaming(file.id.clone());
+
+    let scenario = async {
+        while let Some(event) = progress.next().await {
+            if event == PutProgress::Complete {
+                break;
+            }
+        }
+        // Give the holder time to store the shards
+        task::sleep(Duration::from_secs(2)).await;
+
+        let collect = async {
+            let mut received = Vec::new();
+            while let Some(shard) = shards.next().await {
+                received.push(shard.expect("get failed"));
+            }
+            received
+        };
+        match future::select(
+            getter.start_listening(0).boxed_local(),
+            collect.boxed_local(),
+        )
+        .await
+        {
+            future::Either::Right((received, _)) => received,
+            future::Either::Left((result, _)) => {
+                panic!("getter stopped: {:?}", result)
+            }
+        }
+    };
+
+    let network = future::join(putter.start_listening(0), holder.start_listening(0));
+    let mut received = match timeout(
+        Duration::from_secs(120),
+        future::select(network.boxed_local(), scenario.boxed_local()),
+    )
+    .await
+    .expect("put then get timed out")
+    {
+        future::Either::Right((received, _)) => received,
+        future::Either::Left(_) => panic!("the nodes stopped"),
+    };
+
+    received.sort_by_key(|(index, _)| *index);
+    let got: Vec<u8> = received.into_iter().flat_map(|(_, data)| data).collect();
+    assert_eq!(got, bytes);
+}